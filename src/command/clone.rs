@@ -1,13 +1,29 @@
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
 
 use anyhow::anyhow;
+use anyhow::Context;
 use anyhow::Result;
 use reqwest::Url;
 use structopt::StructOpt;
 
-use crate::packfile::refs;
-use crate::store::Repo;
+use crate::packfile::refs::{
+    self,
+    GitRef,
+};
+use crate::progress;
+use crate::remote::refspec::Refspec;
+use crate::store::{
+    Config,
+    Repo,
+    Sha,
+};
+
+/// Tags are copied as they are, but aren't fetched again by default.
+const TAG_REFSPEC: &str = "+refs/tags/*:refs/tags/*";
 
 #[derive(StructOpt)]
 #[structopt(name = "clone", about = "clone a remote repository")]
@@ -15,10 +31,49 @@ pub struct SubcommandClone {
     #[structopt(parse(try_from_str = super::parse_git_url))]
     remote_url: Url,
     dir: Option<PathBuf>,
+    /// Copy objects from a local repository instead of hardlinking them
+    #[structopt(long)]
+    no_hardlinks: bool,
+    /// Borrow objects from a local repository through alternates instead of copying them
+    #[structopt(long, short = "s")]
+    shared: bool,
+    /// Clone a local repository through the regular transport instead of copying its objects
+    #[structopt(long)]
+    no_local: bool,
+    /// Don't report progress
+    #[structopt(long, short = "q")]
+    quiet: bool,
+    /// Report progress even when stderr isn't a terminal
+    #[structopt(long)]
+    progress: bool,
+    /// Check out this branch, or tag, instead of the one the remote's HEAD points to
+    #[structopt(long, short = "b")]
+    branch: Option<String>,
+    /// Only fetch the branch being checked out, along with the tags in its history
+    #[structopt(long)]
+    single_branch: bool,
+    /// Name the remote this instead of origin
+    #[structopt(long, short = "o", default_value = "origin")]
+    origin: String,
+    #[structopt(flatten)]
+    transport: super::TransportOptions,
+}
+
+///
+/// The ref a clone checks out, and the commit to check out.
+///
+struct Checkout {
+    /// The remote's name for the ref, e.g. `refs/heads/master`.
+    remote_ref: String,
+    id: String,
 }
 
 impl SubcommandClone {
     pub fn execute(&self) -> Result<()> {
+        progress::configure(self.quiet, self.progress);
+        if !refs::is_valid_ref_name(&format!("refs/remotes/{}", self.origin)) {
+            return Err(anyhow!("'{}' is not a valid remote name", self.origin));
+        }
         let dir = self
             .dir
             .clone()
@@ -31,19 +86,474 @@ impl SubcommandClone {
             })
             .ok_or_else(|| anyhow!("could not infer repo directory from url"))?;
 
-        let mut client = super::create_client(&self.remote_url)?;
-        println!("Cloning into \"{}\"...", dir.as_os_str().to_string_lossy());
-
-        let refs = client.discover_refs()?;
-        let packfile_data = client.fetch_packfile(&refs)?;
+        let local_path = match self.remote_url.scheme() {
+            "file" if !self.no_local => Some(
+                self.remote_url
+                    .to_file_path()
+                    .map_err(|_| anyhow!("invalid file url: {}", self.remote_url))?,
+            ),
+            _ => None,
+        };
 
-        let repo = Repo::from_packfile(&dir, &packfile_data)?;
+        if !self.quiet {
+            println!("Cloning into \"{}\"...", dir.as_os_str().to_string_lossy());
+        }
+        let (repo, refs, head_target) = match local_path {
+            Some(source) => self.clone_local(&source, &dir)?,
+            None => {
+                // There's no repository yet, so only the global config applies.
+                let config = Config::load(None)?;
+                let mut client = super::create_client(&self.remote_url, &config, &self.transport)?;
+                let refs = client.discover_refs()?;
+                let head_target = client.head_target().map(str::to_owned);
+                let checkout = self.choose_checkout(&refs, head_target.as_deref())?;
+                let wanted = self.wanted_refs(&refs, &checkout)?;
+                let packfile_data = client.fetch_packfile(&wanted)?;
+                (
+                    Repo::from_packfile(&dir, &packfile_data)?,
+                    refs,
+                    head_target,
+                )
+            }
+        };
+        let checkout = self.choose_checkout(&refs, head_target.as_deref())?;
 
-        refs::create_refs(repo.gitdir(), &refs)?;
-        refs::update_head(repo.gitdir(), &refs)?;
+        let refs = if self.single_branch {
+            self.single_branch_refs(&repo, &refs, &checkout)?
+        } else {
+            refs
+        };
+        let fetch = self.fetch_refspec(&checkout)?;
+        // The config comes first since it decides which refs get reflogs.
+        self.write_config(&repo, &fetch, &checkout)?;
+        let message = format!("clone: from {}", self.remote_url);
+        let tags = Refspec::parse(TAG_REFSPEC)?;
+        refs::create_refs(&repo, &[fetch.clone(), tags], &refs, &message)?;
+        // The remote's HEAD is recorded even when we check out something else.
+        let remote_head = refs::remote_head_branch(&refs, head_target.as_deref())
+            .and_then(|branch| fetch.map(&branch.name));
+        if let Some(tracking) = remote_head {
+            refs::set_symbolic_ref(
+                repo.gitdir(),
+                &format!("refs/remotes/{}/HEAD", self.origin),
+                &tracking,
+                &message,
+            )?;
+        }
 
-        // Checkout head and format refs
+        let checkout = match checkout {
+            Some(checkout) => checkout,
+            // The remote is empty, so there's nothing to check out.
+            None => return Ok(()),
+        };
+        if checkout.remote_ref.starts_with("refs/heads/") {
+            refs::set_head_branch(repo.gitdir(), &checkout.remote_ref, &checkout.id, &message)?;
+        } else {
+            // Tags are checked out at the commit they point to.
+            let id = repo.peel(&Sha::from_hex(checkout.id.as_bytes())?)?;
+            refs::set_head_detached(repo.gitdir(), &id.hex(), &message)?;
+        }
         repo.checkout_head()?;
         Ok(())
     }
+
+    ///
+    /// Maps the remote's branches to remote-tracking branches, or just the
+    /// one being checked out for a single branch clone.
+    ///
+    fn fetch_refspec(&self, checkout: &Option<Checkout>) -> Result<Refspec> {
+        let branch = checkout
+            .as_ref()
+            .map(|c| &c.remote_ref[..])
+            .and_then(|name| name.strip_prefix("refs/heads/"));
+        let branch = match branch {
+            Some(branch) if self.single_branch => branch,
+            _ => "*",
+        };
+        Refspec::parse(&format!(
+            "+refs/heads/{}:refs/remotes/{}/{}",
+            branch, self.origin, branch
+        ))
+    }
+
+    ///
+    /// Writes the new repository's config, recording the remote we cloned
+    /// from and making the checked out branch track its counterpart there.
+    ///
+    fn write_config(
+        &self,
+        repo: &Repo,
+        fetch: &Refspec,
+        checkout: &Option<Checkout>,
+    ) -> Result<()> {
+        let mut config = Config::default();
+        config.add("core.repositoryformatversion", "0");
+        config.add("core.filemode", "true");
+        config.add("core.bare", "false");
+        config.add("core.logallrefupdates", "true");
+
+        let remote = format!("remote.{}", self.origin);
+        config.add(&format!("{}.url", remote), self.remote_url.as_str());
+        let branch = checkout
+            .as_ref()
+            .map(|c| &c.remote_ref[..])
+            .filter(|name| name.starts_with("refs/heads/"));
+        config.add(&format!("{}.fetch", remote), &fetch.to_string());
+
+        if let Some(name) = branch {
+            let section = format!("branch.{}", name.trim_start_matches("refs/heads/"));
+            config.add(&format!("{}.remote", section), &self.origin);
+            config.add(&format!("{}.merge", section), name);
+        }
+        config.write(repo.gitdir().join("config"))
+    }
+
+    ///
+    /// Picks the ref to check out: the branch or tag given with `--branch`,
+    /// or else the branch the remote's HEAD points to.
+    ///
+    fn choose_checkout(
+        &self,
+        refs: &[GitRef],
+        head_target: Option<&str>,
+    ) -> Result<Option<Checkout>> {
+        let chosen = match &self.branch {
+            Some(name) => [
+                format!("refs/heads/{}", name),
+                format!("refs/tags/{}", name),
+            ]
+            .iter()
+            .find_map(|full_name| refs.iter().find(|r| &r.name == full_name))
+            .ok_or_else(|| {
+                anyhow!(
+                    "remote branch {} not found in upstream {}",
+                    name,
+                    self.origin
+                )
+            })?,
+            // A HEAD which matches no branch is checked out detached.
+            None => match refs::remote_head_branch(refs, head_target)
+                .or_else(|| refs.iter().find(|r| r.name == "HEAD"))
+            {
+                Some(chosen) => chosen,
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(Checkout {
+            remote_ref: chosen.name.clone(),
+            id: chosen.id.clone(),
+        }))
+    }
+
+    ///
+    /// The refs to fetch objects for: those the refspecs map along with the
+    /// one being checked out, or just that one for a single branch clone.
+    ///
+    fn wanted_refs(&self, refs: &[GitRef], checkout: &Option<Checkout>) -> Result<Vec<GitRef>> {
+        let refspecs = if self.single_branch {
+            Vec::new()
+        } else {
+            vec![self.fetch_refspec(checkout)?, Refspec::parse(TAG_REFSPEC)?]
+        };
+        let checkout = checkout.as_ref().map(|c| &c.remote_ref);
+        Ok(refs
+            .iter()
+            .filter(|r| {
+                checkout == Some(&r.name) || refspecs.iter().any(|spec| spec.map(&r.name).is_some())
+            })
+            .cloned()
+            .collect())
+    }
+
+    ///
+    /// Narrows the refs recorded for a single branch clone down to that
+    /// branch and the tags whose objects came along with it.
+    ///
+    fn single_branch_refs(
+        &self,
+        repo: &Repo,
+        refs: &[GitRef],
+        checkout: &Option<Checkout>,
+    ) -> Result<Vec<GitRef>> {
+        let mut kept = Vec::new();
+        for r in refs {
+            let keep = if r.name.starts_with("refs/tags/") {
+                repo.has_object(&Sha::from_hex(r.id.as_bytes())?)
+            } else {
+                checkout.as_ref().is_some_and(|c| c.remote_ref == r.name)
+            };
+            if keep {
+                kept.push(r.clone());
+            }
+        }
+        Ok(kept)
+    }
+
+    ///
+    /// Clones a repository on the local filesystem by linking or copying its
+    /// objects directly, rather than having it build a pack.
+    ///
+    fn clone_local(
+        &self,
+        source: &Path,
+        dir: &Path,
+    ) -> Result<(Repo, Vec<GitRef>, Option<String>)> {
+        let source = Repo::open(source)?;
+        let refs = refs::read_refs(source.gitdir())?;
+        let head_target = refs::read_head_target(source.gitdir())?;
+
+        let source_objects = fs::canonicalize(source.gitdir().join("objects"))?;
+        let objects_dir = dir.join(".git").join("objects");
+        fs::create_dir_all(objects_dir.join("info"))?;
+        if self.shared {
+            let mut alternates = source_objects.as_os_str().as_bytes().to_vec();
+            alternates.push(b'\n');
+            fs::write(objects_dir.join("info/alternates"), alternates)?;
+        } else {
+            copy_objects(&source_objects, &objects_dir, !self.no_hardlinks)
+                .context("copy objects")?;
+        }
+        Ok((Repo::open(dir)?, refs, head_target))
+    }
+}
+
+///
+/// Recursively copies the objects directory `from` into `to`, hardlinking
+/// files when `hardlink` is set. If a link can't be made, e.g. because the
+/// directories are on different filesystems, files are copied instead.
+///
+fn copy_objects(from: &Path, to: &Path, mut hardlink: bool) -> io::Result<()> {
+    let mut pending = vec![(from.to_owned(), to.to_owned())];
+    while let Some((from, to)) = pending.pop() {
+        fs::create_dir_all(&to)?;
+        for entry in fs::read_dir(&from)? {
+            let entry = entry?;
+            let target = to.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                pending.push((entry.path(), target));
+                continue;
+            }
+            if hardlink && fs::hard_link(entry.path(), &target).is_ok() {
+                continue;
+            }
+            hardlink = false;
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::packfile::reflog;
+
+    static PACK: &str = "objects/pack/pack-79f006bb5e8d079fdbe07e7ce41f97f4db7d341c.pack";
+    static MASTER: &[u8] = b"33676d1c63d868803ed110b13be4e616bc8a29b7";
+    static MERGE: &[u8] = b"3c7cfac73a699ef415bc737ce5529ac66c5692a9";
+
+    fn clone(source: &Path, dir: &Path, configure: impl FnOnce(&mut SubcommandClone)) -> Repo {
+        let mut clone = options(source, dir);
+        configure(&mut clone);
+        clone.execute().unwrap();
+        Repo::open(dir).unwrap()
+    }
+
+    fn options(source: &Path, dir: &Path) -> SubcommandClone {
+        SubcommandClone {
+            remote_url: Url::from_file_path(source).unwrap(),
+            dir: Some(dir.to_owned()),
+            no_hardlinks: false,
+            shared: false,
+            no_local: false,
+            quiet: true,
+            progress: false,
+            branch: None,
+            single_branch: false,
+            origin: "origin".into(),
+            transport: Default::default(),
+        }
+    }
+
+    fn source_repo(tmp: &Path) -> PathBuf {
+        let source = tmp.join("simple.git");
+        copy_objects(Path::new("tests/data/repos/simple.git"), &source, false).unwrap();
+        source
+    }
+
+    #[test]
+    fn cloning_locally_with_hardlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_repo(tmp.path());
+        let dir = tmp.path().join("simple");
+        let repo = clone(&source, &dir, |_| {});
+
+        let source_pack = fs::metadata(source.join(PACK)).unwrap();
+        let cloned_pack = fs::metadata(repo.gitdir().join(PACK)).unwrap();
+        assert_eq!(source_pack.ino(), cloned_pack.ino());
+        assert!(dir.join("git.txt").is_file());
+        assert!(repo.gitdir().join("refs/remotes/origin/master").is_file());
+    }
+
+    #[test]
+    fn cloning_locally_without_hardlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_repo(tmp.path());
+        let dir = tmp.path().join("simple");
+        let repo = clone(&source, &dir, |c| c.no_hardlinks = true);
+
+        let source_pack = fs::metadata(source.join(PACK)).unwrap();
+        let cloned_pack = fs::metadata(repo.gitdir().join(PACK)).unwrap();
+        assert_ne!(source_pack.ino(), cloned_pack.ino());
+    }
+
+    #[test]
+    fn cloning_locally_with_shared_objects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_repo(tmp.path());
+        let dir = tmp.path().join("simple");
+        let repo = clone(&source, &dir, |c| c.shared = true);
+
+        assert!(!repo.gitdir().join(PACK).exists());
+        assert!(repo.gitdir().join("objects/info/alternates").is_file());
+        let master = Sha::from_hex(MASTER).unwrap();
+        assert!(repo.read_object(&master).is_ok());
+    }
+
+    #[test]
+    fn cloning_locally_through_the_transport() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_repo(tmp.path());
+        let dir = tmp.path().join("simple");
+        let repo = clone(&source, &dir, |c| c.no_local = true);
+
+        assert!(!repo.gitdir().join(PACK).exists());
+        assert!(dir.join("git.txt").is_file());
+    }
+
+    #[test]
+    fn checking_out_the_branch_the_remote_head_points_to() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_repo(tmp.path());
+        // Both branches are at the same commit, so only the symref can say
+        // which one HEAD is on.
+        fs::write(source.join("HEAD"), "ref: refs/heads/test\n").unwrap();
+        fs::copy(
+            source.join("refs/heads/test"),
+            source.join("refs/heads/master"),
+        )
+        .unwrap();
+
+        for no_local in [false, true] {
+            let dir = tmp.path().join(format!("simple-{}", no_local));
+            let repo = clone(&source, &dir, |c| c.no_local = no_local);
+            let head = fs::read_to_string(repo.gitdir().join("HEAD")).unwrap();
+            assert_eq!(head, "ref: refs/heads/test\n");
+            assert!(repo.gitdir().join("refs/heads/test").is_file());
+        }
+    }
+
+    #[test]
+    fn cloning_a_single_branch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_repo(tmp.path());
+        let dir = tmp.path().join("simple");
+        let repo = clone(&source, &dir, |c| {
+            c.no_local = true;
+            c.branch = Some("test".into());
+            c.single_branch = true;
+        });
+
+        let head = fs::read_to_string(repo.gitdir().join("HEAD")).unwrap();
+        assert_eq!(head, "ref: refs/heads/test\n");
+        assert!(repo.gitdir().join("refs/remotes/origin/test").is_file());
+        assert!(!repo.gitdir().join("refs/remotes/origin/master").exists());
+        assert_eq!(
+            repo.config().unwrap().get("remote.origin.fetch"),
+            Some("+refs/heads/test:refs/remotes/origin/test")
+        );
+    }
+
+    #[test]
+    fn cloning_a_tag() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_repo(tmp.path());
+        let dir = tmp.path().join("simple");
+        let repo = clone(&source, &dir, |c| c.branch = Some("test_tag".into()));
+
+        // The tag is checked out at its commit, with HEAD detached.
+        let head = fs::read(repo.gitdir().join("HEAD")).unwrap();
+        assert_eq!(head, [MERGE, b"\n"].concat());
+
+        let mut clone = options(&source, &tmp.path().join("missing"));
+        clone.branch = Some("missing".into());
+        clone.origin = "upstream".into();
+        let err = clone.execute().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "remote branch missing not found in upstream upstream"
+        );
+    }
+
+    #[test]
+    fn recording_the_remote_in_the_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_repo(tmp.path());
+        let dir = tmp.path().join("simple");
+        let repo = clone(&source, &dir, |c| c.origin = "upstream".into());
+
+        let config = repo.config().unwrap();
+        let url = Url::from_file_path(&source).unwrap();
+        assert_eq!(config.get("remote.upstream.url"), Some(url.as_str()));
+        assert_eq!(
+            config.get("remote.upstream.fetch"),
+            Some("+refs/heads/*:refs/remotes/upstream/*")
+        );
+        assert_eq!(config.get("branch.master.remote"), Some("upstream"));
+        assert_eq!(config.get("branch.master.merge"), Some("refs/heads/master"));
+        assert_eq!(
+            fs::read_to_string(repo.gitdir().join("refs/remotes/upstream/HEAD")).unwrap(),
+            "ref: refs/remotes/upstream/master\n"
+        );
+        assert!(!repo.gitdir().join("refs/remotes/origin").exists());
+        let head_log = reflog::read(repo.gitdir(), "HEAD").unwrap();
+        assert_eq!(head_log.len(), 1);
+        assert_eq!(head_log[0].message, format!("clone: from {}", url));
+
+        let mut clone = options(&source, &tmp.path().join("invalid"));
+        clone.origin = "bad..name".into();
+        assert!(clone.execute().is_err());
+    }
+
+    #[test]
+    fn keeping_hierarchical_ref_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_repo(tmp.path());
+        let test = fs::read(source.join("refs/heads/test")).unwrap();
+        for name in ["feature/login", "bugfix/login"] {
+            fs::create_dir_all(source.join("refs/heads").join(name).parent().unwrap()).unwrap();
+        }
+        fs::write(source.join("refs/heads/feature/login"), &test).unwrap();
+        fs::write(
+            source.join("refs/heads/bugfix/login"),
+            [MASTER, b"\n"].concat(),
+        )
+        .unwrap();
+        fs::create_dir_all(source.join("refs/notes")).unwrap();
+        fs::write(source.join("refs/notes/commits"), &test).unwrap();
+
+        let dir = tmp.path().join("simple");
+        let repo = clone(&source, &dir, |c| c.no_local = true);
+        let remotes = repo.gitdir().join("refs/remotes/origin");
+        assert_eq!(fs::read(remotes.join("feature/login")).unwrap(), test);
+        assert_eq!(
+            fs::read(remotes.join("bugfix/login")).unwrap(),
+            [MASTER, b"\n"].concat()
+        );
+        // Only branches and tags are covered by the refspecs.
+        assert!(!remotes.join("commits").exists());
+        assert!(!repo.gitdir().join("refs/notes").exists());
+    }
 }
@@ -0,0 +1,43 @@
+use anyhow::{
+    anyhow,
+    Result,
+};
+use structopt::StructOpt;
+
+use crate::store::Repo;
+
+#[derive(StructOpt)]
+#[structopt(name = "commit-graph", about = "write the commit-graph")]
+pub struct SubcommandCommitGraph {
+    #[structopt(subcommand)]
+    command: CommitGraphCommand,
+}
+
+#[derive(StructOpt)]
+enum CommitGraphCommand {
+    /// Write a commit-graph file
+    Write {
+        /// Include every commit reachable from the refs
+        #[structopt(long)]
+        reachable: bool,
+        /// Add a layer to the commit-graph chain for the commits it lacks
+        #[structopt(long)]
+        split: bool,
+    },
+}
+
+impl SubcommandCommitGraph {
+    pub fn execute(&self) -> Result<()> {
+        let repo = Repo::from_enclosing()?;
+        match self.command {
+            CommitGraphCommand::Write { reachable, split } => {
+                if !reachable {
+                    return Err(anyhow!(
+                        "only writing the commits reachable from refs is supported, use --reachable"
+                    ));
+                }
+                repo.write_commit_graph(split)
+            }
+        }
+    }
+}
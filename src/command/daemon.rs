@@ -0,0 +1,31 @@
+use std::net::TcpListener;
+use std::path::PathBuf;
+
+use anyhow::{
+    Context,
+    Result,
+};
+use structopt::StructOpt;
+
+use crate::server::daemon::Daemon;
+
+#[derive(StructOpt)]
+#[structopt(name = "daemon", about = "serve repositories over the git:// protocol")]
+pub struct SubcommandDaemon {
+    /// Directory that requested repository paths are relative to
+    #[structopt(long, parse(from_os_str))]
+    base_path: PathBuf,
+    /// Serve all repositories, even those without a git-daemon-export-ok file
+    #[structopt(long)]
+    export_all: bool,
+    #[structopt(long, default_value = "9418")]
+    port: u16,
+}
+
+impl SubcommandDaemon {
+    pub fn execute(&self) -> Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", self.port))
+            .with_context(|| format!("listen on port {}", self.port))?;
+        Daemon::new(&self.base_path, self.export_all).serve(listener)
+    }
+}
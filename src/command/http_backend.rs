@@ -0,0 +1,59 @@
+use std::env;
+use std::net::TcpListener;
+use std::path::PathBuf;
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result,
+};
+use structopt::StructOpt;
+
+use crate::server::http;
+use crate::server::http_backend::HttpBackend;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "http-backend",
+    about = "serve repositories over smart HTTP, as a CGI program or standalone"
+)]
+pub struct SubcommandHttpBackend {
+    /// Directory that requested repository paths are relative to [default: $GIT_PROJECT_ROOT]
+    #[structopt(long, parse(from_os_str))]
+    base_path: Option<PathBuf>,
+    /// Serve all repositories, even those without a git-daemon-export-ok file
+    #[structopt(long)]
+    export_all: bool,
+    /// Allow pushing with git-receive-pack
+    #[structopt(long)]
+    enable_receive_pack: bool,
+    /// Listen for HTTP connections on this port instead of running as CGI
+    #[structopt(long)]
+    port: Option<u16>,
+}
+
+impl SubcommandHttpBackend {
+    pub fn execute(&self) -> Result<()> {
+        let base_path = match &self.base_path {
+            Some(base_path) => base_path.clone(),
+            None => env::var_os("GIT_PROJECT_ROOT")
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow!("--base-path or GIT_PROJECT_ROOT must be set"))?,
+        };
+        let export_all = self.export_all || env::var_os("GIT_HTTP_EXPORT_ALL").is_some();
+        match self.port {
+            Some(port) => {
+                let backend = HttpBackend::new(base_path, export_all, self.enable_receive_pack);
+                let listener = TcpListener::bind(("0.0.0.0", port))
+                    .with_context(|| format!("listen on port {}", port))?;
+                http::serve(backend, listener)
+            }
+            None => {
+                // As with git-http-backend, pushes are allowed by default once
+                // the web server has authenticated the user.
+                let receive_pack = self.enable_receive_pack || env::var_os("REMOTE_USER").is_some();
+                HttpBackend::new(base_path, export_all, receive_pack).run_cgi()
+            }
+        }
+    }
+}
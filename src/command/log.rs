@@ -1,21 +1,40 @@
-use anyhow::Result;
+use anyhow::{
+    anyhow,
+    Result,
+};
 use structopt::StructOpt;
 
-use crate::store::Repo;
+use crate::store::{
+    Repo,
+    RevWalk,
+};
 
 #[derive(StructOpt)]
 #[structopt(name = "log", about = "show commit logs")]
 pub struct SubcommandLog {
     revision: Option<String>,
+    /// Only show commits which change these paths
+    #[structopt(last = true)]
+    paths: Vec<String>,
 }
 
 impl SubcommandLog {
     pub fn execute(&self) -> Result<()> {
         let repo = Repo::from_enclosing()?;
-        let rev = self.revision.clone().unwrap_or_else(|| "HEAD".into());
-        // Refactor this into a commit walker and pass a closure that calls
-        // std::process::Command::new("less") to pipe it
-        repo.log(&rev)?;
+        let rev = self.revision.as_deref().unwrap_or("HEAD");
+        let mut walk = RevWalk::new(&repo).limit_paths(self.paths.clone());
+        walk.push_revs(&repo.parse_rev_args(&[rev])?)?;
+        for (i, sha) in walk.enumerate() {
+            let sha = sha?;
+            let object = repo.read_object(&sha)?;
+            let commit = object
+                .as_commit()
+                .ok_or_else(|| anyhow!("failed to parse commit {}", sha))?;
+            if i > 0 {
+                println!();
+            }
+            print!("{}", commit);
+        }
         Ok(())
     }
 }
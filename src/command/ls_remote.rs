@@ -12,6 +12,8 @@ use crate::packfile::refs::GitRef;
 pub struct ListRemote {
     #[structopt(parse(try_from_str = super::parse_git_url))]
     remote_url: Url,
+    #[structopt(flatten)]
+    transport: super::TransportOptions,
 }
 
 ///
@@ -19,10 +21,11 @@ pub struct ListRemote {
 ///
 impl ListRemote {
     pub fn execute(&self) -> Result<()> {
-        let mut client = super::create_client(&self.remote_url)?;
+        let config = super::load_config()?;
+        let mut client = super::create_client(&self.remote_url, &config, &self.transport)?;
         let pktlines = client.discover_refs()?;
         for p in &pktlines {
-            let &GitRef { ref id, ref name } = p;
+            let GitRef { id, name } = p;
             println!("{}\t{}", id, name);
         }
         Ok(())
@@ -1,16 +1,48 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
 use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
 use reqwest::Url;
+use structopt::StructOpt;
 
 use crate::remote::httpclient::GitHttpClient;
-use crate::remote::sshclient::GitSSHClient;
-use crate::remote::tcpclient::GitTcpClient;
+use crate::remote::localclient::LocalClient;
+use crate::remote::prompt::Prompter;
+use crate::remote::sshclient::{
+    GitSSHClient,
+    HostKeyChecking,
+    SshOptions,
+};
+use crate::remote::sshcommandclient::{
+    SshCommandClient,
+    SshProgram,
+    SshVariant,
+};
+use crate::remote::tcpclient::{
+    self,
+    GitTcpClient,
+};
 use crate::remote::GitClient;
+use crate::store::{
+    Config,
+    Repo,
+};
 
 pub mod clone;
+pub mod commit_graph;
+pub mod daemon;
+pub mod http_backend;
 pub mod log;
 pub mod ls_remote;
+pub mod multi_pack_index;
+pub mod pack_refs;
+pub mod reflog;
+pub mod repack;
+pub mod rev_list;
+pub mod rev_parse;
 pub mod test_delta;
 
 fn parse_git_url(input: &str) -> Result<Url> {
@@ -18,17 +50,12 @@ fn parse_git_url(input: &str) -> Result<Url> {
     if let Ok(u) = input.parse::<Url>() {
         return Ok(u);
     }
-    parse_scp_url(input)
-        .finish()
-        .map(|(_, url)| url)
-        .map_err(|e| {
-            // We need to map the output error, since by default nom
-            // will tie it to the lifetime of the input string.
-            anyhow::Error::from(nom::error::Error {
-                input: e.input.to_string(),
-                code: e.code,
-            })
-        })
+    if let Ok((_, url)) = parse_scp_url(input).finish() {
+        return Ok(url);
+    }
+    // Anything else is taken to be the path of a local repository.
+    let path = fs::canonicalize(input).or_else(|_| env::current_dir().map(|d| d.join(input)))?;
+    Url::from_file_path(&path).map_err(|_| anyhow!("invalid repository path: {}", input))
 }
 
 fn parse_scp_url(input: &str) -> nom::IResult<&str, Url> {
@@ -52,29 +79,70 @@ fn parse_scp_url(input: &str) -> nom::IResult<&str, Url> {
     })(input)
 }
 
-fn create_client(remote_url: &Url) -> Result<Box<dyn GitClient>> {
+///
+/// Loads the configuration of the repository we are in, if any.
+///
+fn load_config() -> Result<Config> {
+    match Repo::from_enclosing() {
+        Ok(repo) => repo.config(),
+        Err(_) => Config::load(None),
+    }
+}
+
+// Options for connecting to remotes, shared by the commands which do. A doc
+// comment here would replace their about text when flattened into them.
+#[derive(StructOpt, Default)]
+pub struct TransportOptions {
+    /// Private key to use with the built-in ssh client (ssh.variant=libssh2)
+    #[structopt(long, short = "i", parse(from_os_str))]
+    identity_file: Option<PathBuf>,
+    /// Whether the built-in ssh client refuses unknown hosts ("yes") or adds them ("accept-new")
+    #[structopt(long, default_value = "yes")]
+    strict_host_key_checking: HostKeyChecking,
+}
+
+fn create_client(
+    remote_url: &Url,
+    config: &Config,
+    transport: &TransportOptions,
+) -> Result<Box<dyn GitClient>> {
     match remote_url.scheme() {
         "ssh" => {
-            let host = remote_url
-                .host_str()
-                .ok_or_else(|| anyhow!("host required for ssh"))?;
-            let path = remote_url.path();
-            let client = GitSSHClient::new(host, path).with_context(|| "create ssh client")?;
+            let program = SshProgram::from_config(config)?;
+            if program.variant != SshVariant::Libssh2 {
+                let client = SshCommandClient::connect(remote_url, &program)?;
+                return Ok(Box::new(client));
+            }
+            let options = SshOptions {
+                identity_file: transport.identity_file.clone(),
+                host_key_checking: transport.strict_host_key_checking,
+                known_hosts_file: None,
+                prompter: Prompter::from_config(config),
+            };
+            let client =
+                GitSSHClient::connect(remote_url, &options).with_context(|| "create ssh client")?;
             Ok(Box::new(client))
         }
         "http" | "https" => {
-            let client =
-                GitHttpClient::new(remote_url.clone()).with_context(|| "create http client")?;
+            let client = GitHttpClient::new(remote_url.clone(), config)
+                .with_context(|| "create http client")?;
             Ok(Box::new(client))
         }
         "git" => {
             let host = remote_url
                 .host_str()
-                .ok_or_else(|| anyhow!("host required for ssh"))?;
+                .ok_or_else(|| anyhow!("host required for git"))?;
+            let port = remote_url.port().unwrap_or(tcpclient::DEFAULT_PORT);
             let path = remote_url.path();
-            let client = GitTcpClient::connect(host, path)?;
+            let client = GitTcpClient::connect(host, port, path)?;
             Ok(Box::new(client))
         }
+        "file" => {
+            let path = remote_url
+                .to_file_path()
+                .map_err(|_| anyhow!("invalid file url: {}", remote_url))?;
+            Ok(Box::new(LocalClient::open(path)?))
+        }
         scheme => Err(anyhow!("unsupported url scheme: {}", scheme)),
     }
 }
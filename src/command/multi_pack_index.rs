@@ -0,0 +1,32 @@
+use anyhow::Result;
+use structopt::StructOpt;
+
+use crate::store::Repo;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "multi-pack-index",
+    about = "write and verify the multi-pack-index"
+)]
+pub struct SubcommandMultiPackIndex {
+    #[structopt(subcommand)]
+    command: MultiPackIndexCommand,
+}
+
+#[derive(StructOpt)]
+enum MultiPackIndexCommand {
+    /// Write a multi-pack-index covering every pack
+    Write,
+    /// Check the multi-pack-index agrees with the packs it covers
+    Verify,
+}
+
+impl SubcommandMultiPackIndex {
+    pub fn execute(&self) -> Result<()> {
+        let repo = Repo::from_enclosing()?;
+        match self.command {
+            MultiPackIndexCommand::Write => repo.write_multi_pack_index(),
+            MultiPackIndexCommand::Verify => repo.verify_multi_pack_index(),
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use anyhow::Result;
+use structopt::StructOpt;
+
+use crate::packfile::refs;
+use crate::store::Repo;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "pack-refs",
+    about = "pack heads and tags for efficient repository access"
+)]
+pub struct SubcommandPackRefs {
+    /// Pack every ref, rather than only tags and refs which are already packed
+    #[structopt(long)]
+    all: bool,
+    /// Leave the loose refs in place after packing them
+    #[structopt(long)]
+    no_prune: bool,
+}
+
+impl SubcommandPackRefs {
+    pub fn execute(&self) -> Result<()> {
+        let repo = Repo::from_enclosing()?;
+        refs::pack_refs(&repo, self.all, !self.no_prune)
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+use chrono::Local;
+use structopt::StructOpt;
+
+use crate::packfile::reflog;
+use crate::store;
+use crate::store::Repo;
+
+#[derive(StructOpt)]
+#[structopt(name = "reflog", about = "manage reflog information")]
+pub struct SubcommandReflog {
+    #[structopt(subcommand)]
+    command: Option<ReflogCommand>,
+}
+
+#[derive(StructOpt)]
+enum ReflogCommand {
+    /// Show the log of a ref, HEAD by default
+    Show {
+        #[structopt(default_value = "HEAD")]
+        name: String,
+    },
+    /// Prune entries older than the expiry date
+    Expire {
+        /// Prune entries older than this, or everything with "all" and nothing with "never"
+        #[structopt(long, default_value = "90.days.ago")]
+        expire: String,
+        /// Expire the logs of every ref
+        #[structopt(long)]
+        all: bool,
+        refs: Vec<String>,
+    },
+    /// Delete single entries, given as ref@{n}
+    Delete {
+        #[structopt(required = true)]
+        entries: Vec<String>,
+    },
+}
+
+impl SubcommandReflog {
+    pub fn execute(&self) -> Result<()> {
+        let repo = Repo::from_enclosing()?;
+        let show_head = ReflogCommand::Show {
+            name: "HEAD".into(),
+        };
+        match self.command.as_ref().unwrap_or(&show_head) {
+            ReflogCommand::Show { name } => show(&repo, name),
+            ReflogCommand::Expire { expire, all, refs } => expire_logs(&repo, expire, *all, refs),
+            ReflogCommand::Delete { entries } => delete_entries(&repo, entries),
+        }
+    }
+}
+
+///
+/// The full name of the ref whose log `name` refers to. The log of a ref
+/// which has since been deleted can still be named in full.
+///
+fn log_name(repo: &Repo, name: &str) -> Result<String> {
+    Ok(store::expand_ref_name(repo.gitdir(), name)?.unwrap_or_else(|| name.to_owned()))
+}
+
+fn show(repo: &Repo, name: &str) -> Result<()> {
+    let entries = reflog::read(repo.gitdir(), &log_name(repo, name)?)?;
+    for (i, entry) in entries.iter().rev().enumerate() {
+        println!(
+            "{} {}@{{{}}}: {}",
+            &entry.new.hex()[..7],
+            name,
+            i,
+            entry.message
+        );
+    }
+    Ok(())
+}
+
+fn expire_logs(repo: &Repo, expire: &str, all: bool, refs: &[String]) -> Result<()> {
+    let cutoff = match expire {
+        "never" => return Ok(()),
+        "all" => i64::MAX,
+        date => reflog::parse_date(date, Local::now())?,
+    };
+    let names = if all {
+        reflog::logged_refs(repo.gitdir())?
+    } else if !refs.is_empty() {
+        refs.iter()
+            .map(|r| log_name(repo, r))
+            .collect::<Result<_>>()?
+    } else {
+        return Err(anyhow!(
+            "no reflog specified; use --all to expire every one"
+        ));
+    };
+    for name in names {
+        let mut entries = reflog::read(repo.gitdir(), &name)?;
+        let count = entries.len();
+        entries.retain(|entry| entry.timestamp >= cutoff);
+        if entries.len() != count {
+            reflog::write(repo.gitdir(), &name, &entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn delete_entries(repo: &Repo, entries: &[String]) -> Result<()> {
+    // Entries are counted back from the newest, so they're all removed from
+    // a log at once so that each keeps its meaning.
+    let mut by_ref = BTreeMap::<String, Vec<usize>>::new();
+    for spec in entries {
+        let (name, n) = spec
+            .strip_suffix('}')
+            .and_then(|rest| rest.rsplit_once("@{"))
+            .and_then(|(name, n)| Some((name, n.parse::<usize>().ok()?)))
+            .ok_or_else(|| anyhow!("not a reflog entry: {}", spec))?;
+        let name = if name.is_empty() { "HEAD" } else { name };
+        by_ref.entry(log_name(repo, name)?).or_default().push(n);
+    }
+    for (name, ns) in by_ref {
+        let mut log = reflog::read(repo.gitdir(), &name)?;
+        let len = log.len();
+        if let Some(n) = ns.iter().find(|&&n| n >= len) {
+            return Err(anyhow!("no reflog entry {}@{{{}}}", name, n));
+        }
+        let mut i = 0;
+        log.retain(|_| {
+            let keep = !ns.contains(&(len - 1 - i));
+            i += 1;
+            keep
+        });
+        reflog::write(repo.gitdir(), &name, &log)?;
+    }
+    Ok(())
+}
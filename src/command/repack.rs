@@ -0,0 +1,33 @@
+use anyhow::{
+    anyhow,
+    Result,
+};
+use structopt::StructOpt;
+
+use crate::store::Repo;
+
+#[derive(StructOpt)]
+#[structopt(name = "repack", about = "pack unpacked objects in a repository")]
+pub struct SubcommandRepack {
+    /// Pack everything reachable from the refs, reflogs and index into a single pack
+    #[structopt(short = "a")]
+    all: bool,
+    /// Remove the packs and loose objects the new pack makes redundant
+    #[structopt(short = "d")]
+    delete: bool,
+    /// Write reachability bitmaps for the new pack
+    #[structopt(short = "b", long)]
+    write_bitmap_index: bool,
+}
+
+impl SubcommandRepack {
+    pub fn execute(&self) -> Result<()> {
+        if !self.all {
+            return Err(anyhow!(
+                "only packing everything into a single pack is supported, use -a"
+            ));
+        }
+        let repo = Repo::from_enclosing()?;
+        repo.repack(self.delete, self.write_bitmap_index)
+    }
+}
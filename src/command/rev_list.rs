@@ -0,0 +1,153 @@
+use std::collections::HashSet;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+
+use crate::store::{
+    EntryMode,
+    Repo,
+    RevWalk,
+    Sha,
+};
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "rev-list",
+    about = "list commit objects in reverse chronological order",
+    setting = AppSettings::AllowLeadingHyphen
+)]
+pub struct SubcommandRevList {
+    /// Print the number of commits instead of listing them
+    #[structopt(long)]
+    count: bool,
+    /// Stop after this many commits
+    #[structopt(long, short = "n")]
+    max_count: Option<usize>,
+    /// Follow only the first parent of merge commits
+    #[structopt(long)]
+    first_parent: bool,
+    /// Show no parent before all of its children
+    #[structopt(long)]
+    topo_order: bool,
+    /// Also list the trees and blobs used by the listed commits
+    #[structopt(long)]
+    objects: bool,
+    /// With paths, follow every parent of a merge rather than only one it matches
+    #[structopt(long)]
+    full_history: bool,
+    /// List the commits oldest first
+    #[structopt(long)]
+    reverse: bool,
+    /// Revisions to include, ^<rev> to exclude, A..B, A...B, --all and --not
+    #[structopt(required = true)]
+    revisions: Vec<String>,
+    /// Only list commits which change these paths
+    #[structopt(last = true)]
+    paths: Vec<String>,
+}
+
+impl SubcommandRevList {
+    pub fn execute(&self) -> Result<()> {
+        let repo = Repo::from_enclosing()?;
+        let mut walk = RevWalk::new(&repo)
+            .first_parent(self.first_parent)
+            .topo_order(self.topo_order)
+            .limit_paths(self.paths.clone())
+            .simplify_history(!self.full_history);
+        let revs = repo.parse_rev_args(&self.revisions)?;
+        // The bitmaps know which commits are reachable without a walk, but
+        // not which of them touch the paths or are first parents.
+        if self.count && self.paths.is_empty() && !self.first_parent && self.max_count.is_none() {
+            if let Some(count) = repo.count_commits_with_bitmap(&revs)? {
+                println!("{}", count);
+                return Ok(());
+            }
+        }
+        walk.push_revs(&revs)?;
+
+        let max_count = self.max_count.unwrap_or(usize::MAX);
+        let mut commits = walk.by_ref().take(max_count).collect::<Result<Vec<_>>>()?;
+        if self.reverse {
+            commits.reverse();
+        }
+
+        if self.count {
+            println!("{}", commits.len());
+            return Ok(());
+        }
+        for commit in &commits {
+            println!("{}", commit);
+        }
+        if self.objects {
+            let mut seen = HashSet::new();
+            for sha in walk.hidden() {
+                mark_tree_seen(&repo, &repo.commit_tree(sha)?, &mut seen)?;
+            }
+            for commit in &commits {
+                list_tree(&repo, repo.commit_tree(commit)?, "", &mut seen)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// Marks every object in a tree as seen, so that objects used by excluded
+/// commits aren't listed.
+///
+fn mark_tree_seen(repo: &Repo, tree: &Sha, seen: &mut HashSet<Sha>) -> Result<()> {
+    if !seen.insert(*tree) {
+        return Ok(());
+    }
+    let object = repo.read_object(tree)?;
+    let tree = object
+        .as_tree()
+        .ok_or_else(|| anyhow!("failed to parse tree {}", tree))?;
+    for entry in &tree.entries {
+        match entry.mode {
+            EntryMode::SubDirectory => mark_tree_seen(repo, &entry.sha, seen)?,
+            EntryMode::Gitlink => {}
+            _ => {
+                seen.insert(entry.sha);
+            }
+        }
+    }
+    Ok(())
+}
+
+///
+/// Prints each object in a tree not seen before along with its path, the
+/// tree itself first.
+///
+fn list_tree(repo: &Repo, tree: Sha, path: &str, seen: &mut HashSet<Sha>) -> Result<()> {
+    if !seen.insert(tree) {
+        return Ok(());
+    }
+    println!("{} {}", tree, path);
+    let object = repo.read_object(&tree)?;
+    let entries = object
+        .as_tree()
+        .ok_or_else(|| anyhow!("failed to parse tree {}", tree))?
+        .entries;
+    for entry in entries {
+        let entry_path = match path {
+            "" => entry.path.clone(),
+            _ => format!("{}/{}", path, entry.path),
+        };
+        match entry.mode {
+            EntryMode::SubDirectory => list_tree(repo, entry.sha, &entry_path, seen)?,
+            // Submodule commits live in another repository.
+            EntryMode::Gitlink => {}
+            _ => {
+                if seen.insert(entry.sha) {
+                    println!("{} {}", entry.sha, entry_path);
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use std::env;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+
+use crate::packfile::refs;
+use crate::store::{
+    Repo,
+    RevArg,
+    Sha,
+};
+
+/// How many digits `--short` abbreviates to when not given a length.
+const DEFAULT_ABBREV: usize = 7;
+
+#[derive(StructOpt)]
+#[structopt(name = "rev-parse", about = "pick out and massage parameters", setting = AppSettings::AllowLeadingHyphen)]
+pub struct SubcommandRevParse {
+    /// Require exactly one revision, which must name an existing object
+    #[structopt(long)]
+    verify: bool,
+    /// Abbreviate SHAs to the shortest unique prefix of at least this many digits
+    #[structopt(long, require_equals = true)]
+    short: Option<Option<usize>>,
+    /// Print the short name of the ref each revision names
+    #[structopt(long)]
+    abbrev_ref: bool,
+    /// Print the path of the git directory
+    #[structopt(long)]
+    git_dir: bool,
+    /// Print the path of the top of the working tree
+    #[structopt(long)]
+    show_toplevel: bool,
+    /// Revisions, ranges or --all and --not, as accepted by rev-list
+    revisions: Vec<String>,
+}
+
+impl SubcommandRevParse {
+    pub fn execute(&self) -> Result<()> {
+        let repo = Repo::from_enclosing()?;
+        if self.git_dir {
+            // Like git, keep it short from the top of the working tree.
+            if repo.workdir() == Some(env::current_dir()?.as_path()) {
+                println!(".git");
+            } else {
+                println!("{}", repo.gitdir().display());
+            }
+        }
+        if self.show_toplevel {
+            let workdir = repo
+                .workdir()
+                .ok_or_else(|| anyhow!("this operation must be run in a work tree"))?;
+            println!("{}", workdir.display());
+        }
+
+        if self.verify {
+            let [revision] = &self.revisions[..] else {
+                return Err(anyhow!("needed a single revision"));
+            };
+            let sha = repo.rev_parse(revision)?;
+            if !repo.has_object(&sha) {
+                return Err(anyhow!("needed a single revision"));
+            }
+            if self.abbrev_ref {
+                return print_ref_name(&repo, revision);
+            }
+            self.print(
+                &repo,
+                RevArg {
+                    sha,
+                    exclude: false,
+                },
+            );
+            return Ok(());
+        }
+        if self.abbrev_ref {
+            return self
+                .revisions
+                .iter()
+                .try_for_each(|revision| print_ref_name(&repo, revision));
+        }
+        let mut negated = false;
+        for revision in &self.revisions {
+            match revision.as_str() {
+                "--not" => negated = !negated,
+                // Unlike rev-list, rev-parse leaves HEAD out of --all.
+                "--all" => {
+                    for r in refs::read_refs(repo.gitdir())? {
+                        if r.name != "HEAD" {
+                            let sha = Sha::from_hex(r.id.as_bytes())?;
+                            self.print(
+                                &repo,
+                                RevArg {
+                                    sha,
+                                    exclude: negated,
+                                },
+                            );
+                        }
+                    }
+                }
+                _ => {
+                    for mut rev in repo.parse_rev_args(&[revision])? {
+                        rev.exclude ^= negated;
+                        self.print(&repo, rev);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn print(&self, repo: &Repo, rev: RevArg) {
+        let prefix = if rev.exclude { "^" } else { "" };
+        if let Some(len) = self.short {
+            let abbrev = repo.abbreviate(&rev.sha, len.unwrap_or(DEFAULT_ABBREV));
+            println!("{}{}", prefix, abbrev);
+        } else {
+            println!("{}{}", prefix, rev.sha);
+        }
+    }
+}
+
+fn print_ref_name(repo: &Repo, revision: &str) -> Result<()> {
+    let (prefix, revision) = match revision.strip_prefix('^') {
+        Some(revision) => ("^", revision),
+        None => ("", revision),
+    };
+    let name = match repo.symbolic_full_name(revision)? {
+        Some(name) => shorten_ref_name(&name).to_owned(),
+        // A detached HEAD is still called HEAD.
+        None if revision == "HEAD" || revision == "@" => "HEAD".to_owned(),
+        None => return Err(anyhow!("{} is not a ref", revision)),
+    };
+    println!("{}{}", prefix, name);
+    Ok(())
+}
+
+///
+/// Strips the prefix git would leave out when showing a ref's short name.
+///
+fn shorten_ref_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
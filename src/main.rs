@@ -4,16 +4,29 @@ use structopt::StructOpt;
 mod command;
 mod delta;
 mod packfile;
+mod progress;
 mod remote;
+mod server;
 mod store;
+#[cfg(test)]
+mod test_support;
 
 #[derive(StructOpt)]
 #[structopt(about = "a toy git implementation in rust", version = env!("CARGO_PKG_VERSION"))]
 #[structopt(flatten)]
 enum Git {
     Clone(command::clone::SubcommandClone),
+    CommitGraph(command::commit_graph::SubcommandCommitGraph),
+    Daemon(command::daemon::SubcommandDaemon),
+    HttpBackend(command::http_backend::SubcommandHttpBackend),
     ListRemote(command::ls_remote::ListRemote),
     Log(command::log::SubcommandLog),
+    MultiPackIndex(command::multi_pack_index::SubcommandMultiPackIndex),
+    PackRefs(command::pack_refs::SubcommandPackRefs),
+    Reflog(command::reflog::SubcommandReflog),
+    Repack(command::repack::SubcommandRepack),
+    RevList(command::rev_list::SubcommandRevList),
+    RevParse(command::rev_parse::SubcommandRevParse),
     TestDelta(command::test_delta::SubCommandTestDelta),
 }
 
@@ -21,8 +34,17 @@ fn main() -> Result<()> {
     let git = Git::from_args();
     match git {
         Git::Clone(c) => c.execute(),
+        Git::CommitGraph(c) => c.execute(),
+        Git::Daemon(c) => c.execute(),
+        Git::HttpBackend(c) => c.execute(),
         Git::ListRemote(c) => c.execute(),
         Git::Log(c) => c.execute(),
+        Git::MultiPackIndex(c) => c.execute(),
+        Git::PackRefs(c) => c.execute(),
+        Git::Reflog(c) => c.execute(),
+        Git::Repack(c) => c.execute(),
+        Git::RevList(c) => c.execute(),
+        Git::RevParse(c) => c.execute(),
         Git::TestDelta(c) => c.execute(),
     }
 }
@@ -0,0 +1,522 @@
+//!
+//! Reachability bitmaps, stored beside a pack as `pack-<sha>.bitmap`. Each
+//! bit stands for one of the pack's objects, in the order they're stored in
+//! the pack, and a commit's bitmap has the bits set for everything reachable
+//! from it. See gitformat-pack(5).
+//!
+//! The bitmaps are compressed with EWAH, which replaces runs of words that
+//! are all zeros or all ones with a count.
+//!
+use std::io::{
+    self,
+    Read,
+    Write,
+};
+use std::path::Path;
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result,
+};
+use byteorder::{
+    BigEndian,
+    ReadBytesExt,
+    WriteBytesExt,
+};
+
+use super::PackIndex;
+use crate::store::{
+    ObjectType,
+    Sha,
+};
+
+const SIGNATURE: &[u8; 4] = b"BITM";
+const VERSION: u16 = 1;
+
+/// Set when every object reachable from the pack's commits is in the pack,
+/// which git requires.
+const OPT_FULL_DAG: u16 = 0x1;
+
+/// How many entries back a bitmap may be XORed against.
+const MAX_XOR_OFFSET: usize = 160;
+
+/// The bits of an EWAH marker word: whether the run is of ones, then the
+/// run's length in words, then how many literal words follow.
+const RUNNING_BIT: u64 = 1;
+const RUNNING_LEN_BITS: u32 = 32;
+const LITERAL_BITS: u32 = 31;
+const MAX_RUNNING_LEN: u64 = (1 << RUNNING_LEN_BITS) - 1;
+const MAX_LITERALS: usize = (1 << LITERAL_BITS) - 1;
+
+///
+/// An uncompressed bitmap.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    pub fn get(&self, i: usize) -> bool {
+        self.words
+            .get(i / 64)
+            .is_some_and(|word| word & (1 << (i % 64)) != 0)
+    }
+
+    pub fn set(&mut self, i: usize) {
+        if self.words.len() <= i / 64 {
+            self.words.resize(i / 64 + 1, 0);
+        }
+        self.words[i / 64] |= 1 << (i % 64);
+    }
+
+    pub fn or(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    pub fn and(&mut self, other: &Bitmap) {
+        self.words.truncate(other.words.len());
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+
+    pub fn and_not(&mut self, other: &Bitmap) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= !other;
+        }
+    }
+
+    fn xor(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word ^= other;
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    ///
+    /// Iterates over the positions of the set bits, in order.
+    ///
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+    }
+
+    ///
+    /// Reads an EWAH bitmap of at most `max_bits` bits from the front of
+    /// `reader`. Every count is checked against what's left before it's
+    /// trusted, so a corrupt bitmap can't make us allocate wildly.
+    ///
+    fn read_ewah(reader: &mut &[u8], max_bits: usize) -> Result<Self> {
+        let num_bits = reader.read_u32::<BigEndian>()? as usize;
+        if num_bits > max_bits {
+            return Err(anyhow!(
+                "bitmap has {} bits, more than its pack's objects",
+                num_bits
+            ));
+        }
+        let num_words = reader.read_u32::<BigEndian>()? as usize;
+        // Each word takes 8 bytes, then comes the last marker's position.
+        if num_words > reader.len().saturating_sub(4) / 8 {
+            return Err(anyhow!("bitmap is truncated"));
+        }
+        let mut compressed = Vec::with_capacity(num_words);
+        for _ in 0..num_words {
+            compressed.push(reader.read_u64::<BigEndian>()?);
+        }
+        // The position of the last marker, which only matters for appending.
+        reader.read_u32::<BigEndian>()?;
+
+        let len = num_bits.div_ceil(64);
+        let mut words = Vec::with_capacity(len);
+        let mut i = 0;
+        while i < compressed.len() {
+            let marker = compressed[i];
+            let run_len = ((marker >> 1) & MAX_RUNNING_LEN) as usize;
+            let literals = (marker >> (1 + RUNNING_LEN_BITS)) as usize;
+            if run_len > len - words.len() || literals > len - words.len() - run_len {
+                return Err(anyhow!("bitmap is longer than its {} bits", num_bits));
+            }
+            let run = if marker & RUNNING_BIT != 0 { !0 } else { 0 };
+            words.resize(words.len() + run_len, run);
+            let literals = compressed
+                .get(i + 1..i + 1 + literals)
+                .ok_or_else(|| anyhow!("bitmap is truncated"))?;
+            words.extend_from_slice(literals);
+            i += 1 + literals.len();
+        }
+        words.resize(len, 0);
+        Ok(Bitmap { words })
+    }
+
+    fn write_ewah<W: Write>(&self, writer: &mut W, num_bits: usize) -> Result<()> {
+        let mut words = self.words.clone();
+        words.resize(num_bits.div_ceil(64), 0);
+
+        let mut compressed = Vec::new();
+        let mut last_marker = 0;
+        let mut i = 0;
+        while i < words.len() || compressed.is_empty() {
+            let run = words.get(i).copied().filter(|w| *w == 0 || *w == !0);
+            let mut run_len = 0;
+            while run.is_some() && words.get(i) == run.as_ref() && run_len < MAX_RUNNING_LEN {
+                run_len += 1;
+                i += 1;
+            }
+            let start = i;
+            while i < words.len() && words[i] != 0 && words[i] != !0 && i - start < MAX_LITERALS {
+                i += 1;
+            }
+            last_marker = compressed.len();
+            let running_bit = (run == Some(!0)) as u64;
+            compressed.push(
+                running_bit | (run_len << 1) | (((i - start) as u64) << (1 + RUNNING_LEN_BITS)),
+            );
+            compressed.extend_from_slice(&words[start..i]);
+        }
+
+        writer.write_u32::<BigEndian>(num_bits as u32)?;
+        writer.write_u32::<BigEndian>(compressed.len() as u32)?;
+        for word in compressed {
+            writer.write_u64::<BigEndian>(word)?;
+        }
+        writer.write_u32::<BigEndian>(last_marker as u32)?;
+        Ok(())
+    }
+}
+
+///
+/// The bitmaps of a pack: one for each type of object, and one for each of
+/// the commits chosen when it was written.
+///
+pub struct PackBitmap {
+    pack_sha: Sha,
+    // The pack's objects, sorted, along with their position in the pack.
+    // An object's place in this list is its position in the index.
+    positions: Vec<(Sha, usize)>,
+    // The pack's objects in the order they're stored.
+    objects: Vec<Sha>,
+    commits: Bitmap,
+    trees: Bitmap,
+    blobs: Bitmap,
+    tags: Bitmap,
+    // The bitmaps of the chosen commits, sorted.
+    reachable: Vec<(Sha, Bitmap)>,
+}
+
+impl PackBitmap {
+    ///
+    /// Creates an empty set of bitmaps for the pack with this index.
+    ///
+    pub fn new(index: &PackIndex) -> Self {
+        let mut by_offset = index
+            .entries()
+            .map(|(sha, offset)| (offset, *sha))
+            .collect::<Vec<_>>();
+        by_offset.sort();
+        let objects = by_offset
+            .into_iter()
+            .map(|(_, sha)| sha)
+            .collect::<Vec<_>>();
+        let mut positions = objects
+            .iter()
+            .enumerate()
+            .map(|(i, sha)| (*sha, i))
+            .collect::<Vec<_>>();
+        positions.sort();
+        PackBitmap {
+            pack_sha: *index.pack_sha(),
+            positions,
+            objects,
+            commits: Bitmap::default(),
+            trees: Bitmap::default(),
+            blobs: Bitmap::default(),
+            tags: Bitmap::default(),
+            reachable: Vec::new(),
+        }
+    }
+
+    ///
+    /// Loads the bitmaps for the pack with this index, or `None` if it has
+    /// none.
+    ///
+    pub fn open<P: AsRef<Path>>(path: P, index: &PackIndex) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        match std::fs::read(path) {
+            Ok(contents) => Self::parse(&contents, index)
+                .with_context(|| format!("read {}", path.display()))
+                .map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(contents: &[u8], index: &PackIndex) -> Result<Self> {
+        if contents.len() < 32 + 20 {
+            return Err(anyhow!("bitmap is truncated"));
+        }
+        let (mut body, trailer) = contents.split_at(contents.len() - 20);
+        if Sha::compute_from_bytes(body) != Sha::from_bytes(trailer)? {
+            return Err(anyhow!("bitmap checksum mismatch"));
+        }
+        let mut signature = [0; 4];
+        body.read_exact(&mut signature)?;
+        if &signature != SIGNATURE {
+            return Err(anyhow!("not a bitmap"));
+        }
+        let version = body.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(anyhow!("unsupported bitmap version: {}", version));
+        }
+        let options = body.read_u16::<BigEndian>()?;
+        if options & OPT_FULL_DAG == 0 {
+            return Err(anyhow!("bitmap doesn't cover everything reachable"));
+        }
+        let num_entries = body.read_u32::<BigEndian>()? as usize;
+        let mut pack_sha = [0; 20];
+        body.read_exact(&mut pack_sha)?;
+        if pack_sha != *index.pack_sha().as_bytes() {
+            return Err(anyhow!("bitmap doesn't match its pack"));
+        }
+
+        let mut bitmap = PackBitmap::new(index);
+        let num_bits = bitmap.objects.len();
+        bitmap.commits = Bitmap::read_ewah(&mut body, num_bits)?;
+        bitmap.trees = Bitmap::read_ewah(&mut body, num_bits)?;
+        bitmap.blobs = Bitmap::read_ewah(&mut body, num_bits)?;
+        bitmap.tags = Bitmap::read_ewah(&mut body, num_bits)?;
+
+        // Bitmaps may be stored XORed with one of those just before them.
+        let mut entries: Vec<(Sha, Bitmap)> = Vec::with_capacity(num_entries);
+        for _ in 0..num_entries {
+            let index_position = body.read_u32::<BigEndian>()? as usize;
+            let xor_offset = body.read_u8()? as usize;
+            let _flags = body.read_u8()?;
+            let mut reachable = Bitmap::read_ewah(&mut body, num_bits)?;
+            if xor_offset > MAX_XOR_OFFSET || xor_offset > entries.len() {
+                return Err(anyhow!("bitmap XOR offset {} is out of bounds", xor_offset));
+            }
+            if xor_offset > 0 {
+                reachable.xor(&entries[entries.len() - xor_offset].1);
+            }
+            let (sha, _) = bitmap
+                .positions
+                .get(index_position)
+                .ok_or_else(|| anyhow!("bitmap commit {} is out of bounds", index_position))?;
+            entries.push((*sha, reachable));
+        }
+        // Whatever follows, such as the name-hash cache, only helps with
+        // finding deltas or loading the bitmaps lazily.
+        entries.sort_by_key(|(sha, _)| *sha);
+        bitmap.reachable = entries;
+        Ok(bitmap)
+    }
+
+    ///
+    /// Encodes the bitmaps in git's format, with each commit's bitmap
+    /// stored as it is rather than XORed against another.
+    ///
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let num_bits = self.objects.len();
+        let mut contents = Vec::new();
+        contents.write_all(SIGNATURE)?;
+        contents.write_u16::<BigEndian>(VERSION)?;
+        contents.write_u16::<BigEndian>(OPT_FULL_DAG)?;
+        contents.write_u32::<BigEndian>(self.reachable.len() as u32)?;
+        contents.write_all(self.pack_sha.as_bytes())?;
+        for types in [&self.commits, &self.trees, &self.blobs, &self.tags] {
+            types.write_ewah(&mut contents, num_bits)?;
+        }
+        for (sha, reachable) in &self.reachable {
+            let index_position = self
+                .positions
+                .binary_search_by_key(sha, |(sha, _)| *sha)
+                .map_err(|_| anyhow!("{} isn't in the pack", sha))?;
+            contents.write_u32::<BigEndian>(index_position as u32)?;
+            contents.write_u8(0)?;
+            contents.write_u8(0)?;
+            reachable.write_ewah(&mut contents, num_bits)?;
+        }
+        let checksum = Sha::compute_from_bytes(&contents);
+        contents.write_all(checksum.as_bytes())?;
+        Ok(contents)
+    }
+
+    ///
+    /// The position of an object in the pack, which is its bit.
+    ///
+    pub fn position(&self, sha: &Sha) -> Option<usize> {
+        self.positions
+            .binary_search_by_key(sha, |(sha, _)| *sha)
+            .ok()
+            .map(|i| self.positions[i].1)
+    }
+
+    pub fn object_at(&self, position: usize) -> &Sha {
+        &self.objects[position]
+    }
+
+    ///
+    /// The pack's objects in the order they're stored.
+    ///
+    pub fn objects(&self) -> &[Sha] {
+        &self.objects
+    }
+
+    ///
+    /// The bitmap of the objects of a type.
+    ///
+    pub fn of_type(&self, obj_type: ObjectType) -> &Bitmap {
+        match obj_type {
+            ObjectType::Commit => &self.commits,
+            ObjectType::Tree => &self.trees,
+            ObjectType::Blob => &self.blobs,
+            ObjectType::Tag => &self.tags,
+        }
+    }
+
+    pub fn type_at(&self, position: usize) -> Option<ObjectType> {
+        [
+            ObjectType::Commit,
+            ObjectType::Tree,
+            ObjectType::Blob,
+            ObjectType::Tag,
+        ]
+        .into_iter()
+        .find(|obj_type| self.of_type(*obj_type).get(position))
+    }
+
+    pub fn set_type(&mut self, position: usize, obj_type: ObjectType) {
+        match obj_type {
+            ObjectType::Commit => self.commits.set(position),
+            ObjectType::Tree => self.trees.set(position),
+            ObjectType::Blob => self.blobs.set(position),
+            ObjectType::Tag => self.tags.set(position),
+        }
+    }
+
+    ///
+    /// The bitmap of everything reachable from a commit, if one was stored
+    /// for it.
+    ///
+    pub fn reachable(&self, commit: &Sha) -> Option<&Bitmap> {
+        self.reachable
+            .binary_search_by_key(commit, |(sha, _)| *sha)
+            .ok()
+            .map(|i| &self.reachable[i].1)
+    }
+
+    pub fn set_reachable(&mut self, commit: Sha, reachable: Bitmap) {
+        match self
+            .reachable
+            .binary_search_by_key(&commit, |(sha, _)| *sha)
+        {
+            Ok(i) => self.reachable[i].1 = reachable,
+            Err(i) => self.reachable.insert(i, (commit, reachable)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bitmap: &Bitmap, num_bits: usize) -> Bitmap {
+        let mut encoded = Vec::new();
+        bitmap.write_ewah(&mut encoded, num_bits).unwrap();
+        Bitmap::read_ewah(&mut &encoded[..], num_bits).unwrap()
+    }
+
+    #[test]
+    fn compressing_bitmaps() {
+        let mut bitmap = Bitmap::default();
+        for i in (0..64 * 3).chain([400, 401, 1000]) {
+            bitmap.set(i);
+        }
+        let num_bits = 64 * 20;
+        let decoded = round_trip(&bitmap, num_bits);
+        assert_eq!(
+            decoded.ones().collect::<Vec<_>>(),
+            bitmap.ones().collect::<Vec<_>>()
+        );
+        assert_eq!(decoded.count_ones(), 64 * 3 + 3);
+        assert!(decoded.get(1000) && !decoded.get(999));
+
+        // Runs of ones and zeros each take a marker word, which also says
+        // how many literal words follow.
+        let mut encoded = Vec::new();
+        bitmap.write_ewah(&mut encoded, num_bits).unwrap();
+        let num_words = u32::from_be_bytes(encoded[4..8].try_into().unwrap());
+        assert_eq!(num_words, 4 + 2);
+
+        assert_eq!(round_trip(&Bitmap::default(), 0).count_ones(), 0);
+    }
+
+    #[test]
+    fn rejecting_corrupt_bitmaps() {
+        let ewah = |num_bits: u32, num_words: u32, words: &[u64]| {
+            let mut encoded = Vec::new();
+            encoded.write_u32::<BigEndian>(num_bits).unwrap();
+            encoded.write_u32::<BigEndian>(num_words).unwrap();
+            for word in words {
+                encoded.write_u64::<BigEndian>(*word).unwrap();
+            }
+            encoded.write_u32::<BigEndian>(0).unwrap();
+            encoded
+        };
+        let read = |encoded: Vec<u8>| Bitmap::read_ewah(&mut &encoded[..], 128);
+
+        // A run of both words, then a literal word.
+        assert!(read(ewah(128, 1, &[1 | (2 << 1)])).is_ok());
+        assert!(read(ewah(128, 2, &[1 << 33, 5])).is_ok());
+        // More words than there are bytes for.
+        assert!(read(ewah(128, u32::MAX, &[0])).is_err());
+        // Runs and literals past the end of the bits.
+        assert!(read(ewah(128, 1, &[1 | (3 << 1)])).is_err());
+        assert!(read(ewah(128, 1, &[u64::MAX])).is_err());
+        assert!(read(ewah(64, 3, &[2 << 33, 1, 2])).is_err());
+        // More bits than the pack has objects.
+        assert!(read(ewah(129, 1, &[0])).is_err());
+    }
+
+    #[test]
+    fn combining_bitmaps() {
+        let mut a = Bitmap::default();
+        let mut b = Bitmap::default();
+        for i in [1, 70, 200] {
+            a.set(i);
+        }
+        for i in [70, 300] {
+            b.set(i);
+        }
+        let mut union = a.clone();
+        union.or(&b);
+        assert_eq!(union.ones().collect::<Vec<_>>(), [1, 70, 200, 300]);
+        let mut difference = a.clone();
+        difference.and_not(&b);
+        assert_eq!(difference.ones().collect::<Vec<_>>(), [1, 200]);
+        let mut intersection = a.clone();
+        intersection.and(&b);
+        assert_eq!(intersection.ones().collect::<Vec<_>>(), [70]);
+        a.xor(&b);
+        assert_eq!(a.ones().collect::<Vec<_>>(), [1, 200, 300]);
+    }
+}
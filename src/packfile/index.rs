@@ -29,7 +29,10 @@ use std::io::{
 };
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{
+    anyhow,
+    Result,
+};
 use byteorder::{
     BigEndian,
     ReadBytesExt,
@@ -46,6 +49,7 @@ static VERSION: u32 = 2;
 /// Version 2 of the Git Packfile Index containing separate
 /// tables for the offsets, fanouts, and shas.
 ///
+#[derive(Clone)]
 pub struct PackIndex {
     fanout: [u32; 256],
     offsets: Vec<u32>,
@@ -72,17 +76,23 @@ impl PackIndex {
         Self::parse(&contents).map(Some)
     }
 
-    #[allow(unused)]
-    fn parse(mut content: &[u8]) -> Result<Self> {
+    pub fn parse(mut content: &[u8]) -> Result<Self> {
+        if content.len() < 20 {
+            return Err(anyhow!("index is truncated"));
+        }
         let checksum = Sha::compute_from_bytes(&content[..content.len() - 20]);
 
         // Parse header
         let mut magic = [0; 4];
         content.read_exact(&mut magic)?;
-        assert_eq!(magic, MAGIC);
+        if magic != MAGIC {
+            return Err(anyhow!("not a pack index"));
+        }
 
         let version = content.read_u32::<BigEndian>()?;
-        assert_eq!(version, VERSION);
+        if version != VERSION {
+            return Err(anyhow!("unsupported index version: {}", version));
+        }
 
         // Parse Fanout table
         let mut fanout = [0; 256];
@@ -122,7 +132,9 @@ impl PackIndex {
         content.read_exact(&mut idx_sha_content)?;
         let idx_sha = Sha::from_bytes(&idx_sha_content[..])?;
 
-        assert_eq!(idx_sha, checksum);
+        if idx_sha != checksum {
+            return Err(anyhow!("index checksum mismatch"));
+        }
 
         Ok(PackIndex {
             fanout,
@@ -168,7 +180,6 @@ impl PackIndex {
     ///
     /// Returns the offset in the packfile for the given SHA, if any.
     ///
-    #[allow(dead_code)]
     pub fn find(&self, sha: &Sha) -> Option<usize> {
         let fan = sha.as_bytes()[0] as usize;
         let start = if fan > 0 {
@@ -184,6 +195,41 @@ impl PackIndex {
             .ok()
     }
 
+    ///
+    /// The checksum of the packfile this indexes.
+    ///
+    pub fn pack_sha(&self) -> &Sha {
+        &self.pack_sha
+    }
+
+    ///
+    /// Iterates over the SHAs in the index in order, along with their offsets
+    /// in the packfile.
+    ///
+    pub fn entries(&self) -> impl Iterator<Item = (&Sha, usize)> {
+        self.shas
+            .iter()
+            .zip(&self.offsets)
+            .map(|(sha, offset)| (sha, *offset as usize))
+    }
+
+    ///
+    /// Returns the SHAs in the index which start with the given hex prefix,
+    /// which must be at least two digits long.
+    ///
+    pub fn find_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a Sha> + 'a {
+        let fan = u8::from_str_radix(&prefix[..2], 16).unwrap_or(0) as usize;
+        let start = if fan > 0 {
+            self.fanout[fan - 1] as usize
+        } else {
+            0
+        };
+        let end = self.fanout[fan] as usize;
+        self.shas[start..end]
+            .iter()
+            .filter(move |sha| sha.hex().starts_with(prefix))
+    }
+
     ///
     /// Creates an index from a list of objects and their offsets
     /// into the packfile.
@@ -196,7 +242,7 @@ impl PackIndex {
         let mut checksums: Vec<u32> = Vec::with_capacity(size);
 
         // Sort the objects by SHA
-        objects.sort_by(|&(_, _, ref oa), &(_, _, ref ob)| oa.sha().cmp(&ob.sha()));
+        objects.sort_by_key(|(_, _, oa)| oa.sha());
 
         for &(offset, crc, ref obj) in objects.iter() {
             let sha = obj.sha();
@@ -230,12 +276,10 @@ mod tests {
     use super::*;
     use crate::packfile::PackFile;
 
-    static PACK_FILE: &'static str =
-        "tests/data/packs/pack-73e0a23f5ebfc74c7ea1940e2843a408ce1789d0.pack";
-    static IDX_FILE: &'static str =
-        "tests/data/packs/pack-73e0a23f5ebfc74c7ea1940e2843a408ce1789d0.idx";
+    static PACK_FILE: &str = "tests/data/packs/pack-73e0a23f5ebfc74c7ea1940e2843a408ce1789d0.pack";
+    static IDX_FILE: &str = "tests/data/packs/pack-73e0a23f5ebfc74c7ea1940e2843a408ce1789d0.idx";
 
-    static COMMIT: &'static [u8] = b"fb6fb3d9b81142566f4b2466857b0302617768de";
+    static COMMIT: &[u8] = b"fb6fb3d9b81142566f4b2466857b0302617768de";
 
     #[test]
     fn reading_an_index() {
@@ -270,6 +314,19 @@ mod tests {
         assert_eq!(idx_encoded, test_encoded);
     }
 
+    #[test]
+    fn building_an_index_from_a_packfile() {
+        let mut pack_bytes = Vec::new();
+        let mut file = File::open(PACK_FILE).unwrap();
+        file.read_to_end(&mut pack_bytes).unwrap();
+        let pack = PackFile::parse(&pack_bytes).unwrap();
+
+        let mut idx_bytes = Vec::new();
+        let mut file = File::open(IDX_FILE).unwrap();
+        file.read_to_end(&mut idx_bytes).unwrap();
+        assert_eq!(pack.index.encode().unwrap(), idx_bytes);
+    }
+
     #[test]
     fn read_and_write_should_be_inverses() {
         let mut bytes = Vec::new();
@@ -1,5 +1,8 @@
+mod bitmap;
 mod index;
+pub mod reflog;
 pub mod refs;
+mod writer;
 
 use std::collections::HashMap;
 use std::fs::{
@@ -26,7 +29,13 @@ use byteorder::{
 };
 use crc32fast::Hasher as CrcHasher;
 
+pub use self::bitmap::{
+    Bitmap,
+    PackBitmap,
+};
 pub use self::index::PackIndex;
+pub use self::writer::PackWriter;
+use crate::progress::Progress;
 use crate::store::{
     ObjectType,
     PackedObject,
@@ -66,6 +75,15 @@ pub struct RefDelta {
     patch: Vec<u8>,
 }
 
+///
+/// Where the base of a delta-encoded entry is: earlier in the same pack, or
+/// wherever the object with that SHA is.
+///
+pub enum DeltaBase {
+    Offset(usize),
+    Sha(Sha),
+}
+
 #[derive(Debug)]
 pub struct PackEntryNotFound;
 
@@ -77,19 +95,6 @@ impl std::fmt::Display for PackEntryNotFound {
 
 impl std::error::Error for PackEntryNotFound {}
 
-impl PackEntry {
-    pub fn crc32(&self) -> u32 {
-        let content = match self {
-            PackEntry::Base(b) => &b.content[..][..],
-            PackEntry::RefDelta(d) => &d.patch[..],
-            PackEntry::OfsDelta(o) => &o.patch[..],
-        };
-        let mut h = CrcHasher::new();
-        h.update(content);
-        h.finalize()
-    }
-}
-
 impl PackFile {
     pub fn open<P: AsRef<Path>>(p: P) -> Result<Self> {
         let path = p.as_ref();
@@ -107,7 +112,14 @@ impl PackFile {
         PackFile::parse_with_index(contents, None)
     }
 
-    fn parse_with_index(mut contents: &[u8], idx: Option<PackIndex>) -> Result<Self> {
+    ///
+    /// Parses a packfile, using `idx` as its index if given instead of
+    /// building one from the pack's contents.
+    ///
+    pub fn parse_with_index(mut contents: &[u8], idx: Option<PackIndex>) -> Result<Self> {
+        if contents.len() < HEADER_LENGTH + 20 {
+            return Err(anyhow!("packfile is truncated"));
+        }
         let sha_computed = Sha::compute_from_bytes(&contents[..contents.len() - 20]);
 
         let magic = contents.read_u32::<BigEndian>().context("magic number")?;
@@ -117,16 +129,32 @@ impl PackFile {
         if magic == MAGIC_HEADER {
             let contents_len = contents.len();
             let checksum = &contents[(contents_len - 20)..contents_len];
-            assert_eq!(checksum, sha_computed.as_bytes());
+            if checksum != sha_computed.as_bytes() {
+                return Err(anyhow!("packfile checksum mismatch"));
+            }
 
             // Use slice::split_at
             contents = &contents[..contents_len - 20];
             let index = if let Some(i) = idx {
                 i
             } else {
-                let objects = Objects::new(contents, num_objects)
-                    .collect::<Result<Vec<_>, _>>()
-                    .with_context(|| "constructing packfile index")?;
+                let mut objects = Objects::new(contents, num_objects);
+                let mut resolved = Vec::with_capacity(num_objects);
+                let mut progress = None;
+                while let Some(object) = objects.next() {
+                    resolved.push(object.with_context(|| "constructing packfile index")?);
+                    if objects.resolve {
+                        progress
+                            .get_or_insert_with(|| {
+                                Progress::new("Resolving deltas", Some(objects.num_deltas))
+                            })
+                            .inc();
+                    }
+                }
+                if let Some(progress) = progress {
+                    progress.finish();
+                }
+                let objects = resolved;
                 PackIndex::from_objects(objects, &sha_computed)
             };
 
@@ -138,7 +166,7 @@ impl PackFile {
                 index,
             })
         } else {
-            unreachable!("Packfile failed to parse");
+            Err(anyhow!("not a packfile"))
         }
     }
 
@@ -178,6 +206,15 @@ impl PackFile {
         &self.sha
     }
 
+    #[allow(dead_code)]
+    pub fn num_objects(&self) -> usize {
+        self.num_objects
+    }
+
+    pub fn contains(&self, sha: &Sha) -> bool {
+        self.index.find(sha).is_some()
+    }
+
     pub fn find_by_sha(&self, sha: &Sha) -> Result<PackedObject> {
         self.index
             .find(sha)
@@ -192,7 +229,10 @@ impl PackFile {
             .and_then(|offset| self.read_at_offset(offset))
     }
 
-    fn find_by_offset(&self, mut offset: usize) -> Result<PackedObject> {
+    ///
+    /// Reads the object at an offset into the packfile, as found in an index.
+    ///
+    pub fn find_by_offset(&self, mut offset: usize) -> Result<PackedObject> {
         // Read the initial offset.
         //
         // If it is a base object, return the enclosing object.
@@ -238,6 +278,20 @@ impl PackFile {
         Ok(accum)
     }
 
+    ///
+    /// Reads how the entry at `offset` is stored, if as a delta: where its
+    /// base is, and the patch turning that into the object.
+    ///
+    pub fn find_delta_by_offset(&self, offset: usize) -> Result<Option<(DeltaBase, Vec<u8>)>> {
+        Ok(match self.read_at_offset(offset)? {
+            PackEntry::Base(_) => None,
+            PackEntry::OfsDelta(delta) => {
+                Some((DeltaBase::Offset(offset - delta.offset), delta.patch))
+            }
+            PackEntry::RefDelta(delta) => Some((DeltaBase::Sha(delta.base), delta.patch)),
+        })
+    }
+
     fn read_at_offset(&self, offset: usize) -> Result<PackEntry> {
         let total_offset = offset - HEADER_LENGTH;
         let contents = &self.encoded_objects[total_offset..];
@@ -246,6 +300,82 @@ impl PackFile {
     }
 }
 
+///
+/// Reads exactly one packfile from the stream, returning its raw bytes.
+///
+/// The sender may keep the connection open after the pack, so the only way to
+/// find its end is to walk each of the entries announced in the header.
+///
+pub fn read_packfile<R: BufRead>(reader: R) -> Result<Vec<u8>> {
+    read_packfile_with_progress(reader, false)
+}
+
+///
+/// Reads a packfile sent by a remote, as with `read_packfile`, showing a
+/// "Receiving objects" meter while it arrives.
+///
+pub fn receive_packfile<R: BufRead>(reader: R) -> Result<Vec<u8>> {
+    read_packfile_with_progress(reader, true)
+}
+
+fn read_packfile_with_progress<R: BufRead>(reader: R, show_progress: bool) -> Result<Vec<u8>> {
+    let mut reader = RecordingReader {
+        inner: reader,
+        recorded: Vec::new(),
+    };
+    let mut header = [0u8; HEADER_LENGTH];
+    reader.read_exact(&mut header).context("pack header")?;
+    let num_objects = (&header[8..]).read_u32::<BigEndian>()? as usize;
+    let mut progress =
+        Some(Progress::new("Receiving objects", Some(num_objects))).filter(|_| show_progress);
+    {
+        let mut entries = EntryReader::new(&mut reader);
+        for count in 1..=num_objects {
+            entries.read_object()?;
+            if let Some(progress) = &mut progress {
+                progress.set_bytes((entries.consumed_bytes() + HEADER_LENGTH) as u64);
+                progress.set(count);
+            }
+        }
+    }
+    let mut checksum = [0u8; 20];
+    reader.read_exact(&mut checksum).context("pack checksum")?;
+    if let Some(mut progress) = progress {
+        progress.set_bytes(reader.recorded.len() as u64);
+        progress.finish();
+    }
+    Ok(reader.recorded)
+}
+
+///
+/// Keeps a copy of every byte consumed from the inner reader.
+///
+struct RecordingReader<R> {
+    inner: R,
+    recorded: Vec<u8>,
+}
+
+impl<R: BufRead> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..count]);
+        Ok(count)
+    }
+}
+
+impl<R: BufRead> BufRead for RecordingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, count: usize) {
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.recorded.extend_from_slice(&buf[..count]);
+        }
+        self.inner.consume(count);
+    }
+}
+
 ///
 /// An iterator over the objects within a packfile, along
 /// with their offsets.
@@ -257,6 +387,7 @@ pub struct Objects<R> {
     base_offsets: HashMap<usize, Sha>,
     ref_deltas: Vec<(usize, u32, RefDelta)>,
     ofs_deltas: Vec<(usize, u32, OfsDelta)>,
+    num_deltas: usize,
     resolve: bool,
 }
 
@@ -272,6 +403,7 @@ where
             base_objects: HashMap::new(),
             base_offsets: HashMap::new(),
             ofs_deltas: Vec::new(),
+            num_deltas: 0,
             resolve: false,
         }
     }
@@ -341,11 +473,17 @@ where
                 Ok(o) => o,
                 Err(e) => return Some(Err(e)),
             };
-            let checksum = object.crc32();
+            let checksum = self.reader.entry_crc32();
 
             match object {
-                PackEntry::OfsDelta(delta) => self.ofs_deltas.push((offset, checksum, delta)),
-                PackEntry::RefDelta(delta) => self.ref_deltas.push((offset, checksum, delta)),
+                PackEntry::OfsDelta(delta) => {
+                    self.num_deltas += 1;
+                    self.ofs_deltas.push((offset, checksum, delta))
+                }
+                PackEntry::RefDelta(delta) => {
+                    self.num_deltas += 1;
+                    self.ref_deltas.push((offset, checksum, delta))
+                }
                 PackEntry::Base(base) => {
                     {
                         let sha = base.sha();
@@ -370,6 +508,8 @@ where
 pub struct EntryReader<R> {
     inner: R,
     consumed_bytes: usize,
+    // The CRC32 of the raw bytes of the entry being read, as stored in the index.
+    crc: CrcHasher,
 }
 
 impl<R> EntryReader<R>
@@ -380,10 +520,12 @@ where
         EntryReader {
             inner,
             consumed_bytes: 0,
+            crc: CrcHasher::new(),
         }
     }
 
     pub fn read_object(&mut self) -> Result<PackEntry> {
+        self.crc = CrcHasher::new();
         let mut c = self.read_u8()?;
         let type_id = (c >> 4) & 7;
 
@@ -400,7 +542,7 @@ where
         }
 
         match type_id {
-            1 | 2 | 3 | 4 => {
+            1..=4 => {
                 let content = self.decompress_content(size)?;
                 let base_type = match type_id {
                     1 => ObjectType::Commit,
@@ -430,7 +572,7 @@ where
                     patch: content,
                 }))
             }
-            _ => return Err(anyhow!("unexpected id for git object: {}", type_id)),
+            _ => Err(anyhow!("unexpected id for git object: {}", type_id)),
         }
     }
 
@@ -456,16 +598,27 @@ where
         self.consumed_bytes
     }
 
+    ///
+    /// The CRC32 of the compressed entry most recently read.
+    ///
+    pub fn entry_crc32(&self) -> u32 {
+        self.crc.clone().finalize()
+    }
+
     #[inline]
     fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
         self.consumed_bytes += buf.len();
-        self.inner.read_exact(buf)
+        self.inner.read_exact(buf)?;
+        self.crc.update(buf);
+        Ok(())
     }
 
     #[inline]
     fn read_u8(&mut self) -> io::Result<u8> {
         self.consumed_bytes += 1;
-        self.inner.read_u8()
+        let c = self.inner.read_u8()?;
+        self.crc.update(&[c]);
+        Ok(c)
     }
 
     fn decompress_content(&mut self, size: usize) -> Result<Vec<u8>> {
@@ -478,13 +631,15 @@ where
         let mut decompressor = Decompress::new(true);
         loop {
             let last_total_in = decompressor.total_in();
-            let res = {
+            let (res, nread) = {
                 let zlib_buffer = self.inner.fill_buf()?;
-                decompressor.decompress_vec(zlib_buffer, &mut object_buffer, Flush::None)
+                let res = decompressor.decompress_vec(zlib_buffer, &mut object_buffer, Flush::None);
+                let nread = (decompressor.total_in() - last_total_in) as usize;
+                self.crc.update(&zlib_buffer[..nread]);
+                (res, nread)
             };
-            let nread = decompressor.total_in() - last_total_in;
-            self.inner.consume(nread as usize);
-            self.consumed_bytes += nread as usize;
+            self.inner.consume(nread);
+            self.consumed_bytes += nread;
 
             match res {
                 Ok(Status::StreamEnd) => {
@@ -519,16 +674,15 @@ mod tests {
 
     use super::*;
 
-    static PACK_FILE: &'static str =
-        "tests/data/packs/pack-79f006bb5e8d079fdbe07e7ce41f97f4db7d341c.pack";
+    static PACK_FILE: &str = "tests/data/packs/pack-79f006bb5e8d079fdbe07e7ce41f97f4db7d341c.pack";
 
     static BASE_OFFSET: usize = 2154;
-    static BASE_SHA: &'static [u8] = b"7e690abcc93718dbf26ddea5c6ede644a63a5b34";
+    static BASE_SHA: &[u8] = b"7e690abcc93718dbf26ddea5c6ede644a63a5b34";
     // We need to test reading an object with a non-trivial delta
     // chain (4).
-    static DELTA_SHA: &'static [u8] = b"9b104dc31028e46f2f7d0b8a29989ab9a5155d41";
+    static DELTA_SHA: &[u8] = b"9b104dc31028e46f2f7d0b8a29989ab9a5155d41";
     static DELTA_OFFSET: usize = 2461;
-    static DELTA_CONTENT: &'static str =
+    static DELTA_CONTENT: &str =
         "This is a test repo, used for testing the capabilities of the rgit tool. \
         rgit is a implementation of\n\
         the Git version control tool written in Rust.\n\n\
@@ -554,6 +708,20 @@ mod tests {
         assert_eq!(on_disk, encoded);
     }
 
+    #[test]
+    fn reading_a_packfile_from_a_stream() {
+        let mut on_disk = Vec::new();
+        File::open(PACK_FILE)
+            .unwrap()
+            .read_to_end(&mut on_disk)
+            .unwrap();
+        let mut stream = on_disk.clone();
+        stream.extend_from_slice(b"trailing data");
+
+        let read = read_packfile(&stream[..]).unwrap();
+        assert_eq!(read, on_disk);
+    }
+
     #[test]
     fn reading_a_packed_object_by_offset() {
         let pack = read_pack();
@@ -0,0 +1,406 @@
+//!
+//! Reflogs, which record each value a ref has had in `logs/<ref>`.
+//!
+//! Every line of a reflog is one change to the ref, oldest first:
+//!
+//! -- <old sha> SP <new sha> SP <name> SP <<email>> SP <timestamp> SP <tz> TAB <message> LF
+//!
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result,
+};
+use chrono::{
+    DateTime,
+    Duration,
+    Local,
+    NaiveDate,
+    NaiveDateTime,
+    TimeZone,
+};
+
+use crate::packfile::refs::LockFile;
+use crate::store::{
+    Config,
+    Sha,
+};
+
+///
+/// A single change recorded in a reflog.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    pub old: Sha,
+    pub new: Sha,
+    /// Who made the change, as `Name <email>`.
+    pub identity: String,
+    /// Seconds since the epoch.
+    pub timestamp: i64,
+    /// The committer's offset from UTC, e.g. `-0800`.
+    pub tz: String,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    ///
+    /// An entry for a change being made now, by whoever the configuration
+    /// says we are.
+    ///
+    pub fn now(config: &Config, old: Sha, new: Sha, message: &str) -> Self {
+        let now = Local::now();
+        let offset = now.offset().local_minus_utc() / 60;
+        ReflogEntry {
+            old,
+            new,
+            identity: identity(config),
+            timestamp: now.timestamp(),
+            tz: format!(
+                "{}{:02}{:02}",
+                if offset < 0 { '-' } else { '+' },
+                offset.abs() / 60,
+                offset.abs() % 60
+            ),
+            // Each entry is a single line.
+            message: message.lines().next().unwrap_or("").to_owned(),
+        }
+    }
+
+    pub fn parse(line: &str) -> Result<Self> {
+        let error = || anyhow!("bad reflog line: {:?}", line);
+        let (header, message) = line.split_once('\t').unwrap_or((line, ""));
+        let (old, rest) = header.split_once(' ').ok_or_else(error)?;
+        let (new, rest) = rest.split_once(' ').ok_or_else(error)?;
+        let (identity, rest) = rest.rsplit_once("> ").ok_or_else(error)?;
+        let (timestamp, tz) = rest.split_once(' ').ok_or_else(error)?;
+        Ok(ReflogEntry {
+            old: Sha::from_hex(old.as_bytes()).map_err(|_| error())?,
+            new: Sha::from_hex(new.as_bytes()).map_err(|_| error())?,
+            identity: format!("{}>", identity),
+            timestamp: timestamp.parse().map_err(|_| error())?,
+            tz: tz.to_owned(),
+            message: message.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for ReflogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}\t{}",
+            self.old, self.new, self.identity, self.timestamp, self.tz, self.message
+        )
+    }
+}
+
+///
+/// Who we are, from `GIT_COMMITTER_NAME` and `GIT_COMMITTER_EMAIL`, then
+/// `user.name` and `user.email`, and finally the login name and host.
+///
+fn identity(config: &Config) -> String {
+    let user = env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .unwrap_or_else(|_| "unknown".into());
+    let name = env::var("GIT_COMMITTER_NAME")
+        .ok()
+        .or_else(|| config.get("user.name").map(str::to_owned))
+        .unwrap_or_else(|| user.clone());
+    let email = env::var("GIT_COMMITTER_EMAIL")
+        .ok()
+        .or_else(|| config.get("user.email").map(str::to_owned))
+        .unwrap_or_else(|| {
+            let host = fs::read_to_string("/etc/hostname").unwrap_or_default();
+            format!("{}@{}", user, host.trim())
+        });
+    format!("{} <{}>", name, email)
+}
+
+fn log_path(gitdir: &Path, name: &str) -> PathBuf {
+    gitdir.join("logs").join(name)
+}
+
+///
+/// Whether changes to `name` should be logged. Refs which already have a
+/// reflog always are; otherwise it depends on `core.logAllRefUpdates`,
+/// which defaults to logging branches and HEAD outside of bare repositories.
+///
+pub fn should_log(gitdir: &Path, config: &Config, name: &str) -> bool {
+    if log_path(gitdir, name).is_file() {
+        return true;
+    }
+    let bare = match config.get_bool("core.bare") {
+        Ok(Some(bare)) => bare,
+        _ => gitdir.file_name() != Some(OsStr::new(".git")),
+    };
+    let setting = config
+        .get("core.logallrefupdates")
+        .map(str::to_ascii_lowercase);
+    match setting.as_deref() {
+        Some("always") => true,
+        Some("true") | Some("yes") | Some("on") | Some("1") | Some("") => {
+            is_logged_by_default(name)
+        }
+        Some(_) => false,
+        None => !bare && is_logged_by_default(name),
+    }
+}
+
+fn is_logged_by_default(name: &str) -> bool {
+    name == "HEAD"
+        || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+///
+/// Reads the reflog for `name`, oldest entry first. A ref without a reflog
+/// has no entries.
+///
+pub fn read(gitdir: &Path, name: &str) -> Result<Vec<ReflogEntry>> {
+    let path = log_path(gitdir, name);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("{}", path.display())),
+    };
+    contents.lines().map(ReflogEntry::parse).collect()
+}
+
+///
+/// Adds an entry to the end of the reflog for `name`, creating it if needed.
+/// The reflog is locked meanwhile, as `write` locks it, so the entry can't
+/// be lost to a reflog being rewritten.
+///
+pub fn append(gitdir: &Path, name: &str, entry: &ReflogEntry) -> Result<()> {
+    let path = log_path(gitdir, name);
+    let _lock = LockFile::acquire(path.clone())?;
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    file.write_all(format!("{}\n", entry).as_bytes())?;
+    Ok(())
+}
+
+///
+/// Replaces the reflog for `name` with `entries`.
+///
+pub fn write(gitdir: &Path, name: &str, entries: &[ReflogEntry]) -> Result<()> {
+    let mut lock = LockFile::acquire(log_path(gitdir, name))?;
+    for entry in entries {
+        writeln!(lock, "{}", entry)?;
+    }
+    lock.commit()
+}
+
+///
+/// Removes the reflog for `name`, if it has one.
+///
+pub fn delete(gitdir: &Path, name: &str) -> Result<()> {
+    match fs::remove_file(log_path(gitdir, name)) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+///
+/// Lists every ref which has a reflog.
+///
+pub fn logged_refs(gitdir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut pending = vec![(gitdir.join("logs"), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let file_name = match entry.file_name().into_string() {
+                Ok(name) if !name.ends_with(".lock") => name,
+                _ => continue,
+            };
+            let name = format!("{}{}", prefix, file_name);
+            if entry.file_type()?.is_dir() {
+                pending.push((entry.path(), format!("{}/", name)));
+            } else {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+///
+/// Looks up the value `name` had according to the selector in
+/// `name@{selector}`: either the nth entry back, where 0 is the current
+/// value, or the value it had at a given date.
+///
+pub fn lookup(gitdir: &Path, name: &str, selector: &str) -> Result<Sha> {
+    let entries = read(gitdir, name)?;
+    if entries.is_empty() {
+        return Err(anyhow!("ref {} has no reflog", name));
+    }
+    if let Ok(n) = selector.parse::<usize>() {
+        return entries
+            .iter()
+            .rev()
+            .nth(n)
+            .map(|entry| entry.new)
+            .ok_or_else(|| anyhow!("log for {} only has {} entries", name, entries.len()));
+    }
+    let date = parse_date(selector, Local::now())?;
+    match entries.iter().rev().find(|entry| entry.timestamp <= date) {
+        Some(entry) => Ok(entry.new),
+        // Before the log starts, the best we know is the first value it had.
+        None => {
+            let first = &entries[0];
+            let zero = Sha::from_array(&[0u8; 20]);
+            Ok(if first.old == zero {
+                first.new
+            } else {
+                first.old
+            })
+        }
+    }
+}
+
+///
+/// Parses the dates accepted by `@{...}` and `--expire` into seconds since
+/// the epoch: `now`, `yesterday`, relative dates such as `2.weeks.ago` or
+/// `3 days ago`, `YYYY-MM-DD` with an optional local time, and raw
+/// timestamps.
+///
+pub fn parse_date(input: &str, now: DateTime<Local>) -> Result<i64> {
+    let input = input.trim();
+    let error = || anyhow!("invalid date: {}", input);
+    match input {
+        "now" => return Ok(now.timestamp()),
+        "yesterday" => return Ok((now - Duration::days(1)).timestamp()),
+        _ => {}
+    }
+    if let Ok(timestamp) = input.parse::<i64>() {
+        return Ok(timestamp);
+    }
+
+    let words = input
+        .split(|c: char| c == '.' || c.is_whitespace())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>();
+    if let [count, unit, rest @ ..] = &words[..] {
+        if let (Ok(count), [] | ["ago"]) = (count.parse::<i64>(), rest) {
+            let unit = unit.strip_suffix('s').unwrap_or(unit);
+            let duration = match unit {
+                "second" => Duration::seconds(count),
+                "minute" => Duration::minutes(count),
+                "hour" => Duration::hours(count),
+                "day" => Duration::days(count),
+                "week" => Duration::weeks(count),
+                "month" => Duration::days(30 * count),
+                "year" => Duration::days(365 * count),
+                _ => return Err(error()),
+            };
+            return Ok((now - duration).timestamp());
+        }
+    }
+
+    let datetime = NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(input, "%Y-%m-%dT%H:%M:%S"))
+        .or_else(|_| NaiveDate::parse_from_str(input, "%Y-%m-%d").map(|d| d.and_hms(0, 0, 0)))
+        .map_err(|_| error())?;
+    Local
+        .from_local_datetime(&datetime)
+        .earliest()
+        .map(|d| d.timestamp())
+        .ok_or_else(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packfile::refs;
+    use crate::store::Repo;
+
+    #[test]
+    fn parsing_entries() {
+        let line = "0000000000000000000000000000000000000000 \
+                    33676d1c63d868803ed110b13be4e616bc8a29b7 \
+                    A U Thor <author@example.com> 1700000000 -0800\tclone: from /tmp/x";
+        let entry = ReflogEntry::parse(line).unwrap();
+        assert_eq!(entry.identity, "A U Thor <author@example.com>");
+        assert_eq!(entry.timestamp, 1700000000);
+        assert_eq!(entry.tz, "-0800");
+        assert_eq!(entry.message, "clone: from /tmp/x");
+        assert_eq!(entry.to_string(), line);
+
+        assert!(ReflogEntry::parse("not a reflog line").is_err());
+    }
+
+    #[test]
+    fn parsing_dates() {
+        let now = Local.timestamp(1_700_000_000, 0);
+        assert_eq!(parse_date("now", now).unwrap(), 1_700_000_000);
+        assert_eq!(parse_date("1234", now).unwrap(), 1234);
+        assert_eq!(
+            parse_date("2.weeks.ago", now).unwrap(),
+            1_700_000_000 - 14 * 86400
+        );
+        assert_eq!(
+            parse_date("90 days ago", now).unwrap(),
+            1_700_000_000 - 90 * 86400
+        );
+        assert_eq!(parse_date("1.hour", now).unwrap(), 1_700_000_000 - 3600);
+        let date = parse_date("2023-11-14", now).unwrap();
+        assert_eq!(parse_date("2023-11-14 00:00:00", now).unwrap(), date);
+        assert!(parse_date("2.fortnights.ago", now).is_err());
+        assert!(parse_date("someday", now).is_err());
+    }
+
+    #[test]
+    fn logging_ref_updates() {
+        let tmp = tempfile::tempdir().unwrap();
+        let gitdir = tmp.path().join(".git");
+        fs::create_dir_all(gitdir.join("objects")).unwrap();
+        fs::create_dir_all(gitdir.join("refs")).unwrap();
+        fs::write(gitdir.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+        let first = Sha::compute_from_bytes(b"first");
+        let second = Sha::compute_from_bytes(b"second");
+
+        refs::update_ref(&gitdir, "refs/heads/master", &first, "first").unwrap();
+        refs::update_ref(&gitdir, "refs/heads/master", &second, "second\nmore").unwrap();
+        let log = read(&gitdir, "refs/heads/master").unwrap();
+        let changes = log
+            .iter()
+            .map(|e| (e.old, e.new, &e.message[..]))
+            .collect::<Vec<_>>();
+        let zero = Sha::from_array(&[0u8; 20]);
+        assert_eq!(changes, [(zero, first, "first"), (first, second, "second")]);
+        // HEAD points to master, so its log follows along.
+        assert_eq!(read(&gitdir, "HEAD").unwrap(), log);
+
+        let repo = Repo::open(tmp.path()).unwrap();
+        let resolve = |name| repo.rev_parse(name);
+        assert_eq!(resolve("master@{0}").unwrap(), second);
+        assert_eq!(resolve("master@{1}").unwrap(), first);
+        assert_eq!(resolve("@{1}").unwrap(), first);
+        assert!(resolve("master@{2}").is_err());
+        assert_eq!(resolve("master@{now}").unwrap(), second);
+        assert_eq!(resolve("master@{1.year.ago}").unwrap(), first);
+
+        refs::delete_ref(&gitdir, "refs/heads/master", "delete").unwrap();
+        assert!(read(&gitdir, "refs/heads/master").unwrap().is_empty());
+        assert_eq!(logged_refs(&gitdir).unwrap(), ["HEAD"]);
+    }
+}
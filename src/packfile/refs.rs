@@ -1,80 +1,965 @@
-use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Write;
-use std::path::Path;
+use std::path::{
+    Path,
+    PathBuf,
+};
 
-use anyhow::Result;
+use anyhow::{
+    anyhow,
+    Result,
+};
 
-#[derive(Debug)]
+use crate::packfile::reflog;
+use crate::packfile::reflog::ReflogEntry;
+use crate::remote::refspec::Refspec;
+use crate::store;
+use crate::store::{
+    Config,
+    Repo,
+    Sha,
+};
+
+#[derive(Debug, Clone)]
 pub struct GitRef {
     pub id: String,
     pub name: String,
 }
 
-pub fn create_refs<P: AsRef<Path>>(gitdir: P, refs: &[GitRef]) -> Result<()> {
-    let (tags, branches): (Vec<_>, Vec<_>) = refs
-        .iter()
-        .filter(|r| !r.name.ends_with("^{}"))
-        .partition(|r| r.name.starts_with("refs/tags"));
+/// The file holding refs which have been packed together.
+const PACKED_REFS: &str = "packed-refs";
+
+/// The traits we write in the packed-refs header: every ref is peeled where
+/// possible and the refs are sorted by name.
+const PACKED_REFS_HEADER: &str = "# pack-refs with: peeled fully-peeled sorted \n";
+
+///
+/// A ref stored in `packed-refs`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedRef {
+    pub name: String,
+    pub id: Sha,
+    /// What an annotated tag ultimately points to, if known.
+    pub peeled: Option<Sha>,
+}
+
+///
+/// The refs stored together in `packed-refs`, sorted by name.
+///
+/// Loose refs take precedence over these, so a ref may be updated by writing
+/// a loose file without rewriting this one.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackedRefs {
+    refs: Vec<PackedRef>,
+}
+
+impl PackedRefs {
+    ///
+    /// Reads the repository's packed refs, which may not exist.
+    ///
+    pub fn read<P: AsRef<Path>>(gitdir: P) -> Result<Self> {
+        match fs::read_to_string(gitdir.as_ref().join(PACKED_REFS)) {
+            Ok(contents) => PackedRefs::parse(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(PackedRefs::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // -- # pack-refs with: peeled fully-peeled sorted
+    // -- <sha> SP <name>
+    // -- ^<peeled sha>
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut refs: Vec<PackedRef> = Vec::new();
+        let mut sorted = false;
+        for (i, line) in contents.lines().enumerate() {
+            let error = || anyhow!("bad packed-refs line {}: {:?}", i + 1, line);
+            if let Some(traits) = line.strip_prefix("# pack-refs with:") {
+                sorted = traits.split_whitespace().any(|t| t == "sorted");
+            } else if let Some(peeled) = line.strip_prefix('^') {
+                let last = refs.last_mut().ok_or_else(error)?;
+                last.peeled = Some(Sha::from_hex(peeled.as_bytes()).map_err(|_| error())?);
+            } else if !line.is_empty() && !line.starts_with('#') {
+                let (id, name) = line.split_once(' ').ok_or_else(error)?;
+                refs.push(PackedRef {
+                    name: name.to_owned(),
+                    id: Sha::from_hex(id.as_bytes()).map_err(|_| error())?,
+                    peeled: None,
+                });
+            }
+        }
+        if !sorted {
+            refs.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        Ok(PackedRefs { refs })
+    }
+
+    pub fn find(&self, name: &str) -> Option<&PackedRef> {
+        self.refs
+            .binary_search_by(|r| r.name.as_str().cmp(name))
+            .ok()
+            .map(|i| &self.refs[i])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PackedRef> {
+        self.refs.iter()
+    }
 
+    ///
+    /// Adds a ref, replacing any with the same name.
+    ///
+    pub fn insert(&mut self, packed: PackedRef) {
+        match self
+            .refs
+            .binary_search_by(|r| r.name.as_str().cmp(&packed.name))
+        {
+            Ok(i) => self.refs[i] = packed,
+            Err(i) => self.refs.insert(i, packed),
+        }
+    }
+
+    ///
+    /// Removes the named ref, returning whether it was there.
+    ///
+    pub fn remove(&mut self, name: &str) -> bool {
+        match self.refs.binary_search_by(|r| r.name.as_str().cmp(name)) {
+            Ok(i) => {
+                self.refs.remove(i);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    ///
+    /// Replaces the repository's `packed-refs`, writing to a lock file first
+    /// so readers never see it half written.
+    ///
+    pub fn write<P: AsRef<Path>>(&self, gitdir: P) -> Result<()> {
+        let mut lock = LockFile::acquire(gitdir.as_ref().join(PACKED_REFS))?;
+        lock.write_all(self.to_string().as_bytes())?;
+        lock.commit()
+    }
+}
+
+impl fmt::Display for PackedRefs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(PACKED_REFS_HEADER)?;
+        for r in &self.refs {
+            writeln!(f, "{} {}", r.id, r.name)?;
+            if let Some(peeled) = r.peeled {
+                writeln!(f, "^{}", peeled)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// Lists HEAD followed by every ref stored in the repository, with each
+/// resolved to the SHA it ultimately points to.
+///
+pub fn read_refs<P: AsRef<Path>>(gitdir: P) -> Result<Vec<GitRef>> {
     let gitdir = gitdir.as_ref();
-    write_refs(gitdir.join("refs/remotes/origin"), &branches)?;
-    write_refs(gitdir.join("refs/tags"), &tags)?;
-    Ok(())
+    let packed = PackedRefs::read(gitdir)?;
+    let mut names = Vec::new();
+    collect_ref_names(&gitdir.join("refs"), "refs", &mut names)?;
+    names.extend(packed.iter().map(|r| r.name.clone()));
+    names.sort();
+    names.dedup();
+
+    let mut refs = Vec::with_capacity(names.len() + 1);
+    // A HEAD pointing to an unborn branch has nothing to advertise.
+    if let Ok(sha) = store::resolve_ref(gitdir, "HEAD") {
+        refs.push(GitRef {
+            id: sha.hex(),
+            name: "HEAD".into(),
+        });
+    }
+    for name in names {
+        // Refs only found in packed-refs can't be symbolic, so there's no
+        // need to go through the files again for them.
+        let sha = match packed.find(&name) {
+            Some(r) if !gitdir.join(&name).is_file() => r.id,
+            _ => store::resolve_ref(gitdir, &name)?,
+        };
+        refs.push(GitRef {
+            id: sha.hex(),
+            name,
+        });
+    }
+    Ok(refs)
 }
 
-fn write_refs<P1: AsRef<Path>>(dir: P1, refs: &[&GitRef]) -> Result<()> {
-    for r in refs {
-        let simple_name = Path::new(&r.name)
-            .file_name()
-            .expect("constructed path should have a filename");
-        create_ref(dir.as_ref(), simple_name, &r.id)?;
+///
+/// Returns the ref HEAD points to, or `None` if HEAD is detached.
+///
+pub fn read_head_target<P: AsRef<Path>>(gitdir: P) -> Result<Option<String>> {
+    let contents = fs::read_to_string(gitdir.as_ref().join("HEAD"))?;
+    Ok(contents
+        .strip_prefix("ref: ")
+        .map(|target| target.trim().to_owned()))
+}
+
+fn collect_ref_names(dir: &Path, prefix: &str, names: &mut Vec<String>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for dir_entry in fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let file_name = dir_entry.file_name();
+        let file_name = match file_name.to_str() {
+            Some(name) if !name.ends_with(".lock") => name,
+            // Skip refs with names that can't be represented.
+            _ => continue,
+        };
+        let name = format!("{}/{}", prefix, file_name);
+        if dir_entry.file_type()?.is_dir() {
+            collect_ref_names(&dir_entry.path(), &name, names)?;
+        } else {
+            names.push(name);
+        }
     }
     Ok(())
 }
 
-pub fn update_head<P: AsRef<Path>>(gitdir: P, refs: &[GitRef]) -> Result<()> {
-    if let Some(head) = refs.iter().find(|r| r.name == "HEAD") {
-        let sha1 = &head.id;
-        let true_ref = refs.iter().find(|r| r.name != "HEAD" && r.id == *sha1);
-        let relpath = true_ref.map_or("refs/heads/master", |r| &r.name[..]);
+///
+/// Records a remote's refs locally, under the names the first matching
+/// refspec maps them to. Refs which no refspec covers are left out.
+///
+/// Unless the refspec starts with `+`, a ref which already exists is only
+/// updated if that's a fast-forward. Either every ref is created or, if any
+/// can't be, none are.
+///
+pub fn create_refs(
+    repo: &Repo,
+    refspecs: &[Refspec],
+    refs: &[GitRef],
+    message: &str,
+) -> Result<()> {
+    let mut transaction = RefTransaction::begin(repo.gitdir(), message);
+    for r in refs.iter().filter(|r| !r.name.ends_with("^{}")) {
+        let mapped = refspecs
+            .iter()
+            .find_map(|spec| spec.map(&r.name).map(|name| (spec, name)));
+        let (spec, name) = match mapped {
+            Some(mapped) => mapped,
+            None => continue,
+        };
+        let new = Sha::from_hex(r.id.as_bytes())?;
+        if spec.force() {
+            transaction.update(&name, new, None);
+            continue;
+        }
+        let zero = Sha::from_array(&[0u8; 20]);
+        let old = store::resolve_ref(repo.gitdir(), &name).unwrap_or(zero);
+        if old != zero && old != new && !repo.is_ancestor(old, new)? {
+            return Err(anyhow!(
+                "refusing to update {} to {}: not a fast-forward",
+                name,
+                new
+            ));
+        }
+        transaction.update(&name, new, Some(old));
+    }
+    transaction.commit()
+}
 
-        let path = gitdir.as_ref().join(relpath);
-        let (dir, name) = split_path(&path).unwrap();
-        create_ref(dir, name, sha1)?;
-        create_sym_ref(gitdir, "HEAD", relpath)?;
+///
+/// Picks the branch a remote's HEAD points to: the one the server named, if
+/// it did, and otherwise a branch at the same commit, preferring master.
+///
+/// Returns `None` if the remote has no HEAD, or it matches no branch.
+///
+pub fn remote_head_branch<'r>(refs: &'r [GitRef], head_target: Option<&str>) -> Option<&'r GitRef> {
+    let head = refs.iter().find(|r| r.name == "HEAD")?;
+    let mut branches = refs.iter().filter(|r| r.name.starts_with("refs/heads/"));
+    if let Some(target) = head_target {
+        return branches.find(|r| r.name == target);
     }
-    Ok(())
+    let candidates = branches.filter(|r| r.id == head.id).collect::<Vec<_>>();
+    candidates
+        .iter()
+        .find(|r| r.name == "refs/heads/master")
+        .or_else(|| candidates.first())
+        .copied()
+}
+
+///
+/// Creates the branch `name` (e.g. `refs/heads/master`) at `id` and points
+/// HEAD at it.
+///
+pub fn set_head_branch<P: AsRef<Path>>(
+    gitdir: P,
+    name: &str,
+    id: &str,
+    message: &str,
+) -> Result<()> {
+    let mut transaction = RefTransaction::begin(gitdir, message);
+    transaction.update(name, Sha::from_hex(id.as_bytes())?, None);
+    transaction.update_symbolic("HEAD", name);
+    transaction.commit()
+}
+
+///
+/// Points the symbolic ref `name` (e.g. `refs/remotes/origin/HEAD`) at the
+/// ref `target`.
+///
+pub fn set_symbolic_ref<P: AsRef<Path>>(
+    gitdir: P,
+    name: &str,
+    target: &str,
+    message: &str,
+) -> Result<()> {
+    let mut transaction = RefTransaction::begin(gitdir, message);
+    transaction.update_symbolic(name, target);
+    transaction.commit()
+}
+
+///
+/// Points HEAD straight at a commit rather than a branch.
+///
+pub fn set_head_detached<P: AsRef<Path>>(gitdir: P, id: &str, message: &str) -> Result<()> {
+    let mut transaction = RefTransaction::begin(gitdir, message);
+    transaction.update_no_deref("HEAD", Sha::from_hex(id.as_bytes())?, None);
+    transaction.commit()
+}
+
+///
+/// Points the named ref (e.g. `refs/heads/master`) at the given SHA, creating it if needed.
+///
+#[allow(dead_code)]
+pub fn update_ref<P: AsRef<Path>>(gitdir: P, name: &str, sha: &Sha, message: &str) -> Result<()> {
+    let mut transaction = RefTransaction::begin(gitdir, message);
+    transaction.update(name, *sha, None);
+    transaction.commit()
 }
 
 ///
-/// Creates a ref in the given repository.
+/// Removes the named ref from the given repository, whether it's loose,
+/// packed or both.
+///
+#[allow(dead_code)]
+pub fn delete_ref<P: AsRef<Path>>(gitdir: P, name: &str, message: &str) -> Result<()> {
+    let mut transaction = RefTransaction::begin(gitdir, message);
+    transaction.delete(name, None);
+    transaction.commit()
+}
+
+///
+/// A set of ref updates which are applied together or not at all.
+///
+/// Each ref is locked by creating `<ref>.lock` beside it, and its new value
+/// is written there and renamed into place once every lock is held and every
+/// expected old value has been checked. A lock which already exists means
+/// someone else is updating the ref, and the transaction fails.
+///
+/// Expected old values are given as `Some(sha)`, where the zero SHA means
+/// the ref must not exist yet. `None` skips the check.
 ///
-fn create_ref<P1: AsRef<Path>, P2: AsRef<Path>>(dir: P1, name: P2, id: &str) -> Result<()> {
-    let dir = dir.as_ref();
-    if !dir.exists() {
-        fs::create_dir_all(dir)?;
+/// Each change is recorded in the ref's reflog with the transaction's
+/// message, as is a change to the branch HEAD points to in HEAD's reflog.
+/// The reflogs are written while the refs are still locked, so they never
+/// miss a change which has been made.
+///
+pub struct RefTransaction {
+    gitdir: PathBuf,
+    message: String,
+    updates: Vec<RefUpdate>,
+}
+
+struct RefUpdate {
+    name: String,
+    change: RefChange,
+    expected_old: Option<Sha>,
+    /// Whether an update to a symbolic HEAD goes to the branch instead.
+    deref: bool,
+}
+
+enum RefChange {
+    Update(Sha),
+    Symbolic(String),
+    Delete,
+}
+
+impl RefTransaction {
+    pub fn begin<P: AsRef<Path>>(gitdir: P, message: &str) -> Self {
+        RefTransaction {
+            gitdir: gitdir.as_ref().to_owned(),
+            message: message.to_owned(),
+            updates: Vec::new(),
+        }
+    }
+
+    ///
+    /// Points `name` at `new`. When `name` is HEAD and it points to a
+    /// branch, the branch is updated instead.
+    ///
+    pub fn update(&mut self, name: &str, new: Sha, expected_old: Option<Sha>) {
+        self.push(name, RefChange::Update(new), expected_old, true);
+    }
+
+    ///
+    /// Points `name` at `new`, replacing it even if it's symbolic, as when
+    /// detaching HEAD.
+    ///
+    pub fn update_no_deref(&mut self, name: &str, new: Sha, expected_old: Option<Sha>) {
+        self.push(name, RefChange::Update(new), expected_old, false);
+    }
+
+    ///
+    /// Points `name` at the ref `target`, e.g. HEAD at `refs/heads/master`.
+    ///
+    pub fn update_symbolic(&mut self, name: &str, target: &str) {
+        self.push(name, RefChange::Symbolic(target.to_owned()), None, false);
+    }
+
+    pub fn delete(&mut self, name: &str, expected_old: Option<Sha>) {
+        self.push(name, RefChange::Delete, expected_old, false);
+    }
+
+    fn push(&mut self, name: &str, change: RefChange, expected_old: Option<Sha>, deref: bool) {
+        self.updates.push(RefUpdate {
+            name: name.to_owned(),
+            change,
+            expected_old,
+            deref,
+        });
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        // Read before anything is locked, so a bad config can't leave a
+        // change made but not logged.
+        let config = Config::load(Some(&self.gitdir))?;
+        let head_target = read_head_target(&self.gitdir).ok().flatten();
+        for update in &mut self.updates {
+            if update.deref && update.name == "HEAD" {
+                if let Some(target) = &head_target {
+                    update.name = target.clone();
+                }
+            }
+        }
+
+        // Locking in a consistent order keeps two transactions from each
+        // holding a lock the other needs.
+        self.updates.sort_by(|a, b| a.name.cmp(&b.name));
+        for pair in self.updates.windows(2) {
+            if pair[0].name == pair[1].name {
+                return Err(anyhow!("multiple updates for ref {}", pair[0].name));
+            }
+        }
+        for update in &self.updates {
+            if update.name != "HEAD" && !is_valid_ref_name(&update.name) {
+                return Err(anyhow!("refusing to update invalid ref {}", update.name));
+            }
+        }
+
+        // Should anything fail from here on, the locks are released as
+        // they're dropped and no ref has changed.
+        let mut locks = Vec::with_capacity(self.updates.len());
+        for update in &self.updates {
+            locks.push(LockFile::acquire(self.gitdir.join(&update.name))?);
+        }
+        let zero = Sha::from_array(&[0u8; 20]);
+        let mut olds = Vec::with_capacity(self.updates.len());
+        for update in &self.updates {
+            let current = store::resolve_ref(&self.gitdir, &update.name).unwrap_or(zero);
+            olds.push(current);
+            let expected = match update.expected_old {
+                Some(expected) => expected,
+                None => continue,
+            };
+            if current != expected {
+                return Err(anyhow!(
+                    "ref {} is at {} but expected {}",
+                    update.name,
+                    current,
+                    expected
+                ));
+            }
+        }
+        for (update, lock) in self.updates.iter().zip(&mut locks) {
+            match &update.change {
+                RefChange::Update(sha) => writeln!(lock, "{}", sha)?,
+                RefChange::Symbolic(target) => writeln!(lock, "ref: {}", target)?,
+                RefChange::Delete => {}
+            }
+        }
+
+        // Packed copies of deleted refs go first, since a packed ref left
+        // behind would reappear once its loose file is gone.
+        let deleted = self
+            .updates
+            .iter()
+            .filter(|u| matches!(u.change, RefChange::Delete))
+            .map(|u| &u.name[..])
+            .collect::<Vec<_>>();
+        let mut packed = PackedRefs::read(&self.gitdir)?;
+        let mut missing = deleted
+            .iter()
+            .filter(|name| packed.find(name).is_none() && !self.gitdir.join(name).is_file());
+        if let Some(name) = missing.next() {
+            return Err(anyhow!("no such ref: {}", name));
+        }
+        if deleted
+            .iter()
+            .fold(false, |changed, name| packed.remove(name) | changed)
+        {
+            packed.write(&self.gitdir)?;
+        }
+
+        self.log(&config, head_target.as_deref(), &olds)?;
+        for (update, lock) in self.updates.iter().zip(locks) {
+            match update.change {
+                RefChange::Delete => {
+                    match fs::remove_file(self.gitdir.join(&update.name)) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                    drop(lock);
+                    remove_empty_dirs(&self.gitdir, &update.name);
+                }
+                _ => lock.commit()?,
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Records the changes about to be committed in the reflogs, given the
+    /// value each ref had before and the branch HEAD points to.
+    ///
+    fn log(&self, config: &Config, head_target: Option<&str>, olds: &[Sha]) -> Result<()> {
+        let zero = Sha::from_array(&[0u8; 20]);
+        let head_updated = self.updates.iter().any(|u| u.name == "HEAD");
+        for (update, &old) in self.updates.iter().zip(olds) {
+            let new = match &update.change {
+                RefChange::Update(sha) => *sha,
+                // The target may be changing in this same transaction.
+                RefChange::Symbolic(target) => match self.new_value(target) {
+                    Some(sha) => sha,
+                    None => store::resolve_ref(&self.gitdir, target).unwrap_or(zero),
+                },
+                RefChange::Delete => {
+                    reflog::delete(&self.gitdir, &update.name)?;
+                    continue;
+                }
+            };
+            let entry = ReflogEntry::now(config, old, new, &self.message);
+            if reflog::should_log(&self.gitdir, config, &update.name) {
+                reflog::append(&self.gitdir, &update.name, &entry)?;
+            }
+            let through_head = !head_updated && head_target == Some(&update.name[..]);
+            if through_head && reflog::should_log(&self.gitdir, config, "HEAD") {
+                reflog::append(&self.gitdir, "HEAD", &entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn new_value(&self, name: &str) -> Option<Sha> {
+        self.updates.iter().find_map(|u| match &u.change {
+            RefChange::Update(sha) if u.name == name => Some(*sha),
+            _ => None,
+        })
+    }
+}
+
+///
+/// An exclusive lock on a file, taken by creating `<file>.lock`. Whatever is
+/// written to the lock replaces the file when it's committed, and the lock
+/// is removed, leaving the file alone, if it's dropped first.
+///
+pub struct LockFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: Option<File>,
+}
+
+impl LockFile {
+    pub fn acquire(path: PathBuf) -> Result<Self> {
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        if let Some(dir) = lock_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => anyhow!(
+                    "unable to lock {}: another process may be updating it",
+                    path.display()
+                ),
+                _ => anyhow!("unable to lock {}: {}", path.display(), e),
+            })?;
+        Ok(LockFile {
+            path,
+            lock_path,
+            file: Some(file),
+        })
+    }
+
+    ///
+    /// Moves the lock into place as the new contents of the file.
+    ///
+    pub fn commit(mut self) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+        fs::rename(&self.lock_path, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Write for LockFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file
+            .as_mut()
+            .expect("lock should be open until committed")
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file
+            .as_mut()
+            .expect("lock should be open until committed")
+            .flush()
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // After a commit the lock has already been renamed away.
+        if self.file.is_some() {
+            let _ = fs::remove_file(&self.lock_path);
+        }
     }
-    let mut full_path = dir.to_owned();
-    full_path.push(name);
-    let mut file = File::create(full_path)?;
-    file.write_fmt(format_args!("{}\n", id))?;
-    Ok(())
 }
 
 ///
-/// Creates a symbolic ref in the given repository.
+/// Moves loose refs into `packed-refs`: every ref with `all`, and otherwise
+/// only tags and refs which were already packed. Annotated tags are
+/// recorded along with what they peel to.
 ///
-fn create_sym_ref<P: AsRef<Path>>(gitdir: P, name: &str, the_ref: &str) -> Result<()> {
-    let path = gitdir.as_ref().join(name);
-    let mut file = File::create(path)?;
-    file.write_fmt(format_args!("ref: {}\n", the_ref))?;
+/// The loose files are removed afterwards when `prune` is set. Symbolic
+/// refs are never packed.
+///
+pub fn pack_refs(repo: &Repo, all: bool, prune: bool) -> Result<()> {
+    let gitdir = repo.gitdir();
+    let mut packed = PackedRefs::read(gitdir)?;
+    let mut names = Vec::new();
+    collect_ref_names(&gitdir.join("refs"), "refs", &mut names)?;
+    names.sort();
+
+    let mut loose = Vec::new();
+    for name in names {
+        if !all && !name.starts_with("refs/tags/") && packed.find(&name).is_none() {
+            continue;
+        }
+        let contents = fs::read_to_string(gitdir.join(&name))?;
+        if contents.starts_with("ref: ") {
+            continue;
+        }
+        let id = Sha::from_hex(contents.trim().as_bytes())
+            .map_err(|_| anyhow!("invalid ref {}: {:?}", name, contents))?;
+        let peeled = Some(repo.peel(&id)?).filter(|peeled| *peeled != id);
+        packed.insert(PackedRef {
+            name: name.clone(),
+            id,
+            peeled,
+        });
+        loose.push(name);
+    }
+    packed.write(gitdir)?;
+
+    if prune {
+        for name in loose {
+            // Holding the ref's lock keeps us from racing a transaction.
+            let lock = LockFile::acquire(gitdir.join(&name))?;
+            fs::remove_file(gitdir.join(&name))?;
+            drop(lock);
+            remove_empty_dirs(gitdir, &name);
+        }
+    }
     Ok(())
 }
 
-fn split_path<P: AsRef<Path>>(path: &P) -> Option<(&Path, &OsStr)> {
-    let path = path.as_ref();
-    path.file_name()
-        .and_then(|fname| path.parent().map(|p| (p, fname)))
+///
+/// Removes the directories left empty by removing the loose ref `name`,
+/// stopping short of the namespace directories such as `refs/heads`.
+///
+fn remove_empty_dirs(gitdir: &Path, name: &str) {
+    let mut dir = Path::new(name).parent();
+    while let Some(d) = dir.filter(|d| d.components().count() > 2) {
+        // This fails once a directory isn't empty, which is where we stop.
+        if fs::remove_dir(gitdir.join(d)).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+///
+/// Returns true if `name` is safe to use as the name of a ref under `refs/`.
+///
+pub fn is_valid_ref_name(name: &str) -> bool {
+    name.starts_with("refs/")
+        && name.split('/').all(|component| {
+            !component.is_empty()
+                && !component.starts_with('.')
+                && !component.ends_with(".lock")
+                && !component.contains("..")
+        })
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::copy_dir;
+
+    static MASTER: &str = "33676d1c63d868803ed110b13be4e616bc8a29b7";
+    static MERGE: &str = "3c7cfac73a699ef415bc737ce5529ac66c5692a9";
+
+    fn names_and_ids(gitdir: &Path) -> Vec<(String, String)> {
+        read_refs(gitdir)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.name, r.id))
+            .collect()
+    }
+
+    #[test]
+    fn parsing_packed_refs() {
+        let contents = format!(
+            "{merge} refs/tags/v1\n\
+             ^{master}\n\
+             {master} refs/heads/main\n",
+            master = MASTER,
+            merge = MERGE
+        );
+        let packed = PackedRefs::parse(&contents).unwrap();
+        let names = packed.iter().map(|r| &r.name[..]).collect::<Vec<_>>();
+        assert_eq!(names, ["refs/heads/main", "refs/tags/v1"]);
+        let tag = packed.find("refs/tags/v1").unwrap();
+        assert_eq!(tag.peeled.unwrap().hex(), MASTER);
+        assert!(packed.find("refs/heads/missing").is_none());
+
+        let written = packed.to_string();
+        assert!(written.starts_with(PACKED_REFS_HEADER));
+        assert_eq!(PackedRefs::parse(&written).unwrap(), packed);
+
+        assert!(PackedRefs::parse("^0000\n").is_err());
+        assert!(PackedRefs::parse("not-a-sha refs/heads/main\n").is_err());
+    }
+
+    #[test]
+    fn packing_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let gitdir = dir.path().join("simple.git");
+        copy_dir(Path::new("tests/data/repos/simple.git"), &gitdir);
+        let before = names_and_ids(&gitdir);
+
+        // Without --all, only tags are packed.
+        let repo = Repo::open(&gitdir).unwrap();
+        pack_refs(&repo, false, true).unwrap();
+        assert!(!gitdir.join("refs/tags/test_tag").exists());
+        assert!(gitdir.join("refs/heads/master").exists());
+
+        pack_refs(&repo, true, true).unwrap();
+        assert!(!gitdir.join("refs/heads/master").exists());
+        assert!(gitdir.join("refs/heads").is_dir());
+        assert_eq!(names_and_ids(&gitdir), before);
+        let packed = PackedRefs::read(&gitdir).unwrap();
+        let tag = packed.find("refs/tags/test_tag").unwrap();
+        assert_eq!(tag.peeled.unwrap().hex(), MERGE);
+        assert_eq!(packed.find("refs/heads/master").unwrap().peeled, None);
+        assert_eq!(store::resolve_ref(&gitdir, "master").unwrap().hex(), MASTER);
+
+        // Loose refs take precedence over packed ones.
+        let merge = Sha::from_hex(MERGE.as_bytes()).unwrap();
+        update_ref(&gitdir, "refs/heads/master", &merge, "test").unwrap();
+        assert_eq!(store::resolve_ref(&gitdir, "master").unwrap(), merge);
+
+        delete_ref(&gitdir, "refs/heads/master", "test").unwrap();
+        assert!(store::resolve_ref(&gitdir, "master").is_err());
+        assert!(PackedRefs::read(&gitdir)
+            .unwrap()
+            .find("refs/heads/master")
+            .is_none());
+        assert!(delete_ref(&gitdir, "refs/heads/master", "test").is_err());
+    }
+
+    #[test]
+    fn updating_refs_all_or_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let gitdir = dir.path().join("simple.git");
+        copy_dir(Path::new("tests/data/repos/simple.git"), &gitdir);
+        let before = names_and_ids(&gitdir);
+        let master = Sha::from_hex(MASTER.as_bytes()).unwrap();
+        let merge = Sha::from_hex(MERGE.as_bytes()).unwrap();
+        let zero = Sha::from_array(&[0u8; 20]);
+
+        // One stale expectation stops every update.
+        let mut transaction = RefTransaction::begin(&gitdir, "test");
+        transaction.update("refs/heads/new", master, Some(zero));
+        transaction.update("refs/heads/master", merge, Some(merge));
+        transaction.delete("refs/heads/test", None);
+        assert!(transaction.commit().is_err());
+        assert_eq!(names_and_ids(&gitdir), before);
+
+        // As does a ref someone else has locked.
+        fs::write(gitdir.join("refs/heads/test.lock"), "").unwrap();
+        let mut transaction = RefTransaction::begin(&gitdir, "test");
+        transaction.update("refs/heads/new", master, Some(zero));
+        transaction.update("refs/heads/test", master, None);
+        assert!(transaction.commit().is_err());
+        assert_eq!(names_and_ids(&gitdir), before);
+        assert!(!gitdir.join("refs/heads/new.lock").exists());
+        fs::remove_file(gitdir.join("refs/heads/test.lock")).unwrap();
+
+        let mut transaction = RefTransaction::begin(&gitdir, "test");
+        transaction.update("refs/heads/feature/new", master, Some(zero));
+        transaction.update("refs/heads/master", merge, Some(master));
+        transaction.delete("refs/heads/test", None);
+        transaction.update_symbolic("HEAD", "refs/heads/feature/new");
+        transaction.commit().unwrap();
+        assert_eq!(store::resolve_ref(&gitdir, "HEAD").unwrap(), master);
+        assert_eq!(store::resolve_ref(&gitdir, "master").unwrap(), merge);
+        assert!(store::resolve_ref(&gitdir, "test").is_err());
+        assert_eq!(
+            fs::read_to_string(gitdir.join("HEAD")).unwrap(),
+            "ref: refs/heads/feature/new\n"
+        );
+
+        let mut transaction = RefTransaction::begin(&gitdir, "test");
+        transaction.delete("refs/heads/missing", None);
+        assert!(transaction.commit().is_err());
+    }
+
+    #[test]
+    fn checking_expected_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let gitdir = dir.path().join("simple.git");
+        copy_dir(Path::new("tests/data/repos/simple.git"), &gitdir);
+        let master = Sha::from_hex(MASTER.as_bytes()).unwrap();
+        let merge = Sha::from_hex(MERGE.as_bytes()).unwrap();
+
+        let mut transaction = RefTransaction::begin(&gitdir, "test");
+        transaction.update("refs/heads/master", merge, Some(merge));
+        let err = transaction.commit().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "ref refs/heads/master is at {} but expected {}",
+                MASTER, MERGE
+            )
+        );
+        assert!(!gitdir.join("refs/heads/master.lock").exists());
+
+        // A ref which is only packed is checked against its packed value.
+        let repo = Repo::open(&gitdir).unwrap();
+        pack_refs(&repo, true, true).unwrap();
+        assert!(!gitdir.join("refs/heads/master").exists());
+        let mut transaction = RefTransaction::begin(&gitdir, "test");
+        transaction.update("refs/heads/master", merge, Some(merge));
+        assert!(transaction.commit().is_err());
+        let mut transaction = RefTransaction::begin(&gitdir, "test");
+        transaction.update("refs/heads/master", merge, Some(master));
+        transaction.commit().unwrap();
+        assert_eq!(store::resolve_ref(&gitdir, "master").unwrap(), merge);
+    }
+
+    #[test]
+    fn creating_refs_through_refspecs() {
+        let dir = tempfile::tempdir().unwrap();
+        let gitdir = dir.path().join("simple.git");
+        copy_dir(Path::new("tests/data/repos/simple.git"), &gitdir);
+        let repo = Repo::open(&gitdir).unwrap();
+        let test = store::resolve_ref(&gitdir, "test").unwrap().hex();
+        let remote_ref = |id: &str| GitRef {
+            name: "refs/heads/test".into(),
+            id: id.into(),
+        };
+
+        // Moving the branch forward is fine without forcing it.
+        let specs = [Refspec::parse("refs/heads/*:refs/heads/*").unwrap()];
+        create_refs(&repo, &specs, &[remote_ref(MERGE)], "test").unwrap();
+        assert_eq!(store::resolve_ref(&gitdir, "test").unwrap().hex(), MERGE);
+
+        // Moving it back isn't.
+        let err = create_refs(&repo, &specs, &[remote_ref(&test)], "test")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("not a fast-forward"));
+        assert_eq!(store::resolve_ref(&gitdir, "test").unwrap().hex(), MERGE);
+
+        let forced = Refspec::parse("+refs/heads/*:refs/heads/*").unwrap();
+        create_refs(&repo, &[forced], &[remote_ref(&test)], "test").unwrap();
+        assert_eq!(store::resolve_ref(&gitdir, "test").unwrap().hex(), test);
+    }
+
+    #[test]
+    fn refusing_locked_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let gitdir = dir.path().join("simple.git");
+        copy_dir(Path::new("tests/data/repos/simple.git"), &gitdir);
+        let merge = Sha::from_hex(MERGE.as_bytes()).unwrap();
+        let lock = gitdir.join("refs/heads/master.lock");
+        fs::write(&lock, "").unwrap();
+
+        let mut transaction = RefTransaction::begin(&gitdir, "test");
+        transaction.update("refs/heads/master", merge, None);
+        let err = transaction.commit().err().unwrap();
+        assert!(err.to_string().contains("another process"));
+        // Someone else's lock is left for them to finish with.
+        assert!(lock.exists());
+        assert_eq!(store::resolve_ref(&gitdir, "master").unwrap().hex(), MASTER);
+        assert!(reflog::read(&gitdir, "refs/heads/master")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn updating_through_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let gitdir = dir.path().join("simple.git");
+        copy_dir(Path::new("tests/data/repos/simple.git"), &gitdir);
+        let master = Sha::from_hex(MASTER.as_bytes()).unwrap();
+        let merge = Sha::from_hex(MERGE.as_bytes()).unwrap();
+        // The repository is bare, so only refs with reflogs are logged.
+        fs::create_dir_all(gitdir.join("logs/refs/heads")).unwrap();
+        fs::write(gitdir.join("logs/HEAD"), "").unwrap();
+        fs::write(gitdir.join("logs/refs/heads/master"), "").unwrap();
+
+        // HEAD points to master, which is what moves.
+        let mut transaction = RefTransaction::begin(&gitdir, "test");
+        transaction.update("HEAD", merge, Some(master));
+        transaction.commit().unwrap();
+        assert_eq!(
+            fs::read_to_string(gitdir.join("HEAD")).unwrap(),
+            "ref: refs/heads/master\n"
+        );
+        assert_eq!(store::resolve_ref(&gitdir, "master").unwrap(), merge);
+        let log = reflog::read(&gitdir, "refs/heads/master").unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(reflog::read(&gitdir, "HEAD").unwrap(), log);
+
+        set_head_detached(&gitdir, MASTER, "detach").unwrap();
+        assert_eq!(
+            fs::read_to_string(gitdir.join("HEAD")).unwrap(),
+            format!("{}\n", MASTER)
+        );
+        assert_eq!(store::resolve_ref(&gitdir, "master").unwrap(), merge);
+    }
 }
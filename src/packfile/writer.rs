@@ -0,0 +1,161 @@
+use std::io::Write;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+use byteorder::{
+    BigEndian,
+    WriteBytesExt,
+};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use super::MAGIC_HEADER;
+use crate::store::{
+    ObjectType,
+    PackedObject,
+    Sha,
+};
+
+static VERSION: u32 = 2;
+
+///
+/// Streams objects into the packfile format.
+///
+/// Objects are written whole unless they're given as a delta that already
+/// exists, since working out new deltas isn't supported.
+///
+pub struct PackWriter<W> {
+    writer: W,
+    digest: sha1::Sha1,
+    remaining: usize,
+}
+
+impl<W: Write> PackWriter<W> {
+    ///
+    /// Writes the pack header announcing `num_objects` objects to follow.
+    ///
+    pub fn new(writer: W, num_objects: usize) -> Result<Self> {
+        use sha1::Digest;
+
+        let mut pack_writer = PackWriter {
+            writer,
+            digest: sha1::Sha1::new(),
+            remaining: num_objects,
+        };
+        let mut header = Vec::with_capacity(super::HEADER_LENGTH);
+        header.write_u32::<BigEndian>(MAGIC_HEADER)?;
+        header.write_u32::<BigEndian>(VERSION)?;
+        header.write_u32::<BigEndian>(num_objects as u32)?;
+        pack_writer.write_hashed(&header)?;
+        Ok(pack_writer)
+    }
+
+    pub fn write_object(&mut self, object: &PackedObject) -> Result<()> {
+        let type_id = match object.obj_type {
+            ObjectType::Commit => 1,
+            ObjectType::Tree => 2,
+            ObjectType::Blob => 3,
+            ObjectType::Tag => 4,
+        };
+        self.write_entry(type_id, &[], &object.content)
+    }
+
+    ///
+    /// Writes an object as `patch` applied to the object `base`, which must
+    /// come earlier in the same pack for it to be indexed.
+    ///
+    pub fn write_ref_delta(&mut self, base: &Sha, patch: &[u8]) -> Result<()> {
+        self.write_entry(7, base.as_bytes(), patch)
+    }
+
+    ///
+    /// Writes the trailing checksum, returning it along with the inner writer.
+    ///
+    pub fn finish(mut self) -> Result<(Sha, W)> {
+        use sha1::Digest;
+
+        if self.remaining != 0 {
+            return Err(anyhow!(
+                "pack is missing {} objects declared in header",
+                self.remaining
+            ));
+        }
+        let bytes: [u8; 20] = self.digest.finalize().into();
+        let sha = Sha::from_array(&bytes);
+        self.writer.write_all(sha.as_bytes())?;
+        self.writer.flush()?;
+        Ok((sha, self.writer))
+    }
+
+    fn write_entry(&mut self, type_id: u8, prefix: &[u8], content: &[u8]) -> Result<()> {
+        if self.remaining == 0 {
+            return Err(anyhow!("wrote more objects than declared in header"));
+        }
+        self.remaining -= 1;
+
+        let header = encode_entry_header(type_id, content.len());
+
+        let mut z = ZlibEncoder::new(Vec::new(), Compression::Default);
+        z.write_all(content)?;
+        let compressed = z.finish()?;
+
+        self.write_hashed(&header)?;
+        self.write_hashed(prefix)?;
+        self.write_hashed(&compressed)?;
+        Ok(())
+    }
+
+    fn write_hashed(&mut self, bytes: &[u8]) -> Result<()> {
+        use sha1::Digest;
+
+        self.digest.update(bytes);
+        self.writer.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+// The inverse of the size header parsed in `EntryReader::read_object`.
+//
+// The first byte holds the type in bits 4-6 and the low 4 bits of the size,
+// while each following byte holds the next 7 bits. The MSB is set on every byte
+// but the last.
+fn encode_entry_header(type_id: u8, size: usize) -> Vec<u8> {
+    let mut header = Vec::new();
+    let mut c = (type_id << 4) | (size & 15) as u8;
+    let mut size = size >> 4;
+    while size > 0 {
+        header.push(c | 0x80);
+        c = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    header.push(c);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packfile::PackFile;
+
+    #[test]
+    fn written_packs_can_be_read() {
+        let objects = [
+            PackedObject::new(ObjectType::Blob, b"hello world\n".to_vec()),
+            PackedObject::new(ObjectType::Blob, vec![b'x'; 1000]),
+        ];
+        let mut writer = PackWriter::new(Vec::new(), objects.len()).unwrap();
+        for object in &objects {
+            writer.write_object(object).unwrap();
+        }
+        let (sha, encoded) = writer.finish().unwrap();
+
+        let pack = PackFile::parse(&encoded).unwrap();
+        assert_eq!(pack.sha(), &sha);
+        for object in &objects {
+            let read = pack.find_by_sha(&object.sha()).unwrap();
+            assert_eq!(read.content, object.content);
+        }
+    }
+}
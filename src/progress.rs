@@ -0,0 +1,209 @@
+use std::io;
+use std::io::{
+    IsTerminal,
+    Write,
+};
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+// Off unless a command turns it on, so servers stay quiet.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// Set by `--quiet`, which hides the server's messages as well.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// How often a meter is redrawn when its percentage hasn't changed.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+///
+/// Turns progress output on or off for the rest of the command. By default
+/// it's shown only when stderr is a terminal; `--quiet` hides it and
+/// `--progress` forces it on.
+///
+pub fn configure(quiet: bool, progress: bool) {
+    let enabled = progress || (!quiet && io::stderr().is_terminal());
+    ENABLED.store(enabled, Ordering::Relaxed);
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+///
+/// A progress meter drawn on a single line of stderr, like
+/// "Receiving objects:  45% (14/30), 1.20 MiB | 2.40 MiB/s".
+///
+pub struct Progress {
+    title: String,
+    total: Option<usize>,
+    count: usize,
+    bytes: Option<u64>,
+    enabled: bool,
+    start: Instant,
+    last_draw: Option<(Instant, Option<usize>)>,
+}
+
+impl Progress {
+    ///
+    /// Starts a meter counting up to `total`, or without a percentage if the
+    /// total isn't known.
+    ///
+    pub fn new<S: Into<String>>(title: S, total: Option<usize>) -> Self {
+        Progress {
+            title: title.into(),
+            total,
+            count: 0,
+            bytes: None,
+            enabled: enabled(),
+            start: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    pub fn set(&mut self, count: usize) {
+        self.count = count;
+        self.draw(false);
+    }
+
+    pub fn inc(&mut self) {
+        self.set(self.count + 1);
+    }
+
+    ///
+    /// Records the bytes transferred so far, which are shown along with the
+    /// rate on the next update.
+    ///
+    pub fn set_bytes(&mut self, bytes: u64) {
+        self.bytes = Some(bytes);
+    }
+
+    pub fn finish(mut self) {
+        self.draw(true);
+    }
+
+    fn draw(&mut self, done: bool) {
+        if !self.enabled {
+            return;
+        }
+        let percent = self
+            .total
+            .map(|total| (self.count * 100).checked_div(total).unwrap_or(100));
+        let now = Instant::now();
+        if let Some((last, last_percent)) = self.last_draw {
+            if !done && percent == last_percent && now - last < REDRAW_INTERVAL {
+                return;
+            }
+        }
+        self.last_draw = Some((now, percent));
+
+        let mut line = format!("{}: ", self.title);
+        match (percent, self.total) {
+            (Some(percent), Some(total)) => {
+                line.push_str(&format!("{:3}% ({}/{})", percent, self.count, total))
+            }
+            _ => line.push_str(&self.count.to_string()),
+        }
+        if let Some(bytes) = self.bytes {
+            let elapsed = (now - self.start).as_secs_f64().max(0.001);
+            line.push_str(&format!(
+                ", {} | {}/s",
+                human_bytes(bytes as f64),
+                human_bytes(bytes as f64 / elapsed)
+            ));
+        }
+        let end = if done { ", done.\n" } else { "\r" };
+        let _ = write!(io::stderr(), "{}{}", line, end);
+    }
+}
+
+fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024.0 {
+        return format!("{} bytes", bytes as u64);
+    }
+    let mut value = bytes / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+///
+/// Shows messages from the server's progress channel.
+///
+/// The server updates its progress lines in place with `\r`, and may split a
+/// line across packets. Each complete line is shown with a "remote: " prefix
+/// and, on a terminal, cleared to the end so shorter updates don't leave
+/// parts of longer ones behind. Nothing is shown with `--quiet`; when
+/// progress is just off, the server was asked not to send any, so its other
+/// messages still get through.
+///
+#[derive(Default)]
+pub struct RemoteMessages {
+    partial: Vec<u8>,
+}
+
+impl RemoteMessages {
+    pub fn write(&mut self, data: &[u8]) {
+        if quiet() {
+            return;
+        }
+        self.partial.extend_from_slice(data);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\r' || b == b'\n') {
+            let line = self.partial.drain(..=end).collect::<Vec<_>>();
+            let (message, terminator) = line.split_at(line.len() - 1);
+            show_remote_line(message, terminator[0]);
+        }
+    }
+
+    ///
+    /// Shows anything left without a line ending.
+    ///
+    pub fn finish(&mut self) {
+        if !self.partial.is_empty() {
+            let message = std::mem::take(&mut self.partial);
+            show_remote_line(&message, b'\n');
+        }
+    }
+}
+
+fn show_remote_line(message: &[u8], terminator: u8) {
+    let mut stderr = io::stderr();
+    // Blank lines are just the end of an earlier \r update.
+    if message.is_empty() {
+        let _ = stderr.write_all(&[terminator]);
+        return;
+    }
+    let clear = if stderr.is_terminal() { "\x1b[K" } else { "" };
+    let _ = write!(
+        stderr,
+        "remote: {}{}{}",
+        String::from_utf8_lossy(message),
+        clear,
+        terminator as char
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting_sizes() {
+        assert_eq!(human_bytes(512.0), "512 bytes");
+        assert_eq!(human_bytes(1536.0), "1.50 KiB");
+        assert_eq!(human_bytes(3.0 * 1024.0 * 1024.0), "3.00 MiB");
+    }
+}
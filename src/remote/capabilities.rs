@@ -0,0 +1,191 @@
+use std::fmt;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use crate::progress;
+
+///
+/// The capabilities a server advertises after its first ref, such as
+/// `multi_ack_detailed`, `side-band-64k` or `symref=HEAD:refs/heads/master`.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    entries: Vec<(String, Option<String>)>,
+}
+
+impl Capabilities {
+    ///
+    /// Parses a space separated capability list, where each entry is either a
+    /// name or `name=value`.
+    ///
+    pub fn parse(list: &str) -> Self {
+        let entries = list
+            .split(' ')
+            .filter(|c| !c.is_empty())
+            .map(|c| match c.split_once('=') {
+                Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+                None => (c.to_owned(), None),
+            })
+            .collect();
+        Capabilities { entries }
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.entries.iter().any(|(n, _)| n == name)
+    }
+
+    ///
+    /// The value of the first `name=value` entry.
+    ///
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, v)| n == name && v.is_some())
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    ///
+    /// The values of every `name=value` entry, for capabilities such as
+    /// `symref` which may be repeated.
+    ///
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .filter_map(|(_, v)| v.as_deref())
+    }
+
+    ///
+    /// The symbolic refs the server told us about, as (name, target) pairs.
+    ///
+    // -- symref=HEAD:refs/heads/master
+    pub fn symrefs(&self) -> Vec<(&str, &str)> {
+        self.get_all("symref")
+            .filter_map(|s| s.split_once(':'))
+            .collect()
+    }
+
+    ///
+    /// The ref the symbolic ref `name` points to, if the server said.
+    ///
+    pub fn symref_target(&self, name: &str) -> Option<&str> {
+        self.symrefs()
+            .into_iter()
+            .find(|&(n, _)| n == name)
+            .map(|(_, target)| target)
+    }
+
+    #[allow(dead_code)]
+    pub fn agent(&self) -> Option<&str> {
+        self.get("agent")
+    }
+
+    ///
+    /// The hash algorithm the server's objects use, which is SHA-1 unless
+    /// stated otherwise.
+    ///
+    pub fn object_format(&self) -> &str {
+        self.get("object-format").unwrap_or("sha1")
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.entries.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(name)?;
+            if let Some(value) = value {
+                write!(f, "={}", value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// The capabilities to request when fetching from a server which advertised
+/// `server`, picking the best of each alternative it supports.
+///
+pub fn fetch_capabilities(server: &Capabilities) -> Result<Vec<String>> {
+    if server.object_format() != "sha1" {
+        return Err(anyhow!(
+            "the remote uses the unsupported object format {}",
+            server.object_format()
+        ));
+    }
+
+    let mut wanted = Vec::new();
+    let mut first_of = |names: &[&str]| {
+        if let Some(name) = names.iter().find(|n| server.has(n)) {
+            wanted.push(name.to_string());
+        }
+    };
+    first_of(&["multi_ack_detailed", "multi_ack"]);
+    first_of(&["side-band-64k", "side-band"]);
+    // No thin-pack: indexing only finds delta bases within the pack itself.
+    first_of(&["ofs-delta"]);
+    first_of(&["include-tag"]);
+    // The server's progress messages are turned off along with our own.
+    if !progress::enabled() {
+        first_of(&["no-progress"]);
+    }
+    // Only servers which send an agent expect to be sent one.
+    if server.has("agent") {
+        wanted.push(agent());
+    }
+    Ok(wanted)
+}
+
+///
+/// The agent capability we send, as both client and server.
+///
+pub fn agent() -> String {
+    format!("agent=rgit/{}", env!("CARGO_PKG_VERSION"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_capabilities() {
+        let caps = Capabilities::parse(
+            "multi_ack thin-pack side-band side-band-64k ofs-delta shallow \
+             symref=HEAD:refs/heads/main symref=refs/remotes/origin/HEAD:refs/remotes/origin/main \
+             object-format=sha1 agent=git/2.39.2",
+        );
+        assert!(caps.has("side-band-64k"));
+        assert!(!caps.has("no-progress"));
+        assert_eq!(caps.agent(), Some("git/2.39.2"));
+        assert_eq!(caps.object_format(), "sha1");
+        assert_eq!(
+            caps.symrefs(),
+            [
+                ("HEAD", "refs/heads/main"),
+                ("refs/remotes/origin/HEAD", "refs/remotes/origin/main")
+            ]
+        );
+        assert_eq!(
+            Capabilities::parse(&caps.to_string()),
+            caps,
+            "display should round trip"
+        );
+    }
+
+    #[test]
+    fn requesting_what_the_server_supports() {
+        let server = Capabilities::parse("multi_ack side-band thin-pack include-tag no-progress");
+        let wanted = fetch_capabilities(&server).unwrap();
+        // Progress is off unless a command turns it on.
+        assert_eq!(
+            wanted,
+            ["multi_ack", "side-band", "include-tag", "no-progress"]
+        );
+
+        let server = Capabilities::parse("object-format=sha256");
+        assert!(fetch_capabilities(&server).is_err());
+    }
+}
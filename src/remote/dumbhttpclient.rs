@@ -0,0 +1,400 @@
+use std::collections::HashSet;
+use std::io::Read;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use reqwest::blocking::Client;
+use reqwest::StatusCode;
+use reqwest::Url;
+
+use super::credential::Credential;
+use super::httpconfig::{
+    LowSpeed,
+    LowSpeedReader,
+};
+use super::GitClient;
+use crate::packfile::refs::GitRef;
+use crate::packfile::{
+    PackFile,
+    PackIndex,
+    PackWriter,
+};
+use crate::store::{
+    EntryMode,
+    ObjectType,
+    PackedObject,
+    Sha,
+};
+
+///
+/// A client for the "dumb" HTTP protocol, where the server is a plain file
+/// server exposing the repository directory.
+///
+/// Refs are read from `info/refs` (as written by `git update-server-info`),
+/// and objects are fetched one at a time by walking from the wanted refs. Each
+/// object is requested in loose form first, falling back to whichever of the
+/// packs listed in `objects/info/packs` contains it according to its index.
+///
+pub struct DumbHttpClient {
+    url: Url,
+    client: Client,
+    // Credentials which worked for the smart discovery request, if any.
+    credential: Option<Credential>,
+    low_speed: Option<LowSpeed>,
+    // The branch in the remote's HEAD file, if it's a symbolic ref.
+    head_target: Option<String>,
+    // Indexes for the remote's packs, fetched the first time an object is
+    // not found in loose form.
+    remote_packs: Option<Vec<RemotePack>>,
+    packs: Vec<PackFile>,
+}
+
+struct RemotePack {
+    name: String,
+    index: PackIndex,
+    downloaded: bool,
+}
+
+impl DumbHttpClient {
+    ///
+    /// Creates a client for the repository at `url`, which must end with a '/'.
+    ///
+    pub fn new(
+        url: Url,
+        client: Client,
+        credential: Option<Credential>,
+        low_speed: Option<LowSpeed>,
+    ) -> Self {
+        DumbHttpClient {
+            url,
+            client,
+            credential,
+            low_speed,
+            head_target: None,
+            remote_packs: None,
+            packs: Vec::new(),
+        }
+    }
+
+    ///
+    /// Returns the refs listed in the contents of `info/refs`, along with HEAD.
+    ///
+    pub fn refs_from_info(&mut self, info_refs: &str) -> Result<Vec<GitRef>> {
+        let refs = parse_info_refs(info_refs)?;
+        let head = self
+            .get("HEAD")?
+            .and_then(|head| String::from_utf8(head).ok());
+        let head = head.as_deref().map(str::trim_end);
+        self.head_target = head
+            .and_then(|head| head.strip_prefix("ref: "))
+            .map(|target| target.to_owned());
+        let head = head.and_then(|head| match head.strip_prefix("ref: ") {
+            Some(target) => refs.iter().find(|r| r.name == target).map(|r| r.id.clone()),
+            None => Some(head.to_owned()),
+        });
+        Ok(head
+            .map(|id| GitRef {
+                id,
+                name: "HEAD".into(),
+            })
+            .into_iter()
+            .chain(refs)
+            .collect())
+    }
+
+    ///
+    /// Fetches the file at `path` relative to the repository, returning `None`
+    /// if it doesn't exist.
+    ///
+    fn get(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let url = self.url.join(path)?;
+        let mut request = self.client.get(url);
+        if let Some(Credential {
+            username: Some(username),
+            password,
+            ..
+        }) = &self.credential
+        {
+            request = request.basic_auth(username, password.as_ref());
+        }
+        let res = request.send()?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let mut data = Vec::new();
+                LowSpeedReader::new(res, self.low_speed).read_to_end(&mut data)?;
+                Ok(Some(data))
+            }
+            status => Err(anyhow!("server responded {} for {}", status, path)),
+        }
+    }
+
+    fn fetch_object(&mut self, sha: &Sha) -> Result<PackedObject> {
+        if let Some(pack) = self.packs.iter().find(|p| p.contains(sha)) {
+            return pack.find_by_sha(sha);
+        }
+        let hex = sha.hex();
+        let loose_path = format!("objects/{}/{}", &hex[..2], &hex[2..]);
+        if let Some(data) = self.get(&loose_path)? {
+            let object = PackedObject::read_loose(&data[..])
+                .with_context(|| format!("read loose object {}", sha))?;
+            if object.sha() != *sha {
+                return Err(anyhow!("object {} has the wrong hash", sha));
+            }
+            return Ok(object);
+        }
+        self.fetch_pack_containing(sha)?;
+        self.packs
+            .last()
+            .ok_or_else(|| anyhow!("object {} not found on remote", sha))?
+            .find_by_sha(sha)
+    }
+
+    fn fetch_pack_containing(&mut self, sha: &Sha) -> Result<()> {
+        if self.remote_packs.is_none() {
+            self.remote_packs = Some(self.fetch_pack_indexes()?);
+        }
+        let remote_packs = self.remote_packs.as_ref().expect("packs were fetched");
+        let position = remote_packs
+            .iter()
+            .position(|p| !p.downloaded && p.index.find(sha).is_some())
+            .ok_or_else(|| anyhow!("object {} not found on remote", sha))?;
+
+        let pack_path = format!("objects/pack/{}.pack", remote_packs[position].name);
+        let data = self
+            .get(&pack_path)?
+            .ok_or_else(|| anyhow!("{} is missing on the remote", pack_path))?;
+        let remote_pack = &mut self.remote_packs.as_mut().expect("packs were fetched")[position];
+        remote_pack.downloaded = true;
+        let pack = PackFile::parse_with_index(&data, Some(remote_pack.index.clone()))
+            .with_context(|| format!("parse {}", pack_path))?;
+        self.packs.push(pack);
+        Ok(())
+    }
+
+    ///
+    /// Fetches the index of each pack listed in `objects/info/packs`.
+    ///
+    /// -- P pack-<sha>.pack
+    ///
+    fn fetch_pack_indexes(&self) -> Result<Vec<RemotePack>> {
+        let packs = match self.get("objects/info/packs")? {
+            Some(packs) => String::from_utf8(packs)?,
+            None => return Ok(Vec::new()),
+        };
+        let mut remote_packs = Vec::new();
+        for line in packs.lines() {
+            let name = match line.strip_prefix("P ") {
+                Some(file_name) => file_name.trim().trim_end_matches(".pack"),
+                None => continue,
+            };
+            let idx_path = format!("objects/pack/{}.idx", name);
+            let data = self
+                .get(&idx_path)?
+                .ok_or_else(|| anyhow!("missing pack index {}", idx_path))?;
+            let index = PackIndex::parse(&data).with_context(|| format!("parse {}", idx_path))?;
+            remote_packs.push(RemotePack {
+                name: name.to_owned(),
+                index,
+                downloaded: false,
+            });
+        }
+        Ok(remote_packs)
+    }
+}
+
+impl GitClient for DumbHttpClient {
+    fn discover_refs(&mut self) -> Result<Vec<GitRef>> {
+        let info_refs = self
+            .get("info/refs")?
+            .ok_or_else(|| anyhow!("repository not found"))?;
+        self.refs_from_info(&String::from_utf8(info_refs)?)
+    }
+
+    fn fetch_packfile(&mut self, want: &[GitRef]) -> Result<Vec<u8>> {
+        let mut pending = want
+            .iter()
+            .filter(|r| !r.name.ends_with("^{}"))
+            .map(|r| Sha::from_hex(r.id.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Since there's no server to build a pack for us, walk the history
+        // ourselves and assemble a pack out of everything we fetched.
+        let mut seen = HashSet::new();
+        let mut objects = Vec::new();
+        while let Some(sha) = pending.pop() {
+            if !seen.insert(sha) {
+                continue;
+            }
+            let object = self.fetch_object(&sha)?;
+            match object.obj_type {
+                ObjectType::Commit => {
+                    let commit = object
+                        .as_commit()
+                        .ok_or_else(|| anyhow!("failed to parse commit {}", sha))?;
+                    pending.extend(commit.parents.iter());
+                    pending.push(commit.tree);
+                }
+                ObjectType::Tree => {
+                    let tree = object
+                        .as_tree()
+                        .ok_or_else(|| anyhow!("failed to parse tree {}", sha))?;
+                    // Submodule commits live in another repository.
+                    pending.extend(
+                        tree.entries
+                            .iter()
+                            .filter(|e| e.mode != EntryMode::Gitlink)
+                            .map(|e| e.sha),
+                    );
+                }
+                ObjectType::Tag => {
+                    let tag = object
+                        .as_tag()
+                        .ok_or_else(|| anyhow!("failed to parse tag {}", sha))?;
+                    pending.push(tag.object);
+                }
+                ObjectType::Blob => {}
+            }
+            objects.push(object);
+        }
+
+        let mut writer = PackWriter::new(Vec::new(), objects.len())?;
+        for object in &objects {
+            writer.write_object(object)?;
+        }
+        let (_, pack) = writer.finish()?;
+        Ok(pack)
+    }
+
+    fn head_target(&self) -> Option<&str> {
+        self.head_target.as_deref()
+    }
+}
+
+///
+/// Parses the contents of `info/refs`, one ref per line.
+///
+/// -- obj-id HT name LF
+///
+fn parse_info_refs(info_refs: &str) -> Result<Vec<GitRef>> {
+    info_refs
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (id, name) = line
+                .split_once('\t')
+                .ok_or_else(|| anyhow!("malformed line in info/refs: {:?}", line))?;
+            Ok(GitRef {
+                id: id.to_owned(),
+                name: name.to_owned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{
+        BufRead,
+        BufReader,
+        Write,
+    };
+    use std::net::TcpListener;
+    use std::path::{
+        Path,
+        PathBuf,
+    };
+    use std::thread;
+
+    use super::*;
+    use crate::packfile::refs;
+    use crate::store::Repo;
+    use crate::test_support::copy_dir;
+
+    static MASTER: &str = "33676d1c63d868803ed110b13be4e616bc8a29b7";
+
+    // Serves files beneath `root`, like a static web server would.
+    fn serve_files(root: PathBuf) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap();
+                match fs::read(root.join(&path[1..])) {
+                    Ok(body) => {
+                        write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\
+                             Content-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        )
+                        .unwrap();
+                        stream.write_all(&body).unwrap();
+                    }
+                    Err(_) => stream
+                        .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                        .unwrap(),
+                }
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn cloning_over_dumb_http() {
+        let dir = tempfile::tempdir().unwrap();
+        let gitdir = dir.path().join("simple.git");
+        copy_dir(Path::new("tests/data/repos/simple.git"), &gitdir);
+
+        // Add a commit in loose form on top of master, so that fetching it
+        // needs both loose objects and the pack.
+        let repo = Repo::open(&gitdir).unwrap();
+        let master = Sha::from_hex(MASTER.as_bytes()).unwrap();
+        let tree = repo.read_object(&master).unwrap().as_commit().unwrap().tree;
+        let commit = PackedObject::new(
+            ObjectType::Commit,
+            format!(
+                "tree {}\nparent {}\nauthor A <a@example.com> 0 +0000\n\
+                 committer A <a@example.com> 0 +0000\n\nloose\n",
+                tree, master
+            )
+            .into_bytes(),
+        );
+        commit.write(&gitdir).unwrap();
+        refs::update_ref(&gitdir, "refs/heads/loose", &commit.sha(), "test").unwrap();
+
+        // What `git update-server-info` would write.
+        let info_refs = refs::read_refs(&gitdir)
+            .unwrap()
+            .into_iter()
+            .filter(|r| r.name != "HEAD")
+            .map(|r| format!("{}\t{}\n", r.id, r.name))
+            .collect::<String>();
+        fs::create_dir_all(gitdir.join("info")).unwrap();
+        fs::write(gitdir.join("info/refs"), info_refs).unwrap();
+        fs::create_dir_all(gitdir.join("objects/info")).unwrap();
+        fs::write(
+            gitdir.join("objects/info/packs"),
+            "P pack-79f006bb5e8d079fdbe07e7ce41f97f4db7d341c.pack\n\n",
+        )
+        .unwrap();
+
+        let port = serve_files(dir.path().to_owned());
+        let url = format!("http://127.0.0.1:{}/simple.git/", port);
+        let mut client = DumbHttpClient::new(url.parse().unwrap(), Client::new(), None, None);
+        let refs = client.discover_refs().unwrap();
+        assert_eq!(refs[0].name, "HEAD");
+        assert_eq!(refs[0].id, MASTER);
+
+        let packfile_data = client.fetch_packfile(&refs).unwrap();
+        let pack = PackFile::parse(&packfile_data).unwrap();
+        assert_eq!(pack.num_objects(), 31);
+        assert!(pack.contains(&commit.sha()));
+    }
+}
@@ -0,0 +1,362 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use reqwest::blocking::ClientBuilder;
+use reqwest::header::{
+    HeaderMap,
+    HeaderName,
+    HeaderValue,
+};
+use reqwest::{
+    Certificate,
+    Identity,
+    Proxy,
+};
+
+use super::prompt::Prompter;
+use crate::store::Config;
+
+///
+/// Settings for HTTP remotes, read from the `http.*` config and the
+/// environment variables git uses to override them.
+///
+#[derive(Debug)]
+pub struct HttpOptions {
+    /// `http.proxy`; otherwise the usual `HTTPS_PROXY` etc. variables apply.
+    proxy: Option<String>,
+    ssl_verify: bool,
+    ca_info: Option<PathBuf>,
+    ca_path: Option<PathBuf>,
+    /// A PKCS#12 client certificate.
+    ssl_cert: Option<PathBuf>,
+    ssl_cert_password_protected: bool,
+    connect_timeout: Option<Duration>,
+    pub low_speed: Option<LowSpeed>,
+    /// Requests larger than this are sent with chunked encoding.
+    pub post_buffer: usize,
+    extra_headers: HeaderMap,
+    prompter: Prompter,
+}
+
+const DEFAULT_POST_BUFFER: usize = 1024 * 1024;
+
+///
+/// Aborts transfers slower than `limit` bytes per second for `time`, as with
+/// `http.lowSpeedLimit` and `http.lowSpeedTime`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LowSpeed {
+    pub limit: u64,
+    pub time: Duration,
+}
+
+impl HttpOptions {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Self::from_config_and_env(config, |var| env::var(var).ok())
+    }
+
+    fn from_config_and_env<E>(config: &Config, env: E) -> Result<Self>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let setting =
+            |var: &str, key: &str| env(var).or_else(|| config.get(key).map(|v| v.to_owned()));
+        let path_setting = |var: &str, key: &str| setting(var, key).map(|p| expand_home(&p));
+        let number = |var: &str, key: &str| -> Result<Option<u64>> {
+            setting(var, key)
+                .map(|v| {
+                    v.parse::<u64>()
+                        .with_context(|| format!("invalid number for {}: {}", key, v))
+                })
+                .transpose()
+        };
+
+        let ssl_verify = match env("GIT_SSL_NO_VERIFY") {
+            Some(_) => false,
+            None => config.get_bool("http.sslVerify")?.unwrap_or(true),
+        };
+        let ssl_cert_password_protected = match env("GIT_SSL_CERT_PASSWORD_PROTECTED") {
+            Some(_) => true,
+            None => config
+                .get_bool("http.sslCertPasswordProtected")?
+                .unwrap_or(false),
+        };
+        let low_speed = match (
+            number("GIT_HTTP_LOW_SPEED_LIMIT", "http.lowSpeedLimit")?,
+            number("GIT_HTTP_LOW_SPEED_TIME", "http.lowSpeedTime")?,
+        ) {
+            (Some(limit), Some(time)) if limit > 0 && time > 0 => Some(LowSpeed {
+                limit,
+                time: Duration::from_secs(time),
+            }),
+            _ => None,
+        };
+        let connect_timeout = number("GIT_HTTP_CONNECT_TIMEOUT", "http.connectTimeout")?
+            .filter(|&t| t > 0)
+            .map(Duration::from_secs);
+
+        let post_buffer = match config.get("http.postBuffer") {
+            Some(value) => value
+                .parse::<usize>()
+                .with_context(|| format!("invalid number for http.postBuffer: {}", value))?,
+            None => DEFAULT_POST_BUFFER,
+        };
+
+        Ok(HttpOptions {
+            proxy: config
+                .get("http.proxy")
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_owned()),
+            ssl_verify,
+            ca_info: path_setting("GIT_SSL_CAINFO", "http.sslCAInfo"),
+            ca_path: path_setting("GIT_SSL_CAPATH", "http.sslCAPath"),
+            ssl_cert: path_setting("GIT_SSL_CERT", "http.sslCert"),
+            ssl_cert_password_protected,
+            connect_timeout,
+            low_speed,
+            post_buffer,
+            extra_headers: extra_headers(config)?,
+            prompter: Prompter::from_config(config),
+        })
+    }
+
+    ///
+    /// A client builder with these settings applied.
+    ///
+    pub fn client_builder(&self) -> Result<ClientBuilder> {
+        let mut builder = ClientBuilder::new()
+            .default_headers(self.extra_headers.clone())
+            // Stalled transfers are caught by the low speed limit instead.
+            .timeout(self.low_speed.map(|l| l.time));
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = &self.proxy {
+            // Like curl, a proxy without a scheme is taken to be http.
+            let proxy = if proxy.contains("://") {
+                proxy.clone()
+            } else {
+                format!("http://{}", proxy)
+            };
+            builder = builder.proxy(Proxy::all(&proxy).context("invalid http.proxy")?);
+        }
+
+        if !self.ssl_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        let mut ca_files = Vec::new();
+        ca_files.extend(self.ca_info.clone());
+        if let Some(dir) = &self.ca_path {
+            for entry in fs::read_dir(dir).with_context(|| format!("read {}", dir.display()))? {
+                let path = entry?.path();
+                if path.is_file() {
+                    ca_files.push(path);
+                }
+            }
+        }
+        for path in ca_files {
+            let pem = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+            for cert in split_pem_certificates(&pem) {
+                let cert = Certificate::from_pem(cert)
+                    .with_context(|| format!("read certificate from {}", path.display()))?;
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        if let Some(path) = &self.ssl_cert {
+            let der = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+            let password = if self.ssl_cert_password_protected {
+                let prompt = format!("Certificate Password for '{}': ", path.display());
+                self.prompter.prompt(&prompt, false)?
+            } else {
+                String::new()
+            };
+            let identity = Identity::from_pkcs12_der(&der, &password).with_context(|| {
+                format!(
+                    "read client certificate {} (only PKCS#12 is supported)",
+                    path.display()
+                )
+            })?;
+            builder = builder.identity(identity);
+        }
+        Ok(builder)
+    }
+}
+
+///
+/// The headers from `http.extraHeader` to send with every request, such as
+/// `Authorization: Bearer <token>`.
+///
+fn extra_headers(config: &Config) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for header in config.get_all("http.extraHeader") {
+        // An empty value clears the headers configured before it.
+        if header.is_empty() {
+            headers.clear();
+            continue;
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid http.extraHeader: {}", header))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .with_context(|| format!("invalid http.extraHeader: {}", header))?;
+        let mut value = HeaderValue::from_str(value.trim())
+            .with_context(|| format!("invalid http.extraHeader: {}", header))?;
+        value.set_sensitive(true);
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+// Config paths may start with ~/ for the home directory.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+///
+/// Splits a bundle of PEM certificates, as CA files usually are, into the
+/// individual certificates.
+///
+fn split_pem_certificates(pem: &[u8]) -> Vec<&[u8]> {
+    const END: &[u8] = b"-----END CERTIFICATE-----";
+    let mut certs = Vec::new();
+    let mut rest = pem;
+    while let Some(end) = rest.windows(END.len()).position(|w| w == END) {
+        let (cert, remaining) = rest.split_at(end + END.len());
+        certs.push(cert);
+        rest = remaining;
+    }
+    certs
+}
+
+///
+/// Wraps a response body, failing reads once the transfer has been slower
+/// than the limit for the configured time.
+///
+pub struct LowSpeedReader<R> {
+    inner: R,
+    low_speed: Option<LowSpeed>,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl<R: Read> LowSpeedReader<R> {
+    pub fn new(inner: R, low_speed: Option<LowSpeed>) -> Self {
+        LowSpeedReader {
+            inner,
+            low_speed,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for LowSpeedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(LowSpeed { limit, time }) = self.low_speed {
+            self.window_bytes += n as u64;
+            let elapsed = self.window_start.elapsed();
+            if elapsed >= time {
+                if self.window_bytes < limit * elapsed.as_secs() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "transfer slower than {} bytes/sec for {} seconds",
+                            limit,
+                            time.as_secs()
+                        ),
+                    ));
+                }
+                self.window_start = Instant::now();
+                self.window_bytes = 0;
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn reading_settings_from_config() {
+        let config = Config::parse(
+            "[http]\n\
+             \tproxy = proxy.example.com:3128\n\
+             \tsslVerify = false\n\
+             \tsslCAInfo = ~/ca.pem\n\
+             \tlowSpeedLimit = 1000\n\
+             \tlowSpeedTime = 10\n\
+             \tconnectTimeout = 5\n",
+        )
+        .unwrap();
+        let options = HttpOptions::from_config_and_env(&config, |_| None).unwrap();
+        assert_eq!(options.proxy.as_deref(), Some("proxy.example.com:3128"));
+        assert!(!options.ssl_verify);
+        assert!(options.ca_info.unwrap().ends_with("ca.pem"));
+        assert_eq!(
+            options.low_speed,
+            Some(LowSpeed {
+                limit: 1000,
+                time: Duration::from_secs(10)
+            })
+        );
+        assert_eq!(options.connect_timeout, Some(Duration::from_secs(5)));
+
+        let options = HttpOptions::from_config_and_env(&Config::default(), |var| {
+            Some("1".to_owned()).filter(|_| var == "GIT_SSL_NO_VERIFY")
+        })
+        .unwrap();
+        assert!(!options.ssl_verify);
+        assert_eq!(options.low_speed, None);
+    }
+
+    #[test]
+    fn splitting_a_ca_bundle() {
+        let bundle = b"# first\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                       -----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        let certs = split_pem_certificates(bundle);
+        assert_eq!(certs.len(), 2);
+        assert!(certs[1].ends_with(b"BBBB\n-----END CERTIFICATE-----"));
+    }
+
+    #[test]
+    fn aborting_slow_transfers() {
+        // Yields a byte at a time, slowly.
+        struct Slow;
+        impl Read for Slow {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_millis(300));
+                buf[0] = 0;
+                Ok(1)
+            }
+        }
+        let low_speed = LowSpeed {
+            limit: 100,
+            time: Duration::from_secs(1),
+        };
+        let mut reader = LowSpeedReader::new(Slow, Some(low_speed));
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
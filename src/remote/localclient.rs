@@ -0,0 +1,79 @@
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+
+use super::capabilities;
+use super::capabilities::Capabilities;
+use super::GitClient;
+use crate::packfile::refs::GitRef;
+use crate::server::UploadPack;
+use crate::store::Repo;
+
+///
+/// A client for a repository on the local filesystem.
+///
+/// Rather than spawning a separate process, this runs the upload-pack side of
+/// the conversation in-process, so it goes through the same protocol as any
+/// other transport.
+///
+pub struct LocalClient {
+    repo: Repo,
+    capabilities: Capabilities,
+}
+
+impl LocalClient {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let repo = Repo::open(path)
+            .with_context(|| format!("open local repository {}", path.display()))?;
+        Ok(LocalClient {
+            repo,
+            capabilities: Capabilities::default(),
+        })
+    }
+}
+
+impl GitClient for LocalClient {
+    fn discover_refs(&mut self) -> Result<Vec<GitRef>> {
+        let mut advertisement = Vec::new();
+        UploadPack::new(&self.repo).advertise_refs(&mut advertisement)?;
+
+        let response = super::receive(&mut Cursor::new(advertisement))?;
+        let (capabilities, refs) = super::parse_lines(&response)?;
+        self.capabilities = capabilities;
+        Ok(refs)
+    }
+
+    fn fetch_packfile(&mut self, want: &[GitRef]) -> Result<Vec<u8>> {
+        let capabilities = capabilities::fetch_capabilities(&self.capabilities)?;
+        let request = super::create_negotiation_request(&capabilities[..], want);
+
+        let mut response = Vec::new();
+        UploadPack::new(&self.repo).serve(&mut Cursor::new(request), &mut response)?;
+        super::receive_with_sideband(&mut Cursor::new(response))
+    }
+
+    fn head_target(&self) -> Option<&str> {
+        self.capabilities.symref_target("HEAD")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packfile::PackFile;
+
+    #[test]
+    fn fetching_from_a_local_repository() {
+        let mut client = LocalClient::open("tests/data/repos/simple.git").unwrap();
+        let refs = client.discover_refs().unwrap();
+        assert_eq!(refs[0].name, "HEAD");
+        assert!(refs.iter().any(|r| r.name == "refs/tags/test_tag^{}"));
+
+        let packfile_data = client.fetch_packfile(&refs).unwrap();
+        let pack = PackFile::parse(&packfile_data).unwrap();
+        assert_eq!(pack.num_objects(), 30);
+    }
+}
@@ -1,69 +1,127 @@
+use std::io;
+use std::io::BufRead;
 use std::io::Read;
-use std::io::Write;
 use std::str;
 
 use anyhow::anyhow;
-use anyhow::Context;
 use anyhow::Result;
 
+use self::capabilities::Capabilities;
+use self::pktline::{
+    Packet,
+    ReadPktLineExt,
+    WritePktLineExt,
+};
+use crate::packfile;
 use crate::packfile::refs::GitRef;
+use crate::progress::RemoteMessages;
+use crate::store::Sha;
 
+pub mod capabilities;
+pub mod credential;
+pub mod dumbhttpclient;
 pub mod httpclient;
+pub mod httpconfig;
+pub mod localclient;
+pub mod pktline;
+pub mod prompt;
+pub mod refspec;
 pub mod sshclient;
+pub mod sshcommandclient;
 pub mod tcpclient;
 
 pub trait GitClient {
     fn discover_refs(&mut self) -> Result<Vec<GitRef>>;
     fn fetch_packfile(&mut self, want: &[GitRef]) -> Result<Vec<u8>>;
-}
 
-///
-/// Encodes a packet-line for communcation.
-///
-fn write_pktline(msg: &str, buf: &mut Vec<u8>) {
-    write!(buf, "{:04x}{}", 4 + msg.len() as u8, msg).expect("write into vec cannot fail");
-}
-
-fn write_as_pktline(line: &[&str], buf: &mut Vec<u8>) {
-    let mut total = 0;
-    for item in line {
-        total += item.len();
+    ///
+    /// Fetches a pack of the wanted refs, first negotiating with the server
+    /// so that objects reachable from `haves` are left out. The haves should
+    /// be ordered with the most recent commits first.
+    ///
+    /// Transports which don't negotiate fetch everything.
+    ///
+    fn fetch_packfile_with_haves(&mut self, want: &[GitRef], haves: &[Sha]) -> Result<Vec<u8>> {
+        let _ = haves;
+        self.fetch_packfile(want)
     }
-    write!(buf, "{:04x}", 4 + total as u8).expect("write into vec cannot fail");
-    for item in line.iter() {
-        buf.write_all(item.as_bytes())
-            .expect("write into vec cannot fail");
+
+    ///
+    /// The branch the server's HEAD points to, if it said so along with its
+    /// refs.
+    ///
+    fn head_target(&self) -> Option<&str> {
+        None
     }
 }
 
 // Create a want request for each packet
 // append capabilities to the first ref request
-// only send refs that are not peeled and in refs/{heads,tags}
+// skip peeled refs, whose objects come along with their tags
 // -- PKT-LINE("want" SP obj-id SP capability-list LF)
 // -- PKT-LINE("want" SP obj-id LF)
-fn create_negotiation_request(capabilities: &[&str], refs: &[GitRef]) -> Vec<u8> {
+// -- flush-pkt
+fn create_want_request(capabilities: &[String], refs: &[GitRef]) -> Vec<u8> {
     let mut lines = Vec::new();
-    let filtered = refs.iter().filter(|&&GitRef { name: ref r, .. }| {
-        !r.ends_with("^{}") && (r.starts_with("refs/heads") || r.starts_with("refs/tags"))
-    });
+    let filtered = refs
+        .iter()
+        .filter(|&GitRef { name: r, .. }| !r.ends_with("^{}"));
     for (i, r) in filtered.enumerate() {
-        let &GitRef { id: ref o, .. } = r;
+        let GitRef { id: o, .. } = r;
         if i == 0 {
             let caps = capabilities.join(" ");
             // if this is a space it is correctly multiplexed
-            write_as_pktline(&["want ", &o[..], " ", &caps[..], "\n"], &mut lines);
+            let line = format!("want {} {}\n", o, caps);
+            lines.write_data(line.as_bytes()).expect("write into vec");
         }
-        write_as_pktline(&["want ", &o[..], "\n"], &mut lines);
+        let line = format!("want {}\n", o);
+        lines.write_data(line.as_bytes()).expect("write into vec");
     }
-    lines.write_all(b"0000").expect("write into vec");
-    write_pktline("done\n", &mut lines);
+    lines.write_flush().expect("write into vec");
     lines
 }
 
+// Request everything wanted without negotiating.
+fn create_negotiation_request(capabilities: &[String], refs: &[GitRef]) -> Vec<u8> {
+    let mut lines = create_want_request(capabilities, refs);
+    lines.write_data(b"done\n").expect("write into vec");
+    lines
+}
+
+///
+/// Reads the server's response to a round of negotiation, up to its final
+/// NAK, returning the objects acknowledged as common and whether the server
+/// is ready to send a pack.
+///
+// -- PKT-LINE("ACK" SP obj-id SP ("common" | "ready") LF)
+// -- PKT-LINE("NAK" LF)
+pub fn receive_acks<R: Read>(reader: &mut R) -> Result<(Vec<Sha>, bool)> {
+    let mut common = Vec::new();
+    let mut ready = false;
+    loop {
+        let line = match reader.read_data()? {
+            Some(line) => line,
+            None => return Ok((common, ready)),
+        };
+        let text = str::from_utf8(&line)?.trim_end();
+        if text == "NAK" {
+            return Ok((common, ready));
+        }
+        let mut parts = text.split(' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("ACK"), Some(id), status) => {
+                common.push(Sha::from_hex(id.as_bytes())?);
+                ready |= status == Some("ready");
+            }
+            _ => return Err(anyhow!("unexpected negotiation response: {}", text)),
+        }
+    }
+}
+
 ///
 /// Parses all packetlines received from the server into a list of capabilities and a list of refs.
 ///
-fn parse_lines(lines: &[String]) -> Result<(Vec<String>, Vec<GitRef>)> {
+pub fn parse_lines(lines: &[String]) -> Result<(Capabilities, Vec<GitRef>)> {
     let mut iter = lines.iter().map(|s| s.trim_end());
 
     // First line contains capabilities separated by '\0'
@@ -83,14 +141,9 @@ fn parse_lines(lines: &[String]) -> Result<(Vec<String>, Vec<GitRef>)> {
 ///
 /// Parses the first packetline from the server into a list of capabilities and a ref.
 ///
-fn parse_first_line(line: &str) -> (Vec<String>, GitRef) {
-    let split = line.split('\0').collect::<Vec<_>>();
-    let the_ref = parse_line(split[0]);
-    let capabilities = split[1]
-        .split(' ')
-        .map(|s| s.to_owned())
-        .collect::<Vec<_>>();
-    (capabilities, the_ref)
+fn parse_first_line(line: &str) -> (Capabilities, GitRef) {
+    let (the_ref, capabilities) = line.split_once('\0').unwrap_or((line, ""));
+    (Capabilities::parse(capabilities), parse_line(the_ref))
 }
 
 ///
@@ -110,18 +163,12 @@ fn parse_line(line: &str) -> GitRef {
 /// Reads and parses packet-lines from the given connection
 /// until a null packet is received.
 ///
-fn receive<R: Read>(reader: &mut R) -> Result<Vec<String>> {
+pub fn receive<R: Read>(reader: &mut R) -> Result<Vec<String>> {
     let mut lines = Vec::new();
-    let mut line = Vec::new();
-    loop {
-        read_packet_line(reader, &mut line)?;
-        if line.is_empty() {
-            return Ok(lines);
-        } else {
-            let s = str::from_utf8(&line[..])?;
-            lines.push(s.into())
-        }
+    while let Some(line) = reader.read_data()? {
+        lines.push(String::from_utf8(line)?);
     }
+    Ok(lines)
 }
 
 ///
@@ -135,44 +182,100 @@ fn receive<R: Read>(reader: &mut R) -> Result<Vec<String>> {
 ///    3. Error message from server, abort operation
 ///
 pub fn receive_with_sideband<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
-    let mut packfile_data = Vec::new();
-    let mut line = Vec::new();
-    loop {
-        read_packet_line(reader, &mut line)?;
-        match &line[..] {
-            b"NAK\n" => continue,
-            [1, packdata @ ..] => packfile_data.extend_from_slice(packdata),
-            [2, msg @ ..] => {
-                let msg = str::from_utf8(msg)?;
-                eprint!("{}", msg);
-            }
-            [3, msg @ ..] => {
-                let msg = str::from_utf8(msg)?;
-                eprint!("error: {}", msg);
-                return Err(anyhow!("git server returned error",));
-            }
-            [] => return Ok(packfile_data),
-            _ => return Err(anyhow!("invalid response from server")),
-        }
+    let mut sideband = SidebandReader::new(reader);
+    if sideband.fill_buf()?.is_empty() {
+        return Ok(Vec::new());
     }
+    let pack = packfile::receive_packfile(&mut sideband)?;
+    // Whatever follows the pack is more progress, up to the final flush.
+    io::copy(&mut sideband, &mut io::sink())?;
+    Ok(pack)
 }
 
 ///
-/// Reads and parses a pkt-line from the server.
+/// Reads the pack data from a multiplexed response, showing progress
+/// messages as they arrive and ending at the flush-pkt.
 ///
-fn read_packet_line<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> Result<()> {
-    let mut header = [0; 4];
-    reader.read_exact(&mut header).context("pkt-line header")?;
-    let length_str = str::from_utf8(&header[..])?;
-    let length = u64::from_str_radix(length_str, 16)?;
-
-    if length > 4 {
-        buf.resize((length - 4) as usize, 0);
-        reader.read_exact(&mut buf[..])?;
-        Ok(())
-    } else {
-        buf.clear();
-        Ok(())
+struct SidebandReader<'r, R> {
+    inner: &'r mut R,
+    packet: Vec<u8>,
+    // How much of the current packet has been consumed, including the band.
+    pos: usize,
+    done: bool,
+    messages: RemoteMessages,
+}
+
+impl<'r, R: Read> SidebandReader<'r, R> {
+    fn new(inner: &'r mut R) -> Self {
+        SidebandReader {
+            inner,
+            packet: Vec::new(),
+            pos: 0,
+            done: false,
+            messages: RemoteMessages::default(),
+        }
+    }
+
+    fn next_packet(&mut self) -> Result<()> {
+        loop {
+            let packet = match self.inner.read_packet()? {
+                Packet::Data(packet) => packet,
+                Packet::Flush => {
+                    self.messages.finish();
+                    self.done = true;
+                    return Ok(());
+                }
+                Packet::Err(message) => {
+                    self.messages.finish();
+                    return Err(anyhow!("remote error: {}", message));
+                }
+                packet => return Err(anyhow!("unexpected {:?} packet from server", packet)),
+            };
+            match &packet[..] {
+                // The end of negotiation, which comes before the pack.
+                b"NAK\n" => continue,
+                ack if ack.starts_with(b"ACK ") => continue,
+                [1, ..] => {
+                    self.packet = packet;
+                    self.pos = 1;
+                    return Ok(());
+                }
+                [2, msg @ ..] => self.messages.write(msg),
+                [3, msg @ ..] => {
+                    self.messages.finish();
+                    eprint!("error: {}", String::from_utf8_lossy(msg));
+                    return Err(anyhow!("git server returned error"));
+                }
+                _ => return Err(anyhow!("invalid response from server")),
+            }
+        }
+    }
+}
+
+impl<'r, R: Read> Read for SidebandReader<'r, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+impl<'r, R: Read> BufRead for SidebandReader<'r, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while !self.done && self.pos >= self.packet.len() {
+            self.next_packet()
+                .map_err(|e| io::Error::other(format!("{:#}", e)))?;
+        }
+        if self.done {
+            return Ok(&[]);
+        }
+        Ok(&self.packet[self.pos..])
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.pos += count;
     }
 }
 
@@ -182,7 +285,11 @@ mod tests {
 
     #[test]
     fn test_create_negotation_request() {
-        let capabilities = &["multi_ack_detailed", "side-band-64k", "agent=git/1.8.1"];
+        let capabilities = &[
+            "multi_ack_detailed".to_owned(),
+            "side-band-64k".to_owned(),
+            "agent=git/1.8.1".to_owned(),
+        ];
         let refs = &[
             GitRef {
                 name: "refs/heads/master".into(),
@@ -197,7 +304,7 @@ mod tests {
                 id: "def456".into(),
             },
             GitRef {
-                name: "ignored".into(),
+                name: "refs/notes/commits".into(),
                 id: "def456".into(),
             },
             GitRef {
@@ -209,8 +316,35 @@ mod tests {
                        0010want abc123\n\
                        0010want def456\n\
                        0010want def456\n\
+                       0010want def456\n\
                        00000009done\n";
         let req = create_negotiation_request(capabilities, refs);
         assert_eq!(req, expected);
     }
+
+    #[test]
+    fn receiving_a_pack_split_across_sideband_packets() {
+        let pack =
+            std::fs::read("tests/data/packs/pack-79f006bb5e8d079fdbe07e7ce41f97f4db7d341c.pack")
+                .unwrap();
+        let mut response = Vec::new();
+        response.write_data(b"NAK\n").unwrap();
+        response.write_data(b"\x02Counting objects: 1\r").unwrap();
+        for chunk in pack.chunks(100) {
+            response.write_data(&[&[1][..], chunk].concat()).unwrap();
+        }
+        response.write_data(b"\x02Total 3 (delta 0)\n").unwrap();
+        response.write_flush().unwrap();
+
+        let received = receive_with_sideband(&mut &response[..]).unwrap();
+        assert_eq!(received, pack);
+    }
+
+    #[test]
+    fn receiving_an_error_from_the_server() {
+        let mut response = Vec::new();
+        response.write_data(b"\x03access denied\n").unwrap();
+        let err = receive_with_sideband(&mut &response[..]).unwrap_err();
+        assert!(format!("{:#}", err).contains("git server returned error"));
+    }
 }
@@ -0,0 +1,230 @@
+use std::io;
+use std::io::{
+    Read,
+    Write,
+};
+use std::str;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+
+/// The largest pkt-line, including its four byte header.
+pub const MAX_PKTLINE_LEN: usize = 65520;
+
+/// The largest payload a single pkt-line may carry.
+pub const MAX_PKTLINE_DATA_LEN: usize = MAX_PKTLINE_LEN - 4;
+
+///
+/// A single pkt-line, as framed by the git protocols.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Data(Vec<u8>),
+    /// `0000`, which ends a message.
+    Flush,
+    /// `0001`, which separates sections of a message in protocol v2.
+    Delim,
+    /// `0002`, which ends a response in stateless protocol v2.
+    ResponseEnd,
+    /// An `ERR` packet, which the server sends instead of its response when it
+    /// can't carry on.
+    Err(String),
+}
+
+///
+/// Reads pkt-lines from any reader, one at a time.
+///
+/// Nothing is read beyond the end of each packet, so whatever follows (a
+/// packfile, say) can be read straight from the same reader.
+///
+pub trait ReadPktLineExt: Read {
+    ///
+    /// Reads the next packet.
+    ///
+    // -- pkt-len = 4*(HEXDIG)
+    // -- pkt-line = data-pkt / flush-pkt / delim-pkt / response-end-pkt
+    fn read_packet(&mut self) -> Result<Packet> {
+        let mut header = [0; 4];
+        self.read_exact(&mut header).context("pkt-line header")?;
+        // from_str_radix would also accept a leading sign.
+        if !header.iter().all(u8::is_ascii_hexdigit) {
+            return Err(anyhow!(
+                "invalid pkt-line header: {:?}",
+                String::from_utf8_lossy(&header)
+            ));
+        }
+        let length = usize::from_str_radix(str::from_utf8(&header)?, 16)?;
+        match length {
+            0 => return Ok(Packet::Flush),
+            1 => return Ok(Packet::Delim),
+            2 => return Ok(Packet::ResponseEnd),
+            3 => return Err(anyhow!("invalid pkt-line length: 3")),
+            n if n > MAX_PKTLINE_LEN => {
+                return Err(anyhow!("pkt-line length {} exceeds the maximum", n))
+            }
+            _ => {}
+        }
+        let mut data = vec![0; length - 4];
+        self.read_exact(&mut data).context("pkt-line data")?;
+        match data.strip_prefix(b"ERR ") {
+            Some(message) => {
+                let message = String::from_utf8_lossy(message);
+                Ok(Packet::Err(message.trim_end_matches('\n').to_owned()))
+            }
+            None => Ok(Packet::Data(data)),
+        }
+    }
+
+    ///
+    /// Reads the next line of a message, returning `None` at the flush which
+    /// ends it. `ERR` packets are returned as errors.
+    ///
+    fn read_data(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.read_packet()? {
+            Packet::Data(data) => Ok(Some(data)),
+            Packet::Flush => Ok(None),
+            Packet::Err(message) => Err(anyhow!("remote error: {}", message)),
+            packet => Err(anyhow!("unexpected {:?} packet", packet)),
+        }
+    }
+}
+
+impl<R: Read + ?Sized> ReadPktLineExt for R {}
+
+///
+/// Writes pkt-lines to any writer.
+///
+pub trait WritePktLineExt: Write {
+    fn write_packet(&mut self, packet: &Packet) -> io::Result<()> {
+        match packet {
+            Packet::Data(data) => self.write_data(data),
+            Packet::Flush => self.write_all(b"0000"),
+            Packet::Delim => self.write_all(b"0001"),
+            Packet::ResponseEnd => self.write_all(b"0002"),
+            Packet::Err(message) => self.write_data(format!("ERR {}\n", message).as_bytes()),
+        }
+    }
+
+    ///
+    /// Writes `data` as a single pkt-line, failing if it's too long for one.
+    ///
+    fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() > MAX_PKTLINE_DATA_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pkt-line data exceeds maximum length",
+            ));
+        }
+        write!(self, "{:04x}", 4 + data.len())?;
+        self.write_all(data)
+    }
+
+    ///
+    /// Writes a flush-pkt, marking the end of a message.
+    ///
+    fn write_flush(&mut self) -> io::Result<()> {
+        self.write_packet(&Packet::Flush)
+    }
+}
+
+impl<W: Write + ?Sized> WritePktLineExt for W {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(mut input: &[u8]) -> Result<Vec<Packet>> {
+        let mut packets = Vec::new();
+        while !input.is_empty() {
+            packets.push(input.read_packet()?);
+        }
+        Ok(packets)
+    }
+
+    #[test]
+    fn reading_each_kind_of_packet() {
+        let packets = read_all(b"000ahello\n0004000100020000000fERR denied\n").unwrap();
+        assert_eq!(
+            packets,
+            vec![
+                Packet::Data(b"hello\n".to_vec()),
+                Packet::Data(Vec::new()),
+                Packet::Delim,
+                Packet::ResponseEnd,
+                Packet::Flush,
+                Packet::Err("denied".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejecting_malformed_packets() {
+        for input in [&b"0003"[..], b"+00a", b"zzzz", b"fff1", b"000ashort", b"00"] {
+            assert!(
+                (&input[..]).read_packet().is_err(),
+                "accepted {:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn writing_long_lines() {
+        let mut out = Vec::new();
+        let line = vec![b'a'; 300];
+        out.write_data(&line).unwrap();
+        assert_eq!(&out[..4], b"0130");
+        assert_eq!((&out[..]).read_packet().unwrap(), Packet::Data(line));
+
+        let mut out = Vec::new();
+        out.write_data(&[0; MAX_PKTLINE_DATA_LEN]).unwrap();
+        assert_eq!(&out[..4], b"fff0");
+        assert!(out.write_data(&[0; MAX_PKTLINE_DATA_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn round_trips_of_random_packets() {
+        // A small xorshift generator keeps the test deterministic.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut packets = Vec::new();
+        for _ in 0..500 {
+            let packet = match next() % 8 {
+                0 => Packet::Flush,
+                1 => Packet::Delim,
+                2 => Packet::ResponseEnd,
+                3 => Packet::Err(format!("error {}", next())),
+                _ => {
+                    let len = (next() % MAX_PKTLINE_DATA_LEN as u64) as usize;
+                    let len = if next() % 4 == 0 { len } else { len % 512 };
+                    let mut data = (0..len).map(|_| next() as u8).collect::<Vec<_>>();
+                    // Data can't be mistaken for an error.
+                    if data.starts_with(b"ERR ") {
+                        data[0] = b'e';
+                    }
+                    Packet::Data(data)
+                }
+            };
+            packets.push(packet);
+        }
+
+        let mut out = Vec::new();
+        for packet in &packets {
+            out.write_packet(packet).unwrap();
+        }
+        assert_eq!(read_all(&out).unwrap(), packets);
+
+        // Truncating the stream anywhere must fail cleanly rather than panic.
+        for _ in 0..200 {
+            let cut = (next() % out.len() as u64) as usize;
+            let _ = read_all(&out[..cut]);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::env;
+use std::fs::{
+    File,
+    OpenOptions,
+};
+use std::io::{
+    BufRead,
+    BufReader,
+    Write,
+};
+use std::process::{
+    Command,
+    Stdio,
+};
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::store::Config;
+
+///
+/// Asks the user for input such as passwords.
+///
+/// Like git, an askpass program from `GIT_ASKPASS`, `core.askPass` or
+/// `SSH_ASKPASS` is preferred, falling back to the terminal. Setting
+/// `GIT_TERMINAL_PROMPT=0` disables the terminal fallback.
+///
+#[derive(Debug, Clone, Default)]
+pub struct Prompter {
+    askpass: Option<String>,
+}
+
+impl Prompter {
+    pub fn from_config(config: &Config) -> Self {
+        let askpass = env::var("GIT_ASKPASS")
+            .ok()
+            .or_else(|| config.get("core.askPass").map(|p| p.to_owned()))
+            .or_else(|| env::var("SSH_ASKPASS").ok())
+            .filter(|p| !p.is_empty());
+        Prompter { askpass }
+    }
+
+    ///
+    /// Shows `prompt` and returns the user's answer. When `echo` is false,
+    /// what the user types is hidden, as for a password.
+    ///
+    pub fn prompt(&self, prompt: &str, echo: bool) -> Result<String> {
+        match &self.askpass {
+            Some(program) => askpass(program, prompt),
+            None => terminal_prompt(prompt, echo),
+        }
+    }
+}
+
+fn askpass(program: &str, prompt: &str) -> Result<String> {
+    // Like GIT_SSH_COMMAND, the program is run through the shell.
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", program))
+        .arg(program)
+        .arg(prompt)
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("run askpass program {:?}", program))?;
+    if !output.status.success() {
+        return Err(anyhow!("askpass program {:?} failed", program));
+    }
+    let answer = String::from_utf8(output.stdout)?;
+    Ok(answer.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn terminal_prompt(prompt: &str, echo: bool) -> Result<String> {
+    if env::var("GIT_TERMINAL_PROMPT").is_ok_and(|v| v == "0" || v == "false") {
+        return Err(anyhow!(
+            "could not read '{}': terminal prompts disabled",
+            prompt.trim_end_matches(&[':', ' '][..])
+        ));
+    }
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("no terminal to prompt on")?;
+    tty.write_all(prompt.as_bytes())?;
+    tty.flush()?;
+
+    if !echo {
+        set_echo(&tty, false)?;
+    }
+    let mut answer = String::new();
+    let result = BufReader::new(&tty).read_line(&mut answer);
+    if !echo {
+        set_echo(&tty, true)?;
+        // The user's newline wasn't echoed either.
+        tty.write_all(b"\n")?;
+    }
+    result?;
+    Ok(answer.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn set_echo(tty: &File, echo: bool) -> Result<()> {
+    let status = Command::new("stty")
+        .arg(if echo { "echo" } else { "-echo" })
+        .stdin(tty.try_clone()?)
+        .status()
+        .context("run stty")?;
+    if !status.success() {
+        return Err(anyhow!("could not change terminal echo"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prompting_through_askpass() {
+        let prompter = Prompter {
+            askpass: Some("printf 'secret for %s\\n'".into()),
+        };
+        let answer = prompter.prompt("Password: ", false).unwrap();
+        assert_eq!(answer, "secret for Password: ");
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+///
+/// A mapping from a remote's refs to our own, such as
+/// `+refs/heads/*:refs/remotes/origin/*`.
+///
+/// Either both sides are patterns containing a single `*`, which matches
+/// any run of characters (including `/`), or neither is.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refspec {
+    force: bool,
+    src: String,
+    dst: String,
+}
+
+impl Refspec {
+    // -- [+]<src>:<dst>
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (force, rest) = match spec.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let (src, dst) = rest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid refspec '{}': missing destination", spec))?;
+        let stars = (src.matches('*').count(), dst.matches('*').count());
+        if !matches!(stars, (0, 0) | (1, 1)) || src.is_empty() || dst.is_empty() {
+            return Err(anyhow!("invalid refspec '{}'", spec));
+        }
+        Ok(Refspec {
+            force,
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+        })
+    }
+
+    ///
+    /// Whether refs may be updated even when it isn't a fast-forward.
+    ///
+    pub fn force(&self) -> bool {
+        self.force
+    }
+
+    ///
+    /// Maps the remote ref `name` to our ref, or `None` if the refspec
+    /// doesn't cover it.
+    ///
+    pub fn map(&self, name: &str) -> Option<String> {
+        match self.src.split_once('*') {
+            Some((prefix, suffix)) => {
+                let matched = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+                Some(self.dst.replacen('*', matched, 1))
+            }
+            None if name == self.src => Some(self.dst.clone()),
+            None => None,
+        }
+    }
+}
+
+impl fmt::Display for Refspec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.force {
+            f.write_str("+")?;
+        }
+        write!(f, "{}:{}", self.src, self.dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapping_refs() {
+        let spec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap();
+        assert!(spec.force());
+        assert_eq!(
+            spec.map("refs/heads/feature/login").as_deref(),
+            Some("refs/remotes/origin/feature/login")
+        );
+        assert_eq!(spec.map("refs/tags/v1.0"), None);
+        assert_eq!(spec.map("HEAD"), None);
+        assert_eq!(spec.to_string(), "+refs/heads/*:refs/remotes/origin/*");
+
+        let spec = Refspec::parse("refs/heads/main:refs/remotes/origin/main").unwrap();
+        assert!(!spec.force());
+        assert_eq!(
+            spec.map("refs/heads/main").as_deref(),
+            Some("refs/remotes/origin/main")
+        );
+        assert_eq!(spec.map("refs/heads/main2"), None);
+    }
+
+    #[test]
+    fn rejecting_malformed_refspecs() {
+        for spec in [
+            "refs/heads/*",
+            "refs/heads/*:refs/remotes/origin/main",
+            "refs/*/*:refs/*/*",
+            "+:refs/heads/main",
+        ] {
+            assert!(Refspec::parse(spec).is_err(), "accepted {}", spec);
+        }
+    }
+}
@@ -1,63 +1,359 @@
+use std::env;
+use std::fs;
+use std::fs::OpenOptions;
 use std::io::Write;
 use std::net::TcpStream;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::str::FromStr;
 
+use anyhow::anyhow;
+use anyhow::Context;
 use anyhow::Result;
-use ssh2::Session;
+use reqwest::Url;
+use ssh2::{
+    Channel,
+    CheckResult,
+    ErrorCode,
+    HostKeyType,
+    KeyboardInteractivePrompt,
+    KnownHostFileKind,
+    Prompt,
+    Session,
+};
 
+use super::capabilities;
+use super::capabilities::Capabilities;
+use super::pktline::WritePktLineExt;
+use super::prompt::Prompter;
+use super::sshcommandclient::{
+    repo_path,
+    sq_quote,
+};
 use super::GitClient;
 use crate::packfile::refs::GitRef;
 
+const DEFAULT_PORT: u16 = 22;
+
+// libssh2 couldn't read the key file, most likely because it needs a passphrase.
+const LIBSSH2_ERROR_FILE: i32 = -16;
+
+///
+/// A client which runs `git-upload-pack` over ssh using the built-in libssh2
+/// client, rather than an external program.
+///
+/// A single channel is used for the whole conversation, so the refs are only
+/// advertised once.
+///
 pub struct GitSSHClient {
-    sess: Session,
-    repo: String,
+    // The session must outlive the channel, so it's kept alongside it.
+    _sess: Session,
+    channel: Channel,
+    capabilities: Capabilities,
+    requested: bool,
+}
+
+///
+/// What to do with a host which isn't in the known_hosts file. Hosts whose
+/// key has changed are always refused.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HostKeyChecking {
+    /// Refuse to connect to unknown hosts.
+    #[default]
+    Strict,
+    /// Add unknown hosts to the known_hosts file.
+    AcceptNew,
+}
+
+impl FromStr for HostKeyChecking {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "yes" | "strict" => Ok(HostKeyChecking::Strict),
+            "accept-new" => Ok(HostKeyChecking::AcceptNew),
+            _ => Err(anyhow!("unknown host key checking mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SshOptions {
+    /// A private key to authenticate with, instead of the ssh agent.
+    pub identity_file: Option<PathBuf>,
+    pub host_key_checking: HostKeyChecking,
+    /// Defaults to `~/.ssh/known_hosts`.
+    pub known_hosts_file: Option<PathBuf>,
+    pub prompter: Prompter,
 }
 
 impl GitSSHClient {
-    pub fn new(host: &str, repo: &str) -> Result<Self> {
-        let stream = TcpStream::connect(host)?;
+    pub fn connect(url: &Url, options: &SshOptions) -> Result<Self> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("host required for ssh"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let user = match url.username() {
+            "" => env::var("USER").context("no user given for ssh")?,
+            user => user.to_owned(),
+        };
+
+        let stream = TcpStream::connect((host, port))
+            .with_context(|| format!("connect to {} port {}", host, port))?;
         let mut sess = Session::new()?;
         sess.set_tcp_stream(stream);
         sess.handshake()?;
-        {
-            let mut agent = sess.agent()?;
-            agent.connect()?;
-            sess.userauth_agent("git")?;
-        }
-        assert!(sess.authenticated());
 
+        let (key, key_type) = sess
+            .host_key()
+            .ok_or_else(|| anyhow!("no host key from {}", host))?;
+        let known_hosts_file = match &options.known_hosts_file {
+            Some(path) => path.clone(),
+            None => default_known_hosts_file()?,
+        };
+        check_host_key(
+            &sess,
+            host,
+            port,
+            key,
+            key_type,
+            &known_hosts_file,
+            options.host_key_checking,
+        )?;
+        authenticate(&sess, &user, host, options)?;
+
+        let mut channel = sess.channel_session()?;
+        let command = format!("git-upload-pack {}", sq_quote(&repo_path(url)));
+        channel.exec(&command)?;
         Ok(GitSSHClient {
-            sess,
-            repo: repo.to_owned(),
+            _sess: sess,
+            channel,
+            capabilities: Capabilities::default(),
+            requested: false,
         })
     }
 }
 
 impl GitClient for GitSSHClient {
     fn discover_refs(&mut self) -> Result<Vec<GitRef>> {
-        let mut chan = self.sess.channel_session()?;
-        let command = format!("git-upload-pack {}", self.repo);
-        chan.exec(&command)?;
-
-        let response = super::receive(&mut chan)?;
-        let (_server_capabilities, refs) = super::parse_lines(&response)?;
+        let response = super::receive(&mut self.channel)?;
+        let (capabilities, refs) = super::parse_lines(&response)?;
+        self.capabilities = capabilities;
         Ok(refs)
     }
 
     fn fetch_packfile(&mut self, want: &[GitRef]) -> Result<Vec<u8>> {
-        let capabilities = ["multi_ack_detailed", "side-band-64k", "agent=git/1.8.1"];
+        let capabilities = capabilities::fetch_capabilities(&self.capabilities)?;
+        let request = super::create_negotiation_request(&capabilities[..], want);
 
-        // FIXME: We shouldn't have to call this command twice because then we are just
-        // doing twice the work receiving the refs.
-        let command = format!("git-upload-pack {}", self.repo);
-        let mut chan = self.sess.channel_session()?;
-        chan.exec(&command)?;
+        self.channel.write_all(&request[..])?;
+        self.channel.flush()?;
+        self.requested = true;
+        super::receive_with_sideband(&mut self.channel)
+    }
+
+    fn head_target(&self) -> Option<&str> {
+        self.capabilities.symref_target("HEAD")
+    }
+}
 
-        super::receive(&mut chan)?;
-        //let (_server_capabilities, refs) = super::parse_lines(&response);
+impl Drop for GitSSHClient {
+    fn drop(&mut self) {
+        // As with the ssh program, tell the server we're done if we never asked
+        // for anything.
+        if !self.requested {
+            let _ = self.channel.write_flush();
+        }
+        let _ = self.channel.send_eof();
+        let _ = self.channel.wait_close();
+    }
+}
 
-        let request = super::create_negotiation_request(&capabilities[..], want);
+fn default_known_hosts_file() -> Result<PathBuf> {
+    let home = env::var_os("HOME").ok_or_else(|| anyhow!("HOME is not set"))?;
+    Ok(Path::new(&home).join(".ssh/known_hosts"))
+}
+
+///
+/// Verifies the server's host key against the known_hosts file, adding it
+/// if the host is new and `checking` allows it.
+///
+fn check_host_key(
+    sess: &Session,
+    host: &str,
+    port: u16,
+    key: &[u8],
+    key_type: HostKeyType,
+    known_hosts_file: &Path,
+    checking: HostKeyChecking,
+) -> Result<()> {
+    let mut known_hosts = sess.known_hosts()?;
+    if known_hosts_file.exists() {
+        known_hosts
+            .read_file(known_hosts_file, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("read {}", known_hosts_file.display()))?;
+    }
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow!(
+            "host key for {} has changed; refusing to connect",
+            host
+        )),
+        CheckResult::Failure => Err(anyhow!("could not check the host key for {}", host)),
+        CheckResult::NotFound if checking == HostKeyChecking::Strict => Err(anyhow!(
+            "no host key is known for {} and strict checking is enabled",
+            host
+        )),
+        CheckResult::NotFound => {
+            let name = if port == DEFAULT_PORT {
+                host.to_owned()
+            } else {
+                format!("[{}]:{}", host, port)
+            };
+            let key_type = match key_type {
+                HostKeyType::Rsa => "ssh-rsa",
+                HostKeyType::Dss => "ssh-dss",
+                HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+                HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+                HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+                HostKeyType::Ed255219 => "ssh-ed25519",
+                HostKeyType::Unknown => return Err(anyhow!("unknown host key type")),
+            };
+
+            // Append rather than having libssh2 rewrite the file, which would
+            // drop anything it doesn't understand.
+            if let Some(dir) = known_hosts_file.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(known_hosts_file)
+                .with_context(|| format!("open {}", known_hosts_file.display()))?;
+            writeln!(file, "{} {} {}", name, key_type, base64::encode(key))?;
+            eprintln!(
+                "Warning: Permanently added '{}' to the list of known hosts.",
+                name
+            );
+            Ok(())
+        }
+    }
+}
+
+///
+/// Authenticates as `user`, trying the identity file (or the ssh agent), then
+/// keyboard-interactive and password prompts, as the server allows.
+///
+fn authenticate(sess: &Session, user: &str, host: &str, options: &SshOptions) -> Result<()> {
+    let methods = sess.auth_methods(user)?.to_owned();
+    if sess.authenticated() {
+        return Ok(());
+    }
+
+    if methods.contains("publickey") {
+        match &options.identity_file {
+            Some(identity) => {
+                let result = sess.userauth_pubkey_file(user, None, identity, None);
+                if let Err(e) = result {
+                    if e.code() != ErrorCode::Session(LIBSSH2_ERROR_FILE) {
+                        return Err(e).with_context(|| format!("use key {}", identity.display()));
+                    }
+                    let passphrase = options.prompter.prompt(
+                        &format!("Enter passphrase for key '{}': ", identity.display()),
+                        false,
+                    )?;
+                    sess.userauth_pubkey_file(user, None, identity, Some(&passphrase))
+                        .with_context(|| format!("use key {}", identity.display()))?;
+                }
+            }
+            None => {
+                // The agent may simply have no suitable keys.
+                let mut agent = sess.agent()?;
+                if agent.connect().is_ok() {
+                    let _ = sess.userauth_agent(user);
+                }
+            }
+        }
+    }
+    if !sess.authenticated() && methods.contains("keyboard-interactive") {
+        let mut prompt = InteractivePrompt {
+            prompter: &options.prompter,
+        };
+        let _ = sess.userauth_keyboard_interactive(user, &mut prompt);
+    }
+    if !sess.authenticated() && methods.contains("password") {
+        let password = options
+            .prompter
+            .prompt(&format!("{}@{}'s password: ", user, host), false)?;
+        sess.userauth_password(user, &password)?;
+    }
+
+    if sess.authenticated() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{}@{}: permission denied ({})",
+            user,
+            host,
+            methods
+        ))
+    }
+}
+
+struct InteractivePrompt<'a> {
+    prompter: &'a Prompter,
+}
+
+impl<'a> KeyboardInteractivePrompt for InteractivePrompt<'a> {
+    fn prompt<'b>(
+        &mut self,
+        _username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'b>],
+    ) -> Vec<String> {
+        if !instructions.is_empty() {
+            eprintln!("{}", instructions);
+        }
+        // An empty answer fails authentication, which is reported afterwards.
+        prompts
+            .iter()
+            .map(|p| self.prompter.prompt(&p.text, p.echo).unwrap_or_default())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checking_host_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let known_hosts_file = dir.path().join("ssh/known_hosts");
+        let sess = Session::new().unwrap();
+        let key = b"not really a key";
+        let check = |host, key: &[u8], checking| {
+            check_host_key(
+                &sess,
+                host,
+                2222,
+                key,
+                HostKeyType::Ed255219,
+                &known_hosts_file,
+                checking,
+            )
+        };
+
+        assert!(check("example.com", key, HostKeyChecking::Strict).is_err());
+        check("example.com", key, HostKeyChecking::AcceptNew).unwrap();
+        let contents = fs::read_to_string(&known_hosts_file).unwrap();
+        assert!(contents.starts_with("[example.com]:2222 ssh-ed25519 "));
 
-        chan.write_all(&request[..])?;
-        super::receive_with_sideband(&mut chan)
+        check("example.com", key, HostKeyChecking::Strict).unwrap();
+        assert!(check("example.com", b"another key", HostKeyChecking::AcceptNew).is_err());
     }
 }
@@ -0,0 +1,389 @@
+use std::env;
+use std::io::{
+    BufReader,
+    Write,
+};
+use std::path::Path;
+use std::process::{
+    Child,
+    ChildStdin,
+    ChildStdout,
+    Command,
+    Stdio,
+};
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use reqwest::Url;
+
+use super::capabilities;
+use super::capabilities::Capabilities;
+use super::pktline::WritePktLineExt;
+use super::GitClient;
+use crate::packfile::refs::GitRef;
+use crate::store::Config;
+
+///
+/// A client which runs `git-upload-pack` on the remote through an ssh
+/// program, talking to it over the program's stdin and stdout.
+///
+/// Since the user's own ssh does the connecting, its configuration (e.g.
+/// `~/.ssh/config`, ProxyJump, agents and hardware keys) applies as usual.
+///
+pub struct SshCommandClient {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+    capabilities: Capabilities,
+    requested: bool,
+}
+
+///
+/// The flavour of ssh program, which determines how options are passed.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SshVariant {
+    Ssh,
+    Simple,
+    Plink,
+    Putty,
+    TortoisePlink,
+    /// Use the built-in libssh2 client rather than running a program.
+    Libssh2,
+}
+
+impl SshVariant {
+    fn from_name(name: &str) -> Result<Option<Self>> {
+        match name {
+            "auto" => Ok(None),
+            "ssh" => Ok(Some(SshVariant::Ssh)),
+            "simple" => Ok(Some(SshVariant::Simple)),
+            "plink" => Ok(Some(SshVariant::Plink)),
+            "putty" => Ok(Some(SshVariant::Putty)),
+            "tortoiseplink" => Ok(Some(SshVariant::TortoisePlink)),
+            "libssh2" => Ok(Some(SshVariant::Libssh2)),
+            _ => Err(anyhow!("unknown ssh variant: {}", name)),
+        }
+    }
+
+    ///
+    /// Guesses the variant from the name of the program. Anything unknown is
+    /// assumed to accept OpenSSH's options.
+    ///
+    fn detect(program: &str) -> Self {
+        let name = Path::new(program)
+            .file_stem()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match &name[..] {
+            "plink" => SshVariant::Plink,
+            "putty" => SshVariant::Putty,
+            "tortoiseplink" => SshVariant::TortoisePlink,
+            _ => SshVariant::Ssh,
+        }
+    }
+}
+
+///
+/// The ssh program to run, chosen in order of precedence from
+/// `GIT_SSH_COMMAND`, `core.sshCommand`, `GIT_SSH` or plain `ssh`.
+///
+#[derive(Debug)]
+pub struct SshProgram {
+    command: String,
+    // Whether `command` is a shell command rather than the path to a program.
+    shell: bool,
+    pub variant: SshVariant,
+}
+
+impl SshProgram {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let (command, shell) = if let Ok(command) = env::var("GIT_SSH_COMMAND") {
+            (command, true)
+        } else if let Some(command) = config.get("core.sshCommand") {
+            (command.to_owned(), true)
+        } else if let Ok(program) = env::var("GIT_SSH") {
+            (program, false)
+        } else {
+            ("ssh".to_owned(), false)
+        };
+        let variant = match env::var("GIT_SSH_VARIANT") {
+            Ok(variant) => SshVariant::from_name(&variant)?,
+            Err(_) => config
+                .get("ssh.variant")
+                .map(SshVariant::from_name)
+                .transpose()?
+                .flatten(),
+        };
+        let variant = variant.unwrap_or_else(|| {
+            let program = if shell {
+                command.split_whitespace().next().unwrap_or_default()
+            } else {
+                &command[..]
+            };
+            SshVariant::detect(program)
+        });
+        Ok(SshProgram {
+            command,
+            shell,
+            variant,
+        })
+    }
+
+    ///
+    /// Builds the command which runs `remote_command` on `host`.
+    ///
+    fn command(
+        &self,
+        user: Option<&str>,
+        host: &str,
+        port: Option<u16>,
+        remote_command: &str,
+    ) -> Result<Command> {
+        // These would otherwise be taken as options by ssh.
+        if host.starts_with('-') || user.is_some_and(|u| u.starts_with('-')) {
+            return Err(anyhow!("strange hostname '{}' blocked", host));
+        }
+        let mut args = Vec::new();
+        match (self.variant, port) {
+            (SshVariant::TortoisePlink, _) => args.push("-batch".to_owned()),
+            (SshVariant::Simple, Some(_)) => {
+                return Err(anyhow!(
+                    "ssh variant 'simple' does not support setting port"
+                ))
+            }
+            _ => {}
+        }
+        if let Some(port) = port {
+            let flag = match self.variant {
+                SshVariant::Ssh => "-p",
+                _ => "-P",
+            };
+            args.push(flag.to_owned());
+            args.push(port.to_string());
+        }
+        args.push(match user {
+            Some(user) => format!("{}@{}", user, host),
+            None => host.to_owned(),
+        });
+        args.push(remote_command.to_owned());
+
+        let mut command = if self.shell {
+            // Let the shell split the user's command, then pass our arguments
+            // through untouched.
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("{} \"$@\"", self.command))
+                .arg(&self.command);
+            command
+        } else {
+            Command::new(&self.command)
+        };
+        command.args(args);
+        Ok(command)
+    }
+}
+
+impl SshCommandClient {
+    pub fn connect(url: &Url, program: &SshProgram) -> Result<Self> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("host required for ssh"))?;
+        // IPv6 addresses are bracketed in URLs but not on the command line.
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let user = Some(url.username()).filter(|u| !u.is_empty());
+        let remote_command = format!("git-upload-pack {}", sq_quote(&repo_path(url)));
+
+        let mut child = program
+            .command(user, host, url.port(), &remote_command)?
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("run ssh command {:?}", program.command))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(SshCommandClient {
+            child,
+            stdin: Some(stdin),
+            stdout: BufReader::new(stdout),
+            capabilities: Capabilities::default(),
+            requested: false,
+        })
+    }
+}
+
+impl GitClient for SshCommandClient {
+    fn discover_refs(&mut self) -> Result<Vec<GitRef>> {
+        let response = super::receive(&mut self.stdout)?;
+        let (capabilities, refs) = super::parse_lines(&response)?;
+        self.capabilities = capabilities;
+        Ok(refs)
+    }
+
+    fn fetch_packfile(&mut self, want: &[GitRef]) -> Result<Vec<u8>> {
+        let capabilities = capabilities::fetch_capabilities(&self.capabilities)?;
+        let request = super::create_negotiation_request(&capabilities[..], want);
+
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| anyhow!("connection already closed"))?;
+        stdin.write_all(&request[..])?;
+        stdin.flush()?;
+        self.requested = true;
+        super::receive_with_sideband(&mut self.stdout)
+    }
+
+    fn head_target(&self) -> Option<&str> {
+        self.capabilities.symref_target("HEAD")
+    }
+}
+
+impl Drop for SshCommandClient {
+    fn drop(&mut self) {
+        if let Some(mut stdin) = self.stdin.take() {
+            // Tell the server we don't want anything if we never asked, so it
+            // exits cleanly rather than complaining about the hang up.
+            if !self.requested {
+                let _ = stdin.write_flush();
+            }
+        }
+        let _ = self.child.wait();
+    }
+}
+
+///
+/// The repository path to pass to the remote `git-upload-pack`.
+///
+/// Paths starting with `~` are relative to a home directory, which the
+/// server expands itself.
+///
+pub fn repo_path(url: &Url) -> String {
+    let path = url.path();
+    match path.strip_prefix('/') {
+        Some(rest) if rest.starts_with('~') => rest.to_owned(),
+        _ => path.to_owned(),
+    }
+}
+
+///
+/// Quotes `s` for a POSIX shell, which is how the remote side will see it.
+///
+pub fn sq_quote(s: &str) -> String {
+    let mut quoted = String::from("'");
+    for c in s.chars() {
+        match c {
+            '\'' => quoted.push_str("'\\''"),
+            '!' => quoted.push_str("'\\!'"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn quoting_the_remote_path() {
+        assert_eq!(sq_quote("/repo.git"), "'/repo.git'");
+        assert_eq!(sq_quote("it's!"), "'it'\\''s'\\!''");
+
+        let url = Url::parse("ssh://git@example.com/~alice/repo.git").unwrap();
+        assert_eq!(repo_path(&url), "~alice/repo.git");
+        let url = Url::parse("ssh://git@example.com/srv/repo.git").unwrap();
+        assert_eq!(repo_path(&url), "/srv/repo.git");
+    }
+
+    #[test]
+    fn building_ssh_commands() {
+        let program = SshProgram {
+            command: "ssh".into(),
+            shell: false,
+            variant: SshVariant::Ssh,
+        };
+        let command = program
+            .command(
+                Some("git"),
+                "example.com",
+                Some(2222),
+                "git-upload-pack '/r'",
+            )
+            .unwrap();
+        assert_eq!(command.get_program(), "ssh");
+        assert_eq!(
+            args(&command),
+            ["-p", "2222", "git@example.com", "git-upload-pack '/r'"]
+        );
+
+        let program = SshProgram {
+            command: "ssh -i key".into(),
+            shell: true,
+            variant: SshVariant::Ssh,
+        };
+        let command = program
+            .command(None, "example.com", None, "git-upload-pack '/r'")
+            .unwrap();
+        assert_eq!(command.get_program(), "sh");
+        assert_eq!(
+            args(&command),
+            [
+                "-c",
+                "ssh -i key \"$@\"",
+                "ssh -i key",
+                "example.com",
+                "git-upload-pack '/r'"
+            ]
+        );
+
+        let program = SshProgram {
+            command: "tortoiseplink.exe".into(),
+            shell: false,
+            variant: SshVariant::detect("tortoiseplink.exe"),
+        };
+        let command = program.command(None, "host", Some(22), "cmd").unwrap();
+        assert_eq!(args(&command), ["-batch", "-P", "22", "host", "cmd"]);
+    }
+
+    #[test]
+    fn refusing_hosts_that_look_like_options() {
+        let program = SshProgram {
+            command: "ssh".into(),
+            shell: false,
+            variant: SshVariant::Ssh,
+        };
+        assert!(program
+            .command(None, "-oProxyCommand=evil", None, "cmd")
+            .is_err());
+    }
+
+    #[test]
+    fn reading_the_advertisement_from_the_command() {
+        // Stand in for ssh with a command that prints an advertisement.
+        let program = SshProgram {
+            command: "printf '0040\
+                      33676d1c63d868803ed110b13be4e616bc8a29b7 HEAD\\000side-band-64k\\n0000'; \
+                      true"
+                .into(),
+            shell: true,
+            variant: SshVariant::Ssh,
+        };
+        let url = Url::parse("ssh://example.com/repo.git").unwrap();
+        let mut client = SshCommandClient::connect(&url, &program).unwrap();
+        let refs = client.discover_refs().unwrap();
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].name, "HEAD");
+    }
+}
@@ -1,31 +1,32 @@
 use std::io::Write;
-use std::net::IpAddr;
 use std::net::TcpStream;
-use std::net::ToSocketAddrs;
 
-use anyhow::anyhow;
 use anyhow::Result;
 
+use super::capabilities;
+use super::capabilities::Capabilities;
+use super::pktline::WritePktLineExt;
 use super::GitClient;
 use crate::packfile::refs::GitRef;
 
+/// The port a git daemon listens on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 9418;
+
 pub struct GitTcpClient {
     stream: TcpStream,
     repo: String,
-    host: IpAddr,
+    host: String,
+    capabilities: Capabilities,
 }
 
 impl GitTcpClient {
-    pub fn connect<A: ToSocketAddrs>(addr: A, repo: &str) -> Result<Self> {
-        let addr = addr
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| anyhow!("empty addrs"))?;
-        let stream = TcpStream::connect(addr)?;
+    pub fn connect(host: &str, port: u16, repo: &str) -> Result<Self> {
+        let stream = TcpStream::connect((host, port))?;
         Ok(GitTcpClient {
             repo: repo.to_owned(),
             stream,
-            host: addr.ip(),
+            host: host.to_owned(),
+            capabilities: Capabilities::default(),
         })
     }
 
@@ -35,14 +36,14 @@ impl GitTcpClient {
     fn git_proto_request(&self) -> Vec<u8> {
         let mut request = Vec::new();
         let s: String = [
-            "git-upload-pack /",
+            "git-upload-pack ",
             &self.repo[..],
             "\0host=",
-            &self.host.to_string(),
+            &self.host[..],
             "\0",
         ]
         .concat();
-        super::write_pktline(&s[..], &mut request);
+        request.write_data(s.as_bytes()).expect("write into vec");
         request
     }
 }
@@ -53,15 +54,20 @@ impl GitClient for GitTcpClient {
         self.stream.write_all(&payload)?;
 
         let response = super::receive(&mut self.stream)?;
-        let (_server_capabilities, refs) = super::parse_lines(&response)?;
+        let (capabilities, refs) = super::parse_lines(&response)?;
+        self.capabilities = capabilities;
         Ok(refs)
     }
 
     fn fetch_packfile(&mut self, want: &[GitRef]) -> Result<Vec<u8>> {
-        let capabilities = ["multi_ack_detailed", "side-band-64k", "agent=git/1.8.1"];
+        let capabilities = capabilities::fetch_capabilities(&self.capabilities)?;
         let request = super::create_negotiation_request(&capabilities[..], want);
         self.stream.write_all(&request[..])?;
 
         super::receive_with_sideband(&mut self.stream)
     }
+
+    fn head_target(&self) -> Option<&str> {
+        self.capabilities.symref_target("HEAD")
+    }
 }
@@ -0,0 +1,163 @@
+use std::io::{
+    BufReader,
+    BufWriter,
+};
+use std::net::{
+    TcpListener,
+    TcpStream,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::str;
+use std::thread;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+
+use super::UploadPack;
+use crate::remote::pktline::{
+    Packet,
+    ReadPktLineExt,
+    WritePktLineExt,
+};
+
+///
+/// A server for the git:// protocol, serving repositories found under a
+/// base directory.
+///
+#[derive(Clone)]
+pub struct Daemon {
+    base_path: PathBuf,
+    export_all: bool,
+}
+
+///
+/// The request sent by the client when it first connects.
+///
+/// -- PKT-LINE(service SP pathname NUL [host-parameter NUL] [NUL extra-parameters])
+///
+struct DaemonRequest {
+    service: String,
+    path: String,
+}
+
+impl Daemon {
+    pub fn new<P: AsRef<Path>>(base_path: P, export_all: bool) -> Self {
+        Daemon {
+            base_path: base_path.as_ref().to_owned(),
+            export_all,
+        }
+    }
+
+    ///
+    /// Accepts connections forever, serving each on its own thread.
+    ///
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let daemon = self.clone();
+            thread::spawn(move || {
+                let peer = stream
+                    .peer_addr()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_else(|_| "unknown".into());
+                if let Err(e) = daemon.handle_connection(stream) {
+                    eprintln!("[{}] error: {:#}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+
+        let line = reader
+            .read_data()?
+            .ok_or_else(|| anyhow!("expected a request, not a flush"))?;
+        let request = parse_request(&line)?;
+
+        let result = if request.service != "git-upload-pack" {
+            Err(anyhow!("service not enabled: {}", request.service))
+        } else {
+            super::open_exported_repo(&self.base_path, &request.path, self.export_all)
+        };
+        let repo = match result {
+            Ok(repo) => repo,
+            Err(e) => {
+                // The client only sees a generic message so that we don't
+                // reveal which repositories exist.
+                writer.write_packet(&Packet::Err(
+                    "access denied or repository not exported".to_owned(),
+                ))?;
+                return Err(e.context(request.path));
+            }
+        };
+        let upload_pack = UploadPack::new(&repo);
+        upload_pack.advertise_refs(&mut writer)?;
+        upload_pack.serve(&mut reader, &mut writer)
+    }
+}
+
+fn parse_request(line: &[u8]) -> Result<DaemonRequest> {
+    let line = str::from_utf8(line)?;
+    let command = line
+        .split('\0')
+        .next()
+        .ok_or_else(|| anyhow!("empty request"))?;
+    let (service, path) = command
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("malformed request: {:?}", line))?;
+    Ok(DaemonRequest {
+        service: service.to_owned(),
+        path: path.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packfile::PackFile;
+    use crate::remote::tcpclient::GitTcpClient;
+    use crate::remote::GitClient;
+
+    fn start_daemon(export_all: bool) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let daemon = Daemon::new("tests/data/repos", export_all);
+        thread::spawn(move || daemon.serve(listener));
+        port
+    }
+
+    #[test]
+    fn parsing_a_request() {
+        let request = parse_request(b"git-upload-pack /project.git\0host=myserver.com\0").unwrap();
+        assert_eq!(request.service, "git-upload-pack");
+        assert_eq!(request.path, "/project.git");
+    }
+
+    #[test]
+    fn cloning_from_the_daemon() {
+        let port = start_daemon(true);
+        let mut client = GitTcpClient::connect("127.0.0.1", port, "/simple").unwrap();
+        let refs = client.discover_refs().unwrap();
+        assert_eq!(refs[0].name, "HEAD");
+        assert_eq!(refs[0].id, "33676d1c63d868803ed110b13be4e616bc8a29b7");
+
+        let packfile_data = client.fetch_packfile(&refs).unwrap();
+        let pack = PackFile::parse(&packfile_data).unwrap();
+        assert_eq!(pack.num_objects(), 30);
+    }
+
+    #[test]
+    fn refusing_unexported_repositories() {
+        let port = start_daemon(false);
+        let mut client = GitTcpClient::connect("127.0.0.1", port, "/simple.git").unwrap();
+        assert!(client.discover_refs().is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    BufWriter,
+    Read,
+    Write,
+};
+use std::net::{
+    TcpListener,
+    TcpStream,
+};
+use std::thread;
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result,
+};
+
+use super::http_backend::{
+    self,
+    HttpBackend,
+    HttpRequest,
+    Responder,
+};
+
+///
+/// A minimal HTTP/1.1 server for running `HttpBackend` without a separate
+/// web server. Each connection serves a single request, and responses are
+/// streamed with chunked transfer encoding since their length isn't known
+/// up front.
+///
+pub fn serve(backend: HttpBackend, listener: TcpListener) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let backend = backend.clone();
+        thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "unknown".into());
+            if let Err(e) = handle_connection(&backend, stream) {
+                eprintln!("[{}] error: {:#}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(backend: &HttpBackend, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let (request, headers) = read_request(&mut reader).context("read request")?;
+    let header = |name| find_header(&headers, name);
+    if header("Expect").is_some_and(|e| e.eq_ignore_ascii_case("100-continue")) {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    let chunked = header("Transfer-Encoding").is_some_and(|e| e.eq_ignore_ascii_case("chunked"));
+    let body: Box<dyn Read> = if chunked {
+        Box::new(ChunkedReader::new(reader))
+    } else {
+        let length = match header("Content-Length") {
+            Some(length) => length.parse().context("invalid content length")?,
+            None => 0,
+        };
+        Box::new(reader.take(length))
+    };
+
+    let mut responder = ServerResponder {
+        writer: ChunkedWriter::new(writer),
+        started: false,
+    };
+    let result = backend.handle(&request, body, &mut responder);
+    // Ending the body cleanly on failure would make a truncated response look
+    // complete, so only do so on success.
+    if result.is_ok() && responder.started {
+        responder.writer.finish()?;
+    }
+    result
+}
+
+type Headers = Vec<(String, String)>;
+
+fn read_request<R: BufRead>(reader: &mut R) -> Result<(HttpRequest, Headers)> {
+    let request_line = read_line(reader)?;
+    let mut parts = request_line.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method, target)
+        }
+        _ => return Err(anyhow!("malformed request line: {:?}", request_line)),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("malformed header: {:?}", line))?;
+        headers.push((key.trim().to_owned(), value.trim().to_owned()));
+    }
+    let header = |name| find_header(&headers, name).map(str::to_owned);
+    let request = HttpRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query: query.to_owned(),
+        content_type: header("Content-Type"),
+        content_encoding: header("Content-Encoding"),
+    };
+    Ok((request, headers))
+}
+
+fn find_header<'h>(headers: &'h Headers, name: &str) -> Option<&'h str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| &value[..])
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(anyhow!("unexpected end of request"));
+    }
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+struct ServerResponder<W: Write> {
+    writer: ChunkedWriter<W>,
+    started: bool,
+}
+
+impl<W: Write> Responder for ServerResponder<W> {
+    fn start(&mut self, status: u16, content_type: &str) -> io::Result<&mut dyn Write> {
+        write!(
+            self.writer.inner,
+            "HTTP/1.1 {} {}\r\n\
+             Content-Type: {}\r\n\
+             Cache-Control: no-cache\r\n\
+             Transfer-Encoding: chunked\r\n\
+             Connection: close\r\n\r\n",
+            status,
+            http_backend::reason_phrase(status),
+            content_type,
+        )?;
+        self.started = true;
+        Ok(&mut self.writer)
+    }
+}
+
+///
+/// Decodes a body sent with `Transfer-Encoding: chunked`.
+///
+/// -- chunk = chunk-size [ chunk-ext ] CRLF chunk-data CRLF
+///
+struct ChunkedReader<R> {
+    inner: R,
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> Self {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    fn read_chunk_size(&mut self) -> io::Result<usize> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut line = String::new();
+        self.inner.read_line(&mut line)?;
+        let size = line.trim_end().split(';').next().unwrap_or_default();
+        usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))
+    }
+
+    fn read_crlf(&mut self) -> io::Result<()> {
+        let mut crlf = [0u8; 2];
+        self.inner.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing CRLF after chunk",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.remaining = self.read_chunk_size()?;
+            if self.remaining == 0 {
+                // Skip any trailers up to the final empty line.
+                let mut line = String::new();
+                while self.inner.read_line(&mut line)? > 0 && line.trim_end() != "" {
+                    line.clear();
+                }
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.remaining);
+        let count = self.inner.read(&mut buf[..len])?;
+        if count == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= count;
+        if self.remaining == 0 {
+            self.read_crlf()?;
+        }
+        Ok(count)
+    }
+}
+
+///
+/// Encodes each write as a separate chunk of a chunked body.
+///
+struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    fn new(inner: W) -> Self {
+        ChunkedWriter { inner }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        // An empty chunk would mark the end of the body.
+        if bytes.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", bytes.len())?;
+        self.inner.write_all(bytes)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packfile::PackFile;
+    use crate::remote::httpclient::GitHttpClient;
+    use crate::remote::GitClient;
+    use crate::store::Config;
+
+    #[test]
+    fn chunked_encoding_round_trip() {
+        let mut encoded = Vec::new();
+        {
+            let mut writer = ChunkedWriter::new(&mut encoded);
+            writer.write_all(b"hello ").unwrap();
+            writer.write_all(b"").unwrap();
+            writer.write_all(b"world").unwrap();
+            writer.finish().unwrap();
+        }
+        assert_eq!(encoded, b"6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n");
+
+        let mut decoded = String::new();
+        ChunkedReader::new(&encoded[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "hello world");
+    }
+
+    #[test]
+    fn parsing_a_request() {
+        let raw = b"POST /repo.git/git-upload-pack?x=1 HTTP/1.1\r\n\
+                    Host: localhost\r\n\
+                    content-type: application/x-git-upload-pack-request\r\n\
+                    \r\n";
+        let (request, headers) = read_request(&mut &raw[..]).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/repo.git/git-upload-pack");
+        assert_eq!(request.query, "x=1");
+        assert_eq!(
+            request.content_type.as_deref(),
+            Some("application/x-git-upload-pack-request")
+        );
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn cloning_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let backend = HttpBackend::new("tests/data/repos", true, false);
+        thread::spawn(move || serve(backend, listener));
+
+        let url = format!("http://127.0.0.1:{}/simple.git", port);
+        let mut client = GitHttpClient::new(&url[..], &Config::default()).unwrap();
+        let refs = client.discover_refs().unwrap();
+        assert_eq!(refs[0].name, "HEAD");
+
+        let packfile_data = client.fetch_packfile(&refs).unwrap();
+        let pack = PackFile::parse(&packfile_data).unwrap();
+        assert_eq!(pack.num_objects(), 30);
+    }
+}
@@ -0,0 +1,378 @@
+use std::env;
+use std::io::{
+    self,
+    BufReader,
+    Read,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use anyhow::Result;
+use flate2::read::GzDecoder;
+
+use super::{
+    ReceivePack,
+    UploadPack,
+};
+use crate::remote::pktline::WritePktLineExt;
+
+///
+/// Serves the smart HTTP protocol for repositories found under a base
+/// directory, either as a CGI program or behind the built-in server in
+/// `server::http`.
+///
+/// -- GET  $GIT_URL/info/refs?service=$servicename
+/// -- POST $GIT_URL/$servicename
+///
+#[derive(Clone)]
+pub struct HttpBackend {
+    base_path: PathBuf,
+    export_all: bool,
+    receive_pack: bool,
+}
+
+///
+/// The parts of an HTTP request the backend needs to route and decode it.
+///
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+}
+
+///
+/// Sends the response status and headers, returning a writer for the body.
+///
+pub trait Responder {
+    fn start(&mut self, status: u16, content_type: &str) -> io::Result<&mut dyn Write>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Service {
+    UploadPack,
+    ReceivePack,
+}
+
+impl Service {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "git-upload-pack" => Some(Service::UploadPack),
+            "git-receive-pack" => Some(Service::ReceivePack),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Service::UploadPack => "git-upload-pack",
+            Service::ReceivePack => "git-receive-pack",
+        }
+    }
+}
+
+enum Route<'a> {
+    Advertise(&'a str, Service),
+    Rpc(&'a str, Service),
+}
+
+///
+/// An error which is reported to the client before a response has started.
+///
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new<S: Into<String>>(status: u16, message: S) -> Self {
+        HttpError {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        _ => "Internal Server Error",
+    }
+}
+
+impl HttpBackend {
+    pub fn new<P: AsRef<Path>>(base_path: P, export_all: bool, receive_pack: bool) -> Self {
+        HttpBackend {
+            base_path: base_path.as_ref().to_owned(),
+            export_all,
+            receive_pack,
+        }
+    }
+
+    ///
+    /// Handles a single request, reading its body from `body`.
+    ///
+    /// Errors which occur before the response has started are reported to the
+    /// client with an appropriate status. Anything later can only be returned,
+    /// since the client has already been told the request succeeded.
+    ///
+    pub fn handle<R: Read>(
+        &self,
+        request: &HttpRequest,
+        body: R,
+        responder: &mut dyn Responder,
+    ) -> Result<()> {
+        let route = match self.route(request) {
+            Ok(route) => route,
+            Err(e) => {
+                let out = responder.start(e.status, "text/plain")?;
+                writeln!(out, "{}", e.message)?;
+                return Ok(());
+            }
+        };
+        let path = match route {
+            Route::Advertise(path, _) | Route::Rpc(path, _) => path,
+        };
+        let mut repo = match super::open_exported_repo(&self.base_path, path, self.export_all) {
+            Ok(repo) => repo,
+            Err(e) => {
+                let out = responder.start(404, "text/plain")?;
+                writeln!(out, "Repository not found")?;
+                return Err(e.context(path.to_owned()));
+            }
+        };
+
+        match route {
+            Route::Advertise(_, service) => {
+                let content_type = format!("application/x-{}-advertisement", service.name());
+                let mut out = responder.start(200, &content_type)?;
+                let header = format!("# service={}\n", service.name());
+                out.write_data(header.as_bytes())?;
+                out.write_flush()?;
+                match service {
+                    Service::UploadPack => UploadPack::new(&repo).advertise_refs(&mut out),
+                    Service::ReceivePack => ReceivePack::new(&mut repo).advertise_refs(&mut out),
+                }
+            }
+            Route::Rpc(_, service) => {
+                let body: Box<dyn Read> = match request.content_encoding.as_deref() {
+                    Some("gzip") | Some("x-gzip") => Box::new(GzDecoder::new(body)?),
+                    _ => Box::new(body),
+                };
+                let mut body = BufReader::new(body);
+                let content_type = format!("application/x-{}-result", service.name());
+                let mut out = responder.start(200, &content_type)?;
+                match service {
+                    Service::UploadPack => UploadPack::new(&repo)
+                        .stateless_rpc(true)
+                        .serve(&mut body, &mut out),
+                    Service::ReceivePack => ReceivePack::new(&mut repo).serve(&mut body, &mut out),
+                }
+            }
+        }
+    }
+
+    fn route<'r>(&self, request: &'r HttpRequest) -> Result<Route<'r>, HttpError> {
+        let path = &request.path[..];
+        let (route, method) = if let Some(repo) = path.strip_suffix("/info/refs") {
+            let service = request
+                .query
+                .split('&')
+                .find_map(|param| param.strip_prefix("service="))
+                .ok_or_else(|| HttpError::new(403, "dumb HTTP is not supported"))?;
+            let service = Service::from_name(service)
+                .ok_or_else(|| HttpError::new(403, "unsupported service"))?;
+            (Route::Advertise(repo, service), "GET")
+        } else {
+            let (repo, name) = path
+                .rsplit_once('/')
+                .ok_or_else(|| HttpError::new(404, "not found"))?;
+            let service =
+                Service::from_name(name).ok_or_else(|| HttpError::new(404, "not found"))?;
+            let expected = format!("application/x-{}-request", service.name());
+            if request.method == "POST" && request.content_type.as_deref() != Some(&expected) {
+                return Err(HttpError::new(415, "unsupported content type"));
+            }
+            (Route::Rpc(repo, service), "POST")
+        };
+        if request.method != method {
+            return Err(HttpError::new(405, "method not allowed"));
+        }
+        match route {
+            Route::Advertise(_, Service::ReceivePack) | Route::Rpc(_, Service::ReceivePack)
+                if !self.receive_pack =>
+            {
+                Err(HttpError::new(403, "git-receive-pack is not enabled"))
+            }
+            route => Ok(route),
+        }
+    }
+
+    ///
+    /// Handles the request described by the CGI environment, reading the
+    /// body from stdin and writing the response to stdout.
+    ///
+    pub fn run_cgi(&self) -> Result<()> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        let request = HttpRequest {
+            method: var("REQUEST_METHOD").unwrap_or_else(|| "GET".into()),
+            path: var("PATH_INFO").unwrap_or_default(),
+            query: var("QUERY_STRING").unwrap_or_default(),
+            content_type: var("CONTENT_TYPE"),
+            content_encoding: var("HTTP_CONTENT_ENCODING"),
+        };
+        let stdin = io::stdin();
+        let stdin = stdin.lock();
+        let body: Box<dyn Read> = match var("CONTENT_LENGTH").and_then(|l| l.parse().ok()) {
+            Some(length) => Box::new(stdin.take(length)),
+            None => Box::new(stdin),
+        };
+        let stdout = io::stdout();
+        let mut responder = CgiResponder {
+            writer: stdout.lock(),
+        };
+        self.handle(&request, body, &mut responder)?;
+        responder.writer.flush()?;
+        Ok(())
+    }
+}
+
+struct CgiResponder<W> {
+    writer: W,
+}
+
+impl<W: Write> Responder for CgiResponder<W> {
+    fn start(&mut self, status: u16, content_type: &str) -> io::Result<&mut dyn Write> {
+        write!(
+            self.writer,
+            "Status: {} {}\r\nContent-Type: {}\r\nCache-Control: no-cache\r\n\r\n",
+            status,
+            reason_phrase(status),
+            content_type,
+        )?;
+        Ok(&mut self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+    use crate::packfile::PackFile;
+    use crate::remote;
+    use crate::remote::pktline::ReadPktLineExt;
+
+    struct TestResponder {
+        status: Option<u16>,
+        content_type: String,
+        body: Vec<u8>,
+    }
+
+    impl Responder for TestResponder {
+        fn start(&mut self, status: u16, content_type: &str) -> io::Result<&mut dyn Write> {
+            self.status = Some(status);
+            self.content_type = content_type.to_owned();
+            Ok(&mut self.body)
+        }
+    }
+
+    fn request(method: &str, path: &str, query: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.into(),
+            path: path.into(),
+            query: query.into(),
+            content_type: None,
+            content_encoding: None,
+        }
+    }
+
+    fn handle(request: &HttpRequest, body: &[u8]) -> TestResponder {
+        let backend = HttpBackend::new("tests/data/repos", true, false);
+        let mut responder = TestResponder {
+            status: None,
+            content_type: String::new(),
+            body: Vec::new(),
+        };
+        let _ = backend.handle(request, body, &mut responder);
+        responder
+    }
+
+    #[test]
+    fn advertising_refs() {
+        let response = handle(
+            &request("GET", "/simple.git/info/refs", "service=git-upload-pack"),
+            b"",
+        );
+        assert_eq!(response.status, Some(200));
+        assert_eq!(
+            response.content_type,
+            "application/x-git-upload-pack-advertisement"
+        );
+        assert!(response
+            .body
+            .starts_with(b"001e# service=git-upload-pack\n0000"));
+    }
+
+    #[test]
+    fn rejecting_bad_requests() {
+        let cases = [
+            (request("GET", "/simple.git/info/refs", ""), 403),
+            (
+                request("GET", "/simple.git/info/refs", "service=git-receive-pack"),
+                403,
+            ),
+            (request("GET", "/simple.git/git-upload-pack", ""), 405),
+            (request("POST", "/simple.git/git-upload-pack", ""), 415),
+            (request("GET", "/simple.git/HEAD", ""), 404),
+            (
+                request("GET", "/missing.git/info/refs", "service=git-upload-pack"),
+                404,
+            ),
+        ];
+        for (request, status) in &cases {
+            assert_eq!(
+                handle(request, b"").status,
+                Some(*status),
+                "{}",
+                request.path
+            );
+        }
+    }
+
+    #[test]
+    fn fetching_with_a_gzipped_request() {
+        let mut body = Vec::new();
+        let want = "want 33676d1c63d868803ed110b13be4e616bc8a29b7 side-band-64k\n";
+        body.write_data(want.as_bytes()).unwrap();
+        body.write_flush().unwrap();
+        body.write_data(b"done\n").unwrap();
+        let mut z = GzEncoder::new(Vec::new(), Compression::Default);
+        z.write_all(&body).unwrap();
+        let body = z.finish().unwrap();
+
+        let mut request = request("POST", "/simple/git-upload-pack", "");
+        request.content_type = Some("application/x-git-upload-pack-request".into());
+        request.content_encoding = Some("gzip".into());
+        let response = handle(&request, &body);
+        assert_eq!(response.status, Some(200));
+
+        let mut reader = Cursor::new(response.body);
+        assert_eq!(reader.read_data().unwrap(), Some(b"NAK\n".to_vec()));
+        let pack = remote::receive_with_sideband(&mut reader).unwrap();
+        assert_eq!(PackFile::parse(&pack).unwrap().num_objects(), 29);
+    }
+}
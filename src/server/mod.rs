@@ -0,0 +1,101 @@
+//!
+//! The server side of the git transfer protocols.
+//!
+//! `UploadPack` and `ReceivePack` implement the protocols themselves over any
+//! reader/writer pair, so each transport only needs to locate the repository
+//! and hand it a connection.
+//!
+use std::io::Write;
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+
+use crate::packfile::refs::GitRef;
+use crate::remote::pktline::WritePktLineExt;
+use crate::store::Repo;
+
+pub mod daemon;
+pub mod http;
+pub mod http_backend;
+mod receive_pack;
+mod upload_pack;
+
+pub use self::receive_pack::ReceivePack;
+pub use self::upload_pack::UploadPack;
+
+/// The file which marks a repository as safe to serve without `--export-all`.
+const EXPORT_OK: &str = "git-daemon-export-ok";
+
+///
+/// Opens the repository at `path` beneath `base_path`, trying a `.git` suffix
+/// as well. Paths which would leave `base_path` are rejected.
+///
+fn open_exported_repo(base_path: &Path, path: &str, export_all: bool) -> Result<Repo> {
+    let relative = path
+        .strip_prefix('/')
+        .ok_or_else(|| anyhow!("repository path must be absolute"))?;
+    let relative = Path::new(relative);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(anyhow!("repository path must not leave the base path"));
+    }
+
+    let full_path = base_path.join(relative);
+    let mut with_suffix = full_path.clone().into_os_string();
+    with_suffix.push(".git");
+    let candidates = [full_path, PathBuf::from(with_suffix)];
+    let repo = candidates
+        .iter()
+        .find_map(|candidate| Repo::open(candidate).ok())
+        .ok_or_else(|| anyhow!("no repository found"))?;
+    if !export_all && !repo.gitdir().join(EXPORT_OK).exists() {
+        return Err(anyhow!("repository is not exported"));
+    }
+    Ok(repo)
+}
+
+///
+/// Writes a ref advertisement, with the capabilities attached to the first ref.
+///
+fn write_advertisement<W: Write>(
+    writer: &mut W,
+    refs: &[GitRef],
+    capabilities: &str,
+) -> Result<()> {
+    if refs.is_empty() {
+        let line = format!("{} capabilities^{{}}\0{}\n", "0".repeat(40), capabilities);
+        writer.write_data(line.as_bytes())?;
+    }
+    for (i, GitRef { id, name }) in refs.iter().enumerate() {
+        let line = if i == 0 {
+            format!("{} {}\0{}\n", id, name, capabilities)
+        } else {
+            format!("{} {}\n", id, name)
+        };
+        writer.write_data(line.as_bytes())?;
+    }
+    writer.write_flush()?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refusing_paths_outside_the_base_path() {
+        let base_path = Path::new("tests/data/repos/simple.git");
+        assert!(open_exported_repo(base_path, "/../simple.git", true).is_err());
+        assert!(open_exported_repo(base_path, "/", true).is_ok());
+    }
+}
@@ -0,0 +1,283 @@
+use std::io::{
+    BufRead,
+    Write,
+};
+use std::str;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+
+use crate::packfile;
+use crate::packfile::refs::{
+    self,
+    GitRef,
+    RefTransaction,
+};
+use crate::remote::capabilities;
+use crate::remote::pktline::{
+    ReadPktLineExt,
+    WritePktLineExt,
+};
+use crate::store::{
+    self,
+    Repo,
+    Sha,
+};
+
+///
+/// Serves the `git-receive-pack` service, which lets clients push.
+///
+/// The client sends the ref updates it wants to make followed by a pack
+/// containing any objects we are missing. Each update is applied only if the
+/// ref still has the value the client expects.
+///
+pub struct ReceivePack<'a> {
+    repo: &'a mut Repo,
+}
+
+///
+/// A single update requested by the client, where a zero SHA for `old` means
+/// the ref is created and a zero SHA for `new` means it is deleted.
+///
+struct Command {
+    old: Sha,
+    new: Sha,
+    name: String,
+}
+
+#[derive(Default)]
+struct ClientCapabilities {
+    report_status: bool,
+}
+
+impl<'a> ReceivePack<'a> {
+    pub fn new(repo: &'a mut Repo) -> Self {
+        ReceivePack { repo }
+    }
+
+    pub fn advertise_refs<W: Write>(&self, writer: &mut W) -> Result<()> {
+        // Unlike upload-pack, HEAD is not advertised since it can't be pushed to.
+        let refs = refs::read_refs(self.repo.gitdir())?
+            .into_iter()
+            .filter(|r| r.name != "HEAD")
+            .collect::<Vec<GitRef>>();
+        // We can't resolve deltas against objects outside the pack, so ask
+        // clients not to send thin packs.
+        let capabilities = ["report-status", "delete-refs", "ofs-delta", "no-thin"].join(" ");
+        let capabilities = format!("{} {}", capabilities, capabilities::agent());
+        super::write_advertisement(writer, &refs, &capabilities)
+    }
+
+    ///
+    /// Reads the client's commands and pack, applies the updates, and reports
+    /// the result of each if the client asked for it.
+    ///
+    pub fn serve<R: BufRead, W: Write>(&mut self, reader: &mut R, writer: &mut W) -> Result<()> {
+        let (commands, capabilities) = read_commands(reader)?;
+        if commands.is_empty() {
+            return Ok(());
+        }
+        let zero = Sha::from_array(&[0u8; 20]);
+
+        let unpack_result = if commands.iter().any(|c| c.new != zero) {
+            packfile::read_packfile(reader.by_ref()).and_then(|pack| self.repo.add_packfile(&pack))
+        } else {
+            Ok(())
+        };
+
+        let mut report = Vec::new();
+        match &unpack_result {
+            Ok(()) => report.push("unpack ok\n".to_owned()),
+            Err(e) => report.push(format!("unpack {}\n", e)),
+        }
+        for command in &commands {
+            let result = match &unpack_result {
+                Ok(()) => self.apply(command),
+                Err(_) => Err(anyhow!("unpacker error")),
+            };
+            match result {
+                Ok(()) => report.push(format!("ok {}\n", command.name)),
+                Err(e) => report.push(format!("ng {} {}\n", command.name, e)),
+            }
+        }
+
+        if capabilities.report_status {
+            for line in &report {
+                writer.write_data(line.as_bytes())?;
+            }
+            writer.write_flush()?;
+            writer.flush()?;
+        }
+        unpack_result
+    }
+
+    fn apply(&self, command: &Command) -> Result<()> {
+        let zero = Sha::from_array(&[0u8; 20]);
+        let gitdir = self.repo.gitdir();
+        if !refs::is_valid_ref_name(&command.name) {
+            return Err(anyhow!("funny refname"));
+        }
+        let current = store::resolve_ref(gitdir, &command.name).ok();
+        if current.unwrap_or(zero) != command.old {
+            return Err(anyhow!("fetch first"));
+        }
+        // The old value is checked again under the ref's lock, in case
+        // another push got there first.
+        let mut transaction = RefTransaction::begin(gitdir, "push");
+        // Clients don't echo delete-refs back; advertising it is enough.
+        if command.new == zero {
+            transaction.delete(&command.name, Some(command.old));
+        } else if !self.repo.has_object(&command.new) {
+            return Err(anyhow!("missing necessary objects"));
+        } else {
+            transaction.update(&command.name, command.new, Some(command.old));
+        }
+        transaction.commit()
+    }
+}
+
+///
+/// Reads update commands until a flush packet.
+///
+/// -- PKT-LINE(old-id SP new-id SP name NUL capability-list)
+/// -- PKT-LINE(old-id SP new-id SP name)
+///
+fn read_commands<R: BufRead>(reader: &mut R) -> Result<(Vec<Command>, ClientCapabilities)> {
+    let mut commands = Vec::new();
+    let mut capabilities = ClientCapabilities::default();
+    loop {
+        let line = match reader.read_data()? {
+            Some(line) => line,
+            None => return Ok((commands, capabilities)),
+        };
+        let text = str::from_utf8(&line)?.trim_end_matches('\n');
+        let (command, caps) = match text.split_once('\0') {
+            Some((command, caps)) => (command, Some(caps)),
+            None => (text, None),
+        };
+        if let Some(caps) = caps {
+            capabilities.report_status |= caps.split(' ').any(|c| c == "report-status");
+        }
+        let parts = command.splitn(3, ' ').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return Err(anyhow!("malformed command: {}", command));
+        }
+        commands.push(Command {
+            old: Sha::from_hex(parts[0].as_bytes())?,
+            new: Sha::from_hex(parts[1].as_bytes())?,
+            name: parts[2].to_owned(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use super::*;
+    use crate::packfile::PackWriter;
+    use crate::remote;
+    use crate::store::{
+        ObjectType,
+        PackedObject,
+    };
+    use crate::test_support::copy_dir;
+
+    static MASTER: &str = "33676d1c63d868803ed110b13be4e616bc8a29b7";
+    static TEST: &str = "718e7fc194a0fef1b1067b12689e5d343f533497";
+    static ZERO: &str = "0000000000000000000000000000000000000000";
+
+    fn push(repo: &mut Repo, commands: &[String], objects: &[PackedObject]) -> Vec<String> {
+        let mut request = Vec::new();
+        for (i, command) in commands.iter().enumerate() {
+            let line = if i == 0 {
+                format!("{}\0report-status delete-refs\n", command)
+            } else {
+                format!("{}\n", command)
+            };
+            request.write_data(line.as_bytes()).unwrap();
+        }
+        request.write_flush().unwrap();
+        if !objects.is_empty() {
+            let mut pack = PackWriter::new(&mut request, objects.len()).unwrap();
+            for object in objects {
+                pack.write_object(object).unwrap();
+            }
+            pack.finish().unwrap();
+        }
+
+        let mut response = Vec::new();
+        let _ = ReceivePack::new(repo).serve(&mut Cursor::new(request), &mut response);
+        remote::receive(&mut Cursor::new(response)).unwrap()
+    }
+
+    #[test]
+    fn pushing_updates_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("simple.git");
+        copy_dir(Path::new("tests/data/repos/simple.git"), &path);
+        let mut repo = Repo::open(&path).unwrap();
+
+        let blob = PackedObject::new(ObjectType::Blob, b"pushed\n".to_vec());
+        let tag = PackedObject::new(
+            ObjectType::Tag,
+            format!("object {}\ntype blob\ntag pushed\n\n", blob.sha()).into_bytes(),
+        );
+        let report = push(
+            &mut repo,
+            &[
+                format!("{} {} refs/tags/pushed", ZERO, tag.sha()),
+                format!("{} {} refs/heads/test", TEST, ZERO),
+                format!("{} {} refs/heads/master", TEST, MASTER),
+            ],
+            &[blob, tag.clone()],
+        );
+        assert_eq!(
+            report,
+            [
+                "unpack ok\n",
+                "ok refs/tags/pushed\n",
+                "ok refs/heads/test\n",
+                "ng refs/heads/master fetch first\n",
+            ]
+        );
+
+        let repo = Repo::open(&path).unwrap();
+        assert!(repo.has_object(&tag.sha()));
+        assert_eq!(
+            store::resolve_ref(repo.gitdir(), "refs/tags/pushed").unwrap(),
+            tag.sha()
+        );
+        assert!(store::resolve_ref(repo.gitdir(), "refs/heads/test").is_err());
+    }
+
+    #[test]
+    fn rejecting_updates_to_missing_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("simple.git");
+        copy_dir(Path::new("tests/data/repos/simple.git"), &path);
+        let mut repo = Repo::open(&path).unwrap();
+
+        let blob = PackedObject::new(ObjectType::Blob, b"pushed\n".to_vec());
+        let missing = "abcdefabcdefabcdefabcdefabcdefabcdabcdef";
+        let report = push(
+            &mut repo,
+            &[
+                format!("{} {} refs/heads/missing", ZERO, missing),
+                format!("{} {} refs/heads/../../HEAD", ZERO, MASTER),
+            ],
+            &[blob],
+        );
+        assert_eq!(
+            report,
+            [
+                "unpack ok\n",
+                "ng refs/heads/missing missing necessary objects\n",
+                "ng refs/heads/../../HEAD funny refname\n",
+            ]
+        );
+    }
+}
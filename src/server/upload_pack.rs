@@ -0,0 +1,500 @@
+use std::collections::HashSet;
+use std::io::{
+    self,
+    BufWriter,
+    Read,
+    Write,
+};
+use std::str;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+
+use crate::packfile::refs::{
+    self,
+    GitRef,
+};
+use crate::packfile::PackWriter;
+use crate::remote::capabilities;
+use crate::remote::pktline::{
+    Packet,
+    ReadPktLineExt,
+    WritePktLineExt,
+};
+use crate::store::{
+    ObjectType,
+    Repo,
+    Sha,
+};
+
+const SIDEBAND_PACK: u8 = 1;
+const SIDEBAND_PROGRESS: u8 = 2;
+const SIDEBAND_ERROR: u8 = 3;
+
+// The payload limits of each sideband mode, excluding the pkt-line header and band.
+const SIDEBAND_MAX_LEN: usize = 1000 - 5;
+const SIDEBAND_64K_MAX_LEN: usize = 65520 - 5;
+
+///
+/// Serves the `git-upload-pack` service for a single repository.
+///
+/// The server advertises its refs, reads the client's wants and haves, and
+/// then streams a pack containing everything the client is missing.
+///
+pub struct UploadPack<'a> {
+    repo: &'a Repo,
+    stateless_rpc: bool,
+}
+
+///
+/// The options a client selected from the advertised capabilities.
+///
+#[derive(Default)]
+struct ClientCapabilities {
+    multi_ack_detailed: bool,
+    side_band: bool,
+    side_band_64k: bool,
+    no_progress: bool,
+}
+
+impl ClientCapabilities {
+    fn parse<'s, I: Iterator<Item = &'s str>>(capabilities: I) -> Self {
+        let mut parsed = ClientCapabilities::default();
+        for capability in capabilities {
+            match capability {
+                "multi_ack_detailed" => parsed.multi_ack_detailed = true,
+                "side-band" => parsed.side_band = true,
+                "side-band-64k" => parsed.side_band_64k = true,
+                "no-progress" => parsed.no_progress = true,
+                // Anything else we didn't advertise is safe to ignore.
+                _ => {}
+            }
+        }
+        parsed
+    }
+
+    fn sideband_len(&self) -> Option<usize> {
+        if self.side_band_64k {
+            Some(SIDEBAND_64K_MAX_LEN)
+        } else if self.side_band {
+            Some(SIDEBAND_MAX_LEN)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> UploadPack<'a> {
+    pub fn new(repo: &'a Repo) -> Self {
+        UploadPack {
+            repo,
+            stateless_rpc: false,
+        }
+    }
+
+    ///
+    /// Enables stateless RPC, where each request carries an entire round of
+    /// negotiation and the connection is not kept open between rounds.
+    ///
+    pub fn stateless_rpc(mut self, stateless_rpc: bool) -> Self {
+        self.stateless_rpc = stateless_rpc;
+        self
+    }
+
+    ///
+    /// Writes the ref advertisement, with our capabilities attached to the first ref.
+    ///
+    pub fn advertise_refs<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let refs = self.advertised_refs()?;
+        super::write_advertisement(writer, &refs, &self.capabilities()?)
+    }
+
+    fn advertised_refs(&self) -> Result<Vec<GitRef>> {
+        let mut advertised = Vec::new();
+        for r in refs::read_refs(self.repo.gitdir())? {
+            let peeled = if r.name.starts_with("refs/tags/") {
+                self.peel_tag(&r.id)?
+            } else {
+                None
+            };
+            let name = r.name.clone();
+            advertised.push(r);
+            if let Some(sha) = peeled {
+                advertised.push(GitRef {
+                    id: sha.hex(),
+                    name: format!("{}^{{}}", name),
+                });
+            }
+        }
+        Ok(advertised)
+    }
+
+    ///
+    /// Follows an annotated tag to the object it points to, returning `None`
+    /// if the ref does not name an annotated tag.
+    ///
+    fn peel_tag(&self, id: &str) -> Result<Option<Sha>> {
+        let mut sha = Sha::from_hex(id.as_bytes())?;
+        let mut peeled = false;
+        loop {
+            let object = self.repo.read_object(&sha)?;
+            match object.as_tag() {
+                Some(tag) => sha = tag.object,
+                None if object.obj_type == ObjectType::Tag => {
+                    return Err(anyhow!("failed to parse tag {}", sha))
+                }
+                None => return Ok(if peeled { Some(sha) } else { None }),
+            }
+            peeled = true;
+        }
+    }
+
+    fn capabilities(&self) -> Result<String> {
+        let mut capabilities = vec![
+            "multi_ack_detailed".to_owned(),
+            "side-band-64k".to_owned(),
+            "side-band".to_owned(),
+            "no-progress".to_owned(),
+        ];
+        if let Some(target) = refs::read_head_target(self.repo.gitdir())? {
+            capabilities.push(format!("symref=HEAD:{}", target));
+        }
+        capabilities.push(capabilities::agent());
+        Ok(capabilities.join(" "))
+    }
+
+    ///
+    /// Reads the client's request and sends the resulting pack, if any.
+    ///
+    /// The client may hang up without wanting anything, which happens when it
+    /// only needed the ref advertisement.
+    ///
+    pub fn serve<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        let (wants, capabilities) = match self.read_wants(reader)? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        for want in &wants {
+            if !self.repo.has_object(want) {
+                let message = format!("upload-pack: not our ref {}", want);
+                writer.write_packet(&Packet::Err(message))?;
+                return Err(anyhow!("client requested unknown object {}", want));
+            }
+        }
+        let common = match self.negotiate(reader, writer, &capabilities)? {
+            Some(common) => common,
+            // The client will follow up with another round.
+            None => return Ok(()),
+        };
+        self.send_pack(writer, &wants, &common, &capabilities)
+    }
+
+    fn read_wants<R: Read>(
+        &self,
+        reader: &mut R,
+    ) -> Result<Option<(Vec<Sha>, ClientCapabilities)>> {
+        let mut wants = Vec::new();
+        let mut capabilities = ClientCapabilities::default();
+        loop {
+            let line = match reader.read_data() {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                // Hanging up instead of sending a flush is also allowed.
+                Err(e) if wants.is_empty() && is_eof(&e) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let text = str::from_utf8(&line)?.trim_end();
+            let mut parts = text.split(' ');
+            match (parts.next(), parts.next()) {
+                (Some("want"), Some(id)) => {
+                    if wants.is_empty() {
+                        capabilities = ClientCapabilities::parse(parts);
+                    }
+                    wants.push(Sha::from_hex(id.as_bytes())?);
+                }
+                (Some("shallow"), _) | (Some("deepen"), _) => {
+                    return Err(anyhow!("shallow clones are not supported"));
+                }
+                _ => return Err(anyhow!("unexpected line from client: {}", text)),
+            }
+        }
+        if wants.is_empty() {
+            return Ok(None);
+        }
+        wants.sort();
+        wants.dedup();
+        Ok(Some((wants, capabilities)))
+    }
+
+    ///
+    /// Reads "have" lines until the client is done, acknowledging each object
+    /// we have in common.
+    ///
+    /// Returns `None` if this was a stateless round that ended without "done".
+    ///
+    fn negotiate<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        capabilities: &ClientCapabilities,
+    ) -> Result<Option<Vec<Sha>>> {
+        let mut common = Vec::new();
+        let mut seen = HashSet::new();
+        loop {
+            let line = match reader.read_data()? {
+                Some(line) => line,
+                None => {
+                    if capabilities.multi_ack_detailed || common.is_empty() {
+                        writer.write_data(b"NAK\n")?;
+                    }
+                    writer.flush()?;
+                    if self.stateless_rpc {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+            };
+            let text = str::from_utf8(&line)?.trim_end();
+            if text == "done" {
+                break;
+            }
+            let id = text
+                .strip_prefix("have ")
+                .ok_or_else(|| anyhow!("unexpected line from client: {}", text))?;
+            let sha = Sha::from_hex(id.as_bytes())?;
+            if !seen.insert(sha) || !self.repo.has_object(&sha) {
+                continue;
+            }
+            if capabilities.multi_ack_detailed {
+                let ack = format!("ACK {} common\n", sha);
+                writer.write_data(ack.as_bytes())?;
+            } else if common.is_empty() {
+                let ack = format!("ACK {}\n", sha);
+                writer.write_data(ack.as_bytes())?;
+            }
+            common.push(sha);
+        }
+
+        match common.last() {
+            Some(last) if capabilities.multi_ack_detailed => {
+                let ack = format!("ACK {}\n", last);
+                writer.write_data(ack.as_bytes())?;
+            }
+            // Without multi_ack the first common object was already acknowledged.
+            Some(_) => {}
+            None => writer.write_data(b"NAK\n")?,
+        }
+        Ok(Some(common))
+    }
+
+    fn send_pack<W: Write>(
+        &self,
+        writer: &mut W,
+        wants: &[Sha],
+        common: &[Sha],
+        capabilities: &ClientCapabilities,
+    ) -> Result<()> {
+        let sideband_len = match capabilities.sideband_len() {
+            Some(len) => len,
+            None => {
+                let objects = self.repo.objects_between(wants, common)?;
+                return self.write_pack(writer, &objects);
+            }
+        };
+        let progress = !capabilities.no_progress;
+        let objects = match self.repo.objects_between(wants, common) {
+            Ok(objects) => objects,
+            Err(e) => {
+                let message = format!("{}\n", e);
+                SidebandWriter::new(writer.by_ref(), SIDEBAND_ERROR, sideband_len)
+                    .write_all(message.as_bytes())?;
+                return Err(e);
+            }
+        };
+        if progress {
+            let message = format!("Enumerating objects: {}, done.\n", objects.len());
+            SidebandWriter::new(writer.by_ref(), SIDEBAND_PROGRESS, sideband_len)
+                .write_all(message.as_bytes())?;
+        }
+        let mut pack_writer = BufWriter::with_capacity(
+            sideband_len,
+            SidebandWriter::new(writer.by_ref(), SIDEBAND_PACK, sideband_len),
+        );
+        self.write_pack(&mut pack_writer, &objects)?;
+        pack_writer.flush()?;
+        drop(pack_writer);
+        if progress {
+            let message = format!("Total {} (delta 0), reused 0 (delta 0)\n", objects.len());
+            SidebandWriter::new(writer.by_ref(), SIDEBAND_PROGRESS, sideband_len)
+                .write_all(message.as_bytes())?;
+        }
+        writer.write_flush()?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn write_pack<W: Write>(&self, writer: &mut W, objects: &[Sha]) -> Result<()> {
+        let mut pack = PackWriter::new(writer, objects.len())?;
+        for sha in objects {
+            pack.write_object(&self.repo.read_object(sha)?)?;
+        }
+        pack.finish()?;
+        Ok(())
+    }
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|cause| cause.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+///
+/// Multiplexes writes onto a single sideband channel, splitting them into
+/// pkt-lines no larger than the negotiated limit.
+///
+struct SidebandWriter<W> {
+    writer: W,
+    band: u8,
+    max_len: usize,
+}
+
+impl<W: Write> SidebandWriter<W> {
+    fn new(writer: W, band: u8, max_len: usize) -> Self {
+        SidebandWriter {
+            writer,
+            band,
+            max_len,
+        }
+    }
+}
+
+impl<W: Write> Write for SidebandWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let len = bytes.len().min(self.max_len);
+        let mut packet = Vec::with_capacity(len + 1);
+        packet.push(self.band);
+        packet.extend_from_slice(&bytes[..len]);
+        self.writer.write_data(&packet)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::packfile::PackFile;
+    use crate::remote;
+
+    static REPO: &str = "tests/data/repos/simple.git";
+    static MASTER: &[u8] = b"33676d1c63d868803ed110b13be4e616bc8a29b7";
+    static MERGE: &[u8] = b"3c7cfac73a699ef415bc737ce5529ac66c5692a9";
+
+    fn request(lines: &[&str]) -> Vec<u8> {
+        let mut request = Vec::new();
+        for line in lines {
+            if line.is_empty() {
+                request.write_flush().unwrap();
+            } else {
+                request.write_data(line.as_bytes()).unwrap();
+            }
+        }
+        request
+    }
+
+    fn read_response(response: &[u8], num_lines: usize) -> (Vec<String>, PackFile) {
+        let mut reader = Cursor::new(response);
+        let mut lines = Vec::new();
+        for _ in 0..num_lines {
+            let line = reader.read_data().unwrap().unwrap();
+            lines.push(String::from_utf8(line).unwrap());
+        }
+        let pack = remote::receive_with_sideband(&mut reader).unwrap();
+        (lines, PackFile::parse(&pack).unwrap())
+    }
+
+    #[test]
+    fn advertising_refs() {
+        let repo = Repo::open(REPO).unwrap();
+        let mut advertisement = Vec::new();
+        UploadPack::new(&repo)
+            .advertise_refs(&mut advertisement)
+            .unwrap();
+
+        let lines = remote::receive(&mut Cursor::new(advertisement)).unwrap();
+        let (capabilities, refs) = remote::parse_lines(&lines).unwrap();
+        assert_eq!(capabilities.symrefs(), [("HEAD", "refs/heads/master")]);
+        let names = refs.iter().map(|r| &r.name[..]).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "HEAD",
+                "refs/heads/master",
+                "refs/heads/test",
+                "refs/tags/test_tag",
+                "refs/tags/test_tag^{}",
+            ]
+        );
+        assert_eq!(refs[4].id.as_bytes(), MERGE);
+    }
+
+    #[test]
+    fn sending_a_full_pack() {
+        let repo = Repo::open(REPO).unwrap();
+        let want = format!("want {} side-band-64k\n", str::from_utf8(MASTER).unwrap());
+        let req = request(&[&want, "", "done\n"]);
+
+        let mut response = Vec::new();
+        UploadPack::new(&repo)
+            .serve(&mut Cursor::new(req), &mut response)
+            .unwrap();
+        let (lines, pack) = read_response(&response, 1);
+        assert_eq!(lines, ["NAK\n"]);
+        // Every object but the annotated tag is reachable from master.
+        assert_eq!(pack.num_objects(), 29);
+    }
+
+    #[test]
+    fn sending_a_pack_excluding_common_objects() {
+        let repo = Repo::open(REPO).unwrap();
+        let want = format!(
+            "want {} multi_ack_detailed side-band-64k\n",
+            str::from_utf8(MASTER).unwrap()
+        );
+        let have = format!("have {}\n", str::from_utf8(MERGE).unwrap());
+        let unknown = "have abcdefabcdefabcdefabcdefabcdefabcdabcdef\n";
+        let req = request(&[&want, "", &have, unknown, "", "done\n"]);
+
+        let mut response = Vec::new();
+        UploadPack::new(&repo)
+            .serve(&mut Cursor::new(req), &mut response)
+            .unwrap();
+        let (lines, pack) = read_response(&response, 3);
+        let merge = str::from_utf8(MERGE).unwrap();
+        assert_eq!(
+            lines,
+            [
+                format!("ACK {} common\n", merge),
+                "NAK\n".to_owned(),
+                format!("ACK {}\n", merge),
+            ]
+        );
+        let expected = repo
+            .objects_between(
+                &[Sha::from_hex(MASTER).unwrap()],
+                &[Sha::from_hex(MERGE).unwrap()],
+            )
+            .unwrap();
+        assert_eq!(pack.num_objects(), expected.len());
+        assert!(!pack.contains(&Sha::from_hex(MERGE).unwrap()));
+        assert!(pack.contains(&Sha::from_hex(MASTER).unwrap()));
+    }
+}
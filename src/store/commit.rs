@@ -33,7 +33,6 @@ pub struct Commit<'a> {
     pub tree: Sha,
     pub parents: Vec<Sha>,
     author: Person<'a>,
-    #[allow(dead_code)]
     committer: Person<'a>,
     message: &'a str,
     sha: Sha,
@@ -47,6 +46,17 @@ impl<'a> Commit<'a> {
             _ => None,
         }
     }
+
+    pub fn message(&self) -> &'a str {
+        self.message
+    }
+
+    ///
+    /// When the commit was made, in seconds since the epoch.
+    ///
+    pub fn commit_time(&self) -> i64 {
+        self.committer.timestamp.timestamp()
+    }
 }
 
 impl<'a> Display for Person<'a> {
@@ -61,20 +71,29 @@ impl<'a> Display for Person<'a> {
 impl<'a> Display for Commit<'a> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
         writeln!(f, "commit {}", self.sha.hex())?;
-        write!(f, "{}", self.author)?;
-        for line in self.message.split('\n') {
-            write!(f, "\n    {}", line)?;
+        if self.parents.len() > 1 {
+            let parents = self
+                .parents
+                .iter()
+                .map(|p| p.hex()[..7].to_owned())
+                .collect::<Vec<_>>();
+            writeln!(f, "Merge: {}", parents.join(" "))?;
         }
-        // It's not clear if this is expected, but some commit
-        // messages can lack a final newline.
-        if !self.message.ends_with('\n') {
-            writeln!(f)?;
+        write!(f, "{}", self.author)?;
+        writeln!(f)?;
+        // Like git, leave blank lines unindented and drop trailing ones.
+        for line in self.message.trim_end().lines() {
+            if line.is_empty() {
+                writeln!(f)?;
+            } else {
+                writeln!(f, "    {}", line)?;
+            }
         }
         Ok(())
     }
 }
 
-fn parse_person<'a, E>(input: &'a [u8]) -> IResult<&'a [u8], Person, E>
+fn parse_person<'a, E>(input: &'a [u8]) -> IResult<&'a [u8], Person<'a>, E>
 where
     E: nom::error::ParseError<&'a [u8]>,
     E: nom::error::FromExternalError<&'a [u8], str::Utf8Error>,
@@ -210,7 +229,7 @@ mod tests {
         );
         assert_eq!(commit.message, "Bump version to 1.6");
 
-        let object2 = PackedObject::new(ObjectType::Commit, (&input2[..]).to_owned());
+        let object2 = PackedObject::new(ObjectType::Commit, input2[..].to_owned());
         assert!(Commit::from_raw(&object2).is_some())
     }
 
@@ -225,7 +244,7 @@ mod tests {
         let sha = Sha::from_bytes(&[0u8; 20][..]).unwrap();
         match parse_commit::<nom::error::VerboseError<_>>(input.as_bytes(), sha) {
             Err(nom::Err::Failure(err)) => {
-                for (ctx, e) in err.errors {
+                if let Some((ctx, e)) = err.errors.into_iter().next() {
                     panic!(
                         "Failed to parse commit: {:?}: {:?}",
                         str::from_utf8(ctx).unwrap(),
@@ -237,7 +256,7 @@ mod tests {
                 panic!("Failed to parse commit: unexpected EOF");
             }
             Err(nom::Err::Error(err)) => {
-                for (ctx, e) in err.errors {
+                if let Some((ctx, e)) = err.errors.into_iter().next() {
                     panic!(
                         "Failed to parse commit: {}: {:?}",
                         str::from_utf8(ctx).unwrap(),
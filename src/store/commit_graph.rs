@@ -0,0 +1,724 @@
+//!
+//! The commit-graph, which holds the root tree, parents, date and generation
+//! number of each commit so that history walks needn't inflate and parse the
+//! commits themselves. See gitformat-commit-graph(5).
+//!
+//! The graph is either a single file, `objects/info/commit-graph`, or a chain
+//! of files in `objects/info/commit-graphs`, each adding the commits its
+//! bases don't have.
+//!
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::fs;
+use std::io::{
+    self,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result,
+};
+use byteorder::{
+    BigEndian,
+    ByteOrder,
+    WriteBytesExt,
+};
+
+use crate::packfile::refs::{
+    self,
+    LockFile,
+};
+use crate::store::{
+    Repo,
+    Sha,
+};
+
+const SIGNATURE: &[u8; 4] = b"CGPH";
+const VERSION: u8 = 1;
+const HASH_VERSION_SHA1: u8 = 1;
+
+const CHUNK_FANOUT: &[u8; 4] = b"OIDF";
+const CHUNK_OID_LOOKUP: &[u8; 4] = b"OIDL";
+const CHUNK_COMMIT_DATA: &[u8; 4] = b"CDAT";
+const CHUNK_EXTRA_EDGES: &[u8; 4] = b"EDGE";
+const CHUNK_BASE_GRAPHS: &[u8; 4] = b"BASE";
+
+const HEADER_LEN: usize = 8;
+/// Each entry in the table of contents is a chunk ID and its offset.
+const CHUNK_ENTRY_LEN: usize = 12;
+const COMMIT_DATA_LEN: usize = 36;
+
+/// A parent position meaning there's no such parent.
+const PARENT_NONE: u32 = 0x7000_0000;
+/// Set on the second parent when the rest are listed in the extra edges.
+const PARENT_EXTRA_EDGES: u32 = 0x8000_0000;
+/// Set on the last parent of an octopus merge in the extra edges.
+const LAST_EDGE: u32 = 0x8000_0000;
+
+/// The largest generation number the file has room for.
+const MAX_GENERATION: u32 = (1 << 30) - 1;
+/// The generation of a commit outside the graph, which could be anything.
+pub const GENERATION_INFINITY: u32 = u32::MAX;
+
+///
+/// What a history walk needs to know about a commit.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitInfo {
+    pub tree: Sha,
+    pub parents: Vec<Sha>,
+    /// The committer date, in seconds since the epoch.
+    pub time: i64,
+    /// One more than the largest generation among the parents, starting
+    /// from 1 for root commits. Every ancestor of a commit has a smaller
+    /// generation than it does.
+    pub generation: u32,
+}
+
+///
+/// One file of the commit-graph.
+///
+struct GraphLayer {
+    checksum: Sha,
+    fanout: [u32; 256],
+    oids: Vec<Sha>,
+    commit_data: Vec<u8>,
+    extra_edges: Vec<u32>,
+}
+
+impl GraphLayer {
+    fn parse(contents: &[u8], num_bases: usize) -> Result<Self> {
+        if contents.len() < HEADER_LEN + 20 {
+            return Err(anyhow!("commit-graph is truncated"));
+        }
+        let (body, trailer) = contents.split_at(contents.len() - 20);
+        let checksum = Sha::from_bytes(trailer)?;
+        if Sha::compute_from_bytes(body) != checksum {
+            return Err(anyhow!("commit-graph checksum mismatch"));
+        }
+        if &body[..4] != SIGNATURE {
+            return Err(anyhow!("not a commit-graph"));
+        }
+        if body[4] != VERSION {
+            return Err(anyhow!("unsupported commit-graph version: {}", body[4]));
+        }
+        if body[5] != HASH_VERSION_SHA1 {
+            return Err(anyhow!(
+                "unsupported commit-graph hash version: {}",
+                body[5]
+            ));
+        }
+        if body[7] as usize != num_bases {
+            return Err(anyhow!(
+                "commit-graph has {} bases but is layer {} of its chain",
+                body[7],
+                num_bases
+            ));
+        }
+
+        let chunks = read_chunks(body, HEADER_LEN, body[6] as usize)?;
+        let chunk = |id: &[u8; 4]| chunks.get(id).copied();
+        let required = |id: &[u8; 4]| {
+            chunk(id).ok_or_else(|| {
+                anyhow!(
+                    "commit-graph is missing the {} chunk",
+                    String::from_utf8_lossy(id)
+                )
+            })
+        };
+
+        let fanout_chunk = required(CHUNK_FANOUT)?;
+        if fanout_chunk.len() != 256 * 4 {
+            return Err(anyhow!("commit-graph fanout has the wrong size"));
+        }
+        let mut fanout = [0; 256];
+        BigEndian::read_u32_into(fanout_chunk, &mut fanout);
+        let num_commits = fanout[255] as usize;
+
+        let oid_chunk = required(CHUNK_OID_LOOKUP)?;
+        let commit_data = required(CHUNK_COMMIT_DATA)?;
+        if oid_chunk.len() != num_commits * 20 || commit_data.len() != num_commits * COMMIT_DATA_LEN
+        {
+            return Err(anyhow!("commit-graph chunks don't match its fanout"));
+        }
+        let oids = oid_chunk
+            .chunks(20)
+            .map(Sha::from_bytes)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let extra_edges = chunk(CHUNK_EXTRA_EDGES)
+            .map(|edges| edges.chunks_exact(4).map(BigEndian::read_u32).collect())
+            .unwrap_or_default();
+        Ok(GraphLayer {
+            checksum,
+            fanout,
+            oids,
+            commit_data: commit_data.to_vec(),
+            extra_edges,
+        })
+    }
+
+    fn find(&self, sha: &Sha) -> Option<usize> {
+        let first = sha.as_bytes()[0] as usize;
+        let start = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        let end = self.fanout[first] as usize;
+        self.oids
+            .get(start..end)?
+            .binary_search(sha)
+            .ok()
+            .map(|i| start + i)
+    }
+}
+
+///
+/// Splits the chunks of a file up by their IDs, using the table of contents
+/// following its header. The commit-graph and multi-pack-index share this
+/// layout.
+///
+pub(super) fn read_chunks(
+    body: &[u8],
+    header_len: usize,
+    num_chunks: usize,
+) -> Result<HashMap<[u8; 4], &[u8]>> {
+    let table_end = header_len + (num_chunks + 1) * CHUNK_ENTRY_LEN;
+    let table = body
+        .get(header_len..table_end)
+        .ok_or_else(|| anyhow!("chunk table is truncated"))?;
+    let entries = table
+        .chunks(CHUNK_ENTRY_LEN)
+        .map(|entry| {
+            let mut id = [0; 4];
+            id.copy_from_slice(&entry[..4]);
+            (id, BigEndian::read_u64(&entry[4..]) as usize)
+        })
+        .collect::<Vec<_>>();
+
+    let mut chunks = HashMap::new();
+    for pair in entries.windows(2) {
+        let ((id, start), (_, end)) = (pair[0], pair[1]);
+        let contents = body
+            .get(start..end)
+            .filter(|_| start >= table_end)
+            .ok_or_else(|| anyhow!("chunk offsets are out of bounds"))?;
+        chunks.insert(id, contents);
+    }
+    Ok(chunks)
+}
+
+///
+/// Appends the table of contents and then the chunks themselves to a file
+/// holding just its header, and finishes it with its checksum.
+///
+pub(super) fn write_chunks(contents: &mut Vec<u8>, chunks: &[(&[u8; 4], Vec<u8>)]) -> Result<()> {
+    let mut offset = (contents.len() + (chunks.len() + 1) * CHUNK_ENTRY_LEN) as u64;
+    for (id, chunk) in chunks {
+        contents.extend_from_slice(*id);
+        contents.write_u64::<BigEndian>(offset)?;
+        offset += chunk.len() as u64;
+    }
+    contents.extend_from_slice(&[0; 4]);
+    contents.write_u64::<BigEndian>(offset)?;
+    for (_, chunk) in chunks {
+        contents.extend_from_slice(chunk);
+    }
+    let checksum = Sha::compute_from_bytes(contents);
+    contents.extend_from_slice(checksum.as_bytes());
+    Ok(())
+}
+
+///
+/// The commit-graph of a repository, whether a single file or a chain.
+///
+pub struct CommitGraph {
+    // The base of a chain comes first. Positions within the graph count
+    // through each layer in turn.
+    layers: Vec<GraphLayer>,
+}
+
+impl CommitGraph {
+    ///
+    /// Loads the commit-graph from an objects directory, preferring a
+    /// single file over a chain as git does. Returns `None` if there is
+    /// neither.
+    ///
+    pub fn open<P: AsRef<Path>>(objects_dir: P) -> Result<Option<Self>> {
+        let info_dir = objects_dir.as_ref().join("info");
+        if let Some(contents) = read_if_exists(&info_dir.join("commit-graph"))? {
+            let layer = GraphLayer::parse(&contents, 0).context("read commit-graph")?;
+            return Ok(Some(CommitGraph {
+                layers: vec![layer],
+            }));
+        }
+        let chain = match read_if_exists(&chain_path(&info_dir))? {
+            Some(chain) => String::from_utf8(chain)?,
+            None => return Ok(None),
+        };
+        let mut layers = Vec::new();
+        for hash in chain.lines().filter(|line| !line.is_empty()) {
+            let path = layer_path(&info_dir, hash);
+            let contents = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+            let layer = GraphLayer::parse(&contents, layers.len())
+                .with_context(|| format!("read {}", path.display()))?;
+            if layer.checksum.hex() != hash {
+                return Err(anyhow!("{} doesn't match its checksum", path.display()));
+            }
+            layers.push(layer);
+        }
+        Ok(Some(CommitGraph { layers }))
+    }
+
+    ///
+    /// The number of commits in the graph.
+    ///
+    pub fn len(&self) -> usize {
+        self.layers.iter().map(|layer| layer.oids.len()).sum()
+    }
+
+    fn find(&self, sha: &Sha) -> Option<u32> {
+        let mut base = 0;
+        for layer in &self.layers {
+            if let Some(i) = layer.find(sha) {
+                return Some((base + i) as u32);
+            }
+            base += layer.oids.len();
+        }
+        None
+    }
+
+    ///
+    /// Finds the layer holding a position, and the commit's index in it.
+    ///
+    fn layer_at(&self, position: u32) -> Result<(&GraphLayer, usize)> {
+        let mut i = position as usize;
+        for layer in &self.layers {
+            if i < layer.oids.len() {
+                return Ok((layer, i));
+            }
+            i -= layer.oids.len();
+        }
+        Err(anyhow!(
+            "commit-graph position {} is out of bounds",
+            position
+        ))
+    }
+
+    fn oid_at(&self, position: u32) -> Result<Sha> {
+        let (layer, i) = self.layer_at(position)?;
+        Ok(layer.oids[i])
+    }
+
+    ///
+    /// Looks a commit up, or `None` if it isn't in the graph.
+    ///
+    pub fn lookup(&self, sha: &Sha) -> Result<Option<CommitInfo>> {
+        let position = match self.find(sha) {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+        let (layer, i) = self.layer_at(position)?;
+        let data = &layer.commit_data[i * COMMIT_DATA_LEN..(i + 1) * COMMIT_DATA_LEN];
+        let tree = Sha::from_bytes(&data[..20])?;
+
+        let mut parents = Vec::new();
+        let first = BigEndian::read_u32(&data[20..24]);
+        let second = BigEndian::read_u32(&data[24..28]);
+        if first != PARENT_NONE {
+            parents.push(self.oid_at(first)?);
+        }
+        if second & PARENT_EXTRA_EDGES != 0 {
+            let mut edge = (second & !PARENT_EXTRA_EDGES) as usize;
+            loop {
+                let position = *layer
+                    .extra_edges
+                    .get(edge)
+                    .ok_or_else(|| anyhow!("commit-graph extra edge {} is missing", edge))?;
+                parents.push(self.oid_at(position & !LAST_EDGE)?);
+                if position & LAST_EDGE != 0 {
+                    break;
+                }
+                edge += 1;
+            }
+        } else if second != PARENT_NONE {
+            parents.push(self.oid_at(second)?);
+        }
+
+        // The generation takes the top 30 bits, leaving 34 for the date.
+        let high = BigEndian::read_u32(&data[28..32]);
+        let low = BigEndian::read_u32(&data[32..36]);
+        Ok(Some(CommitInfo {
+            tree,
+            parents,
+            time: (((high & 0x3) as i64) << 32) | low as i64,
+            generation: high >> 2,
+        }))
+    }
+}
+
+pub(super) fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn chain_path(info_dir: &Path) -> PathBuf {
+    info_dir.join("commit-graphs/commit-graph-chain")
+}
+
+fn layer_path(info_dir: &Path, hash: &str) -> PathBuf {
+    info_dir.join(format!("commit-graphs/graph-{}.graph", hash))
+}
+
+impl Repo {
+    ///
+    /// Looks a commit up in the commit-graph, only reading the commit itself
+    /// when it isn't there. Commits outside the graph have a generation of
+    /// `GENERATION_INFINITY`.
+    ///
+    pub fn commit_info(&self, sha: &Sha) -> Result<CommitInfo> {
+        if let Some(graph) = &self.commit_graph {
+            if let Some(info) = graph.lookup(sha)? {
+                return Ok(info);
+            }
+        }
+        let object = self.read_object(sha)?;
+        let commit = object
+            .as_commit()
+            .ok_or_else(|| anyhow!("{} is not a commit", sha))?;
+        Ok(CommitInfo {
+            tree: commit.tree,
+            parents: commit.parents.clone(),
+            time: commit.commit_time(),
+            generation: GENERATION_INFINITY,
+        })
+    }
+
+    ///
+    /// Writes a commit-graph holding every commit reachable from the refs.
+    /// With `split`, the commits which aren't already in the graph are
+    /// written as a new layer on top of the existing chain instead.
+    ///
+    pub fn write_commit_graph(&self, split: bool) -> Result<()> {
+        let info_dir = self.gitdir().join("objects/info");
+        fs::create_dir_all(&info_dir)?;
+
+        let base = match &self.commit_graph {
+            Some(graph) if split && fs::metadata(chain_path(&info_dir)).is_ok() => Some(graph),
+            _ => None,
+        };
+        let mut tips = Vec::new();
+        for r in refs::read_refs(self.gitdir())? {
+            let sha = Sha::from_hex(r.id.as_bytes())?;
+            if let Ok(commit) = self.peel_to(sha, "commit") {
+                tips.push(commit);
+            }
+        }
+        let commits = self.graph_commits(&tips, base)?;
+        if commits.is_empty() && base.is_some() {
+            return Ok(());
+        }
+        let contents = encode_layer(&commits, base)?;
+        let checksum = Sha::from_bytes(&contents[contents.len() - 20..])?;
+
+        if !split {
+            let mut lock = LockFile::acquire(info_dir.join("commit-graph"))?;
+            lock.write_all(&contents)?;
+            lock.commit()?;
+            return remove_chain(&info_dir);
+        }
+
+        // Each layer is named after its checksum, so it's safe to write
+        // before the chain refers to it.
+        fs::create_dir_all(info_dir.join("commit-graphs"))?;
+        let mut lock = LockFile::acquire(layer_path(&info_dir, &checksum.hex()))?;
+        lock.write_all(&contents)?;
+        lock.commit()?;
+
+        let mut chain = LockFile::acquire(chain_path(&info_dir))?;
+        for layer in base.iter().flat_map(|graph| &graph.layers) {
+            writeln!(chain, "{}", layer.checksum)?;
+        }
+        writeln!(chain, "{}", checksum)?;
+        chain.commit()?;
+        // A single file would be read in place of the chain.
+        match fs::remove_file(info_dir.join("commit-graph")) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    ///
+    /// Finds the commits reachable from `tips` which aren't in `base`,
+    /// along with their generation numbers, sorted by SHA.
+    ///
+    fn graph_commits(
+        &self,
+        tips: &[Sha],
+        base: Option<&CommitGraph>,
+    ) -> Result<Vec<(Sha, CommitInfo)>> {
+        let in_base = |sha: &Sha| base.is_some_and(|graph| graph.find(sha).is_some());
+        let mut infos = HashMap::new();
+        let mut pending = tips
+            .iter()
+            .filter(|sha| !in_base(sha))
+            .copied()
+            .collect::<Vec<_>>();
+        while let Some(sha) = pending.pop() {
+            if infos.contains_key(&sha) {
+                continue;
+            }
+            let info = self.commit_info(&sha)?;
+            pending.extend(info.parents.iter().filter(|p| !in_base(p)));
+            infos.insert(sha, info);
+        }
+
+        // Parents need their generations before their children, so go
+        // depth first, finishing each commit after its parents.
+        let mut generations = HashMap::new();
+        let mut done = HashSet::new();
+        for sha in infos.keys() {
+            let mut stack = vec![(*sha, false)];
+            while let Some((sha, parents_done)) = stack.pop() {
+                if done.contains(&sha) {
+                    continue;
+                }
+                let info = &infos[&sha];
+                if !parents_done {
+                    stack.push((sha, true));
+                    stack.extend(
+                        info.parents
+                            .iter()
+                            .filter(|p| infos.contains_key(*p) && !done.contains(*p))
+                            .map(|p| (*p, false)),
+                    );
+                    continue;
+                }
+                let mut generation = 0;
+                for parent in &info.parents {
+                    let parent_generation = match generations.get(parent) {
+                        Some(generation) => *generation,
+                        None => base
+                            .map(|graph| graph.lookup(parent))
+                            .transpose()?
+                            .flatten()
+                            .map(|info| info.generation)
+                            .ok_or_else(|| anyhow!("parent {} of {} is missing", parent, sha))?,
+                    };
+                    generation = generation.max(parent_generation);
+                }
+                generations.insert(sha, (generation + 1).min(MAX_GENERATION));
+                done.insert(sha);
+            }
+        }
+
+        let mut commits = infos
+            .into_iter()
+            .map(|(sha, mut info)| {
+                info.generation = generations[&sha];
+                (sha, info)
+            })
+            .collect::<Vec<_>>();
+        commits.sort_by_key(|(sha, _)| *sha);
+        Ok(commits)
+    }
+}
+
+fn remove_chain(info_dir: &Path) -> Result<()> {
+    match fs::remove_dir_all(info_dir.join("commit-graphs")) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+///
+/// Encodes a commit-graph file holding `commits`, which must be sorted.
+/// With a base, the file is the next layer of its chain and parents may be
+/// in any layer below.
+///
+fn encode_layer(commits: &[(Sha, CommitInfo)], base: Option<&CommitGraph>) -> Result<Vec<u8>> {
+    let base_len = base.map_or(0, |graph| graph.len());
+    let position = |sha: &Sha| -> Result<u32> {
+        match commits.binary_search_by_key(sha, |(sha, _)| *sha) {
+            Ok(i) => Ok((base_len + i) as u32),
+            Err(_) => base
+                .and_then(|graph| graph.find(sha))
+                .ok_or_else(|| anyhow!("{} is missing from the commit-graph", sha)),
+        }
+    };
+
+    let mut fanout = Vec::with_capacity(256 * 4);
+    for byte in 0..=255u8 {
+        let count = commits.partition_point(|(sha, _)| sha.as_bytes()[0] <= byte);
+        fanout.write_u32::<BigEndian>(count as u32)?;
+    }
+    let mut oids = Vec::with_capacity(commits.len() * 20);
+    for (sha, _) in commits {
+        oids.extend_from_slice(sha.as_bytes());
+    }
+
+    let mut commit_data = Vec::with_capacity(commits.len() * COMMIT_DATA_LEN);
+    let mut extra_edges = Vec::new();
+    for (_, info) in commits {
+        commit_data.extend_from_slice(info.tree.as_bytes());
+        let parents = info
+            .parents
+            .iter()
+            .map(position)
+            .collect::<Result<Vec<_>>>()?;
+        let first = parents.first().copied().unwrap_or(PARENT_NONE);
+        let second = match parents.len() {
+            0 | 1 => PARENT_NONE,
+            2 => parents[1],
+            _ => {
+                let start = extra_edges.len() as u32;
+                extra_edges.extend_from_slice(&parents[1..]);
+                *extra_edges.last_mut().unwrap() |= LAST_EDGE;
+                start | PARENT_EXTRA_EDGES
+            }
+        };
+        commit_data.write_u32::<BigEndian>(first)?;
+        commit_data.write_u32::<BigEndian>(second)?;
+        let time = info.time.clamp(0, (1 << 34) - 1) as u64;
+        commit_data.write_u32::<BigEndian>((info.generation << 2) | (time >> 32) as u32)?;
+        commit_data.write_u32::<BigEndian>(time as u32)?;
+    }
+
+    let mut chunks: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (CHUNK_FANOUT, fanout),
+        (CHUNK_OID_LOOKUP, oids),
+        (CHUNK_COMMIT_DATA, commit_data),
+    ];
+    if !extra_edges.is_empty() {
+        let mut edges = Vec::with_capacity(extra_edges.len() * 4);
+        for edge in extra_edges {
+            edges.write_u32::<BigEndian>(edge)?;
+        }
+        chunks.push((CHUNK_EXTRA_EDGES, edges));
+    }
+    let num_bases = base.map_or(0, |graph| graph.layers.len());
+    if let Some(graph) = base {
+        let hashes = graph
+            .layers
+            .iter()
+            .flat_map(|layer| layer.checksum.as_bytes().to_vec())
+            .collect();
+        chunks.push((CHUNK_BASE_GRAPHS, hashes));
+    }
+
+    // The header only has a byte for each count.
+    let num_chunks =
+        u8::try_from(chunks.len()).map_err(|_| anyhow!("too many commit-graph chunks"))?;
+    let num_bases = u8::try_from(num_bases)
+        .map_err(|_| anyhow!("commit-graph chain is too long; write it without --split"))?;
+
+    let mut contents = Vec::new();
+    contents.extend_from_slice(SIGNATURE);
+    contents.extend_from_slice(&[VERSION, HASH_VERSION_SHA1, num_chunks, num_bases]);
+    write_chunks(&mut contents, &chunks)?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::RevWalk;
+    use crate::test_support::copy_repo;
+
+    ///
+    /// Checks the graph agrees with the commits themselves about every
+    /// commit it holds.
+    ///
+    fn check_graph(repo: &Repo, expected_len: usize) {
+        let graph = CommitGraph::open(repo.gitdir().join("objects"))
+            .unwrap()
+            .expect("graph was written");
+        assert_eq!(graph.len(), expected_len);
+        let mut walk = RevWalk::new(repo);
+        walk.push(repo.rev_parse("HEAD").unwrap()).unwrap();
+        for sha in walk {
+            let sha = sha.unwrap();
+            let info = graph.lookup(&sha).unwrap().expect("commit is in the graph");
+            let object = repo.read_object(&sha).unwrap();
+            let commit = object.as_commit().unwrap();
+            assert_eq!(info.tree, commit.tree);
+            assert_eq!(info.parents, commit.parents);
+            assert_eq!(info.time, commit.commit_time());
+        }
+    }
+
+    #[test]
+    fn writing_a_commit_graph() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        Repo::open(&path)
+            .unwrap()
+            .write_commit_graph(false)
+            .unwrap();
+
+        let repo = Repo::open(&path).unwrap();
+        check_graph(&repo, 8);
+        let generation = |rev: &str| {
+            let sha = repo.rev_parse(rev).unwrap();
+            repo.commit_info(&sha).unwrap().generation
+        };
+        assert_eq!(generation("HEAD"), 7);
+        assert_eq!(generation("test"), 2);
+        assert_eq!(generation("test~1"), 1);
+    }
+
+    #[test]
+    fn writing_a_split_commit_graph() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        // Start with a layer for the test branch alone.
+        let master = fs::read_to_string(path.join("refs/heads/master")).unwrap();
+        fs::remove_file(path.join("refs/heads/master")).unwrap();
+        fs::write(path.join("HEAD"), "ref: refs/heads/test\n").unwrap();
+        Repo::open(&path).unwrap().write_commit_graph(true).unwrap();
+        fs::write(path.join("refs/heads/master"), master).unwrap();
+        fs::write(path.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+        Repo::open(&path).unwrap().write_commit_graph(true).unwrap();
+
+        let chain = fs::read_to_string(chain_path(&path.join("objects/info"))).unwrap();
+        assert_eq!(chain.lines().count(), 2);
+        let repo = Repo::open(&path).unwrap();
+        check_graph(&repo, 8);
+        let head = repo.rev_parse("HEAD").unwrap();
+        assert_eq!(repo.commit_info(&head).unwrap().generation, 7);
+    }
+
+    #[test]
+    fn ignoring_a_corrupt_commit_graph() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        Repo::open(&path)
+            .unwrap()
+            .write_commit_graph(false)
+            .unwrap();
+        let graph_path = path.join("objects/info/commit-graph");
+        let mut contents = fs::read(&graph_path).unwrap();
+        contents[20] ^= 0xff;
+        fs::write(&graph_path, contents).unwrap();
+
+        let repo = Repo::open(&path).unwrap();
+        assert!(repo.commit_graph.is_none());
+        let head = repo.rev_parse("HEAD").unwrap();
+        assert_eq!(
+            repo.commit_info(&head).unwrap().generation,
+            GENERATION_INFINITY
+        );
+    }
+}
@@ -0,0 +1,433 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::str::Chars;
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result,
+};
+
+///
+/// Configuration read from git's config files.
+///
+/// Files are read in order of increasing precedence (system, global, then the
+/// repository's own), so for single-valued keys the last value wins.
+///
+/// Keys are written `section.name` or `section.subsection.name`. Section and
+/// variable names are case-insensitive, while subsections are not.
+///
+#[derive(Debug, Default)]
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
+
+impl Config {
+    ///
+    /// Loads the system and global configuration, along with that of the
+    /// repository at `gitdir` if given.
+    ///
+    pub fn load(gitdir: Option<&Path>) -> Result<Self> {
+        let mut config = Config::default();
+        let mut paths = Vec::new();
+        if env::var_os("GIT_CONFIG_NOSYSTEM").is_none() {
+            paths.push(
+                env::var_os("GIT_CONFIG_SYSTEM")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| PathBuf::from("/etc/gitconfig")),
+            );
+        }
+        match env::var_os("GIT_CONFIG_GLOBAL") {
+            Some(global) => paths.push(PathBuf::from(global)),
+            None => {
+                let home = env::var_os("HOME").map(PathBuf::from);
+                let xdg = env::var_os("XDG_CONFIG_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| home.as_ref().map(|h| h.join(".config")));
+                paths.extend(xdg.map(|x| x.join("git/config")));
+                paths.extend(home.map(|h| h.join(".gitconfig")));
+            }
+        }
+        paths.extend(gitdir.map(|g| g.join("config")));
+
+        for path in paths {
+            match fs::read_to_string(&path) {
+                Ok(contents) => {
+                    let parsed =
+                        Config::parse(&contents).with_context(|| format!("{}", path.display()))?;
+                    config.entries.extend(parsed.entries);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("{}", path.display())),
+            }
+        }
+        Ok(config)
+    }
+
+    ///
+    /// Parses the contents of a single config file.
+    ///
+    pub fn parse(contents: &str) -> Result<Self> {
+        Parser {
+            chars: contents.chars().peekable(),
+            line: 1,
+        }
+        .parse()
+    }
+
+    ///
+    /// Returns the last value set for `key`.
+    ///
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_all(key).last()
+    }
+
+    ///
+    /// Returns every value set for `key`, in the order they were read.
+    ///
+    pub fn get_all<'a>(&'a self, key: &str) -> impl Iterator<Item = &'a str> + 'a {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .filter(move |(k, _)| *k == key)
+            .map(|(_, v)| &v[..])
+    }
+
+    ///
+    /// Returns the value of `key` interpreted as a boolean.
+    ///
+    #[allow(dead_code)]
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>> {
+        self.get(key)
+            .map(|value| match &value.to_ascii_lowercase()[..] {
+                "true" | "yes" | "on" | "1" => Ok(true),
+                "false" | "no" | "off" | "0" | "" => Ok(false),
+                _ => Err(anyhow!(
+                    "bad boolean config value '{}' for '{}'",
+                    value,
+                    key
+                )),
+            })
+            .transpose()
+    }
+
+    ///
+    /// Adds a value for `key`, after any it already has.
+    ///
+    pub fn add(&mut self, key: &str, value: &str) {
+        self.entries.push((normalize_key(key), value.to_owned()));
+    }
+
+    ///
+    /// Writes the configuration to `path` in git's config file format,
+    /// replacing whatever was there.
+    ///
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string()).with_context(|| format!("{}", path.display()))
+    }
+}
+
+///
+/// Formats the entries as a config file, starting a new section header
+/// whenever the section changes from one entry to the next.
+///
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut current = None;
+        for (key, value) in &self.entries {
+            let (section, name) = key.rsplit_once('.').unwrap_or(("", key));
+            if current != Some(section) {
+                match section.split_once('.') {
+                    Some((section, subsection)) => writeln!(
+                        f,
+                        "[{} \"{}\"]",
+                        section,
+                        subsection.replace('\\', "\\\\").replace('"', "\\\"")
+                    )?,
+                    None => writeln!(f, "[{}]", section)?,
+                }
+                current = Some(section);
+            }
+            writeln!(f, "\t{} = {}", name, quote_value(value))?;
+        }
+        Ok(())
+    }
+}
+
+// Escapes a value so that it reads back unchanged, quoting it if it has
+// whitespace at either end or characters which would start a comment.
+fn quote_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    let needs_quotes = value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace)
+        || value.contains(['#', ';']);
+    if needs_quotes {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+// Lowercases the section and variable name, leaving any subsection as is.
+fn normalize_key(key: &str) -> String {
+    match (key.find('.'), key.rfind('.')) {
+        (Some(first), Some(last)) => format!(
+            "{}{}{}",
+            key[..first].to_ascii_lowercase(),
+            &key[first..last],
+            key[last..].to_ascii_lowercase()
+        ),
+        _ => key.to_ascii_lowercase(),
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn parse(mut self) -> Result<Config> {
+        let mut config = Config::default();
+        let mut section = None;
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                None => return Ok(config),
+                Some('\n') => {
+                    self.next();
+                }
+                Some('#') | Some(';') => self.skip_line(),
+                Some('[') => {
+                    self.next();
+                    section = Some(self.parse_section()?);
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    let section = section
+                        .as_ref()
+                        .ok_or_else(|| self.error("variable outside of a section"))?;
+                    let (name, value) = self.parse_variable()?;
+                    config
+                        .entries
+                        .push((format!("{}.{}", section, name), value));
+                }
+                Some(_) => return Err(self.error("unexpected character")),
+            }
+        }
+    }
+
+    // -- [section] or [section "subsection"]
+    fn parse_section(&mut self) -> Result<String> {
+        let mut name = String::new();
+        loop {
+            match self.next() {
+                Some(']') => return Ok(name.to_ascii_lowercase()),
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '.' => name.push(c),
+                Some(' ') | Some('\t') => break,
+                _ => return Err(self.error("bad section header")),
+            }
+        }
+        self.skip_whitespace();
+        if self.next() != Some('"') {
+            return Err(self.error("bad section header"));
+        }
+        let mut subsection = String::new();
+        loop {
+            match self.next() {
+                Some('"') => break,
+                Some('\\') => match self.next() {
+                    Some('\n') | None => return Err(self.error("bad section header")),
+                    Some(c) => subsection.push(c),
+                },
+                Some('\n') | None => return Err(self.error("bad section header")),
+                Some(c) => subsection.push(c),
+            }
+        }
+        if self.next() != Some(']') {
+            return Err(self.error("bad section header"));
+        }
+        Ok(format!("{}.{}", name.to_ascii_lowercase(), subsection))
+    }
+
+    // -- name = value
+    // -- name
+    fn parse_variable(&mut self) -> Result<(String, String)> {
+        let mut name = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_ascii_alphanumeric() && c != '-' {
+                break;
+            }
+            name.push(c.to_ascii_lowercase());
+            self.next();
+        }
+        self.skip_whitespace();
+        match self.chars.peek() {
+            // A variable without a value is shorthand for true.
+            None | Some('\n') | Some('#') | Some(';') => {
+                self.skip_line();
+                return Ok((name, "true".into()));
+            }
+            Some('=') => {
+                self.next();
+            }
+            Some(_) => return Err(self.error("expected '=' after variable name")),
+        }
+        self.skip_whitespace();
+        Ok((name, self.parse_value()?))
+    }
+
+    fn parse_value(&mut self) -> Result<String> {
+        let mut value = String::new();
+        // Whitespace is only kept if something other than a comment follows.
+        let mut pending_whitespace = String::new();
+        let mut quoted = false;
+        loop {
+            let c = match self.next() {
+                None | Some('\n') if !quoted => return Ok(value),
+                None | Some('\n') => return Err(self.error("unterminated quote")),
+                Some(c) => c,
+            };
+            match c {
+                '#' | ';' if !quoted => {
+                    self.skip_line();
+                    return Ok(value);
+                }
+                ' ' | '\t' | '\r' if !quoted => {
+                    pending_whitespace.push(c);
+                    continue;
+                }
+                _ => {}
+            }
+            value.push_str(&pending_whitespace);
+            pending_whitespace.clear();
+            match c {
+                '"' => quoted = !quoted,
+                '\\' => match self.next() {
+                    // A trailing backslash continues the value on the next line.
+                    Some('\n') => {}
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => {
+                        value.pop();
+                    }
+                    Some(c @ '\\') | Some(c @ '"') => value.push(c),
+                    _ => return Err(self.error("bad escape sequence")),
+                },
+                c => value.push(c),
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ') | Some('\t') | Some('\r') = self.chars.peek() {
+            self.next();
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.next() {
+            if c == '\n' {
+                break;
+            }
+        }
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        anyhow!("bad config line {}: {}", self.line, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_a_config_file() {
+        let config = Config::parse(
+            "# comment\n\
+             [core]\n\
+             \tbare = false\n\
+             \tsshCommand = ssh -i \"/path/with space\" ; comment\n\
+             [remote \"Origin\"]\n\
+             \turl = https://example.com/repo.git\n\
+             \tfetch = +refs/heads/*:refs/remotes/Origin/*\n\
+             \tfetch = +refs/tags/*:refs/tags/*\n\
+             [http]\n\
+             \tsslVerify\n\
+             \tproxy = \"http://a\\\\b\" \\\n\
+               continued\n",
+        )
+        .unwrap();
+        assert_eq!(config.get("core.bare"), Some("false"));
+        assert_eq!(config.get_bool("core.bare").unwrap(), Some(false));
+        assert_eq!(
+            config.get("CORE.SSHCOMMAND"),
+            Some("ssh -i /path/with space")
+        );
+        assert_eq!(
+            config.get("remote.Origin.url"),
+            Some("https://example.com/repo.git")
+        );
+        assert_eq!(config.get("remote.origin.url"), None);
+        assert_eq!(config.get_all("remote.Origin.fetch").count(), 2);
+        assert_eq!(config.get_bool("http.sslverify").unwrap(), Some(true));
+        assert_eq!(config.get("http.proxy"), Some("http://a\\b continued"));
+    }
+
+    #[test]
+    fn writing_a_config_file() {
+        let mut config = Config::default();
+        config.add("core.bare", "false");
+        config.add("remote.my \"origin\".url", "/path/with space; and more");
+        config.add(
+            "remote.my \"origin\".fetch",
+            "+refs/heads/*:refs/remotes/origin/*",
+        );
+        config.add("branch.master.merge", " refs/heads/master\\");
+        assert_eq!(
+            config.to_string(),
+            "[core]\n\
+             \tbare = false\n\
+             [remote \"my \\\"origin\\\"\"]\n\
+             \turl = \"/path/with space; and more\"\n\
+             \tfetch = +refs/heads/*:refs/remotes/origin/*\n\
+             [branch \"master\"]\n\
+             \tmerge = \" refs/heads/master\\\\\"\n"
+        );
+
+        let parsed = Config::parse(&config.to_string()).unwrap();
+        assert_eq!(parsed.entries, config.entries, "should round trip");
+    }
+
+    #[test]
+    fn rejecting_malformed_config() {
+        assert!(Config::parse("key = value\n").is_err());
+        assert!(Config::parse("[core\n").is_err());
+        assert!(Config::parse("[core]\nkey = \"unterminated\n").is_err());
+    }
+}
@@ -1,7 +1,16 @@
 mod commit;
+mod commit_graph;
+mod config;
+mod multi_pack_index;
 mod object;
+mod reachability;
+mod repack;
+mod revision;
+mod revwalk;
+mod tag;
 mod tree;
 
+use std::collections::HashSet;
 use std::env;
 use std::ffi::OsStr;
 use std::fs::{
@@ -14,6 +23,7 @@ use std::io::{
     Read,
     Write,
 };
+use std::iter;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::fs::PermissionsExt;
@@ -31,14 +41,28 @@ use byteorder::{
 };
 
 use self::commit::Commit;
+use self::commit_graph::CommitGraph;
+use self::multi_pack_index::MultiPackIndex;
 use self::tree::{
-    EntryMode,
     Tree,
     TreeEntry,
 };
-use crate::packfile::PackFile;
+use crate::packfile::refs::PackedRefs;
+use crate::packfile::{
+    PackBitmap,
+    PackFile,
+};
+use crate::progress::{
+    self,
+    Progress,
+};
+pub use crate::store::commit_graph::CommitInfo;
+pub use crate::store::config::Config;
 pub use crate::store::object::ObjectType;
 pub use crate::store::object::PackedObject;
+pub use crate::store::revision::RevArg;
+pub use crate::store::revwalk::RevWalk;
+pub use crate::store::tree::EntryMode;
 
 #[derive(Debug, Clone, Copy, Hash, PartialOrd, Ord, PartialEq, Eq)]
 pub struct Sha {
@@ -123,7 +147,16 @@ impl std::fmt::Display for Sha {
 pub struct Repo {
     dir: PathBuf,
     gitdir: PathBuf,
-    pack: Option<PackFile>,
+    // Other objects directories listed in objects/info/alternates, whose
+    // objects are also available to this repository.
+    alternates: Vec<PathBuf>,
+    packs: Vec<PackFile>,
+    // The multi-pack-index of our own packs, with where each pack it covers
+    // is in `packs`.
+    midx: Option<(MultiPackIndex, Vec<usize>)>,
+    // The bitmaps of one of our packs, if any have them.
+    bitmap: Option<PackBitmap>,
+    commit_graph: Option<CommitGraph>,
 }
 
 impl Repo {
@@ -140,27 +173,86 @@ impl Repo {
         if !is_git_repo(&dir) {
             return Err(anyhow!("not in a git repo"));
         }
-        let gitdir = dir.join(".git");
+        Repo::open(dir)
+    }
+
+    ///
+    /// Loads the repository at the given path, which may either be a working
+    /// directory containing `.git` or a bare repository.
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let dir = path.as_ref().to_owned();
+        let gitdir = if is_git_repo(&dir) {
+            dir.join(".git")
+        } else if is_bare_git_repo(&dir) {
+            dir.clone()
+        } else {
+            return Err(anyhow!("not a git repository: {}", dir.display()));
+        };
 
-        let pack_path = Repo::find_packfile(dir.as_path())?;
-        let pack = match pack_path {
-            Some(path) => {
-                Some(PackFile::open(&path).with_context(|| format!("packfile {:?}", path))?)
+        let alternates = read_alternates(&gitdir.join("objects"))?;
+        let commit_graph = match CommitGraph::open(gitdir.join("objects")) {
+            Ok(graph) => graph,
+            Err(e) => {
+                eprintln!("warning: ignoring commit-graph: {:#}", e);
+                None
             }
-            None => None,
         };
-        Ok(Repo { dir, gitdir, pack })
+        let mut repo = Repo {
+            dir,
+            gitdir,
+            alternates,
+            packs: Vec::new(),
+            midx: None,
+            bitmap: None,
+            commit_graph,
+        };
+        for objects_dir in repo.objects_dirs().collect::<Vec<_>>() {
+            for path in Repo::find_packfiles(&objects_dir)? {
+                let pack = PackFile::open(&path).with_context(|| format!("packfile {:?}", path))?;
+                repo.packs.push(pack);
+            }
+        }
+        repo.midx = repo.load_multi_pack_index();
+        repo.bitmap = repo.load_bitmap();
+        Ok(repo)
     }
 
     pub fn gitdir(&self) -> &Path {
         &self.gitdir
     }
 
-    fn find_packfile(gitdir: &Path) -> Result<Option<PathBuf>> {
-        let mut pack_path = gitdir.to_owned();
-        pack_path.push("objects");
+    ///
+    /// The top of the working tree, or `None` for a bare repository.
+    ///
+    pub fn workdir(&self) -> Option<&Path> {
+        if self.dir == self.gitdir {
+            None
+        } else {
+            Some(&self.dir)
+        }
+    }
+
+    ///
+    /// Loads the configuration for this repository, including the system and
+    /// global configuration.
+    ///
+    pub fn config(&self) -> Result<Config> {
+        Config::load(Some(&self.gitdir))
+    }
+
+    ///
+    /// The objects directories searched for objects, starting with our own.
+    ///
+    fn objects_dirs(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        iter::once(self.gitdir.join("objects")).chain(self.alternates.iter().cloned())
+    }
+
+    fn find_packfiles(objects_dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut pack_path = objects_dir.to_owned();
         pack_path.push("pack");
 
+        let mut packs = Vec::new();
         if pack_path.exists() {
             for dir_entry in fs::read_dir(&pack_path)? {
                 let dir_entry = dir_entry?;
@@ -176,28 +268,40 @@ impl Repo {
                     .file_name()
                     .expect("file_name should be nonempty since path is a file");
                 match fname.to_str() {
-                    Some(name) if name.starts_with("pack") => {
-                        pack_path.push(name);
-                        return Ok(Some(pack_path));
-                    }
+                    Some(name) if name.starts_with("pack") => packs.push(path.clone()),
                     _ => {}
                 }
             }
         }
-        Ok(None)
+        // Keep the search order stable between runs.
+        packs.sort();
+        Ok(packs)
     }
 
     pub fn from_packfile<P: AsRef<Path>>(root: P, packfile_data: &[u8]) -> Result<Self> {
         let root = root.as_ref();
-        let packfile = PackFile::parse(packfile_data)?;
-        let gitdir = root.join(".git");
-        packfile.write(&gitdir)?;
-
-        Ok(Repo {
+        let mut repo = Repo {
             dir: root.to_owned(),
-            gitdir,
-            pack: Some(packfile),
-        })
+            gitdir: root.join(".git"),
+            alternates: Vec::new(),
+            packs: Vec::new(),
+            midx: None,
+            bitmap: None,
+            commit_graph: None,
+        };
+        repo.add_packfile(packfile_data)?;
+        Ok(repo)
+    }
+
+    ///
+    /// Writes the packfile and its index into the repository, making its
+    /// objects available for reading.
+    ///
+    pub fn add_packfile(&mut self, packfile_data: &[u8]) -> Result<()> {
+        let packfile = PackFile::parse(packfile_data)?;
+        packfile.write(&self.gitdir)?;
+        self.packs.push(packfile);
+        Ok(())
     }
 
     ///
@@ -225,25 +329,51 @@ impl Repo {
                 ))
             }
         };
-        tree.ok_or_else(|| anyhow!("failed to retrieve tree"))
-            .and_then(|t| self.walk_tree(&mut path, &t, &mut idx))?;
+        let tree = tree.ok_or_else(|| anyhow!("failed to retrieve tree"))?;
+        // Counting means reading every tree twice, so only do it for show.
+        let total = if progress::enabled() {
+            Some(self.count_files(&tree)?)
+        } else {
+            None
+        };
+        let mut progress = Progress::new("Checking out files", total);
+        self.walk_tree(&mut path, &tree, &mut idx, &mut progress)?;
+        progress.finish();
         let mut idx = Index::new(idx);
         write_index(&self.gitdir, &mut idx).with_context(|| "write index")?;
         Ok(())
     }
 
+    ///
+    /// Counts the files `walk_tree` will check out, for its progress.
+    ///
+    fn count_files(&self, tree: &Tree) -> Result<usize> {
+        let mut count = 0;
+        for entry in &tree.entries {
+            match entry.mode {
+                EntryMode::SubDirectory => {
+                    let child = self
+                        .read_object(&entry.sha)?
+                        .as_tree()
+                        .ok_or_else(|| anyhow!("subdir entry of tree was not tree"))?;
+                    count += self.count_files(&child)?;
+                }
+                EntryMode::Normal | EntryMode::Executable => count += 1,
+                _ => {}
+            }
+        }
+        Ok(count)
+    }
+
     fn walk_tree(
         &self,
         current_path: &mut PathBuf,
         tree: &Tree,
         idx: &mut Vec<IndexEntry>,
+        progress: &mut Progress,
     ) -> Result<()> {
         for entry in &tree.entries {
-            let &TreeEntry {
-                ref path,
-                ref mode,
-                ref sha,
-            } = entry;
+            let TreeEntry { path, mode, sha } = entry;
             current_path.push(path);
             match mode {
                 EntryMode::SubDirectory => {
@@ -252,7 +382,7 @@ impl Repo {
                         .read_object(sha)?
                         .as_tree()
                         .ok_or_else(|| anyhow!("subdir entry of tree was not tree"))?;
-                    self.walk_tree(current_path, &child, idx)?;
+                    self.walk_tree(current_path, &child, idx, progress)?;
                 }
                 EntryMode::Normal | EntryMode::Executable => {
                     let object = self.read_object(sha)?;
@@ -270,6 +400,7 @@ impl Repo {
 
                     let idx_entry = get_index_entry(&self.dir, &current_path, mode.clone(), sha)?;
                     idx.push(idx_entry);
+                    progress.inc();
                 }
                 e => return Err(anyhow!("Unsupported Entry Mode {:?}", e)),
             }
@@ -289,39 +420,188 @@ impl Repo {
 
     pub fn read_object(&self, sha: &Sha) -> Result<PackedObject> {
         // Attempt to read from disk first
-        PackedObject::open(&self.dir, sha).or_else(|err| {
-            // If this isn't there, try to read from the packfile
-            self.pack
-                .as_ref()
-                .ok_or(err)
-                .and_then(|p| p.find_by_sha(sha))
-        })
+        if let Some(objects_dir) = self.objects_dirs().find(|d| PackedObject::exists(d, sha)) {
+            return PackedObject::open(objects_dir, sha);
+        }
+        // If this isn't there, try to read from the packfiles
+        self.find_packed(sha)
+            .ok_or_else(|| anyhow!("object not found: {}", sha))
+            .and_then(|(pack, offset)| pack.find_by_offset(offset))
     }
 
-    pub fn log(&self, rev: &str) -> Result<()> {
-        let mut sha = resolve_ref(&self.dir, rev)?;
-        loop {
+    ///
+    /// Returns true if the object is present either in loose form or in a packfile.
+    ///
+    pub fn has_object(&self, sha: &Sha) -> bool {
+        self.objects_dirs().any(|d| PackedObject::exists(d, sha)) || self.find_packed(sha).is_some()
+    }
+
+    ///
+    /// Lists the objects reachable from `wants` that a client holding `haves`
+    /// needs to be sent. Unless the bitmaps can answer exactly, anything used
+    /// only by history beyond where the walk stopped may be listed even
+    /// though the client has it.
+    ///
+    pub fn objects_between(&self, wants: &[Sha], haves: &[Sha]) -> Result<Vec<Sha>> {
+        if let Some(objects) = self.objects_between_with_bitmap(wants, haves)? {
+            return Ok(objects);
+        }
+        let mut seen = HashSet::new();
+        let mut objects = Vec::new();
+        let mut walk = RevWalk::new(self);
+        // Trees and blobs can be wanted directly, though it's rare.
+        let mut others = Vec::new();
+        for want in wants {
+            // Tags are sent along with what they point to.
+            let mut sha = *want;
+            while let Some(tag) = self.read_object(&sha)?.as_tag() {
+                if seen.insert(sha) {
+                    objects.push(sha);
+                }
+                sha = tag.object;
+            }
+            match self.read_object(&sha)?.obj_type {
+                ObjectType::Commit => walk.push(sha)?,
+                _ => others.push(sha),
+            }
+        }
+        for have in haves {
+            match self.peel_to(*have, "commit") {
+                Ok(commit) => walk.hide(commit)?,
+                Err(_) => {
+                    seen.insert(*have);
+                }
+            }
+        }
+
+        let mut trees = Vec::new();
+        for commit in walk.by_ref() {
+            let commit = commit?;
+            seen.insert(commit);
+            objects.push(commit);
+            trees.push(self.commit_tree(&commit)?);
+        }
+        // The client has everything used by the commits it has, as far as
+        // the walk went.
+        let hidden_trees = walk
+            .hidden()
+            .map(|sha| self.commit_tree(sha))
+            .collect::<Result<Vec<_>>>()?;
+        self.walk_reachable(&hidden_trees, &mut seen, |_| {})?;
+        trees.extend(others);
+        self.walk_reachable(&trees, &mut seen, |sha| objects.push(sha))?;
+        Ok(objects)
+    }
+
+    ///
+    /// The root tree of a commit.
+    ///
+    pub fn commit_tree(&self, sha: &Sha) -> Result<Sha> {
+        let object = self.read_object(sha)?;
+        let commit = object
+            .as_commit()
+            .ok_or_else(|| anyhow!("failed to parse commit {}", sha))?;
+        Ok(commit.tree)
+    }
+
+    fn walk_reachable<F>(&self, tips: &[Sha], seen: &mut HashSet<Sha>, mut f: F) -> Result<()>
+    where
+        F: FnMut(Sha),
+    {
+        let mut pending = tips.to_vec();
+        while let Some(sha) = pending.pop() {
+            if !seen.insert(sha) {
+                continue;
+            }
             let object = self.read_object(&sha)?;
-            let commit = object
-                .as_commit()
-                .expect("Tried to log an object that wasn't a commit");
-            if commit.parents.is_empty() {
-                break;
+            match object.obj_type {
+                ObjectType::Commit => {
+                    let commit = object
+                        .as_commit()
+                        .ok_or_else(|| anyhow!("failed to parse commit {}", sha))?;
+                    pending.extend(commit.parents.iter().rev());
+                    pending.push(commit.tree);
+                }
+                ObjectType::Tree => {
+                    let tree = object
+                        .as_tree()
+                        .ok_or_else(|| anyhow!("failed to parse tree {}", sha))?;
+                    for entry in tree.entries.iter().rev() {
+                        match entry.mode {
+                            EntryMode::SubDirectory => pending.push(entry.sha),
+                            // Submodule commits live in another repository.
+                            EntryMode::Gitlink => {}
+                            // There's no need to read blobs since they don't
+                            // reference anything else.
+                            _ => {
+                                if seen.insert(entry.sha) {
+                                    f(entry.sha);
+                                }
+                            }
+                        }
+                    }
+                }
+                ObjectType::Tag => {
+                    let tag = object
+                        .as_tag()
+                        .ok_or_else(|| anyhow!("failed to parse tag {}", sha))?;
+                    pending.push(tag.object);
+                }
+                ObjectType::Blob => {}
             }
-            sha = commit.parents[0].to_owned();
+            f(sha);
         }
         Ok(())
     }
+
+    ///
+    /// Follows annotated tags from `sha` until reaching an object which isn't
+    /// a tag.
+    ///
+    pub fn peel(&self, sha: &Sha) -> Result<Sha> {
+        let mut sha = *sha;
+        loop {
+            let object = self.read_object(&sha)?;
+            match object.as_tag() {
+                Some(tag) => sha = tag.object,
+                None => return Ok(sha),
+            }
+        }
+    }
 }
 
 fn is_git_repo<P: AsRef<Path>>(p: &P) -> bool {
     p.as_ref().join(".git").exists()
 }
 
+fn is_bare_git_repo<P: AsRef<Path>>(p: &P) -> bool {
+    let p = p.as_ref();
+    p.join("HEAD").is_file() && p.join("objects").is_dir() && p.join("refs").is_dir()
+}
+
+///
+/// Reads the alternate objects directories listed in `objects/info/alternates`,
+/// one per line. Relative paths are relative to the objects directory.
+///
+fn read_alternates(objects_dir: &Path) -> Result<Vec<PathBuf>> {
+    let contents = match fs::read_to_string(objects_dir.join("info/alternates")) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(contents
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| objects_dir.join(line))
+        .collect())
+}
+
 ///
-/// Reads the given ref to a valid SHA.
+/// Reads the given ref to a valid SHA. Short names are expanded as
+/// described in `expand_ref_name`; for the rest of git's revision syntax,
+/// see `Repo::rev_parse`.
 ///
-fn resolve_ref<P: AsRef<Path>>(gitdir: P, name: &str) -> Result<Sha> {
+pub fn resolve_ref<P: AsRef<Path>>(gitdir: P, name: &str) -> Result<Sha> {
     // Check if the name is already a sha.
     let trimmed = name.trim();
     if is_hex_sha(trimmed) {
@@ -335,30 +615,72 @@ fn resolve_ref<P: AsRef<Path>>(gitdir: P, name: &str) -> Result<Sha> {
 /// Returns true if id is a valid SHA-1 hash.
 ///
 fn is_hex_sha(id: &str) -> bool {
-    id.len() == 40 && id.chars().all(|c| c.is_digit(16))
+    id.len() == 40 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+///
+/// Expands a short ref name to the first of these which exists, the same
+/// order git uses:
+///
+/// -- <name> (only HEAD-like names such as ORIG_HEAD, or full names)
+/// -- refs/<name>
+/// -- refs/tags/<name>
+/// -- refs/heads/<name>
+/// -- refs/remotes/<name>
+/// -- refs/remotes/<name>/HEAD
+///
+pub fn expand_ref_name<P: AsRef<Path>>(gitdir: P, name: &str) -> Result<Option<String>> {
+    let gitdir = gitdir.as_ref();
+    // Neither could name a ref, but both could name files outside refs/.
+    if name.contains("..") || name.starts_with('/') {
+        return Ok(None);
+    }
+    let head_like = name.chars().all(|c| c.is_ascii_uppercase() || c == '_');
+    let mut candidates = Vec::new();
+    if head_like || name.starts_with("refs/") {
+        candidates.push(name.to_owned());
+    }
+    candidates.extend(
+        ["refs", "refs/tags", "refs/heads", "refs/remotes"]
+            .iter()
+            .map(|prefix| format!("{}/{}", prefix, name)),
+    );
+    candidates.push(format!("refs/remotes/{}/HEAD", name));
+
+    // A packed candidate beats a loose one further down the list.
+    let packed = PackedRefs::read(gitdir)?;
+    Ok(candidates
+        .into_iter()
+        .find(|c| gitdir.join(c).is_file() || packed.find(c).is_some()))
 }
 
 ///
 /// Reads the symbolic ref and resolve it to the actual ref it represents.
 ///
 fn read_sym_ref<P: AsRef<Path>>(gitdir: P, name: &str) -> Result<Sha> {
-    // Read the symbolic ref directly and parse the actual ref out
-    let mut path = gitdir.as_ref().to_owned();
+    let gitdir = gitdir.as_ref();
+    let full_name =
+        expand_ref_name(gitdir, name)?.ok_or_else(|| anyhow!("no such ref: {}", name))?;
+    let path = gitdir.join(&full_name);
 
-    if name != "HEAD" {
-        if !name.contains('/') {
-            path.push("refs/heads");
-        } else if !name.starts_with("refs/") {
-            path.push("refs/remotes");
+    // Read the actual ref out, falling back to packed-refs if it isn't loose.
+    let mut contents = String::new();
+    match File::open(&path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut contents)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let packed = PackedRefs::read(gitdir)?;
+            return packed
+                .find(&full_name)
+                .map(|r| r.id)
+                .ok_or_else(|| anyhow!("no such ref: {}", full_name));
+        }
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("reading symbolic ref at path {}", path.display()))
         }
     }
-    path.push(name);
-
-    // Read the actual ref out
-    let mut contents = String::new();
-    let mut file = File::open(&path)
-        .with_context(|| format!("reading symbolic ref at path {}", path.display()))?;
-    file.read_to_string(&mut contents)?;
 
     if let Some(stripped) = contents.strip_prefix("ref: ") {
         resolve_ref(gitdir, stripped)
@@ -531,10 +853,10 @@ where
         size,
         ..
     } = entry;
-    let &IndexEntry {
-        ref sha,
-        ref file_mode,
-        ref path,
+    let IndexEntry {
+        sha,
+        file_mode,
+        path,
         ..
     } = entry;
     let path = path.as_os_str();
@@ -553,8 +875,8 @@ where
     w.write_u32::<BigEndian>(device as u32)?;
     w.write_u32::<BigEndian>(inode as u32)?;
     w.write_u32::<BigEndian>(encoded_mode)?;
-    w.write_u32::<BigEndian>(uid as u32)?;
-    w.write_u32::<BigEndian>(gid as u32)?;
+    w.write_u32::<BigEndian>(uid)?;
+    w.write_u32::<BigEndian>(gid)?;
     w.write_u32::<BigEndian>(size as u32)?;
     w.write_all(sha.as_bytes())?;
     w.write_u16::<BigEndian>(flags)?;
@@ -616,8 +938,7 @@ use std::io::Seek;
 
 use byteorder::ReadBytesExt;
 
-#[allow(unused)]
-pub fn read_index<R: BufRead + Seek>(mut r: R) -> Result<Index> {
+pub fn read_index<R: BufRead + Seek>(r: R) -> Result<Index> {
     let mut r = DigestReader::new(r);
 
     // Header
@@ -704,7 +1025,7 @@ fn read_entry<R: BufRead>(mut r: R) -> Result<IndexEntry> {
             // We can probably move them to a method of the type
             (8u32, 0o100644) => (EntryMode::Normal, perms),
             (8u32, 0o000644) => (EntryMode::Normal, perms),
-            (8u32, 0o100755) => (EntryMode::Executable, perms),
+            (8u32, 0o000755) => (EntryMode::Executable, perms),
             (10u32, 0u32) => (EntryMode::Symlink, 0),
             (14u32, 0u32) => (EntryMode::Gitlink, 0),
             _ => {
@@ -931,6 +1252,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn expanding_ref_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let gitdir = crate::test_support::copy_repo(tmp.path());
+        let id = fs::read_to_string(gitdir.join("refs/heads/test")).unwrap();
+        fs::write(gitdir.join("refs/heads/both"), &id).unwrap();
+        fs::write(
+            gitdir.join("packed-refs"),
+            format!("{} refs/tags/both\n", id.trim()),
+        )
+        .unwrap();
+
+        let expand = |name| expand_ref_name(&gitdir, name).unwrap();
+        assert_eq!(expand("test").as_deref(), Some("refs/heads/test"));
+        // Tags come before branches, whether or not they're packed.
+        assert_eq!(expand("both").as_deref(), Some("refs/tags/both"));
+        assert_eq!(expand("heads/both").as_deref(), Some("refs/heads/both"));
+        assert_eq!(expand("missing"), None);
+        assert_eq!(expand("../config"), None);
+        assert_eq!(expand("heads/../../HEAD"), None);
+        assert_eq!(expand("/etc/passwd"), None);
+    }
+
     fn read_file_contents(path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
         let file = File::open(path)?;
         let size = file.metadata()?.size();
@@ -0,0 +1,551 @@
+//!
+//! The multi-pack-index, `objects/pack/multi-pack-index`, which indexes the
+//! objects of every pack in the directory at once so that finding an object
+//! needn't search each pack's own index in turn. See gitformat-pack(5).
+//!
+//! An object in several packs is only listed once, in whichever of those
+//! packs was modified most recently.
+//!
+use std::cmp::Reverse;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result,
+};
+use byteorder::{
+    BigEndian,
+    ByteOrder,
+    WriteBytesExt,
+};
+
+use crate::packfile::refs::LockFile;
+use crate::packfile::{
+    PackFile,
+    PackIndex,
+};
+use crate::store::commit_graph::{
+    read_chunks,
+    read_if_exists,
+    write_chunks,
+};
+use crate::store::{
+    Repo,
+    Sha,
+};
+
+const SIGNATURE: &[u8; 4] = b"MIDX";
+const VERSION: u8 = 1;
+const HASH_VERSION_SHA1: u8 = 1;
+
+const CHUNK_PACK_NAMES: &[u8; 4] = b"PNAM";
+const CHUNK_FANOUT: &[u8; 4] = b"OIDF";
+const CHUNK_OID_LOOKUP: &[u8; 4] = b"OIDL";
+const CHUNK_OBJECT_OFFSETS: &[u8; 4] = b"OOFF";
+const CHUNK_LARGE_OFFSETS: &[u8; 4] = b"LOFF";
+
+/// The signature, versions, chunk and base counts, then the number of packs.
+const HEADER_LEN: usize = 12;
+const OBJECT_OFFSET_LEN: usize = 8;
+
+/// Set on an object's offset when the rest of it indexes the large offsets.
+const LARGE_OFFSET: u32 = 0x8000_0000;
+
+pub struct MultiPackIndex {
+    // The names of the packs' indexes, such as `pack-<sha>.idx`, sorted.
+    pack_names: Vec<String>,
+    fanout: [u32; 256],
+    oids: Vec<Sha>,
+    // The pack each object is read from, as a position in `pack_names`,
+    // and the object's offset there.
+    offsets: Vec<(u32, u64)>,
+}
+
+impl MultiPackIndex {
+    ///
+    /// Loads the multi-pack-index of a pack directory, or `None` if there
+    /// isn't one.
+    ///
+    pub fn open<P: AsRef<Path>>(pack_dir: P) -> Result<Option<Self>> {
+        match read_if_exists(&pack_dir.as_ref().join("multi-pack-index"))? {
+            Some(contents) => Self::parse(&contents)
+                .context("read multi-pack-index")
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn parse(contents: &[u8]) -> Result<Self> {
+        if contents.len() < HEADER_LEN + 20 {
+            return Err(anyhow!("multi-pack-index is truncated"));
+        }
+        let (body, trailer) = contents.split_at(contents.len() - 20);
+        if Sha::compute_from_bytes(body) != Sha::from_bytes(trailer)? {
+            return Err(anyhow!("multi-pack-index checksum mismatch"));
+        }
+        if &body[..4] != SIGNATURE {
+            return Err(anyhow!("not a multi-pack-index"));
+        }
+        if body[4] != VERSION {
+            return Err(anyhow!("unsupported multi-pack-index version: {}", body[4]));
+        }
+        if body[5] != HASH_VERSION_SHA1 {
+            return Err(anyhow!(
+                "unsupported multi-pack-index hash version: {}",
+                body[5]
+            ));
+        }
+        if body[7] != 0 {
+            return Err(anyhow!("multi-pack-index chains aren't supported"));
+        }
+        let num_packs = BigEndian::read_u32(&body[8..12]) as usize;
+
+        let chunks = read_chunks(body, HEADER_LEN, body[6] as usize)?;
+        let required = |id: &[u8; 4]| {
+            chunks.get(id).copied().ok_or_else(|| {
+                anyhow!(
+                    "multi-pack-index is missing the {} chunk",
+                    String::from_utf8_lossy(id)
+                )
+            })
+        };
+
+        // The names are NUL-terminated, then padded with NULs.
+        let pack_names = required(CHUNK_PACK_NAMES)?
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8(name.to_vec()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if pack_names.len() != num_packs {
+            return Err(anyhow!(
+                "multi-pack-index names {} packs but should have {}",
+                pack_names.len(),
+                num_packs
+            ));
+        }
+
+        let fanout_chunk = required(CHUNK_FANOUT)?;
+        if fanout_chunk.len() != 256 * 4 {
+            return Err(anyhow!("multi-pack-index fanout has the wrong size"));
+        }
+        let mut fanout = [0; 256];
+        BigEndian::read_u32_into(fanout_chunk, &mut fanout);
+        let num_objects = fanout[255] as usize;
+
+        let oid_chunk = required(CHUNK_OID_LOOKUP)?;
+        let offset_chunk = required(CHUNK_OBJECT_OFFSETS)?;
+        if oid_chunk.len() != num_objects * 20
+            || offset_chunk.len() != num_objects * OBJECT_OFFSET_LEN
+        {
+            return Err(anyhow!("multi-pack-index chunks don't match its fanout"));
+        }
+        let oids = oid_chunk
+            .chunks(20)
+            .map(Sha::from_bytes)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let large_offsets = chunks.get(CHUNK_LARGE_OFFSETS).map(|chunk| {
+            chunk
+                .chunks_exact(8)
+                .map(BigEndian::read_u64)
+                .collect::<Vec<_>>()
+        });
+        let mut offsets = Vec::with_capacity(num_objects);
+        for entry in offset_chunk.chunks(OBJECT_OFFSET_LEN) {
+            let pack = BigEndian::read_u32(&entry[..4]);
+            if pack as usize >= num_packs {
+                return Err(anyhow!("multi-pack-index pack {} is out of bounds", pack));
+            }
+            let offset = BigEndian::read_u32(&entry[4..]);
+            let offset = if offset & LARGE_OFFSET != 0 {
+                let large = large_offsets
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("missing large offset chunk"))?;
+                let i = (offset & !LARGE_OFFSET) as usize;
+                *large
+                    .get(i)
+                    .ok_or_else(|| anyhow!("multi-pack-index large offset {} is missing", i))?
+            } else {
+                offset as u64
+            };
+            offsets.push((pack, offset));
+        }
+
+        Ok(MultiPackIndex {
+            pack_names,
+            fanout,
+            oids,
+            offsets,
+        })
+    }
+
+    ///
+    /// Finds an object, returning the position of its pack in `pack_names`
+    /// and its offset there.
+    ///
+    pub fn find(&self, sha: &Sha) -> Option<(usize, u64)> {
+        let first = sha.as_bytes()[0] as usize;
+        let start = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        let end = self.fanout[first] as usize;
+        let i = start + self.oids.get(start..end)?.binary_search(sha).ok()?;
+        let (pack, offset) = self.offsets[i];
+        Some((pack as usize, offset))
+    }
+}
+
+///
+/// Encodes a multi-pack-index of `objects`, which must be sorted and listed
+/// once each along with the position of their pack in `pack_names`.
+///
+fn encode(pack_names: &[String], objects: &[(Sha, u32, u64)]) -> Result<Vec<u8>> {
+    let mut names = Vec::new();
+    for name in pack_names {
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+    names.resize(names.len().next_multiple_of(4), 0);
+
+    let mut fanout = Vec::with_capacity(256 * 4);
+    for byte in 0..=255u8 {
+        let count = objects.partition_point(|(sha, _, _)| sha.as_bytes()[0] <= byte);
+        fanout.write_u32::<BigEndian>(count as u32)?;
+    }
+    let mut oids = Vec::with_capacity(objects.len() * 20);
+    for (sha, _, _) in objects {
+        oids.extend_from_slice(sha.as_bytes());
+    }
+
+    // Offsets with the top bit set move out to the large offsets, since
+    // that bit is what marks one as being there.
+    let mut object_offsets = Vec::with_capacity(objects.len() * OBJECT_OFFSET_LEN);
+    let mut large_offsets = Vec::new();
+    for (_, pack, offset) in objects {
+        object_offsets.write_u32::<BigEndian>(*pack)?;
+        if *offset >= LARGE_OFFSET as u64 {
+            let i = (large_offsets.len() / 8) as u32;
+            object_offsets.write_u32::<BigEndian>(i | LARGE_OFFSET)?;
+            large_offsets.write_u64::<BigEndian>(*offset)?;
+        } else {
+            object_offsets.write_u32::<BigEndian>(*offset as u32)?;
+        }
+    }
+
+    let mut chunks: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (CHUNK_PACK_NAMES, names),
+        (CHUNK_FANOUT, fanout),
+        (CHUNK_OID_LOOKUP, oids),
+        (CHUNK_OBJECT_OFFSETS, object_offsets),
+    ];
+    if !large_offsets.is_empty() {
+        chunks.push((CHUNK_LARGE_OFFSETS, large_offsets));
+    }
+
+    let mut contents = Vec::new();
+    contents.extend_from_slice(SIGNATURE);
+    contents.extend_from_slice(&[VERSION, HASH_VERSION_SHA1, chunks.len() as u8, 0]);
+    contents.write_u32::<BigEndian>(pack_names.len() as u32)?;
+    write_chunks(&mut contents, &chunks)?;
+    Ok(contents)
+}
+
+impl Repo {
+    ///
+    /// Finds the pack holding an object and the object's offset there,
+    /// through the multi-pack-index for the packs it covers.
+    ///
+    pub(super) fn find_packed(&self, sha: &Sha) -> Option<(&PackFile, usize)> {
+        let covered = match &self.midx {
+            Some((midx, packs)) => {
+                if let Some((pack, offset)) = midx.find(sha) {
+                    return Some((&self.packs[packs[pack]], offset as usize));
+                }
+                &packs[..]
+            }
+            None => &[],
+        };
+        self.packs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !covered.contains(i))
+            .find_map(|(_, pack)| pack.index.find(sha).map(|offset| (pack, offset)))
+    }
+
+    ///
+    /// Loads the multi-pack-index of our own packs along with where each of
+    /// them is in `packs`. It can't be trusted if any of them have since
+    /// been removed, so it's ignored then, as it is if it can't be read.
+    ///
+    pub(super) fn load_multi_pack_index(&self) -> Option<(MultiPackIndex, Vec<usize>)> {
+        let midx = match MultiPackIndex::open(self.gitdir.join("objects/pack")) {
+            Ok(Some(midx)) => midx,
+            Ok(None) => return None,
+            Err(e) => {
+                eprintln!("warning: ignoring multi-pack-index: {:#}", e);
+                return None;
+            }
+        };
+        let mut positions = Vec::with_capacity(midx.pack_names.len());
+        for name in &midx.pack_names {
+            match self
+                .packs
+                .iter()
+                .position(|pack| *name == format!("pack-{}.idx", pack.sha()))
+            {
+                Some(i) => positions.push(i),
+                None => return None,
+            }
+        }
+        Some((midx, positions))
+    }
+
+    ///
+    /// Writes a multi-pack-index covering every pack in our objects
+    /// directory.
+    ///
+    pub fn write_multi_pack_index(&self) -> Result<()> {
+        let pack_paths = Repo::find_packfiles(&self.gitdir.join("objects"))?;
+        if pack_paths.is_empty() {
+            return Err(anyhow!("no pack files to index"));
+        }
+
+        let mut pack_names = Vec::with_capacity(pack_paths.len());
+        let mut objects = Vec::new();
+        for (i, path) in pack_paths.iter().enumerate() {
+            let idx_path = path.with_extension("idx");
+            let index = PackIndex::open(&idx_path)
+                .with_context(|| format!("read {}", idx_path.display()))?
+                .ok_or_else(|| anyhow!("{} has no index", path.display()))?;
+            let modified = path.metadata()?.modified()?;
+            let name = idx_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| anyhow!("bad pack name: {}", idx_path.display()))?;
+            pack_names.push(name.to_owned());
+            objects.extend(
+                index
+                    .entries()
+                    .map(|(sha, offset)| (*sha, Reverse(modified), i as u32, offset as u64)),
+            );
+        }
+        // Keep the copy in the newest pack, as git does.
+        objects.sort();
+        objects.dedup_by_key(|(sha, _, _, _)| *sha);
+        let objects = objects
+            .into_iter()
+            .map(|(sha, _, pack, offset)| (sha, pack, offset))
+            .collect::<Vec<_>>();
+
+        let contents = encode(&pack_names, &objects)?;
+        let mut lock = LockFile::acquire(self.gitdir.join("objects/pack/multi-pack-index"))?;
+        lock.write_all(&contents)?;
+        lock.commit()
+    }
+
+    ///
+    /// Checks the multi-pack-index against the packs it names: every object
+    /// in them must be listed, and every listed object must be in its pack
+    /// at the offset given.
+    ///
+    pub fn verify_multi_pack_index(&self) -> Result<()> {
+        let pack_dir = self.gitdir.join("objects/pack");
+        let midx = MultiPackIndex::open(&pack_dir)?
+            .ok_or_else(|| anyhow!("there is no multi-pack-index"))?;
+        if !midx.pack_names.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(anyhow!("multi-pack-index pack names are out of order"));
+        }
+        if !midx.oids.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(anyhow!("multi-pack-index object IDs are out of order"));
+        }
+        for byte in 0..=255u8 {
+            let count = midx.oids.partition_point(|sha| sha.as_bytes()[0] <= byte);
+            if midx.fanout[byte as usize] as usize != count {
+                return Err(anyhow!("multi-pack-index fanout is wrong at {:02x}", byte));
+            }
+        }
+
+        let mut indexes = Vec::with_capacity(midx.pack_names.len());
+        for name in &midx.pack_names {
+            let index = PackIndex::open(pack_dir.join(name))
+                .with_context(|| format!("read {}", name))?
+                .ok_or_else(|| anyhow!("pack {} is missing", name))?;
+            if let Some((sha, _)) = index.entries().find(|(sha, _)| midx.find(sha).is_none()) {
+                return Err(anyhow!(
+                    "{} in {} is missing from the multi-pack-index",
+                    sha,
+                    name
+                ));
+            }
+            indexes.push(index);
+        }
+        for (sha, (pack, offset)) in midx.oids.iter().zip(&midx.offsets) {
+            if indexes[*pack as usize].find(sha) != Some(*offset as usize) {
+                return Err(anyhow!(
+                    "multi-pack-index has the wrong offset for {} in {}",
+                    sha,
+                    midx.pack_names[*pack as usize]
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use super::*;
+    use crate::test_support::copy_repo;
+
+    static OWN_PACK: &str = "79f006bb5e8d079fdbe07e7ce41f97f4db7d341c";
+    static OTHER_PACK: &str = "tests/data/packs/pack-73e0a23f5ebfc74c7ea1940e2843a408ce1789d0";
+
+    fn sha(n: u8) -> Sha {
+        Sha::from_bytes(&[n; 20]).unwrap()
+    }
+
+    #[test]
+    fn writing_a_multi_pack_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        for ext in &["pack", "idx"] {
+            let name = Path::new(OTHER_PACK).with_extension(ext);
+            fs::copy(
+                &name,
+                path.join("objects/pack").join(name.file_name().unwrap()),
+            )
+            .unwrap();
+        }
+        let repo = Repo::open(&path).unwrap();
+        assert!(repo.midx.is_none());
+        repo.write_multi_pack_index().unwrap();
+
+        let repo = Repo::open(&path).unwrap();
+        repo.verify_multi_pack_index().unwrap();
+        let (midx, packs) = repo.midx.as_ref().expect("multi-pack-index was written");
+        assert_eq!(packs.len(), 2);
+        // The packs share some objects, which are only listed once.
+        let mut shas = HashSet::new();
+        for pack in &repo.packs {
+            for (sha, _) in pack.index.entries() {
+                let (_, offset) = midx.find(sha).expect("object is indexed");
+                let (found, _) = repo.find_packed(sha).unwrap();
+                assert_eq!(found.index.find(sha), Some(offset as usize));
+                repo.read_object(sha).unwrap();
+                shas.insert(*sha);
+            }
+        }
+        assert_eq!(midx.oids.len(), shas.len());
+        assert!(midx.find(&sha(0xff)).is_none());
+    }
+
+    #[test]
+    fn searching_packs_the_index_lacks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        let pack_dir = path.join("objects/pack");
+        // Index only the smaller pack, so that the objects only the larger
+        // one has must be found by searching it directly.
+        let own = pack_dir.join(format!("pack-{}", OWN_PACK));
+        for ext in &["pack", "idx"] {
+            fs::rename(own.with_extension(ext), tmp.path().join(ext)).unwrap();
+            let name = Path::new(OTHER_PACK).with_extension(ext);
+            fs::copy(&name, pack_dir.join(name.file_name().unwrap())).unwrap();
+        }
+        Repo::open(&path).unwrap().write_multi_pack_index().unwrap();
+        for ext in &["pack", "idx"] {
+            fs::rename(tmp.path().join(ext), own.with_extension(ext)).unwrap();
+        }
+
+        let repo = Repo::open(&path).unwrap();
+        let (midx, _) = repo.midx.as_ref().unwrap();
+        assert_eq!(midx.pack_names.len(), 1);
+        let own = repo
+            .packs
+            .iter()
+            .find(|pack| pack.sha().hex() == OWN_PACK)
+            .unwrap();
+        let mut unindexed = own
+            .index
+            .entries()
+            .filter(|(sha, _)| midx.find(sha).is_none())
+            .peekable();
+        assert!(unindexed.peek().is_some());
+        for (sha, offset) in unindexed {
+            assert_eq!(repo.find_packed(sha).unwrap().1, offset);
+        }
+
+        // Once one of its packs is gone, the index is ignored.
+        let name = &midx.pack_names[0];
+        fs::remove_file(pack_dir.join(name)).unwrap();
+        fs::remove_file(pack_dir.join(name).with_extension("pack")).unwrap();
+        assert!(Repo::open(&path).unwrap().midx.is_none());
+    }
+
+    #[test]
+    fn ignoring_an_unreadable_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        Repo::open(&path).unwrap().write_multi_pack_index().unwrap();
+        let midx_path = path.join("objects/pack/multi-pack-index");
+        let mut contents = fs::read(&midx_path).unwrap();
+        // Claim a base index, as an incremental one in a chain would.
+        let body_len = contents.len() - 20;
+        contents[7] = 1;
+        let checksum = Sha::compute_from_bytes(&contents[..body_len]);
+        contents[body_len..].copy_from_slice(checksum.as_bytes());
+        fs::write(&midx_path, &contents).unwrap();
+
+        let repo = Repo::open(&path).unwrap();
+        assert!(repo.midx.is_none());
+        let head = repo.rev_parse("HEAD").unwrap();
+        repo.read_object(&head).unwrap();
+
+        contents.truncate(body_len);
+        fs::write(&midx_path, &contents).unwrap();
+        assert!(Repo::open(&path).unwrap().midx.is_none());
+    }
+
+    #[test]
+    fn reading_large_offsets() {
+        let names = vec!["pack-a.idx".to_owned(), "pack-b.idx".to_owned()];
+        let objects = vec![
+            (sha(1), 0, 12),
+            (sha(2), 1, 0x9000_0000),
+            (sha(3), 1, 5 << 32),
+        ];
+        let midx = MultiPackIndex::parse(&encode(&names, &objects).unwrap()).unwrap();
+        assert_eq!(midx.pack_names, names);
+        assert_eq!(midx.find(&sha(1)), Some((0, 12)));
+        assert_eq!(midx.find(&sha(2)), Some((1, 0x9000_0000)));
+        assert_eq!(midx.find(&sha(3)), Some((1, 5 << 32)));
+        assert_eq!(midx.find(&sha(4)), None);
+
+        // Offsets are kept to 32 bits when they all fit in 31.
+        let small = vec![(sha(1), 0, 12), (sha(2), 1, 0x7fff_ffff)];
+        let contents = encode(&names, &small).unwrap();
+        let midx = MultiPackIndex::parse(&contents).unwrap();
+        assert_eq!(midx.find(&sha(2)), Some((1, 0x7fff_ffff)));
+        assert!(!contents.windows(4).any(|id| id == CHUNK_LARGE_OFFSETS));
+    }
+
+    #[test]
+    fn missing_large_offsets() {
+        let names = vec!["pack-a.idx".to_owned()];
+        let mut contents = encode(&names, &[(sha(1), 0, 12)]).unwrap();
+        // Mark the only offset as large, though there's no chunk for it.
+        let body_len = contents.len() - 20;
+        contents[body_len - 4] |= 0x80;
+        let checksum = Sha::compute_from_bytes(&contents[..body_len]);
+        contents[body_len..].copy_from_slice(checksum.as_bytes());
+        let err = MultiPackIndex::parse(&contents).err().unwrap();
+        assert_eq!(err.to_string(), "missing large offset chunk");
+    }
+}
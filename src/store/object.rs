@@ -22,13 +22,14 @@ use flate2::Compression;
 
 use crate::delta;
 use crate::store::commit::Commit;
+use crate::store::tag::Tag;
 use crate::store::tree::Tree;
 use crate::store::Sha;
 
 ///
 /// A type of loose object found in the database.
 ///
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ObjectType {
     Tree,
     Commit,
@@ -66,20 +67,26 @@ impl PackedObject {
     }
 
     ///
-    /// Opens the given object from loose form in the repo.
+    /// Opens the given object from loose form in an objects directory.
     ///
-    pub fn open<P: AsRef<Path>>(repo: P, sha: &Sha) -> Result<Self> {
-        let path = object_path(repo, sha);
+    pub fn open<P: AsRef<Path>>(objects_dir: P, sha: &Sha) -> Result<Self> {
+        let path = object_path(objects_dir, sha);
+        let object = PackedObject::read_loose(File::open(path)?)?;
+        assert_eq!(&object.sha(), sha);
+        Ok(object)
+    }
 
+    ///
+    /// Reads an object in loose form, that is a zlib-compressed header
+    /// followed by the object's contents.
+    ///
+    pub fn read_loose<R: Read>(reader: R) -> Result<Self> {
         // FIXME: This can decode with an intermediate buffer.
         let mut inflated = Vec::new();
-        let file = File::open(path)?;
-        let mut z = ZlibDecoder::new(file);
+        let mut z = ZlibDecoder::new(reader);
         z.read_to_end(&mut inflated)
-            .expect("Error inflating object");
-
-        let sha1_checksum = Sha::compute_from_bytes(&inflated);
-        assert_eq!(&sha1_checksum, sha);
+            .context("inflate loose object")?;
+        let sha = Sha::compute_from_bytes(&inflated);
 
         let split_idx = inflated
             .iter()
@@ -92,16 +99,28 @@ impl PackedObject {
 
         let mut footer = Vec::new();
         footer.extend_from_slice(&inflated[split_idx + 1..]);
-
-        assert_eq!(footer.len(), size);
+        if footer.len() != size {
+            return Err(anyhow!(
+                "object size does not match header: {} != {}",
+                footer.len(),
+                size
+            ));
+        }
 
         Ok(PackedObject {
             obj_type,
             content: footer,
-            sha: RefCell::new(Some(*sha)),
+            sha: RefCell::new(Some(sha)),
         })
     }
 
+    ///
+    /// Returns true if the given object exists in loose form in an objects directory.
+    ///
+    pub fn exists<P: AsRef<Path>>(objects_dir: P, sha: &Sha) -> bool {
+        object_path(objects_dir, sha).is_file()
+    }
+
     ///
     /// Encodes the object into packed format, returning the
     /// SHA and encoded representation.
@@ -133,9 +152,9 @@ impl PackedObject {
     /// Encodes this object and writes it to the repo's database.
     ///
     #[allow(unused)]
-    pub fn write(&self, repo: &str) -> Result<()> {
+    pub fn write<P: AsRef<Path>>(&self, gitdir: P) -> Result<()> {
         let (sha, blob) = self.encode();
-        let path = object_path(repo, &sha);
+        let path = object_path(gitdir.as_ref().join("objects"), &sha);
 
         let parent = path
             .parent()
@@ -198,21 +217,31 @@ impl PackedObject {
     /// Parses the internal representation of this object into a Commit.
     /// Returns `None` if the object is not a Commit.
     ///
-    pub fn as_commit(&self) -> Option<Commit> {
+    pub fn as_commit(&self) -> Option<Commit<'_>> {
         if let ObjectType::Commit = self.obj_type {
             Commit::from_raw(self)
         } else {
             None
         }
     }
+
+    ///
+    /// Parses the internal representation of this object into a Tag.
+    /// Returns `None` if the object is not a Tag.
+    ///
+    pub fn as_tag(&self) -> Option<Tag<'_>> {
+        if let ObjectType::Tag = self.obj_type {
+            Tag::from_raw(self)
+        } else {
+            None
+        }
+    }
 }
 
-fn object_path<P: AsRef<Path>>(repo: P, sha: &Sha) -> PathBuf {
+fn object_path<P: AsRef<Path>>(objects_dir: P, sha: &Sha) -> PathBuf {
     let hex_sha = sha.hex();
 
-    let mut path = repo.as_ref().to_owned();
-    path.push(".git");
-    path.push("objects");
+    let mut path = objects_dir.as_ref().to_owned();
     path.push(&hex_sha[..2]);
     path.push(&hex_sha[2..40]);
     path
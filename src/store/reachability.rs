@@ -0,0 +1,277 @@
+//!
+//! Answering reachability questions with a pack's bitmaps, so that listing
+//! the objects behind a set of commits needn't walk every tree.
+//!
+use std::io::Write;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+
+use crate::packfile::refs::LockFile;
+use crate::packfile::{
+    Bitmap,
+    PackBitmap,
+    PackFile,
+};
+use crate::store::{
+    EntryMode,
+    ObjectType,
+    Repo,
+    RevArg,
+    Sha,
+};
+
+/// Packs with fewer commits than this have a bitmap for every commit.
+const MIN_COMMITS: usize = 100;
+/// Otherwise, one commit in every this many by date gets a bitmap, along
+/// with the tips.
+const COMMIT_INTERVAL: usize = 100;
+
+impl Repo {
+    ///
+    /// Loads the bitmaps of the first of our packs to have them, since git
+    /// only ever uses one. They only make things faster, so one we can't
+    /// read is passed over with a warning, as if it were missing.
+    ///
+    pub(super) fn load_bitmap(&self) -> Option<PackBitmap> {
+        let pack_dir = self.gitdir.join("objects/pack");
+        for pack in &self.packs {
+            let path = pack_dir.join(format!("pack-{}.bitmap", pack.sha()));
+            match PackBitmap::open(&path, &pack.index) {
+                Ok(Some(bitmap)) => return Some(bitmap),
+                Ok(None) => {}
+                Err(e) => eprintln!("warning: ignoring bitmap: {:#}", e),
+            }
+        }
+        None
+    }
+
+    ///
+    /// Writes the bitmaps for one of our packs, which must hold everything
+    /// reachable from the commits in it. `tips` are the commits most worth
+    /// a bitmap of their own, usually those the refs point to.
+    ///
+    pub fn write_bitmap(&self, pack: &PackFile, tips: &[Sha]) -> Result<()> {
+        let mut bitmap = PackBitmap::new(&pack.index);
+        let mut commits = Vec::new();
+        for (position, sha) in bitmap.objects().to_vec().into_iter().enumerate() {
+            let obj_type = self.read_object(&sha)?.obj_type;
+            bitmap.set_type(position, obj_type);
+            if obj_type == ObjectType::Commit {
+                commits.push((self.commit_info(&sha)?.time, sha));
+            }
+        }
+
+        // Going from oldest to newest lets each bitmap build on those of
+        // the commits before it.
+        commits.sort();
+        let selected = commits
+            .iter()
+            .rev()
+            .enumerate()
+            .filter(|(i, (_, sha))| {
+                commits.len() < MIN_COMMITS || i % COMMIT_INTERVAL == 0 || tips.contains(sha)
+            })
+            .map(|(_, (_, sha))| *sha)
+            .collect::<Vec<_>>();
+        for sha in selected.into_iter().rev() {
+            let reachable = self
+                .reach(&bitmap, &[sha])?
+                .ok_or_else(|| anyhow!("pack is missing objects reachable from {}", sha))?;
+            bitmap.set_reachable(sha, reachable);
+        }
+
+        let path = self
+            .gitdir
+            .join(format!("objects/pack/pack-{}.bitmap", pack.sha()));
+        let mut lock = LockFile::acquire(path)?;
+        lock.write_all(&bitmap.encode()?)?;
+        lock.commit()
+    }
+
+    ///
+    /// Sets the bit of everything reachable from `tips`, taking the stored
+    /// bitmaps of commits along the way instead of walking past them.
+    /// Returns `None` if anything reachable isn't in the pack, since it has
+    /// no bit of its own.
+    ///
+    fn reach(&self, bitmap: &PackBitmap, tips: &[Sha]) -> Result<Option<Bitmap>> {
+        let mut bits = Bitmap::default();
+        let mut pending = tips.to_vec();
+        let mut trees = Vec::new();
+        while let Some(sha) = pending.pop() {
+            let Some(position) = bitmap.position(&sha) else {
+                return Ok(None);
+            };
+            if bits.get(position) {
+                continue;
+            }
+            if let Some(reachable) = bitmap.reachable(&sha) {
+                bits.or(reachable);
+                continue;
+            }
+            let obj_type = bitmap
+                .type_at(position)
+                .ok_or_else(|| anyhow!("bitmap has no type for {}", sha))?;
+            match obj_type {
+                ObjectType::Commit => {
+                    bits.set(position);
+                    let info = self.commit_info(&sha)?;
+                    trees.push(info.tree);
+                    pending.extend(info.parents);
+                }
+                ObjectType::Tag => {
+                    bits.set(position);
+                    let object = self.read_object(&sha)?;
+                    let tag = object
+                        .as_tag()
+                        .ok_or_else(|| anyhow!("failed to parse tag {}", sha))?;
+                    pending.push(tag.object);
+                }
+                // Trees wait until the commits' bitmaps have had a chance to
+                // cover them.
+                ObjectType::Tree => trees.push(sha),
+                ObjectType::Blob => bits.set(position),
+            }
+        }
+
+        while let Some(sha) = trees.pop() {
+            let Some(position) = bitmap.position(&sha) else {
+                return Ok(None);
+            };
+            if bits.get(position) {
+                continue;
+            }
+            bits.set(position);
+            let tree = self
+                .read_object(&sha)?
+                .as_tree()
+                .ok_or_else(|| anyhow!("failed to parse tree {}", sha))?;
+            for entry in tree.entries {
+                match entry.mode {
+                    EntryMode::SubDirectory => trees.push(entry.sha),
+                    // Submodule commits live in another repository.
+                    EntryMode::Gitlink => {}
+                    _ => match bitmap.position(&entry.sha) {
+                        Some(position) => bits.set(position),
+                        None => return Ok(None),
+                    },
+                }
+            }
+        }
+        Ok(Some(bits))
+    }
+
+    ///
+    /// Lists the objects reachable from `wants` but not `haves` using the
+    /// bitmaps, in the order they're stored in the pack. Returns `None`
+    /// without bitmaps, or when the answer isn't all in their pack.
+    ///
+    pub(super) fn objects_between_with_bitmap(
+        &self,
+        wants: &[Sha],
+        haves: &[Sha],
+    ) -> Result<Option<Vec<Sha>>> {
+        let Some(bitmap) = &self.bitmap else {
+            return Ok(None);
+        };
+        let Some(mut objects) = self.reach(bitmap, wants)? else {
+            return Ok(None);
+        };
+        // The client may claim objects we've never heard of.
+        let haves = haves
+            .iter()
+            .filter(|sha| bitmap.position(sha).is_some())
+            .copied()
+            .collect::<Vec<_>>();
+        let Some(hidden) = self.reach(bitmap, &haves)? else {
+            return Ok(None);
+        };
+        objects.and_not(&hidden);
+        Ok(Some(
+            objects
+                .ones()
+                .map(|position| *bitmap.object_at(position))
+                .collect(),
+        ))
+    }
+
+    ///
+    /// Counts the commits reachable from the included revisions but not the
+    /// excluded ones using the bitmaps, or `None` if they can't answer.
+    ///
+    pub fn count_commits_with_bitmap(&self, revs: &[RevArg]) -> Result<Option<usize>> {
+        let Some(bitmap) = &self.bitmap else {
+            return Ok(None);
+        };
+        let (hidden, shown): (Vec<_>, Vec<_>) = revs.iter().partition(|rev| rev.exclude);
+        let shas = |revs: Vec<&RevArg>| revs.into_iter().map(|rev| rev.sha).collect::<Vec<_>>();
+        let Some(mut commits) = self.reach(bitmap, &shas(shown))? else {
+            return Ok(None);
+        };
+        let Some(hidden) = self.reach(bitmap, &shas(hidden))? else {
+            return Ok(None);
+        };
+        commits.and_not(&hidden);
+        commits.and(bitmap.of_type(ObjectType::Commit));
+        Ok(Some(commits.count_ones()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_support::copy_repo;
+
+    #[test]
+    fn writing_bitmaps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        let repo = Repo::open(&path).unwrap();
+        assert!(repo.bitmap.is_none());
+        let head = repo.rev_parse("HEAD").unwrap();
+        let test = repo.rev_parse("test").unwrap();
+        let all = repo.objects_between(&[head], &[]).unwrap();
+        let mut walked = repo.objects_between(&[head], &[test]).unwrap();
+        repo.write_bitmap(&repo.packs[0], &[head]).unwrap();
+
+        let repo = Repo::open(&path).unwrap();
+        let bitmap = repo.bitmap.as_ref().expect("bitmap was written");
+        assert_eq!(bitmap.of_type(ObjectType::Commit).count_ones(), 8);
+        let reachable = bitmap.reachable(&head).expect("HEAD has a bitmap");
+        assert_eq!(reachable.count_ones(), all.len());
+
+        let mut listed = repo
+            .objects_between_with_bitmap(&[head], &[test])
+            .unwrap()
+            .unwrap();
+        listed.sort();
+        walked.sort();
+        assert_eq!(listed, walked);
+
+        let revs = repo.parse_rev_args(&["test..HEAD"]).unwrap();
+        assert_eq!(repo.count_commits_with_bitmap(&revs).unwrap(), Some(6));
+    }
+
+    #[test]
+    fn ignoring_corrupt_bitmaps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        let repo = Repo::open(&path).unwrap();
+        let head = repo.rev_parse("HEAD").unwrap();
+        repo.write_bitmap(&repo.packs[0], &[head]).unwrap();
+        let bitmap = path.join(format!("objects/pack/pack-{}.bitmap", repo.packs[0].sha()));
+        let contents = fs::read(&bitmap).unwrap();
+        fs::write(&bitmap, &contents[..contents.len() / 2]).unwrap();
+
+        let repo = Repo::open(&path).unwrap();
+        assert!(repo.bitmap.is_none());
+        let revs = repo.parse_rev_args(&["test..HEAD"]).unwrap();
+        assert_eq!(repo.count_commits_with_bitmap(&revs).unwrap(), None);
+        assert!(!repo.objects_between(&[head], &[]).unwrap().is_empty());
+    }
+}
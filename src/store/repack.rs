@@ -0,0 +1,333 @@
+use std::collections::{
+    BTreeMap,
+    HashMap,
+    HashSet,
+};
+use std::fs;
+use std::io::{
+    self,
+    Cursor,
+};
+use std::path::Path;
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+
+use crate::packfile::{
+    reflog,
+    refs,
+};
+use crate::packfile::{
+    DeltaBase,
+    PackFile,
+    PackWriter,
+};
+use crate::progress::Progress;
+use crate::store::{
+    read_index,
+    resolve_ref,
+    EntryMode,
+    PackedObject,
+    Repo,
+    Sha,
+};
+
+impl Repo {
+    ///
+    /// Packs everything reachable into a single new pack, writing bitmaps
+    /// for it too if asked. Objects already stored as deltas against others
+    /// being packed stay that way. With `delete_old`, the packs and loose
+    /// objects made redundant by it are then removed.
+    ///
+    pub fn repack(&self, delete_old: bool, write_bitmap: bool) -> Result<()> {
+        let mut ref_tips = Vec::new();
+        for r in refs::read_refs(self.gitdir())? {
+            ref_tips.push(Sha::from_hex(r.id.as_bytes())?);
+        }
+        let tips = self.repack_tips(&ref_tips)?;
+
+        let objects = self.objects_between(&tips, &[])?;
+        let mut deltas = self.reusable_deltas(&objects)?;
+        let mut progress = Progress::new("Writing objects", Some(objects.len()));
+        let mut writer = PackWriter::new(Vec::new(), objects.len())?;
+        let mut written = HashSet::new();
+        for sha in &objects {
+            if !deltas.contains_key(sha) {
+                writer.write_object(&self.read_object(sha)?)?;
+                written.insert(*sha);
+                progress.inc();
+            }
+        }
+        // Each delta has to follow its base to be indexed.
+        while !deltas.is_empty() {
+            let ready = deltas
+                .iter()
+                .filter(|(_, (base, _))| written.contains(base))
+                .map(|(sha, _)| *sha)
+                .collect::<Vec<_>>();
+            if ready.is_empty() {
+                // Packs storing a pair of objects as deltas of each other
+                // make a cycle, broken by writing one of them whole.
+                let sha = *deltas.keys().next().expect("deltas is nonempty");
+                deltas.remove(&sha);
+                writer.write_object(&self.read_object(&sha)?)?;
+                written.insert(sha);
+                progress.inc();
+            }
+            for sha in ready {
+                let (base, patch) = deltas.remove(&sha).expect("ready deltas are pending");
+                writer.write_ref_delta(&base, &patch)?;
+                written.insert(sha);
+                progress.inc();
+            }
+        }
+        progress.finish();
+        let (_, contents) = writer.finish()?;
+        let pack = PackFile::parse(&contents)?;
+        pack.write(&self.gitdir)?;
+
+        if write_bitmap {
+            self.write_bitmap(&pack, &ref_tips)?;
+        }
+        if delete_old {
+            self.remove_redundant(&pack)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Everything a repack has to keep, besides the refs: HEAD even when
+    /// detached, every value the reflogs remember and the staged blobs.
+    ///
+    fn repack_tips(&self, ref_tips: &[Sha]) -> Result<Vec<Sha>> {
+        let mut tips = ref_tips.to_vec();
+        // HEAD may be unborn.
+        if let Ok(head) = resolve_ref(&self.gitdir, "HEAD") {
+            tips.push(head);
+        }
+        for name in reflog::logged_refs(&self.gitdir)? {
+            for entry in reflog::read(&self.gitdir, &name)? {
+                tips.push(entry.old);
+                tips.push(entry.new);
+            }
+        }
+        match fs::read(self.gitdir.join("index")) {
+            Ok(contents) => {
+                let index = read_index(Cursor::new(&contents[..]))?;
+                for entry in index.entries {
+                    if entry.file_mode != EntryMode::Gitlink {
+                        tips.push(entry.sha);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        // Reflogs start from the null SHA, and may remember objects which
+        // are already gone.
+        tips.retain(|sha| self.has_object(sha));
+        tips.sort();
+        tips.dedup();
+        Ok(tips)
+    }
+
+    ///
+    /// Finds which of `objects` are stored as deltas against others among
+    /// them, keyed by SHA with the base and patch each one uses.
+    ///
+    fn reusable_deltas(&self, objects: &[Sha]) -> Result<BTreeMap<Sha, (Sha, Vec<u8>)>> {
+        let wanted = objects.iter().collect::<HashSet<_>>();
+        // What is at each offset of the packs with offset deltas.
+        let mut packed_at = HashMap::new();
+        let mut deltas = BTreeMap::new();
+        for sha in objects {
+            let Some((pack, offset)) = self.find_packed(sha) else {
+                continue;
+            };
+            let Some((base, patch)) = pack.find_delta_by_offset(offset)? else {
+                continue;
+            };
+            let base = match base {
+                DeltaBase::Sha(base) => base,
+                DeltaBase::Offset(base) => *packed_at
+                    .entry(*pack.sha())
+                    .or_insert_with(|| {
+                        pack.index
+                            .entries()
+                            .map(|(sha, offset)| (offset, *sha))
+                            .collect::<HashMap<_, _>>()
+                    })
+                    .get(&base)
+                    .ok_or_else(|| anyhow!("no object at offset {} of {}", base, pack.sha()))?,
+            };
+            if wanted.contains(&base) {
+                deltas.insert(*sha, (base, patch));
+            }
+        }
+        Ok(deltas)
+    }
+
+    ///
+    /// Removes every pack but the one given and those with a `.keep` file,
+    /// then the loose objects it holds. Whatever else the removed packs hold
+    /// is written out loose first, so that nothing unreachable is lost.
+    ///
+    fn remove_redundant(&self, pack: &PackFile) -> Result<()> {
+        let objects_dir = self.gitdir.join("objects");
+        let name = format!("pack-{}", pack.sha());
+        for path in Repo::find_packfiles(&objects_dir)? {
+            if path.file_stem().is_some_and(|stem| *stem == *name)
+                || path.with_extension("keep").exists()
+            {
+                continue;
+            }
+            let old = PackFile::open(&path)?;
+            for (sha, offset) in old.index.entries() {
+                if !pack.contains(sha) && !PackedObject::exists(&objects_dir, sha) {
+                    old.find_by_offset(offset)?.write(&self.gitdir)?;
+                }
+            }
+            for ext in &["pack", "idx", "bitmap"] {
+                remove_if_exists(&path.with_extension(ext))?;
+            }
+        }
+        // It would name the packs just removed.
+        remove_if_exists(&objects_dir.join("pack/multi-pack-index"))?;
+
+        for dir_entry in fs::read_dir(&objects_dir)? {
+            let dir = dir_entry?.path();
+            let prefix = match dir.file_name().and_then(|name| name.to_str()) {
+                Some(prefix) if prefix.len() == 2 && dir.is_dir() => prefix.to_owned(),
+                _ => continue,
+            };
+            for file_entry in fs::read_dir(&dir)? {
+                let path = file_entry?.path();
+                let name = path.file_name().and_then(|name| name.to_str());
+                let sha = match name
+                    .map(|name| Sha::from_hex(format!("{}{}", prefix, name).as_bytes()))
+                {
+                    Some(Ok(sha)) => sha,
+                    _ => continue,
+                };
+                if pack.contains(&sha) {
+                    fs::remove_file(&path)?;
+                }
+            }
+            // Only succeeds once the directory is empty.
+            let _ = fs::remove_dir(&dir);
+        }
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::ObjectType;
+    use crate::test_support::copy_repo;
+
+    fn count_deltas(pack: &PackFile) -> usize {
+        pack.index
+            .entries()
+            .filter(|(_, offset)| pack.find_delta_by_offset(*offset).unwrap().is_some())
+            .count()
+    }
+
+    #[test]
+    fn repacking_with_bitmaps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        let repo = Repo::open(&path).unwrap();
+        let head = repo.rev_parse("HEAD").unwrap();
+        let mut reachable = repo.objects_between(&[head], &[]).unwrap();
+        let old_deltas = count_deltas(&repo.packs[0]);
+        assert!(old_deltas > 0);
+        // A loose copy of a packed object is redundant too.
+        let tree = repo.commit_tree(&head).unwrap();
+        repo.read_object(&tree).unwrap().write(&path).unwrap();
+        let loose = path.join("objects").join(&tree.hex()[..2]);
+        assert!(loose.exists());
+        repo.repack(true, true).unwrap();
+        assert!(!loose.exists());
+
+        let repo = Repo::open(&path).unwrap();
+        assert_eq!(repo.packs.len(), 1);
+        assert_eq!(count_deltas(&repo.packs[0]), old_deltas);
+        let bitmap = repo.bitmap.as_ref().expect("bitmap was written");
+        assert_eq!(bitmap.of_type(ObjectType::Commit).count_ones(), 8);
+        let mut listed = repo.objects_between(&[head], &[]).unwrap();
+        listed.sort();
+        reachable.sort();
+        assert_eq!(listed, reachable);
+    }
+
+    #[test]
+    fn keeping_what_refs_dont_reach() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        let repo = Repo::open(&path).unwrap();
+        let head = repo.rev_parse("HEAD").unwrap();
+
+        // A commit only the reflog remembers...
+        let content = format!(
+            "tree {}\nparent {}\nauthor a <a@b> 0 +0000\ncommitter a <a@b> 0 +0000\n\nlost\n",
+            repo.commit_tree(&head).unwrap(),
+            head
+        );
+        let lost = PackedObject::new(ObjectType::Commit, content.into_bytes());
+        lost.write(&path).unwrap();
+        fs::create_dir_all(path.join("logs")).unwrap();
+        fs::write(
+            path.join("logs/HEAD"),
+            format!("{} {} a <a@b> 0 +0000\tcommit: lost\n", head, lost.sha()),
+        )
+        .unwrap();
+        // ...and blobs nothing does, one loose and one in another pack.
+        let loose = PackedObject::new(ObjectType::Blob, b"loose\n".to_vec());
+        loose.write(&path).unwrap();
+        let packed = PackedObject::new(ObjectType::Blob, b"packed\n".to_vec());
+        let mut writer = PackWriter::new(Vec::new(), 1).unwrap();
+        writer.write_object(&packed).unwrap();
+        let (_, contents) = writer.finish().unwrap();
+        PackFile::parse(&contents).unwrap().write(&path).unwrap();
+
+        Repo::open(&path).unwrap().repack(true, false).unwrap();
+
+        let repo = Repo::open(&path).unwrap();
+        assert_eq!(repo.packs.len(), 1);
+        assert!(repo.packs[0].contains(&lost.sha()));
+        let objects_dir = path.join("objects");
+        assert!(PackedObject::exists(&objects_dir, &loose.sha()));
+        assert!(PackedObject::exists(&objects_dir, &packed.sha()));
+    }
+
+    #[test]
+    fn leaving_kept_packs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = copy_repo(tmp.path());
+        let blob = PackedObject::new(ObjectType::Blob, b"kept\n".to_vec());
+        let mut writer = PackWriter::new(Vec::new(), 1).unwrap();
+        writer.write_object(&blob).unwrap();
+        let (_, contents) = writer.finish().unwrap();
+        let kept = PackFile::parse(&contents).unwrap();
+        kept.write(&path).unwrap();
+        let kept_path = path.join(format!("objects/pack/pack-{}.pack", kept.sha()));
+        fs::write(kept_path.with_extension("keep"), "").unwrap();
+
+        Repo::open(&path).unwrap().repack(true, false).unwrap();
+
+        let repo = Repo::open(&path).unwrap();
+        assert_eq!(repo.packs.len(), 2);
+        assert!(kept_path.exists());
+        assert!(!PackedObject::exists(path.join("objects"), &blob.sha()));
+    }
+}
@@ -0,0 +1,659 @@
+//!
+//! Parsing git's revision syntax, as described in gitrevisions(7).
+//!
+use std::collections::BinaryHeap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result,
+};
+use regex::Regex;
+
+use crate::packfile::reflog;
+use crate::packfile::refs;
+use crate::remote::refspec::Refspec;
+use crate::store::commit_graph::GENERATION_INFINITY;
+use crate::store::{
+    self,
+    ObjectType,
+    Repo,
+    RevWalk,
+    Sha,
+};
+
+/// The shortest abbreviated SHA we'll look up.
+const MIN_ABBREV_LEN: usize = 4;
+
+///
+/// A revision given on the command line. The commits reachable from it are
+/// either included or, for `^<rev>` and revisions following `--not`,
+/// excluded.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RevArg {
+    pub sha: Sha,
+    pub exclude: bool,
+}
+
+impl Repo {
+    ///
+    /// Resolves a revision to the object it names. Besides SHAs, abbreviated
+    /// SHAs and ref names, this understands:
+    ///
+    /// -- <ref>@{<n>}, <ref>@{<date>}   an earlier value from the reflog
+    /// -- <branch>@{upstream}, @{u}     the branch it tracks
+    /// -- @{-<n>}                       the nth branch checked out before this one
+    /// -- <rev>~<n>                     the nth first-parent ancestor
+    /// -- <rev>^<n>                     the nth parent
+    /// -- <rev>^{<type>}, <rev>^{}      the rev peeled to an object type
+    /// -- <rev>:<path>                  the object at a path in the rev's tree
+    /// -- :/<regex>                     the youngest commit whose message matches
+    ///
+    pub fn rev_parse(&self, spec: &str) -> Result<Sha> {
+        self.parse_revision(spec)
+            .with_context(|| format!("unknown revision '{}'", spec))
+    }
+
+    ///
+    /// Resolves revision arguments as given to rev-list:
+    ///
+    /// -- <rev>        include <rev>
+    /// -- ^<rev>       exclude <rev>
+    /// -- <a>..<b>     include <b> but exclude <a>
+    /// -- <a>...<b>    include both, excluding their merge bases
+    /// -- --all        include every ref
+    /// -- --not        flip the sense of the arguments that follow
+    ///
+    /// Either side of a range may be left out to mean HEAD.
+    ///
+    pub fn parse_rev_args<S: AsRef<str>>(&self, args: &[S]) -> Result<Vec<RevArg>> {
+        let mut revs = Vec::new();
+        let mut negated = false;
+        for arg in args {
+            match arg.as_ref() {
+                "--not" => negated = !negated,
+                "--all" => {
+                    for r in refs::read_refs(self.gitdir())? {
+                        revs.push(RevArg {
+                            sha: Sha::from_hex(r.id.as_bytes())?,
+                            exclude: negated,
+                        });
+                    }
+                }
+                arg => self.parse_rev_arg(arg, negated, &mut revs)?,
+            }
+        }
+        Ok(revs)
+    }
+
+    fn parse_rev_arg(&self, arg: &str, negated: bool, revs: &mut Vec<RevArg>) -> Result<()> {
+        fn or_head(side: &str) -> &str {
+            if side.is_empty() {
+                "HEAD"
+            } else {
+                side
+            }
+        }
+        let mut push = |sha, exclude: bool| {
+            revs.push(RevArg {
+                sha,
+                exclude: exclude != negated,
+            })
+        };
+        // Messages searched for with :/ may contain dots of their own.
+        if arg.starts_with(":/") {
+            push(self.rev_parse(arg)?, false);
+        } else if let Some((a, b)) = arg.split_once("...") {
+            let a = self.rev_parse(or_head(a))?;
+            let b = self.rev_parse(or_head(b))?;
+            let bases = self.merge_bases(self.peel_to(a, "commit")?, self.peel_to(b, "commit")?)?;
+            push(b, false);
+            push(a, false);
+            for base in bases {
+                push(base, true);
+            }
+        } else if let Some((a, b)) = arg.split_once("..") {
+            let a = self.rev_parse(or_head(a))?;
+            push(self.rev_parse(or_head(b))?, false);
+            push(a, true);
+        } else if let Some(rev) = arg.strip_prefix('^') {
+            push(self.rev_parse(rev)?, true);
+        } else {
+            push(self.rev_parse(arg)?, false);
+        }
+        Ok(())
+    }
+
+    ///
+    /// The full name of the ref a revision names, such as `refs/heads/main`
+    /// for `main` or `refs/remotes/origin/main` for `main@{upstream}`. This is
+    /// `None` for a detached HEAD and anything which isn't a ref.
+    ///
+    pub fn symbolic_full_name(&self, spec: &str) -> Result<Option<String>> {
+        let gitdir = self.gitdir();
+        if spec == "HEAD" || spec == "@" {
+            return refs::read_head_target(gitdir);
+        }
+        let selector = spec
+            .strip_suffix('}')
+            .and_then(|rest| rest.rsplit_once("@{"));
+        match selector {
+            Some((name, selector))
+                if matches!(selector.to_ascii_lowercase().as_str(), "upstream" | "u") =>
+            {
+                self.upstream_ref(name).map(Some)
+            }
+            Some(_) => Ok(None),
+            None => store::expand_ref_name(gitdir, spec),
+        }
+    }
+
+    ///
+    /// The shortest prefix of `sha`, at least `min_len` digits long, which
+    /// names no other object.
+    ///
+    pub fn abbreviate(&self, sha: &Sha, min_len: usize) -> String {
+        let hex = sha.hex();
+        (min_len.max(MIN_ABBREV_LEN)..hex.len())
+            .map(|len| &hex[..len])
+            .find(|prefix| self.find_abbreviated(prefix).is_ok())
+            .unwrap_or(&hex)
+            .to_owned()
+    }
+
+    ///
+    /// The best common ancestors of two commits: those shared by both which
+    /// aren't themselves ancestors of another shared commit.
+    ///
+    /// They're found by walking `a`'s history with `b`'s hidden. The walk's
+    /// boundary holds the shared commits nearest to `a`, and every best
+    /// common ancestor is among them.
+    ///
+    pub fn merge_bases(&self, a: Sha, b: Sha) -> Result<Vec<Sha>> {
+        if a == b {
+            return Ok(vec![a]);
+        }
+        let mut walk = RevWalk::new(self);
+        walk.push(a)?;
+        walk.hide(b)?;
+        for commit in walk.by_ref() {
+            commit?;
+        }
+        if walk.hidden().any(|sha| *sha == a) {
+            return Ok(vec![a]);
+        }
+
+        let found = walk.boundary();
+        let mut bases = Vec::new();
+        for sha in &found {
+            let mut redundant = false;
+            for other in found.iter().filter(|other| *other != sha) {
+                if self.is_ancestor(*sha, *other)? {
+                    redundant = true;
+                    break;
+                }
+            }
+            if !redundant {
+                bases.push(*sha);
+            }
+        }
+        Ok(bases)
+    }
+
+    ///
+    /// Whether `ancestor` is reachable from `descendant`, or is the same
+    /// commit. Generation numbers cut the search short: nothing below a
+    /// commit in the graph has as high a generation as it does.
+    ///
+    pub fn is_ancestor(&self, ancestor: Sha, descendant: Sha) -> Result<bool> {
+        let min_generation = self.commit_info(&ancestor)?.generation;
+        let mut seen = HashSet::new();
+        let mut pending = vec![descendant];
+        while let Some(sha) = pending.pop() {
+            if sha == ancestor {
+                return Ok(true);
+            }
+            if !seen.insert(sha) {
+                continue;
+            }
+            let info = self.commit_info(&sha)?;
+            // Commits outside the graph are never below ones inside it, so
+            // this holds even when the ancestor's generation is infinite.
+            if info.generation != GENERATION_INFINITY && info.generation <= min_generation {
+                continue;
+            }
+            pending.extend(info.parents);
+        }
+        Ok(false)
+    }
+
+    fn parse_revision(&self, spec: &str) -> Result<Sha> {
+        if let Some(pattern) = spec.strip_prefix(":/") {
+            return self.find_by_message(pattern);
+        }
+        if let Some(i) = find_outside_braces(spec, |c| c == ':') {
+            let (rev, path) = (&spec[..i], &spec[i + 1..]);
+            if rev.is_empty() {
+                return Err(anyhow!("paths in the index aren't supported"));
+            }
+            let tree = self.peel_to(self.parse_revision(rev)?, "tree")?;
+            return self.find_path(tree, path);
+        }
+
+        let end = find_outside_braces(spec, |c| c == '~' || c == '^').unwrap_or(spec.len());
+        let mut sha = self.resolve_base(&spec[..end])?;
+        let mut rest = &spec[end..];
+        while let Some(op) = rest.chars().next() {
+            if op != '~' && op != '^' {
+                return Err(anyhow!("unexpected '{}' in {}", op, spec));
+            }
+            rest = &rest[1..];
+            if op == '^' && rest.starts_with('{') {
+                let close = rest
+                    .find('}')
+                    .ok_or_else(|| anyhow!("missing '}}' in {}", spec))?;
+                sha = self.peel_to(sha, &rest[1..close])?;
+                rest = &rest[close + 1..];
+                continue;
+            }
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let n = match &rest[..digits] {
+                "" => 1,
+                n => n.parse::<usize>()?,
+            };
+            rest = &rest[digits..];
+            let commit = self.peel_to(sha, "commit")?;
+            sha = match op {
+                '~' => (0..n).try_fold(commit, |sha, _| self.parent(sha, 1))?,
+                '^' if n == 0 => commit,
+                _ => self.parent(commit, n)?,
+            };
+        }
+        Ok(sha)
+    }
+
+    ///
+    /// Resolves the part of a revision before any `~`, `^` or `:`.
+    ///
+    fn resolve_base(&self, base: &str) -> Result<Sha> {
+        let gitdir = self.gitdir();
+        if base.is_empty() || base == "@" {
+            return store::resolve_ref(gitdir, "HEAD");
+        }
+        let selector = base
+            .strip_suffix('}')
+            .and_then(|rest| rest.rsplit_once("@{"));
+        if let Some((name, selector)) = selector {
+            return match selector.to_ascii_lowercase().as_str() {
+                "upstream" | "u" => self.upstream(name),
+                _ if selector.starts_with('-') && name.is_empty() => {
+                    let n = selector[1..].parse::<usize>()?;
+                    self.parse_revision(&self.previous_branch(n)?)
+                }
+                _ => {
+                    let name = if name.is_empty() || name == "@" {
+                        "HEAD".to_owned()
+                    } else {
+                        store::expand_ref_name(gitdir, name)?
+                            .ok_or_else(|| anyhow!("no such ref: {}", name))?
+                    };
+                    reflog::lookup(gitdir, &name, selector)
+                }
+            };
+        }
+        if store::is_hex_sha(base) {
+            return Ok(Sha::from_hex(base.as_bytes())?);
+        }
+        if store::expand_ref_name(gitdir, base)?.is_some() {
+            return store::resolve_ref(gitdir, base);
+        }
+        if base.len() >= MIN_ABBREV_LEN && base.chars().all(|c| c.is_ascii_hexdigit()) {
+            return self.find_abbreviated(&base.to_ascii_lowercase());
+        }
+        Err(anyhow!("no such ref or object: {}", base))
+    }
+
+    ///
+    /// Finds the one object whose SHA starts with `prefix`, looking through
+    /// loose objects and packs alike.
+    ///
+    fn find_abbreviated(&self, prefix: &str) -> Result<Sha> {
+        let mut matches = HashSet::new();
+        let (dir, rest) = prefix.split_at(2);
+        for objects_dir in self.objects_dirs() {
+            let entries = match fs::read_dir(objects_dir.join(dir)) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let file_name = entry?.file_name();
+                let file_name = file_name.to_string_lossy();
+                if file_name.starts_with(rest) {
+                    let hex = format!("{}{}", dir, file_name);
+                    if let Ok(sha) = Sha::from_hex(hex.as_bytes()) {
+                        matches.insert(sha);
+                    }
+                }
+            }
+        }
+        for pack in &self.packs {
+            matches.extend(pack.index.find_prefix(prefix));
+        }
+        match matches.len() {
+            0 => Err(anyhow!("no object starts with {}", prefix)),
+            1 => Ok(matches.into_iter().next().unwrap()),
+            _ => Err(anyhow!("short SHA1 {} is ambiguous", prefix)),
+        }
+    }
+
+    ///
+    /// Peels `sha` to the given type, following tags and going from commits
+    /// to their trees as needed. An empty type peels tags to whatever they
+    /// point to.
+    ///
+    pub fn peel_to(&self, sha: Sha, type_name: &str) -> Result<Sha> {
+        let mut sha = sha;
+        loop {
+            let object = self.read_object(&sha)?;
+            let found = match type_name {
+                "object" => true,
+                "" => !matches!(object.obj_type, ObjectType::Tag),
+                "tag" => matches!(object.obj_type, ObjectType::Tag),
+                "commit" => matches!(object.obj_type, ObjectType::Commit),
+                "tree" => matches!(object.obj_type, ObjectType::Tree),
+                "blob" => matches!(object.obj_type, ObjectType::Blob),
+                _ => return Err(anyhow!("unknown object type {}", type_name)),
+            };
+            if found {
+                return Ok(sha);
+            }
+            match object.obj_type {
+                ObjectType::Tag => {
+                    sha = object
+                        .as_tag()
+                        .ok_or_else(|| anyhow!("failed to parse tag {}", sha))?
+                        .object;
+                }
+                ObjectType::Commit if type_name == "tree" => {
+                    return Ok(object
+                        .as_commit()
+                        .ok_or_else(|| anyhow!("failed to parse commit {}", sha))?
+                        .tree);
+                }
+                _ => return Err(anyhow!("{} can't be peeled to a {}", sha, type_name)),
+            }
+        }
+    }
+
+    ///
+    /// The nth parent of a commit, counting from 1.
+    ///
+    fn parent(&self, sha: Sha, n: usize) -> Result<Sha> {
+        let object = self.read_object(&sha)?;
+        let commit = object
+            .as_commit()
+            .ok_or_else(|| anyhow!("{} is not a commit", sha))?;
+        commit
+            .parents
+            .get(n - 1)
+            .copied()
+            .ok_or_else(|| anyhow!("{} has no parent {}", sha, n))
+    }
+
+    ///
+    /// Looks up a slash separated path in a tree.
+    ///
+    fn find_path(&self, tree: Sha, path: &str) -> Result<Sha> {
+        self.find_path_entry(tree, path)?
+            .ok_or_else(|| anyhow!("path '{}' does not exist", path))
+    }
+
+    ///
+    /// Looks up a slash separated path in a tree, or `None` if there's
+    /// nothing there.
+    ///
+    pub fn find_path_entry(&self, tree: Sha, path: &str) -> Result<Option<Sha>> {
+        let mut sha = tree;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            let tree = match self.read_object(&sha)?.as_tree() {
+                Some(tree) => tree,
+                None => return Ok(None),
+            };
+            match tree.entries.iter().find(|entry| entry.path == component) {
+                Some(entry) => sha = entry.sha,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(sha))
+    }
+
+    ///
+    /// The remote-tracking branch `branch` merges from, as set by
+    /// `branch.<name>.remote` and `branch.<name>.merge`. An empty name
+    /// means the current branch.
+    ///
+    fn upstream(&self, branch: &str) -> Result<Sha> {
+        store::resolve_ref(self.gitdir(), &self.upstream_ref(branch)?)
+    }
+
+    fn upstream_ref(&self, branch: &str) -> Result<String> {
+        let gitdir = self.gitdir();
+        let full_name = if branch.is_empty() || branch == "HEAD" || branch == "@" {
+            refs::read_head_target(gitdir)?.ok_or_else(|| anyhow!("HEAD is detached"))?
+        } else {
+            store::expand_ref_name(gitdir, branch)?
+                .ok_or_else(|| anyhow!("no such branch: {}", branch))?
+        };
+        let short_name = full_name
+            .strip_prefix("refs/heads/")
+            .ok_or_else(|| anyhow!("{} is not a branch", full_name))?;
+
+        let config = self.config()?;
+        let no_upstream = || anyhow!("no upstream configured for branch '{}'", short_name);
+        let remote = config
+            .get(&format!("branch.{}.remote", short_name))
+            .ok_or_else(no_upstream)?;
+        let merge = config
+            .get(&format!("branch.{}.merge", short_name))
+            .ok_or_else(no_upstream)?;
+        // A remote of "." means the upstream is another local branch.
+        if remote == "." {
+            return Ok(merge.to_owned());
+        }
+        for fetch in config.get_all(&format!("remote.{}.fetch", remote)) {
+            if let Some(tracking) = Refspec::parse(fetch)?.map(merge) {
+                return Ok(tracking);
+            }
+        }
+        Err(anyhow!(
+            "upstream branch '{}' is not stored as a remote-tracking branch",
+            merge
+        ))
+    }
+
+    ///
+    /// The nth branch (or commit) checked out before the current one,
+    /// according to the "checkout: moving from <old> to <new>" entries in
+    /// HEAD's reflog.
+    ///
+    fn previous_branch(&self, n: usize) -> Result<String> {
+        let log = reflog::read(self.gitdir(), "HEAD")?;
+        log.iter()
+            .rev()
+            .filter_map(|entry| entry.message.strip_prefix("checkout: moving from "))
+            .filter_map(|moved| moved.split_once(" to "))
+            .map(|(from, _)| from.to_owned())
+            .nth(
+                n.checked_sub(1)
+                    .ok_or_else(|| anyhow!("@{{-0}} is not valid"))?,
+            )
+            .ok_or_else(|| anyhow!("only {} checkouts in the reflog", log.len()))
+    }
+
+    ///
+    /// Searches the history of every ref, youngest commit first, for a
+    /// commit whose message matches `pattern`.
+    ///
+    fn find_by_message(&self, pattern: &str) -> Result<Sha> {
+        let regex = Regex::new(pattern).map_err(|e| anyhow!("invalid regex: {}", e))?;
+        let mut queue = BinaryHeap::new();
+        let mut seen = HashSet::new();
+        for r in refs::read_refs(self.gitdir())? {
+            let sha = Sha::from_hex(r.id.as_bytes())?;
+            if let Ok(commit) = self.peel_to(sha, "commit") {
+                if seen.insert(commit) {
+                    queue.push((self.commit_time(commit)?, commit));
+                }
+            }
+        }
+        while let Some((_, sha)) = queue.pop() {
+            let object = self.read_object(&sha)?;
+            let commit = object
+                .as_commit()
+                .ok_or_else(|| anyhow!("failed to parse commit {}", sha))?;
+            if regex.is_match(commit.message()) {
+                return Ok(sha);
+            }
+            for parent in &commit.parents {
+                if seen.insert(*parent) {
+                    queue.push((self.commit_time(*parent)?, *parent));
+                }
+            }
+        }
+        Err(anyhow!("no commit message matches {}", pattern))
+    }
+
+    fn commit_time(&self, sha: Sha) -> Result<i64> {
+        let object = self.read_object(&sha)?;
+        object
+            .as_commit()
+            .map(|c| c.commit_time())
+            .ok_or_else(|| anyhow!("failed to parse commit {}", sha))
+    }
+}
+
+///
+/// Finds the first character matching `pred` which isn't inside braces,
+/// so that `master@{2 days ago}~1` splits at the `~`.
+///
+fn find_outside_braces(spec: &str, pred: impl Fn(char) -> bool) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            c if depth == 0 && pred(c) => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rev_parse(spec: &str) -> Result<String> {
+        let repo = Repo::open("tests/data/repos/simple.git").unwrap();
+        repo.rev_parse(spec).map(|sha| sha.hex())
+    }
+
+    #[test]
+    fn parsing_revisions() {
+        let cases = [
+            ("HEAD", "33676d1c63d868803ed110b13be4e616bc8a29b7"),
+            ("@", "33676d1c63d868803ed110b13be4e616bc8a29b7"),
+            ("3367", "33676d1c63d868803ed110b13be4e616bc8a29b7"),
+            ("master~1", "59ca2198a5023edc1d154d2abb2478e0cf293bc4"),
+            ("HEAD~3", "8f714d94b892dfd51c1ca4694e0cafcf266e57e8"),
+            ("HEAD~4^2", "718e7fc194a0fef1b1067b12689e5d343f533497"),
+            ("HEAD~4^^", "fb6fb3d9b81142566f4b2466857b0302617768de"),
+            ("HEAD^0", "33676d1c63d868803ed110b13be4e616bc8a29b7"),
+            (
+                "refs/heads/test~1",
+                "fb6fb3d9b81142566f4b2466857b0302617768de",
+            ),
+            ("test_tag", "7a4219fa5df9550fa54636f2783cd7c3cb63b1f3"),
+            ("test_tag^{}", "3c7cfac73a699ef415bc737ce5529ac66c5692a9"),
+            (
+                "test_tag^{tree}",
+                "96d72bfdd10a7260efe7374c0efea711e6c5af59",
+            ),
+            ("test_tag~1", "2f2466ca0129f2b8fec6bb12cb99c2eba9778639"),
+            ("master^{tree}", "98da678f32497f85ca57bb35886f863a4f0c2239"),
+            ("HEAD:git.txt", "7e690abcc93718dbf26ddea5c6ede644a63a5b34"),
+            (
+                "HEAD:a/b/c/bar.sh",
+                "bfeeb05e7ab0c96e478cebffe0db86f0ef388610",
+            ),
+            // The youngest match wins.
+            (":/added line", "718e7fc194a0fef1b1067b12689e5d343f533497"),
+        ];
+        for (spec, expected) in cases {
+            assert_eq!(rev_parse(spec).unwrap(), expected, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn rejecting_bad_revisions() {
+        for spec in [
+            "missing",
+            "HEAD^2",
+            "HEAD~100",
+            "HEAD:missing.txt",
+            "HEAD^{blob}",
+            "HEAD^{nonsense}",
+            "HEAD@{u}",
+            "HEAD~1é",
+            "HEAD^{tree}é",
+            ":/no such message",
+            "zzzz",
+        ] {
+            assert!(rev_parse(spec).is_err(), "accepted {}", spec);
+        }
+    }
+
+    #[test]
+    fn parsing_rev_args() {
+        let repo = Repo::open("tests/data/repos/simple.git").unwrap();
+        let parse = |args: &[&str]| -> Vec<String> {
+            let revs = repo.parse_rev_args(args).unwrap();
+            revs.iter()
+                .map(|rev| {
+                    let prefix = if rev.exclude { "^" } else { "" };
+                    format!("{}{}", prefix, &rev.sha.hex()[..7])
+                })
+                .collect()
+        };
+        assert_eq!(parse(&["HEAD~1..HEAD"]), ["33676d1", "^59ca219"]);
+        assert_eq!(parse(&["test..."]), ["33676d1", "718e7fc", "^718e7fc"]);
+        assert_eq!(
+            parse(&["master", "--not", "HEAD~2", "^HEAD~3"]),
+            ["33676d1", "^63dc0a2", "8f714d9"]
+        );
+        assert_eq!(parse(&["--all"]).len(), 4);
+    }
+
+    #[test]
+    fn finding_merge_bases() {
+        let repo = Repo::open("tests/data/repos/simple.git").unwrap();
+        let sha = |hex: &str| Sha::from_hex(hex.as_bytes()).unwrap();
+        let master = sha("2f2466ca0129f2b8fec6bb12cb99c2eba9778639");
+        let test = sha("718e7fc194a0fef1b1067b12689e5d343f533497");
+        let head = sha("33676d1c63d868803ed110b13be4e616bc8a29b7");
+        assert_eq!(
+            repo.merge_bases(master, test).unwrap(),
+            [sha("fb6fb3d9b81142566f4b2466857b0302617768de")]
+        );
+        assert_eq!(repo.merge_bases(head, test).unwrap(), [test]);
+        assert_eq!(repo.merge_bases(test, head).unwrap(), [test]);
+        assert_eq!(repo.abbreviate(&head, 4), "3367");
+    }
+}
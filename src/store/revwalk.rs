@@ -0,0 +1,452 @@
+//!
+//! Walking commit history, shared by log, rev-list, merge-base and the
+//! objects we send to fetches.
+//!
+use std::cmp::Reverse;
+use std::collections::{
+    BinaryHeap,
+    HashMap,
+    HashSet,
+    VecDeque,
+};
+
+use anyhow::Result;
+
+use crate::store::{
+    CommitInfo,
+    Repo,
+    RevArg,
+    Sha,
+};
+
+/// How many more commits to look at once everything left to walk is hidden,
+/// in case a commit with a skewed clock is still to come.
+const SLOP: usize = 5;
+
+///
+/// An iterator over the commits reachable from those pushed onto it, but not
+/// from those hidden, youngest first by committer date.
+///
+/// When any commit is hidden or topological order is asked for, the whole
+/// walk happens before the first commit comes out; otherwise commits are
+/// produced as they're found.
+///
+pub struct RevWalk<'r> {
+    repo: &'r Repo,
+    queue: BinaryHeap<(i64, Reverse<usize>, Sha)>,
+    // Commits which have been queued, counted to keep the walk stable
+    // between commits with the same date.
+    queued: usize,
+    seen: HashSet<Sha>,
+    infos: HashMap<Sha, CommitInfo>,
+    hidden: HashSet<Sha>,
+    // Commits taken off the queue, with the parents the walk went on to.
+    walked: HashMap<Sha, Vec<Sha>>,
+    first_parent: bool,
+    topo_order: bool,
+    paths: Vec<String>,
+    simplify_history: bool,
+    // The rest of the commits to produce, once a limited walk is done.
+    limited: Option<VecDeque<Sha>>,
+}
+
+impl<'r> RevWalk<'r> {
+    pub fn new(repo: &'r Repo) -> Self {
+        RevWalk {
+            repo,
+            queue: BinaryHeap::new(),
+            queued: 0,
+            seen: HashSet::new(),
+            infos: HashMap::new(),
+            hidden: HashSet::new(),
+            walked: HashMap::new(),
+            first_parent: false,
+            topo_order: false,
+            paths: Vec::new(),
+            simplify_history: true,
+            limited: None,
+        }
+    }
+
+    ///
+    /// Follow only the first parent of merge commits.
+    ///
+    pub fn first_parent(mut self, first_parent: bool) -> Self {
+        self.first_parent = first_parent;
+        self
+    }
+
+    ///
+    /// Produce no parent before all of its children, keeping each line of
+    /// history together where the date order would interleave them.
+    ///
+    pub fn topo_order(mut self, topo_order: bool) -> Self {
+        self.topo_order = topo_order;
+        self
+    }
+
+    ///
+    /// Produce only the commits which change something under the given
+    /// paths, in the sense of being different from each of their parents.
+    ///
+    pub fn limit_paths(mut self, paths: Vec<String>) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    ///
+    /// With limited paths, whether a merge which matches one of its parents
+    /// is followed only down that parent, as git does without
+    /// `--full-history`. This is on by default.
+    ///
+    pub fn simplify_history(mut self, simplify_history: bool) -> Self {
+        self.simplify_history = simplify_history;
+        self
+    }
+
+    ///
+    /// Includes the history of a commit, or of what a tag points to.
+    ///
+    pub fn push(&mut self, sha: Sha) -> Result<()> {
+        let commit = self.repo.peel_to(sha, "commit")?;
+        self.enqueue(commit)
+    }
+
+    ///
+    /// Excludes a commit and all of its ancestors.
+    ///
+    pub fn hide(&mut self, sha: Sha) -> Result<()> {
+        let commit = self.repo.peel_to(sha, "commit")?;
+        self.mark_hidden(commit)
+    }
+
+    ///
+    /// Pushes or hides each revision as given on the command line.
+    ///
+    pub fn push_revs(&mut self, revs: &[RevArg]) -> Result<()> {
+        for rev in revs {
+            if rev.exclude {
+                self.hide(rev.sha)?;
+            } else {
+                self.push(rev.sha)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// The hidden commits the walk has come across so far. Objects used by
+    /// these needn't be listed alongside the commits produced.
+    ///
+    pub fn hidden(&self) -> impl Iterator<Item = &Sha> {
+        self.hidden.iter()
+    }
+
+    ///
+    /// The hidden commits which are parents of commits the walk produced,
+    /// where the history it shows meets the history it hides. This is only
+    /// complete once the walk is done.
+    ///
+    pub fn boundary(&self) -> Vec<Sha> {
+        let mut boundary = self
+            .walked
+            .iter()
+            .filter(|(sha, _)| !self.hidden.contains(sha))
+            .flat_map(|(_, parents)| parents)
+            .filter(|parent| self.hidden.contains(parent))
+            .copied()
+            .collect::<Vec<_>>();
+        boundary.sort();
+        boundary.dedup();
+        boundary
+    }
+
+    fn info(&mut self, sha: Sha) -> Result<&CommitInfo> {
+        if !self.infos.contains_key(&sha) {
+            let info = self.repo.commit_info(&sha)?;
+            self.infos.insert(sha, info);
+        }
+        Ok(&self.infos[&sha])
+    }
+
+    fn enqueue(&mut self, sha: Sha) -> Result<()> {
+        if !self.seen.insert(sha) {
+            return Ok(());
+        }
+        let time = self.info(sha)?.time;
+        self.queue.push((time, Reverse(self.queued), sha));
+        self.queued += 1;
+        Ok(())
+    }
+
+    ///
+    /// Hides a commit along with any of its ancestors the walk has already
+    /// gone past, queueing the rest so the walk hides them in turn.
+    ///
+    fn mark_hidden(&mut self, sha: Sha) -> Result<()> {
+        let mut pending = vec![sha];
+        while let Some(sha) = pending.pop() {
+            if !self.hidden.insert(sha) {
+                continue;
+            }
+            if self.walked.contains_key(&sha) {
+                pending.extend(self.info(sha)?.parents.clone());
+            } else {
+                self.enqueue(sha)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// The parents the walk goes on to from a commit. With limited paths and
+    /// history simplification, a parent matching the commit is the only one
+    /// followed.
+    ///
+    fn parents_to_follow(&mut self, sha: Sha) -> Result<Vec<Sha>> {
+        let mut parents = self.info(sha)?.parents.clone();
+        if self.first_parent {
+            parents.truncate(1);
+        }
+        if !self.paths.is_empty() && self.simplify_history && parents.len() > 1 {
+            for parent in &parents {
+                if self.same_at_paths(sha, *parent)? {
+                    return Ok(vec![*parent]);
+                }
+            }
+        }
+        Ok(parents)
+    }
+
+    ///
+    /// Whether a commit is produced: without limited paths every commit is,
+    /// and otherwise only those which differ from each of their parents.
+    ///
+    fn is_shown(&mut self, sha: Sha) -> Result<bool> {
+        if self.paths.is_empty() {
+            return Ok(true);
+        }
+        let mut parents = self.info(sha)?.parents.clone();
+        if self.first_parent {
+            parents.truncate(1);
+        }
+        if parents.is_empty() {
+            // A root commit is shown for adding the paths.
+            let tree = self.info(sha)?.tree;
+            for path in &self.paths {
+                if self.repo.find_path_entry(tree, path)?.is_some() {
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+        for parent in parents {
+            if self.same_at_paths(sha, parent)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn same_at_paths(&mut self, a: Sha, b: Sha) -> Result<bool> {
+        let a = self.info(a)?.tree;
+        let b = self.info(b)?.tree;
+        for path in &self.paths {
+            if self.repo.find_path_entry(a, path)? != self.repo.find_path_entry(b, path)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    ///
+    /// Takes the next commit off the queue, going on to its parents. Hidden
+    /// commits pass that on to their parents, and come out as `None`.
+    ///
+    fn step(&mut self) -> Result<Option<Option<Sha>>> {
+        let (_, _, sha) = match self.queue.pop() {
+            Some(queued) => queued,
+            None => return Ok(None),
+        };
+        if self.hidden.contains(&sha) {
+            let parents = self.info(sha)?.parents.clone();
+            self.walked.insert(sha, Vec::new());
+            for parent in parents {
+                self.mark_hidden(parent)?;
+            }
+            return Ok(Some(None));
+        }
+        let parents = self.parents_to_follow(sha)?;
+        for parent in &parents {
+            self.enqueue(*parent)?;
+        }
+        self.walked.insert(sha, parents);
+        Ok(Some(Some(sha)))
+    }
+
+    ///
+    /// Walks everything there is to walk, for when a commit can't be
+    /// produced until it's known that no later commit hides it.
+    ///
+    fn limit(&mut self) -> Result<VecDeque<Sha>> {
+        let mut found = Vec::new();
+        let mut slop = SLOP;
+        while let Some(sha) = self.step()? {
+            found.extend(sha);
+            if self
+                .queue
+                .iter()
+                .all(|(_, _, sha)| self.hidden.contains(sha))
+            {
+                slop -= 1;
+                if slop == 0 {
+                    break;
+                }
+            } else {
+                slop = SLOP;
+            }
+        }
+        // A commit may have been found before a younger one hid it.
+        found.retain(|sha| !self.hidden.contains(sha));
+        if self.topo_order {
+            found = self.topo_sort(found);
+        }
+
+        let mut shown = VecDeque::with_capacity(found.len());
+        for sha in found {
+            if self.is_shown(sha)? {
+                shown.push_back(sha);
+            }
+        }
+        Ok(shown)
+    }
+
+    fn topo_sort(&self, commits: Vec<Sha>) -> Vec<Sha> {
+        let listed = commits.iter().collect::<HashSet<_>>();
+        let mut children = HashMap::<Sha, usize>::new();
+        for sha in &commits {
+            for parent in &self.walked[sha] {
+                if listed.contains(parent) {
+                    *children.entry(*parent).or_default() += 1;
+                }
+            }
+        }
+        // The stack is popped from the end, so the youngest tip goes last.
+        let mut stack = commits
+            .iter()
+            .rev()
+            .filter(|sha| !children.contains_key(sha))
+            .copied()
+            .collect::<Vec<_>>();
+
+        let mut sorted = Vec::with_capacity(commits.len());
+        while let Some(sha) = stack.pop() {
+            for parent in &self.walked[&sha] {
+                if let Some(remaining) = children.get_mut(parent) {
+                    *remaining -= 1;
+                    if *remaining == 0 {
+                        stack.push(*parent);
+                    }
+                }
+            }
+            sorted.push(sha);
+        }
+        sorted
+    }
+
+    fn next_commit(&mut self) -> Result<Option<Sha>> {
+        if self.limited.is_none() && (self.topo_order || !self.hidden.is_empty()) {
+            self.limited = Some(self.limit()?);
+        }
+        if let Some(limited) = &mut self.limited {
+            return Ok(limited.pop_front());
+        }
+        while let Some(sha) = self.step()? {
+            match sha {
+                Some(sha) if self.is_shown(sha)? => return Ok(Some(sha)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'r> Iterator for RevWalk<'r> {
+    type Item = Result<Sha>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_commit() {
+            Ok(sha) => sha.map(Ok),
+            Err(e) => {
+                // There's no going on after an error.
+                self.queue.clear();
+                self.limited = Some(VecDeque::new());
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn walk<F>(revs: &[&str], configure: F) -> Vec<String>
+    where
+        F: FnOnce(RevWalk) -> RevWalk,
+    {
+        let repo = Repo::open("tests/data/repos/simple.git").unwrap();
+        let mut walk = configure(RevWalk::new(&repo));
+        walk.push_revs(&repo.parse_rev_args(revs).unwrap()).unwrap();
+        walk.map(|sha| sha.unwrap().hex()[..7].to_owned()).collect()
+    }
+
+    #[test]
+    fn walking_history() {
+        let all = [
+            "33676d1", "59ca219", "63dc0a2", "8f714d9", "3c7cfac", "718e7fc", "2f2466c", "fb6fb3d",
+        ];
+        assert_eq!(walk(&["HEAD"], |w| w), all);
+        assert_eq!(walk(&["HEAD"], |w| w.topo_order(true)), all);
+        assert_eq!(
+            walk(&["HEAD"], |w| w.first_parent(true)),
+            ["33676d1", "59ca219", "63dc0a2", "8f714d9", "3c7cfac", "2f2466c", "fb6fb3d"]
+        );
+        assert_eq!(walk(&["HEAD~4", "^test"], |w| w), ["3c7cfac", "2f2466c"]);
+        assert_eq!(
+            walk(&["test...HEAD~3"], |w| w),
+            ["8f714d9", "3c7cfac", "2f2466c"]
+        );
+    }
+
+    #[test]
+    fn finding_the_boundary() {
+        let repo = Repo::open("tests/data/repos/simple.git").unwrap();
+        let mut walk = RevWalk::new(&repo);
+        walk.push_revs(&repo.parse_rev_args(&["HEAD~4", "^test"]).unwrap())
+            .unwrap();
+        assert_eq!(walk.by_ref().count(), 2);
+        let boundary = walk
+            .boundary()
+            .iter()
+            .map(|sha| sha.hex()[..7].to_owned())
+            .collect::<Vec<_>>();
+        // The merge's second parent, and the first parent's parent.
+        assert_eq!(boundary, ["718e7fc", "fb6fb3d"]);
+    }
+
+    #[test]
+    fn limiting_paths() {
+        let paths = |paths: &[&str]| paths.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        // Both sides of the merge change git.txt, so the merge is shown
+        // along with both of them.
+        assert_eq!(
+            walk(&["HEAD~4"], |w| w.limit_paths(paths(&["git.txt"]))),
+            ["3c7cfac", "718e7fc", "2f2466c", "fb6fb3d"]
+        );
+        assert_eq!(
+            walk(&["HEAD"], |w| w.limit_paths(paths(&["missing.txt"]))),
+            Vec::<String>::new()
+        );
+    }
+}
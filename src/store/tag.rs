@@ -0,0 +1,94 @@
+use std::str;
+
+use nom::bytes::complete as bytes;
+use nom::bytes::complete::tag;
+use nom::character::complete as character;
+use nom::combinator::map;
+use nom::combinator::map_res;
+use nom::sequence;
+use nom::IResult;
+
+use crate::store::ObjectType;
+use crate::store::PackedObject;
+use crate::store::Sha;
+
+///
+/// An annotated tag, pointing at another object in the database.
+///
+pub struct Tag<'a> {
+    pub object: Sha,
+    #[allow(dead_code)]
+    pub obj_type: ObjectType,
+    #[allow(dead_code)]
+    pub name: &'a str,
+}
+
+impl<'a> Tag<'a> {
+    pub fn from_raw(raw: &'a PackedObject) -> Option<Self> {
+        match parse_tag::<(&[u8], nom::error::ErrorKind)>(&raw.content) {
+            IResult::Ok((_, tag)) => Some(tag),
+            _ => None,
+        }
+    }
+}
+
+fn parse_object_type(input: &[u8]) -> Result<ObjectType, ()> {
+    match input {
+        b"commit" => Ok(ObjectType::Commit),
+        b"tree" => Ok(ObjectType::Tree),
+        b"blob" => Ok(ObjectType::Blob),
+        b"tag" => Ok(ObjectType::Tag),
+        _ => Err(()),
+    }
+}
+
+fn parse_tag<'a, E>(input: &'a [u8]) -> IResult<&'a [u8], Tag<'a>, E>
+where
+    E: nom::error::ParseError<&'a [u8]>,
+    E: nom::error::FromExternalError<&'a [u8], str::Utf8Error>,
+    E: nom::error::FromExternalError<&'a [u8], super::DecodeShaError>,
+    E: nom::error::FromExternalError<&'a [u8], ()>,
+{
+    let parts = sequence::tuple((
+        sequence::delimited(
+            tag("object "),
+            map_res(bytes::take(40usize), Sha::from_hex),
+            character::newline,
+        ),
+        sequence::delimited(
+            tag("type "),
+            map_res(bytes::take_until("\n"), parse_object_type),
+            character::newline,
+        ),
+        sequence::delimited(
+            tag("tag "),
+            map_res(bytes::take_until("\n"), str::from_utf8),
+            character::newline,
+        ),
+    ));
+    map(parts, |(object, obj_type, name)| Tag {
+        object,
+        obj_type,
+        name,
+    })(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tag() {
+        let input = b"object 3c7cfac73a699ef415bc737ce5529ac66c5692a9\n\
+            type commit\n\
+            tag test_tag\n\
+            tagger Christian Briones <cwbriones@gmail.com> 1453960206 -0800\n\
+            \n\
+            This is an example of an annotated tag.\n";
+        let object = PackedObject::new(ObjectType::Tag, input.to_vec());
+        let tag = Tag::from_raw(&object).expect("failed to parse tag");
+        assert_eq!(tag.object.hex(), "3c7cfac73a699ef415bc737ce5529ac66c5692a9");
+        assert_eq!(tag.obj_type, ObjectType::Commit);
+        assert_eq!(tag.name, "test_tag");
+    }
+}
@@ -34,6 +34,12 @@ pub enum EntryMode {
 
 impl Tree {
     pub fn parse(content: &[u8]) -> Option<Self> {
+        if content.is_empty() {
+            // The empty tree is valid, but has no entries to parse.
+            return Some(Tree {
+                entries: Vec::new(),
+            });
+        }
         if let Ok((_, entries)) = parse_tree(content) {
             Some(Tree { entries })
         } else {
@@ -0,0 +1,33 @@
+//!
+//! Helpers shared by the tests of several modules.
+//!
+use std::fs;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+///
+/// Copies the directory `from` and everything beneath it to `to`.
+///
+pub fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), &target).unwrap();
+        }
+    }
+}
+
+///
+/// Copies the simple.git test repository into `dir`, returning its path.
+///
+pub fn copy_repo(dir: &Path) -> PathBuf {
+    let repo = dir.join("simple.git");
+    copy_dir(Path::new("tests/data/repos/simple.git"), &repo);
+    repo
+}